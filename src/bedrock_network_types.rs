@@ -0,0 +1,627 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    openai_network_types::{AssistantMessage, Function, Roles, TokenUsage, Tool, ToolCall},
+    provider::{
+        ProviderConversation,
+        ProviderMessage,
+        default_max_output_tokens,
+        parse_json_object_or_wrap,
+        tools_enabled,
+    },
+    types::AssistantSettings,
+};
+
+/// Body of a Bedrock Converse (`/model/{model}/converse`) or ConverseStream call.
+///
+/// The model id travels in the url, so it is not part of the payload.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BedrockConverseRequest {
+    messages: Vec<BedrockMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    system: Vec<BedrockContentBlock>,
+    inference_config: BedrockInferenceConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_config: Option<BedrockToolConfig>,
+}
+
+impl BedrockConverseRequest {
+    pub(crate) fn from_conversation(
+        settings: &AssistantSettings,
+        conversation: ProviderConversation,
+    ) -> Self {
+        let mut messages: Vec<BedrockMessage> = Vec::new();
+
+        // Converse rejects two consecutive turns with the same role,
+        // so e.g. several tool results end up in a single user turn.
+        for message in conversation
+            .messages
+            .into_iter()
+            .filter_map(BedrockMessage::from_provider_message)
+        {
+            match messages.last_mut() {
+                Some(last) if last.role == message.role => {
+                    last.content
+                        .extend(message.content)
+                }
+                _ => messages.push(message),
+            }
+        }
+
+        Self {
+            messages,
+            system: conversation
+                .system_message
+                .map(|text| vec![BedrockContentBlock::Text(text)])
+                .unwrap_or_default(),
+            inference_config: BedrockInferenceConfig {
+                max_tokens: default_max_output_tokens(settings),
+                temperature: settings.temperature,
+                top_p: settings.top_p,
+            },
+            tool_config: tools_enabled(settings).map(|tools| {
+                BedrockToolConfig {
+                    tools: tools
+                        .into_iter()
+                        .filter_map(BedrockTool::from_openai_tool)
+                        .collect(),
+                }
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BedrockInferenceConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct BedrockToolConfig {
+    tools: Vec<BedrockTool>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum BedrockTool {
+    ToolSpec(BedrockToolSpec),
+}
+
+impl BedrockTool {
+    fn from_openai_tool(tool: Tool) -> Option<Self> {
+        let function = tool.function?;
+        Some(Self::ToolSpec(BedrockToolSpec {
+            name: function.name,
+            description: function.description,
+            input_schema: BedrockInputSchema {
+                json: function
+                    .parameters
+                    .unwrap_or_default(),
+            },
+        }))
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BedrockToolSpec {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    input_schema: BedrockInputSchema,
+}
+
+#[derive(Debug, Serialize)]
+struct BedrockInputSchema {
+    json: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct BedrockMessage {
+    role: String,
+    content: Vec<BedrockContentBlock>,
+}
+
+impl BedrockMessage {
+    fn from_provider_message(message: ProviderMessage) -> Option<Self> {
+        let (role, content) = match message.role {
+            Roles::System | Roles::Developer => return None,
+            Roles::Tool => {
+                (
+                    "user",
+                    vec![BedrockContentBlock::ToolResult(
+                        BedrockToolResult {
+                            tool_use_id: message
+                                .tool_call_id
+                                .unwrap_or_default(),
+                            content: vec![BedrockContentBlock::Text(
+                                message.content,
+                            )],
+                        },
+                    )],
+                )
+            }
+            Roles::Assistant => {
+                let mut content = Vec::new();
+                if !message.content.is_empty() {
+                    content.push(BedrockContentBlock::Text(
+                        message.content,
+                    ));
+                }
+                if let Some(tool_calls) = message.tool_calls {
+                    content.extend(
+                        tool_calls
+                            .into_iter()
+                            .map(|call| {
+                                BedrockContentBlock::ToolUse(BedrockToolUse {
+                                    tool_use_id: call.id,
+                                    name: call.function.name,
+                                    input: parse_json_object_or_wrap(&call.function.arguments),
+                                })
+                            }),
+                    );
+                }
+                ("assistant", content)
+            }
+            Roles::User => {
+                if message.content.is_empty() {
+                    return None;
+                }
+                (
+                    "user",
+                    vec![BedrockContentBlock::Text(
+                        message.content,
+                    )],
+                )
+            }
+        };
+
+        if content.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            role: role.to_string(),
+            content,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum BedrockContentBlock {
+    Text(String),
+    ToolUse(BedrockToolUse),
+    ToolResult(BedrockToolResult),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BedrockToolUse {
+    tool_use_id: String,
+    name: String,
+    #[serde(default)]
+    input: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BedrockToolResult {
+    tool_use_id: String,
+    content: Vec<BedrockContentBlock>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct BedrockConverseResponse {
+    output: Option<BedrockOutput>,
+    usage: Option<BedrockUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BedrockOutput {
+    message: Option<BedrockResponseMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BedrockResponseMessage {
+    #[serde(default)]
+    content: Vec<BedrockResponseBlock>,
+}
+
+/// Response blocks are read field by field, so block kinds we do not handle (e.g. `reasoningContent`) are skipped.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BedrockResponseBlock {
+    text: Option<String>,
+    tool_use: Option<BedrockToolUse>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BedrockUsage {
+    input_tokens: usize,
+    output_tokens: usize,
+    total_tokens: usize,
+}
+
+impl From<BedrockUsage> for TokenUsage {
+    fn from(value: BedrockUsage) -> Self {
+        Self {
+            prompt_tokens: value.input_tokens,
+            completion_tokens: value.output_tokens,
+            total_tokens: value.total_tokens,
+            total_time: None,
+            queue_time: None,
+            cache_creation_input_tokens: None,
+            cache_read_input_tokens: None,
+        }
+    }
+}
+
+impl BedrockConverseResponse {
+    pub(crate) fn into_assistant_message(self) -> AssistantMessage {
+        let mut content_parts = Vec::new();
+        let mut tool_calls = Vec::new();
+
+        for block in self
+            .output
+            .and_then(|output| output.message)
+            .map(|message| message.content)
+            .unwrap_or_default()
+        {
+            if let Some(text) = block.text {
+                content_parts.push(text);
+            }
+            if let Some(tool_use) = block.tool_use {
+                tool_calls.push(ToolCall {
+                    id: tool_use.tool_use_id,
+                    r#type: "function".to_string(),
+                    thought_signature: None,
+                    function: Function {
+                        name: tool_use.name,
+                        arguments: serde_json::to_string(&tool_use.input)
+                            .unwrap_or_else(|_| "{}".to_string()),
+                    },
+                });
+            }
+        }
+
+        AssistantMessage {
+            role: Roles::Assistant,
+            content: if content_parts.is_empty() { None } else { Some(content_parts.join("")) },
+            tool_calls: if tool_calls.is_empty() { None } else { Some(tool_calls) },
+            provider_metadata: None,
+            usage: self
+                .usage
+                .map(TokenUsage::from),
+            response_id: None,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
+        }
+    }
+}
+
+/// Payload of a single ConverseStream event; which fields are set depends on the `:event-type` header.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BedrockStreamEvent {
+    content_block_index: Option<usize>,
+    start: Option<BedrockBlockStart>,
+    delta: Option<BedrockBlockDelta>,
+    usage: Option<BedrockUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BedrockBlockStart {
+    tool_use: Option<BedrockToolUseStart>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BedrockToolUseStart {
+    tool_use_id: String,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BedrockBlockDelta {
+    text: Option<String>,
+    tool_use: Option<BedrockToolUseDelta>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BedrockToolUseDelta {
+    #[serde(default)]
+    input: String,
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct BedrockStreamState {
+    pub(crate) text: String,
+    pub(crate) tool_calls: Vec<ToolCall>,
+    pub(crate) usage: Option<TokenUsage>,
+    tool_call_by_block: HashMap<usize, usize>,
+}
+
+impl BedrockStreamState {
+    /// Folds a stream event into the state and returns the text delta to forward, if any.
+    pub(crate) fn apply_event(&mut self, event_type: &str, event: BedrockStreamEvent) -> Option<String> {
+        match event_type {
+            "contentBlockStart" => {
+                if let (Some(index), Some(tool_use)) = (
+                    event.content_block_index,
+                    event
+                        .start
+                        .and_then(|start| start.tool_use),
+                ) {
+                    self.tool_call_by_block
+                        .insert(index, self.tool_calls.len());
+                    self.tool_calls
+                        .push(ToolCall {
+                            id: tool_use.tool_use_id,
+                            r#type: "function".to_string(),
+                            thought_signature: None,
+                            function: Function {
+                                name: tool_use.name,
+                                arguments: String::new(),
+                            },
+                        });
+                }
+                None
+            }
+            "contentBlockDelta" => {
+                let delta = event.delta?;
+                if let Some(tool_use) = delta.tool_use {
+                    let tool_call_index = event
+                        .content_block_index
+                        .and_then(|index| {
+                            self.tool_call_by_block
+                                .get(&index)
+                                .copied()
+                        })?;
+                    self.tool_calls[tool_call_index]
+                        .function
+                        .arguments
+                        .push_str(&tool_use.input);
+                    return None;
+                }
+                let text = delta.text?;
+                self.text.push_str(&text);
+                Some(text)
+            }
+            "metadata" => {
+                self.usage = event
+                    .usage
+                    .map(TokenUsage::from);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn into_assistant_message(self) -> AssistantMessage {
+        AssistantMessage {
+            role: Roles::Assistant,
+            content: if self.text.is_empty() { None } else { Some(self.text) },
+            tool_calls: if self.tool_calls.is_empty() { None } else { Some(self.tool_calls) },
+            provider_metadata: None,
+            usage: self.usage,
+            response_id: None,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
+        }
+    }
+}
+
+/// Builds `{endpoint}/model/{model}/converse` (or `converse-stream`), replacing any model path already in the url.
+pub(crate) fn bedrock_converse_url(base_url: &str, model: &str, stream: bool) -> String {
+    let trimmed = base_url.trim_end_matches('/');
+    let prefix = trimmed
+        .find("/model/")
+        .map(|index| &trimmed[.. index])
+        .unwrap_or(trimmed);
+    let endpoint = if stream { "converse-stream" } else { "converse" };
+    // Model ids (`anthropic.claude-...-v1:0`) and ARNs must be sent percent-encoded for SigV4 to match.
+    let model = model
+        .replace(':', "%3A")
+        .replace('/', "%2F");
+
+    format!("{prefix}/model/{model}/{endpoint}")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        provider::prepare_payload,
+        types::{ApiType, CacheEntry, InputKind, SublimeInputContent},
+    };
+
+    #[test]
+    fn test_bedrock_converse_url() {
+        assert_eq!(
+            bedrock_converse_url(
+                "https://bedrock-runtime.us-east-1.amazonaws.com/",
+                "anthropic.claude-3-5-sonnet-20240620-v1:0",
+                false
+            ),
+            "https://bedrock-runtime.us-east-1.amazonaws.com/model/anthropic.claude-3-5-sonnet-20240620-v1%3A0/converse"
+        );
+        assert_eq!(
+            bedrock_converse_url(
+                "https://bedrock-runtime.us-east-1.amazonaws.com/model/other/converse",
+                "amazon.nova-pro-v1:0",
+                true
+            ),
+            "https://bedrock-runtime.us-east-1.amazonaws.com/model/amazon.nova-pro-v1%3A0/converse-stream"
+        );
+    }
+
+    #[test]
+    fn test_prepare_bedrock_payload_with_tool_roundtrip() {
+        let settings = AssistantSettings {
+            api_type: ApiType::Bedrock,
+            assistant_role: Some("System role".to_string()),
+            advertisement: false,
+            tools: Some(true),
+            max_tokens: Some(512),
+            ..Default::default()
+        };
+
+        let cache_entries = vec![
+            CacheEntry {
+                content: Some("Read two files".to_string()),
+                thinking: None,
+                path: None,
+                scope: None,
+                role: Roles::User,
+                tool_calls: None,
+                tool_call_id: None,
+                provider_metadata: None,
+                created_at: None,
+                citations: None,
+                logprobs: None,
+                user: None,
+                finish_reason: None,
+            },
+            CacheEntry {
+                content: None,
+                thinking: None,
+                path: None,
+                scope: None,
+                role: Roles::Assistant,
+                tool_calls: Some(vec![ToolCall {
+                    id: "tooluse_1".to_string(),
+                    r#type: "function".to_string(),
+                    thought_signature: None,
+                    function: Function {
+                        name: "read_region_content".to_string(),
+                        arguments: "{\"file_path\":\"a.rs\"}".to_string(),
+                    },
+                }]),
+                tool_call_id: None,
+                provider_metadata: None,
+                created_at: None,
+                citations: None,
+                logprobs: None,
+                user: None,
+                finish_reason: None,
+            },
+        ];
+        let inputs = vec![
+            SublimeInputContent {
+                content: Some("a".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::FunctionResult,
+                tool_id: Some("tooluse_1".to_string()),
+            },
+            SublimeInputContent {
+                content: Some("b".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::FunctionResult,
+                tool_id: Some("tooluse_2".to_string()),
+            },
+        ];
+
+        let payload: Value =
+            serde_json::from_str(&prepare_payload(&settings, cache_entries, inputs).unwrap()).unwrap();
+
+        assert!(payload.get("model").is_none());
+        assert!(
+            payload["system"][0]["text"]
+                .as_str()
+                .unwrap()
+                .starts_with("System role")
+        );
+        assert_eq!(
+            payload["inferenceConfig"]["maxTokens"],
+            512
+        );
+        assert_eq!(
+            payload["messages"][1],
+            json!({
+                "role": "assistant",
+                "content": [{
+                    "toolUse": {
+                        "toolUseId": "tooluse_1",
+                        "name": "read_region_content",
+                        "input": {"file_path": "a.rs"}
+                    }
+                }]
+            })
+        );
+        let results = payload["messages"][2]["content"]
+            .as_array()
+            .unwrap();
+        assert_eq!(payload["messages"][2]["role"], "user");
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results[1],
+            json!({"toolResult": {"toolUseId": "tooluse_2", "content": [{"text": "b"}]}})
+        );
+        assert!(payload["toolConfig"]["tools"][0]["toolSpec"]["inputSchema"]["json"].is_object());
+    }
+
+    #[test]
+    fn test_parse_bedrock_response_into_assistant_message() {
+        let response: BedrockConverseResponse = serde_json::from_value(json!({
+            "output": {
+                "message": {
+                    "role": "assistant",
+                    "content": [
+                        {"reasoningContent": {"reasoningText": {"text": "hm"}}},
+                        {"text": "Reading"},
+                        {"toolUse": {"toolUseId": "tooluse_1", "name": "read_region_content", "input": {"file_path": "a.rs"}}}
+                    ]
+                }
+            },
+            "stopReason": "tool_use",
+            "usage": {"inputTokens": 10, "outputTokens": 5, "totalTokens": 15}
+        }))
+        .unwrap();
+
+        let message = response.into_assistant_message();
+        assert_eq!(
+            message.content.as_deref(),
+            Some("Reading")
+        );
+        let tool_calls = message.tool_calls.unwrap();
+        assert_eq!(tool_calls[0].id, "tooluse_1");
+        assert_eq!(
+            tool_calls[0]
+                .function
+                .arguments,
+            "{\"file_path\":\"a.rs\"}"
+        );
+        assert_eq!(
+            message
+                .usage
+                .map(|usage| usage.total_tokens),
+            Some(15)
+        );
+    }
+}
@@ -1,11 +1,53 @@
 use std::{
+    fmt::Debug,
     fs::{File, OpenOptions},
     io::{BufRead, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Result;
-use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use anyhow::{Result, anyhow};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use log::{debug, warn};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    openai_network_types::TokenUsage,
+    types::{CacheEntry, SearchType, TrimStrategy},
+};
+
+/// Storage of the chat history and the current assistant, entries travel as raw json.
+///
+/// Entries are addressed by their position in the store, a malformed one keeps its position taken.
+pub trait CacheBackend: Debug + Send + Sync {
+    /// The entries that parse, each with its position.
+    fn read_indexed_entries(&self) -> Result<Vec<(usize, Value)>>;
+    fn read_entries(&self) -> Result<Vec<Value>> {
+        Ok(self
+            .read_indexed_entries()?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect())
+    }
+    fn write_entry(&self, entry: Value) -> Result<()>;
+    /// Removes entries cached before the `cutoff` unix timestamp.
+    fn drop_older_than(&self, cutoff: i64) -> Result<()>;
+    fn drop_first(&self, lines_num: usize) -> Result<()>;
+    fn drop_last(&self, lines_num: usize) -> Result<()>;
+    /// Removes the entries with positions in `start..end`.
+    fn drop_range(&self, start: usize, end: usize) -> Result<()>;
+    /// Puts `entry` in place of the one at `index`, fails when there is none.
+    fn replace_entry(&self, index: usize, entry: Value) -> Result<()>;
+    fn drop_all(&self) -> Result<()>;
+    /// Positions of the stored entries that fail to parse or do not match their checksum.
+    fn corrupted_entries(&self) -> Result<Vec<usize>>;
+    fn write_model(&self, model: Value) -> Result<()>;
+    fn read_model(&self) -> Result<Value>;
+}
 
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
@@ -13,6 +55,21 @@ pub struct Cacher {
     pub current_model_file: String,
     pub history_file: String,
     pub tokens_count_file: String,
+    pub response_id_file: String,
+    /// Entries older than this many seconds are pruned before every read.
+    pub max_age_secs: Option<u64>,
+    /// History is trimmed with `trim_strategy` after a write takes it past this many entries.
+    pub max_history_entries: Option<usize>,
+    pub trim_strategy: TrimStrategy,
+    backend: Arc<dyn CacheBackend>,
+}
+
+/// Seconds since the unix epoch, the clock `created_at` of cache entries is measured in.
+pub(crate) fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
 }
 
 #[allow(unused)]
@@ -23,58 +80,516 @@ impl Cacher {
         use std::path::{Path, PathBuf};
 
         // TODO: Seems that this conditioning is useless and should be removed by expecting the absolute path only.
-        let (history_file, current_model_file, tokens_count_file) = if Path::new(name).is_absolute() {
-            let base_path = PathBuf::from(name);
-            (
-                base_path
-                    .join("chat_history.jl")
-                    .to_string_lossy()
-                    .into_owned(),
-                base_path
-                    .join("current_assistant.json")
-                    .to_string_lossy()
-                    .into_owned(),
-                base_path
-                    .join("tokens_count.json")
-                    .to_string_lossy()
-                    .into_owned(),
-            )
-        } else {
-            let name_prefix = format!("{}_", name);
-            (
-                format!(
-                    "{}/{}chat_history.jl",
-                    cache_dir, name_prefix
-                ),
-                format!(
-                    "{}/{}current_assistant.json",
-                    cache_dir, name_prefix
-                ),
-                format!(
-                    "{}/{}tokens_count.json",
-                    cache_dir, name_prefix
-                ),
-            )
+        let (history_file, current_model_file, tokens_count_file, response_id_file) =
+            if Path::new(name).is_absolute() {
+                let base_path = PathBuf::from(name);
+                (
+                    base_path
+                        .join("chat_history.jl")
+                        .to_string_lossy()
+                        .into_owned(),
+                    base_path
+                        .join("current_assistant.json")
+                        .to_string_lossy()
+                        .into_owned(),
+                    base_path
+                        .join("tokens_count.json")
+                        .to_string_lossy()
+                        .into_owned(),
+                    base_path
+                        .join("last_response_id.json")
+                        .to_string_lossy()
+                        .into_owned(),
+                )
+            } else {
+                let name_prefix = format!("{}_", name);
+                (
+                    format!(
+                        "{}/{}chat_history.jl",
+                        cache_dir, name_prefix
+                    ),
+                    format!(
+                        "{}/{}current_assistant.json",
+                        cache_dir, name_prefix
+                    ),
+                    format!(
+                        "{}/{}tokens_count.json",
+                        cache_dir, name_prefix
+                    ),
+                    format!(
+                        "{}/{}last_response_id.json",
+                        cache_dir, name_prefix
+                    ),
+                )
+            };
+
+        Self::with_files(
+            history_file,
+            current_model_file,
+            tokens_count_file,
+            response_id_file,
+        )
+    }
+
+    /// Same file layout as `new`, but history and model live in `chat_history.sqlite`.
+    /// Existing `chat_history.jl` and `current_assistant.json` are imported on first open.
+    pub fn new_sqlite(name: &str) -> Result<Self> {
+        let mut cacher = Self::new(name);
+        let database_file = match cacher
+            .history_file
+            .strip_suffix(".jl")
+        {
+            Some(stem) => format!("{stem}.sqlite"),
+            None => format!("{}.sqlite", cacher.history_file),
         };
+        cacher.backend = Arc::new(SqliteCacheBackend::open(
+            &database_file,
+            &cacher.history_file,
+            &cacher.current_model_file,
+        )?);
+        Ok(cacher)
+    }
 
+    /// Same as `new`, but history lines are zstd compressed; plain lines written before stay readable.
+    pub fn new_compressed(name: &str) -> Self {
+        let mut cacher = Self::new(name);
+        cacher.backend = Arc::new(FileCacheBackend {
+            history_file: cacher.history_file.clone(),
+            current_model_file: cacher
+                .current_model_file
+                .clone(),
+            compress: true,
+        });
+        cacher
+    }
+
+    /// History and model kept in memory only, for one-off requests that leave no trace on disk.
+    ///
+    /// The token count and the response id are not kept at all.
+    pub fn in_memory() -> Self {
+        let mut cacher = Self::with_files(
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+        );
+        cacher.backend = Arc::new(MemoryCacheBackend::default());
+        cacher
+    }
+
+    pub fn with_files(
+        history_file: String,
+        current_model_file: String,
+        tokens_count_file: String,
+        response_id_file: String,
+    ) -> Self {
         Self {
+            backend: Arc::new(FileCacheBackend {
+                history_file: history_file.clone(),
+                current_model_file: current_model_file.clone(),
+                compress: false,
+            }),
             current_model_file,
             history_file,
             tokens_count_file,
+            response_id_file,
+            max_age_secs: None,
+            max_history_entries: None,
+            trim_strategy: TrimStrategy::default(),
         }
     }
 
     fn create_file_if_not_exists(path: &str) -> Result<()> {
         if !Path::new(path).exists() {
             File::create(path)?;
-            println!("File created successfully.");
+            debug!("Cache file {} created", path);
+        }
+        Ok(())
+    }
+
+    /// Drops the entries older than `max_age_secs`, a no-op when it is unset.
+    pub fn prune_expired(&self) -> Result<()> {
+        match self.max_age_secs {
+            Some(max_age_secs) => {
+                self.backend
+                    .drop_older_than(unix_timestamp() - max_age_secs as i64)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn read_entries<T: DeserializeOwned>(&self) -> Result<Vec<T>> {
+        Ok(self
+            .read_indexed_entries()?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect())
+    }
+
+    /// The entries that parse as `T`, each with the position `read_entry`, `write_entry_at` and
+    /// `drop_range` take, which counts the malformed lines too.
+    pub fn read_indexed_entries<T: DeserializeOwned>(&self) -> Result<Vec<(usize, T)>> {
+        self.prune_expired()?;
+
+        Ok(self
+            .backend
+            .read_indexed_entries()?
+            .into_iter()
+            .filter_map(|(num, entry)| {
+                serde_json::from_value::<T>(entry)
+                    .map(|entry| (num, entry))
+                    .map_err(|err| {
+                        warn!(
+                            "Malformed line skipped: {} (Error: {})",
+                            num, err
+                        )
+                    })
+                    .ok()
+            })
+            .collect())
+    }
+
+    pub fn write_entry<T: Serialize>(&self, entry: &T) -> Result<()> {
+        self.backend
+            .write_entry(serde_json::to_value(entry)?)?;
+
+        match self.max_history_entries {
+            Some(max_history_entries) => self.trim_to(max_history_entries, self.trim_strategy),
+            None => Ok(()),
+        }
+    }
+
+    /// The entry at `index`, `None` past the end of the history or when that one is malformed.
+    pub fn read_entry<T: DeserializeOwned>(&self, index: usize) -> Result<Option<T>> {
+        self.prune_expired()?;
+
+        self.backend
+            .read_indexed_entries()?
+            .into_iter()
+            .find(|(num, _)| *num == index)
+            .map(|(_, entry)| Ok(serde_json::from_value(entry)?))
+            .transpose()
+    }
+
+    /// Rewrites the entry at `index` in place, the history is left as is when there is none.
+    pub fn write_entry_at<T: Serialize>(&self, index: usize, entry: &T) -> Result<()> {
+        self.backend
+            .replace_entry(index, serde_json::to_value(entry)?)
+    }
+
+    /// Drops the oldest entries until at most `n` are left, along with the malformed lines among them.
+    pub fn trim_to(&self, n: usize, strategy: TrimStrategy) -> Result<()> {
+        let entries = self
+            .backend
+            .read_indexed_entries()?;
+        if entries.len() <= n {
+            return Ok(());
+        }
+
+        let keeps_system = strategy == TrimStrategy::TrimOldestExceptSystem
+            && entries
+                .first()
+                .and_then(|(_, entry)| entry.get("role"))
+                .and_then(Value::as_str)
+                == Some("system");
+        let excess = entries.len() - n;
+        // Where the kept entries start, right past the last one when none is kept.
+        let kept_from = |kept: usize| {
+            match entries.get(kept) {
+                Some((num, _)) => *num,
+                None => {
+                    entries
+                        .last()
+                        .map_or(0, |(num, _)| num + 1)
+                }
+            }
+        };
+
+        if keeps_system && n > 0 {
+            self.backend
+                .drop_range(entries[0].0 + 1, kept_from(1 + excess))
+        } else {
+            self.backend
+                .drop_first(kept_from(excess))
+        }
+    }
+
+    pub fn write_model<T: Serialize>(&self, model: &T) -> Result<()> {
+        self.backend
+            .write_model(serde_json::to_value(model)?)
+    }
+
+    pub fn read_model<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_value(
+            self.backend.read_model()?,
+        )?)
+    }
+
+    pub fn write_tokens_count<T: Serialize>(&self, tokens_count: &T) -> Result<()> {
+        let tokens_count_json = serde_json::to_string(tokens_count)?;
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.tokens_count_file)?;
+
+        writeln!(file, "{}", tokens_count_json)?;
+
+        Ok(())
+    }
+
+    /// Adds `usage` to the running total kept in `tokens_count_file`.
+    pub(crate) fn append_tokens_count(&self, usage: &TokenUsage) -> Result<()> {
+        let mut total = self
+            .read_tokens_count()
+            .unwrap_or_default();
+        total.accumulate(usage);
+        self.write_tokens_count(&total)
+    }
+
+    /// The usage total accumulated so far, `None` until the first request reported one.
+    pub(crate) fn read_tokens_count(&self) -> Option<TokenUsage> {
+        let file = File::open(&self.tokens_count_file).ok()?;
+        let reader = std::io::BufReader::new(file);
+
+        serde_json::from_reader(reader).ok()
+    }
+
+    /// Stores the id of the last Responses API response to chain the next turn onto it.
+    pub fn write_response_id(&self, response_id: &str) -> Result<()> {
+        let response_id_json = serde_json::to_string(response_id)?;
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.response_id_file)?;
+
+        writeln!(file, "{}", response_id_json)?;
+
+        Ok(())
+    }
+
+    pub fn read_response_id(&self) -> Option<String> {
+        let file = File::open(&self.response_id_file).ok()?;
+        let reader = std::io::BufReader::new(file);
+
+        serde_json::from_reader(reader).ok()
+    }
+
+    pub fn drop_response_id(&self) -> Result<()> {
+        if Path::new(&self.response_id_file).exists() {
+            std::fs::remove_file(&self.response_id_file)?;
         }
         Ok(())
     }
 
-    pub fn read_entries<T>(&self) -> Result<Vec<T>>
-    where T: for<'de> Deserialize<'de> {
-        Self::create_file_if_not_exists(&self.history_file);
+    /// Appends the `CacheEntry` objects of a JSON array to the history and returns how many were imported.
+    ///
+    /// Nothing is written unless every entry has a known role and either `content` or `tool_calls`.
+    pub fn import_json(&self, json_str: &str) -> Result<usize> {
+        let values: Vec<Value> = serde_json::from_str(json_str)?;
+        let entries = values
+            .into_iter()
+            .enumerate()
+            .map(|(index, value)| {
+                let mut entry: CacheEntry = serde_json::from_value(value).map_err(|e| {
+                    anyhow!(
+                        "Entry {} is not a valid cache entry: {}",
+                        index,
+                        e
+                    )
+                })?;
+                if entry.content.is_none() && entry.tool_calls.is_none() {
+                    return Err(anyhow!(
+                        "Entry {} has neither content nor tool_calls",
+                        index
+                    ));
+                }
+                entry.created_at = entry
+                    .created_at
+                    .or(Some(unix_timestamp()));
+                Ok(entry)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for entry in &entries {
+            self.write_entry(entry)?;
+        }
+        Ok(entries.len())
+    }
+
+    /// History entries whose content matches `query`, paired with their position in the history.
+    pub fn search(&self, query: &str, search_type: SearchType) -> Result<Vec<(usize, CacheEntry)>> {
+        let query_lowercased = query.to_lowercase();
+        let regex = match search_type {
+            SearchType::Regex => {
+                Some(
+                    regex::RegexBuilder::new(query)
+                        .case_insensitive(true)
+                        .build()?,
+                )
+            }
+            _ => None,
+        };
+
+        Ok(self
+            .read_indexed_entries::<CacheEntry>()?
+            .into_iter()
+            .filter(|(_, entry)| {
+                entry
+                    .content
+                    .as_deref()
+                    .is_some_and(|content| {
+                        match (search_type, &regex) {
+                            (SearchType::Regex, Some(regex)) => regex.is_match(content),
+                            (SearchType::StartsWith, _) => {
+                                content
+                                    .to_lowercase()
+                                    .starts_with(&query_lowercased)
+                            }
+                            _ => {
+                                content
+                                    .to_lowercase()
+                                    .contains(&query_lowercased)
+                            }
+                        }
+                    })
+            })
+            .collect())
+    }
+
+    /// Renders the whole history as a Markdown document, writes it to `path` and returns it.
+    pub fn export_markdown(&self, path: &str) -> Result<String> {
+        let markdown = self
+            .read_entries::<CacheEntry>()?
+            .iter()
+            .map(CacheEntry::to_markdown)
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let markdown = format!("{}\n", markdown);
+
+        std::fs::write(path, &markdown)?;
+        Ok(markdown)
+    }
+
+    /// Directory next to the history file where `generate_image` results are saved.
+    pub(crate) fn images_dir(&self) -> PathBuf {
+        Path::new(&self.history_file)
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join("images")
+    }
+
+    /// Line numbers of the history entries that are malformed or fail their checksum.
+    pub fn validate_history(&self) -> Vec<usize> {
+        self.backend
+            .corrupted_entries()
+            .unwrap_or_default()
+    }
+
+    pub fn drop_first(&self, lines_num: usize) -> Result<()> {
+        self.backend
+            .drop_first(lines_num)
+    }
+
+    /// Removes the `n` most recent entries, e.g. to retry a bad answer.
+    pub fn drop_last(&self, n: usize) -> Result<()> { self.backend.drop_last(n) }
+
+    /// Removes the entries with positions in `start..end`.
+    pub fn drop_range(&self, start: usize, end: usize) -> Result<()> {
+        if start > end {
+            return Err(anyhow!(
+                "Invalid range: start {} is past end {}",
+                start,
+                end
+            ));
+        }
+        self.backend
+            .drop_range(start, end)
+    }
+
+    pub fn drop_all(&self) -> Result<()> {
+        self.backend.drop_all()?;
+        // The server side thread refers to the wiped history, so it must not be continued.
+        self.drop_response_id()
+    }
+
+    #[cfg(test)]
+    fn sublime_cache() -> String { "~/Library/Caches/Sublime Text/Cache".to_string() }
+
+    #[cfg(not(test))]
+    fn sublime_cache() -> String {
+        "~/Library/Caches/Sublime Text/Cache".to_string()
+        // crate::sublime_python::get_sublime_cache()
+        //     .unwrap_or("~/Library/Caches/Sublime Text/Cache".to_string())
+    }
+}
+
+/// The original layout: history as JSON lines, the current assistant as a single JSON file.
+#[derive(Debug)]
+pub struct FileCacheBackend {
+    history_file: String,
+    current_model_file: String,
+    /// Write history lines as base64 encoded zstd frames instead of plain JSON.
+    compress: bool,
+}
+
+/// Separates a history line from the sha256 of its JSON.
+const CHECKSUM_MARKER: &str = " #crc:";
+
+impl FileCacheBackend {
+    fn encode_line(&self, entry: &Value) -> Result<String> {
+        let json = entry.to_string();
+        let checksum = Self::checksum(json.as_bytes());
+        let payload = if self.compress {
+            BASE64_STANDARD.encode(zstd::encode_all(
+                json.as_bytes(),
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?)
+        } else {
+            json
+        };
+        Ok(format!(
+            "{}{}{}",
+            payload, CHECKSUM_MARKER, checksum
+        ))
+    }
+
+    /// Returns the entry and whether it matches its checksum, `None` for lines written without one.
+    ///
+    /// Compressed and plain lines may be mixed in one file, whatever is not a zstd frame is read as JSON.
+    fn decode_line(line: &str) -> serde_json::Result<(Value, Option<bool>)> {
+        let (payload, checksum) = match line.rsplit_once(CHECKSUM_MARKER) {
+            Some((payload, checksum))
+                if checksum.len() == 64
+                    && checksum
+                        .chars()
+                        .all(|char| char.is_ascii_hexdigit()) =>
+            {
+                (payload, Some(checksum))
+            }
+            _ => (line, None),
+        };
+        let json = BASE64_STANDARD
+            .decode(payload)
+            .ok()
+            .and_then(|bytes| zstd::decode_all(bytes.as_slice()).ok())
+            .unwrap_or_else(|| payload.as_bytes().to_vec());
+
+        Ok((
+            serde_json::from_slice(&json)?,
+            checksum.map(|checksum| checksum == Self::checksum(&json)),
+        ))
+    }
+
+    fn checksum(json: &[u8]) -> String { format!("{:x}", Sha256::digest(json)) }
+}
+
+impl CacheBackend for FileCacheBackend {
+    fn read_indexed_entries(&self) -> Result<Vec<(usize, Value)>> {
+        Cacher::create_file_if_not_exists(&self.history_file).ok();
 
         let file = match File::open(&self.history_file) {
             Ok(file) => file,
@@ -88,221 +603,1653 @@ impl Cacher {
             .lines()
             .enumerate()
             .for_each(|(num, line)| {
-                serde_json::from_str::<T>(&line.unwrap_or_default())
-                    .map(|obj| entries.push(obj))
+                Self::decode_line(&line.unwrap_or_default())
+                    .map(|(obj, valid)| {
+                        if valid == Some(false) {
+                            warn!("Checksum mismatch on line {}", num);
+                        }
+                        entries.push((num, obj))
+                    })
                     .unwrap_or_else(|err| {
-                        eprintln!(
+                        warn!(
                             "Malformed line skipped: {} (Error: {})",
                             num, err
                         )
                     });
             });
 
-        Ok(entries)
+        Ok(entries)
+    }
+
+    fn write_entry(&self, entry: Value) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.history_file)?;
+
+        writeln!(file, "{}", self.encode_line(&entry)?)?;
+
+        Ok(())
+    }
+
+    fn drop_older_than(&self, cutoff: i64) -> Result<()> {
+        let file = match File::open(&self.history_file) {
+            Ok(file) => file,
+            Err(_) => return Ok(()),
+        };
+
+        let reader = std::io::BufReader::new(file);
+        let remaining_lines: Vec<_> = reader
+            .lines()
+            .map_while(Result::ok)
+            .filter(|line| {
+                // Entries without a timestamp predate expiration and are kept.
+                Self::decode_line(line)
+                    .ok()
+                    .and_then(|(entry, _)| {
+                        entry
+                            .get("created_at")
+                            .and_then(Value::as_i64)
+                    })
+                    .is_none_or(|created_at| created_at >= cutoff)
+            })
+            .collect();
+
+        let mut file = File::create(&self.history_file)?;
+
+        for line in remaining_lines {
+            writeln!(file, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    fn drop_first(&self, lines_num: usize) -> Result<()> {
+        let file = File::open(&self.history_file)?;
+
+        let reader = std::io::BufReader::new(file);
+        let remaining_lines: Vec<_> = reader
+            .lines()
+            .skip(lines_num)
+            .filter_map(Result::ok)
+            .collect();
+
+        let mut file = File::create(&self.history_file)?;
+
+        for line in remaining_lines {
+            writeln!(file, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    fn drop_last(&self, lines_num: usize) -> Result<()> {
+        let file = File::open(&self.history_file)?;
+
+        let reader = std::io::BufReader::new(file);
+        let mut remaining_lines: Vec<_> = reader
+            .lines()
+            .map_while(Result::ok)
+            .collect();
+        remaining_lines.truncate(
+            remaining_lines
+                .len()
+                .saturating_sub(lines_num),
+        );
+
+        let mut file = File::create(&self.history_file)?;
+
+        for line in remaining_lines {
+            writeln!(file, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    fn drop_range(&self, start: usize, end: usize) -> Result<()> {
+        let file = File::open(&self.history_file)?;
+
+        let reader = std::io::BufReader::new(file);
+        let remaining_lines: Vec<_> = reader
+            .lines()
+            .map_while(Result::ok)
+            .enumerate()
+            .filter(|(num, _)| !(start .. end).contains(num))
+            .map(|(_, line)| line)
+            .collect();
+
+        let mut file = File::create(&self.history_file)?;
+
+        for line in remaining_lines {
+            writeln!(file, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    fn replace_entry(&self, index: usize, entry: Value) -> Result<()> {
+        let file = File::open(&self.history_file)?;
+
+        let reader = std::io::BufReader::new(file);
+        let mut lines: Vec<_> = reader
+            .lines()
+            .map_while(Result::ok)
+            .collect();
+        let history_len = lines.len();
+        let line = lines
+            .get_mut(index)
+            .ok_or_else(|| {
+                anyhow!(
+                    "No history entry {} among {}",
+                    index,
+                    history_len
+                )
+            })?;
+        *line = self.encode_line(&entry)?;
+
+        // A crash halfway leaves the old history behind rather than a truncated one.
+        let tmp_file = format!("{}.tmp", self.history_file);
+        let mut file = File::create(&tmp_file)?;
+        for line in lines {
+            writeln!(file, "{}", line)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp_file, &self.history_file)?;
+
+        Ok(())
+    }
+
+    fn drop_all(&self) -> Result<()> {
+        File::create(&self.history_file)?;
+        Ok(())
+    }
+
+    fn corrupted_entries(&self) -> Result<Vec<usize>> {
+        let file = match File::open(&self.history_file) {
+            Ok(file) => file,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        Ok(std::io::BufReader::new(file)
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| {
+                !matches!(
+                    line.as_deref()
+                        .map(Self::decode_line),
+                    Ok(Ok((_, None | Some(true))))
+                )
+            })
+            .map(|(num, _)| num)
+            .collect())
+    }
+
+    fn write_model(&self, model: Value) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.current_model_file)?;
+
+        writeln!(file, "{}", model)?;
+
+        Ok(())
+    }
+
+    fn read_model(&self) -> Result<Value> {
+        Cacher::create_file_if_not_exists(&self.current_model_file).ok();
+
+        let file = File::open(&self.current_model_file)?;
+        let reader = std::io::BufReader::new(file);
+
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
+
+/// History and current assistant kept in a single SQLite database.
+#[derive(Debug)]
+pub struct SqliteCacheBackend {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteCacheBackend {
+    const SCHEMA_VERSION: i64 = 1;
+
+    /// Opens (or creates) the database, importing the JSON files of the file backend once.
+    pub fn open(database_file: &str, history_file: &str, current_model_file: &str) -> Result<Self> {
+        let connection = Connection::open(database_file)?;
+
+        let version: i64 = connection.query_row("PRAGMA user_version", [], |row| {
+            row.get(0)
+        })?;
+        if version < Self::SCHEMA_VERSION {
+            connection.execute_batch(
+                "BEGIN;
+                 CREATE TABLE IF NOT EXISTS entries (
+                     id INTEGER PRIMARY KEY AUTOINCREMENT,
+                     role TEXT,
+                     created_at INTEGER NOT NULL,
+                     data TEXT NOT NULL
+                 );
+                 CREATE INDEX IF NOT EXISTS entries_role ON entries (role);
+                 CREATE INDEX IF NOT EXISTS entries_created_at ON entries (created_at);
+                 CREATE TABLE IF NOT EXISTS model (
+                     id INTEGER PRIMARY KEY CHECK (id = 1),
+                     data TEXT NOT NULL
+                 );",
+            )?;
+
+            let backend = Self {
+                connection: Mutex::new(connection),
+            };
+            let legacy = FileCacheBackend {
+                history_file: history_file.to_string(),
+                current_model_file: current_model_file.to_string(),
+                compress: false,
+            };
+            if Path::new(history_file).exists() {
+                for entry in legacy.read_entries()? {
+                    backend.write_entry(entry)?;
+                }
+            }
+            if let (true, Ok(model)) = (
+                Path::new(current_model_file).exists(),
+                legacy.read_model(),
+            ) {
+                backend.write_model(model)?;
+            }
+
+            backend
+                .connection()?
+                .execute_batch(&format!(
+                    "PRAGMA user_version = {}; COMMIT;",
+                    Self::SCHEMA_VERSION
+                ))?;
+            return Ok(backend);
+        }
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> Result<std::sync::MutexGuard<'_, Connection>> {
+        self.connection
+            .lock()
+            .map_err(|_| anyhow!("SQLite cache connection is poisoned"))
+    }
+}
+
+impl CacheBackend for SqliteCacheBackend {
+    fn read_indexed_entries(&self) -> Result<Vec<(usize, Value)>> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare("SELECT data FROM entries ORDER BY id")?;
+        let entries = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .enumerate()
+            .filter_map(|(num, data)| {
+                data.map_err(anyhow::Error::from)
+                    .and_then(|data| {
+                        Ok((
+                            num,
+                            serde_json::from_str::<Value>(&data)?,
+                        ))
+                    })
+                    .map_err(|err| {
+                        warn!(
+                            "Malformed row skipped: {} (Error: {})",
+                            num, err
+                        )
+                    })
+                    .ok()
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn write_entry(&self, entry: Value) -> Result<()> {
+        let created_at = entry
+            .get("created_at")
+            .and_then(Value::as_i64)
+            .unwrap_or_else(unix_timestamp);
+        self.connection()?.execute(
+            "INSERT INTO entries (role, created_at, data) VALUES (?1, ?2, ?3)",
+            params![
+                entry
+                    .get("role")
+                    .and_then(Value::as_str),
+                created_at,
+                entry.to_string()
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn drop_older_than(&self, cutoff: i64) -> Result<()> {
+        self.connection()?.execute(
+            "DELETE FROM entries WHERE created_at < ?1",
+            params![cutoff],
+        )?;
+        Ok(())
+    }
+
+    fn drop_first(&self, lines_num: usize) -> Result<()> {
+        self.connection()?.execute(
+            "DELETE FROM entries WHERE id IN (SELECT id FROM entries ORDER BY id LIMIT ?1)",
+            params![lines_num as i64],
+        )?;
+        Ok(())
+    }
+
+    fn drop_last(&self, lines_num: usize) -> Result<()> {
+        self.connection()?.execute(
+            "DELETE FROM entries WHERE id IN (SELECT id FROM entries ORDER BY id DESC LIMIT ?1)",
+            params![lines_num as i64],
+        )?;
+        Ok(())
+    }
+
+    fn drop_range(&self, start: usize, end: usize) -> Result<()> {
+        self.connection()?.execute(
+            "DELETE FROM entries WHERE id IN (SELECT id FROM entries ORDER BY id LIMIT ?1 OFFSET ?2)",
+            params![
+                end.saturating_sub(start) as i64,
+                start as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn replace_entry(&self, index: usize, entry: Value) -> Result<()> {
+        let created_at = entry
+            .get("created_at")
+            .and_then(Value::as_i64)
+            .unwrap_or_else(unix_timestamp);
+        let updated = self.connection()?.execute(
+            "UPDATE entries SET role = ?1, created_at = ?2, data = ?3
+                 WHERE id = (SELECT id FROM entries ORDER BY id LIMIT 1 OFFSET ?4)",
+            params![
+                entry
+                    .get("role")
+                    .and_then(Value::as_str),
+                created_at,
+                entry.to_string(),
+                index as i64
+            ],
+        )?;
+        if updated == 0 {
+            return Err(anyhow!("No history entry {}", index));
+        }
+        Ok(())
+    }
+
+    fn drop_all(&self) -> Result<()> {
+        self.connection()?
+            .execute("DELETE FROM entries", [])?;
+        Ok(())
+    }
+
+    fn corrupted_entries(&self) -> Result<Vec<usize>> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare("SELECT data FROM entries ORDER BY id")?;
+        let corrupted = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .enumerate()
+            .filter(|(_, data)| {
+                !matches!(
+                    data.as_deref()
+                        .map(serde_json::from_str::<Value>),
+                    Ok(Ok(_))
+                )
+            })
+            .map(|(num, _)| num)
+            .collect();
+        Ok(corrupted)
+    }
+
+    fn write_model(&self, model: Value) -> Result<()> {
+        self.connection()?.execute(
+            "INSERT INTO model (id, data) VALUES (1, ?1)
+                 ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+            params![model.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn read_model(&self) -> Result<Value> {
+        let data: Option<String> = self
+            .connection()?
+            .query_row(
+                "SELECT data FROM model WHERE id = 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let data = data.ok_or_else(|| anyhow!("No assistant is stored in the cache"))?;
+        Ok(serde_json::from_str(&data)?)
+    }
+}
+
+/// History and current assistant that live as long as the `Cacher` does, nothing hits the disk.
+#[derive(Debug, Default)]
+pub struct MemoryCacheBackend {
+    entries: Mutex<Vec<Value>>,
+    model: Mutex<Option<Value>>,
+}
+
+impl MemoryCacheBackend {
+    fn entries(&self) -> Result<std::sync::MutexGuard<'_, Vec<Value>>> {
+        self.entries
+            .lock()
+            .map_err(|_| anyhow!("History lock poisoned"))
+    }
+}
+
+impl CacheBackend for MemoryCacheBackend {
+    fn read_indexed_entries(&self) -> Result<Vec<(usize, Value)>> {
+        Ok(self
+            .entries()?
+            .iter()
+            .cloned()
+            .enumerate()
+            .collect())
+    }
+
+    fn write_entry(&self, entry: Value) -> Result<()> {
+        self.entries()?.push(entry);
+        Ok(())
+    }
+
+    fn drop_older_than(&self, cutoff: i64) -> Result<()> {
+        self.entries()?
+            .retain(|entry| {
+                entry
+                    .get("created_at")
+                    .and_then(Value::as_i64)
+                    .is_none_or(|created_at| created_at >= cutoff)
+            });
+        Ok(())
+    }
+
+    fn drop_first(&self, lines_num: usize) -> Result<()> {
+        let mut entries = self.entries()?;
+        let lines_num = lines_num.min(entries.len());
+        entries.drain(.. lines_num);
+        Ok(())
+    }
+
+    fn drop_last(&self, lines_num: usize) -> Result<()> {
+        let mut entries = self.entries()?;
+        let len = entries
+            .len()
+            .saturating_sub(lines_num);
+        entries.truncate(len);
+        Ok(())
+    }
+
+    fn drop_range(&self, start: usize, end: usize) -> Result<()> {
+        let mut entries = self.entries()?;
+        let end = end.min(entries.len());
+        entries.drain(start.min(end) .. end);
+        Ok(())
+    }
+
+    fn replace_entry(&self, index: usize, entry: Value) -> Result<()> {
+        let mut entries = self.entries()?;
+        let history_len = entries.len();
+        *entries
+            .get_mut(index)
+            .ok_or_else(|| {
+                anyhow!(
+                    "No history entry {} among {}",
+                    index,
+                    history_len
+                )
+            })? = entry;
+        Ok(())
+    }
+
+    fn drop_all(&self) -> Result<()> {
+        self.entries()?.clear();
+        Ok(())
+    }
+
+    fn corrupted_entries(&self) -> Result<Vec<usize>> { Ok(Vec::new()) }
+
+    fn write_model(&self, model: Value) -> Result<()> {
+        *self
+            .model
+            .lock()
+            .map_err(|_| anyhow!("Model lock poisoned"))? = Some(model);
+        Ok(())
+    }
+
+    fn read_model(&self) -> Result<Value> {
+        self.model
+            .lock()
+            .map_err(|_| anyhow!("Model lock poisoned"))?
+            .clone()
+            .ok_or_else(|| anyhow!("No assistant is stored in the cache"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::{BufRead, BufReader, Write},
+    };
+
+    use serde::{Deserialize, Serialize};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct TestEntry {
+        id: i32,
+        name: String,
+    }
+
+    #[test]
+    fn test_is_sync_and_send() {
+        fn is_sync<T: Sync>() {}
+        fn is_send<T: Send>() {}
+
+        is_sync::<Cacher>();
+        is_send::<Cacher>();
+    }
+
+    #[test]
+    fn test_write_and_read_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_path = temp_dir
+            .path()
+            .join("test_history.json");
+        let cacher = Cacher::with_files(
+            history_path
+                .to_string_lossy()
+                .to_string(),
+            "".to_string(),
+            "".to_string(),
+            "".to_string(),
+        );
+
+        let entry1 = TestEntry {
+            id: 1,
+            name: "Alice".to_string(),
+        };
+        let entry2 = TestEntry {
+            id: 2,
+            name: "Bob".to_string(),
+        };
+
+        cacher
+            .write_entry(&entry1)
+            .ok();
+        cacher
+            .write_entry(&entry2)
+            .ok();
+
+        let file = File::open(&cacher.history_file).unwrap();
+        let reader = BufReader::new(file);
+        let lines: Vec<_> = reader.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(
+            lines[0]
+                .as_ref()
+                .unwrap()
+                .starts_with(&format!(
+                    "{} #crc:",
+                    serde_json::to_string(&entry1).unwrap()
+                ))
+        );
+        assert!(
+            lines[1]
+                .as_ref()
+                .unwrap()
+                .starts_with(&format!(
+                    "{} #crc:",
+                    serde_json::to_string(&entry2).unwrap()
+                ))
+        );
+
+        let read_entries: Vec<TestEntry> = cacher.read_entries().unwrap();
+
+        assert_eq!(read_entries.len(), 2);
+        assert_eq!(read_entries[0], entry1);
+        assert_eq!(read_entries[1], entry2);
+    }
+
+    #[test]
+    fn test_read_empty_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_path = temp_dir
+            .path()
+            .join("empty_history.json");
+        let cacher = Cacher::with_files(
+            history_path
+                .to_string_lossy()
+                .to_string(),
+            "".to_string(),
+            "".to_string(),
+            "".to_string(),
+        );
+
+        Cacher::create_file_if_not_exists(&cacher.history_file).ok();
+
+        let read_entries: Vec<TestEntry> = cacher.read_entries().unwrap();
+
+        assert!(read_entries.is_empty());
+    }
+
+    #[test]
+    fn test_partial_or_corrupted_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_path = temp_dir
+            .path()
+            .join("corrupted_history.json");
+        let cacher = Cacher::with_files(
+            history_path
+                .to_string_lossy()
+                .to_string(),
+            "".to_string(),
+            "".to_string(),
+            "".to_string(),
+        );
+
+        let entry1 = TestEntry {
+            id: 1,
+            name: "Alice".to_string(),
+        };
+
+        let valid_line = serde_json::to_string(&entry1).unwrap();
+        let corrupted_line = "{ id: 2, name: Bob }";
+
+        let mut file = File::create(&cacher.history_file).unwrap();
+        writeln!(file, "{}", valid_line).unwrap();
+        writeln!(file, "{}", corrupted_line).unwrap();
+
+        let read_entries: Vec<TestEntry> = cacher.read_entries().unwrap();
+
+        assert_eq!(read_entries.len(), 1);
+        assert_eq!(read_entries[0], entry1);
+    }
+
+    #[test]
+    fn test_in_memory_cacher_keeps_history_without_files() {
+        let cacher = Cacher::in_memory();
+        for id in 0 .. 4 {
+            cacher
+                .write_entry(&TestEntry {
+                    id,
+                    name: format!("entry {id}"),
+                })
+                .unwrap();
+        }
+        cacher
+            .drop_range(1, 3)
+            .unwrap();
+        cacher
+            .write_model(&"some_model")
+            .unwrap();
+
+        let ids: Vec<i32> = cacher
+            .read_entries::<TestEntry>()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect();
+        assert_eq!(ids, vec![0, 3]);
+        assert_eq!(
+            cacher
+                .read_model::<String>()
+                .unwrap(),
+            "some_model"
+        );
+        assert_eq!(cacher.read_response_id(), None);
+    }
+
+    fn entry(id: i32) -> TestEntry {
+        TestEntry {
+            id,
+            name: format!("entry {id}"),
+        }
+    }
+
+    #[test]
+    fn test_write_entry_at_replaces_only_that_line() {
+        let temp_dir = TempDir::new().unwrap();
+        let cacher = Cacher::new(
+            temp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        );
+        assert!(
+            cacher
+                .write_entry_at(0, &entry(0))
+                .is_err()
+        );
+        assert_eq!(
+            cacher
+                .read_entry::<TestEntry>(0)
+                .unwrap(),
+            None
+        );
+
+        for id in 0 .. 3 {
+            cacher
+                .write_entry(&entry(id))
+                .unwrap();
+        }
+        cacher
+            .write_entry_at(0, &entry(10))
+            .unwrap();
+        cacher
+            .write_entry_at(2, &entry(12))
+            .unwrap();
+        assert!(
+            cacher
+                .write_entry_at(3, &entry(13))
+                .is_err()
+        );
+
+        let ids: Vec<i32> = cacher
+            .read_entries::<TestEntry>()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect();
+        assert_eq!(ids, vec![10, 1, 12]);
+        assert_eq!(
+            cacher
+                .read_entry::<TestEntry>(2)
+                .unwrap(),
+            Some(entry(12))
+        );
+        assert_eq!(
+            cacher
+                .read_entry::<TestEntry>(3)
+                .unwrap(),
+            None
+        );
+        // Rewritten lines keep their checksums and no temporary file is left behind.
+        assert!(
+            cacher
+                .validate_history()
+                .is_empty()
+        );
+        assert!(!Path::new(&format!("{}.tmp", cacher.history_file)).exists());
+    }
+
+    #[test]
+    fn test_malformed_line_keeps_its_position() {
+        let temp_dir = TempDir::new().unwrap();
+        let cacher = Cacher::new(
+            temp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        );
+        cacher
+            .import_json(
+                r#"[{"role": "user", "content": "first"}, {"role": "assistant", "content": "second"}]"#,
+            )
+            .unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&cacher.history_file)
+            .unwrap();
+        writeln!(file, "not json").unwrap();
+        cacher
+            .import_json(
+                r#"[{"role": "user", "content": "third"}, {"role": "assistant", "content": "fourth"}]"#,
+            )
+            .unwrap();
+
+        let contents = |cacher: &Cacher| -> Vec<(usize, String)> {
+            cacher
+                .read_indexed_entries::<CacheEntry>()
+                .unwrap()
+                .into_iter()
+                .map(|(index, entry)| {
+                    (
+                        index,
+                        entry
+                            .content
+                            .unwrap_or_default(),
+                    )
+                })
+                .collect()
+        };
+        let found: Vec<usize> = cacher
+            .search("third", SearchType::Contains)
+            .unwrap()
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(found, vec![3]);
+        assert_eq!(
+            cacher
+                .read_entry::<CacheEntry>(3)
+                .unwrap()
+                .and_then(|entry| entry.content),
+            Some("third".to_string())
+        );
+        assert!(
+            cacher
+                .read_entry::<CacheEntry>(2)
+                .unwrap()
+                .is_none()
+        );
+
+        // Rewriting and dropping at the index `search` gave hit the very same entry.
+        let mut entry = cacher
+            .read_entry::<CacheEntry>(3)
+            .unwrap()
+            .unwrap();
+        entry.content = Some("third, edited".to_string());
+        cacher
+            .write_entry_at(3, &entry)
+            .unwrap();
+        assert_eq!(
+            contents(&cacher)[2],
+            (3, "third, edited".to_string())
+        );
+        cacher
+            .drop_range(found[0], found[0] + 1)
+            .unwrap();
+        assert_eq!(
+            contents(&cacher),
+            vec![
+                (0, "first".to_string()),
+                (1, "second".to_string()),
+                (3, "fourth".to_string())
+            ]
+        );
+
+        // Trimming counts the entries that parse and takes the malformed line with the dropped ones.
+        cacher
+            .trim_to(1, TrimStrategy::TrimOldest)
+            .unwrap();
+        assert_eq!(
+            contents(&cacher),
+            vec![(0, "fourth".to_string())]
+        );
+        assert!(
+            cacher
+                .validate_history()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_write_entry_at_in_sqlite_and_memory_backends() {
+        let temp_dir = TempDir::new().unwrap();
+        let cachers = [
+            Cacher::new_sqlite(
+                temp_dir
+                    .path()
+                    .to_str()
+                    .unwrap(),
+            )
+            .unwrap(),
+            Cacher::in_memory(),
+        ];
+
+        for cacher in cachers {
+            for id in 0 .. 2 {
+                cacher
+                    .write_entry(&entry(id))
+                    .unwrap();
+            }
+            cacher
+                .write_entry_at(1, &entry(11))
+                .unwrap();
+            assert!(
+                cacher
+                    .write_entry_at(2, &entry(12))
+                    .is_err()
+            );
+
+            assert_eq!(
+                cacher
+                    .read_entry::<TestEntry>(0)
+                    .unwrap(),
+                Some(entry(0))
+            );
+            assert_eq!(
+                cacher
+                    .read_entry::<TestEntry>(1)
+                    .unwrap(),
+                Some(entry(11))
+            );
+            assert_eq!(
+                cacher
+                    .read_entry::<TestEntry>(2)
+                    .unwrap(),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_sqlite_backend_entries_and_model() {
+        let temp_dir = TempDir::new().unwrap();
+        let cacher = Cacher::new_sqlite(
+            temp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        )
+        .unwrap();
+
+        for id in 1 ..= 3 {
+            cacher
+                .write_entry(&TestEntry {
+                    id,
+                    name: format!("entry {id}"),
+                })
+                .unwrap();
+        }
+        cacher.drop_first(1).unwrap();
+
+        let entries: Vec<TestEntry> = cacher.read_entries().unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.id)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+
+        let mut settings = AssistantSettings {
+            chat_model: "gpt-4o-mini".to_string(),
+            ..Default::default()
+        };
+        cacher
+            .write_model(&settings)
+            .unwrap();
+        settings.chat_model = "o3".to_string();
+        cacher
+            .write_model(&settings)
+            .unwrap();
+        assert_eq!(
+            cacher
+                .read_model::<AssistantSettings>()
+                .unwrap()
+                .chat_model,
+            "o3"
+        );
+
+        cacher.drop_all().unwrap();
+        assert!(
+            cacher
+                .read_entries::<TestEntry>()
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            !temp_dir
+                .path()
+                .join("chat_history.jl")
+                .exists()
+        );
     }
 
-    pub fn write_entry<T: Serialize>(&self, entry: &T) -> Result<()> {
-        let entry_json = serde_json::to_string(entry)?;
+    #[test]
+    fn test_sqlite_backend_imports_jsonl_once() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir
+            .path()
+            .to_str()
+            .unwrap();
 
-        let mut file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&self.history_file)?;
+        let file_cacher = Cacher::new(path);
+        let user_entry = || {
+            CacheEntry {
+                content: Some("Hello".to_string()),
+                thinking: None,
+                role: Roles::User,
+                tool_calls: None,
+                path: None,
+                scope: None,
+                tool_call_id: None,
+                provider_metadata: None,
+                created_at: None,
+                citations: None,
+                logprobs: None,
+                user: None,
+                finish_reason: None,
+            }
+        };
+        file_cacher
+            .write_entry(&user_entry())
+            .unwrap();
+        file_cacher
+            .write_model(&AssistantSettings::default())
+            .unwrap();
 
-        writeln!(file, "{}", entry_json)?;
+        let cacher = Cacher::new_sqlite(path).unwrap();
+        let entries: Vec<CacheEntry> = cacher.read_entries().unwrap();
+        assert_eq!(entries, vec![user_entry()]);
+        assert!(
+            cacher
+                .read_model::<AssistantSettings>()
+                .is_ok()
+        );
 
-        Ok(())
+        let role: String = Connection::open(
+            temp_dir
+                .path()
+                .join("chat_history.sqlite"),
+        )
+        .unwrap()
+        .query_row("SELECT role FROM entries", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+        assert_eq!(role, "user");
+
+        // Reopening must not import the same lines again.
+        drop(cacher);
+        let reopened = Cacher::new_sqlite(path).unwrap();
+        assert_eq!(
+            reopened
+                .read_entries::<CacheEntry>()
+                .unwrap()
+                .len(),
+            1
+        );
     }
 
-    pub fn write_model<T: Serialize>(&self, model: &T) -> Result<()> {
-        let model_json = serde_json::to_string(model)?;
-
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&self.current_model_file)?;
+    #[test]
+    fn test_append_tokens_count_keeps_running_total() {
+        let temp_dir = TempDir::new().unwrap();
+        let cacher = Cacher::new(
+            temp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        );
+        assert_eq!(cacher.read_tokens_count(), None);
 
-        writeln!(file, "{}", model_json)?;
+        let usage = TokenUsage {
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            total_tokens: 15,
+            ..Default::default()
+        };
+        cacher
+            .append_tokens_count(&usage)
+            .unwrap();
+        cacher
+            .append_tokens_count(&TokenUsage {
+                cache_read_input_tokens: Some(4),
+                ..usage
+            })
+            .unwrap();
 
-        Ok(())
+        assert_eq!(
+            cacher.read_tokens_count(),
+            Some(TokenUsage {
+                prompt_tokens: 20,
+                completion_tokens: 10,
+                total_tokens: 30,
+                cache_read_input_tokens: Some(4),
+                ..Default::default()
+            })
+        );
     }
 
-    pub fn read_model<T: DeserializeOwned>(&self) -> Result<T> {
-        Self::create_file_if_not_exists(&self.current_model_file);
+    #[test]
+    fn test_response_id_roundtrip_and_drop_all() {
+        let temp_dir = TempDir::new().unwrap();
+        let cacher = Cacher::new(
+            temp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        );
 
-        let file = File::open(&self.current_model_file)?;
-        let reader = std::io::BufReader::new(file);
+        assert_eq!(cacher.read_response_id(), None);
 
-        // Read the file and deserialize it into the desired type `T`
-        let model: T = serde_json::from_reader(reader)?;
+        cacher
+            .write_response_id("resp_1")
+            .unwrap();
+        cacher
+            .write_response_id("resp_2")
+            .unwrap();
+        assert_eq!(
+            cacher.read_response_id(),
+            Some("resp_2".to_string())
+        );
 
-        Ok(model)
+        cacher.drop_all().unwrap();
+        assert_eq!(cacher.read_response_id(), None);
     }
 
-    pub fn drop_first(&self, lines_num: usize) -> Result<()> {
-        let file = File::open(&self.history_file)?;
+    #[test]
+    fn test_compressed_entries_are_smaller_and_readable() {
+        let plain_dir = TempDir::new().unwrap();
+        let compressed_dir = TempDir::new().unwrap();
+        let plain = Cacher::new(
+            plain_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        );
+        let compressed = Cacher::new_compressed(
+            compressed_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        );
 
-        let reader = std::io::BufReader::new(file);
-        let remaining_lines: Vec<_> = reader
-            .lines()
-            .skip(lines_num)
-            .filter_map(Result::ok)
+        let entries: Vec<TestEntry> = (0 .. 10)
+            .map(|id| {
+                TestEntry {
+                    id,
+                    name: "fn main() { println!(\"hello\"); }\n"
+                        .repeat(30)
+                        .chars()
+                        .take(1000)
+                        .collect(),
+                }
+            })
             .collect();
-
-        let mut file = File::create(&self.history_file)?;
-
-        for line in remaining_lines {
-            writeln!(file, "{}", line)?;
+        for entry in &entries {
+            plain
+                .write_entry(entry)
+                .unwrap();
+            compressed
+                .write_entry(entry)
+                .unwrap();
         }
 
-        Ok(())
+        assert_eq!(
+            compressed
+                .read_entries::<TestEntry>()
+                .unwrap(),
+            entries
+        );
+        let plain_size = std::fs::metadata(&plain.history_file)
+            .unwrap()
+            .len();
+        let compressed_size = std::fs::metadata(&compressed.history_file)
+            .unwrap()
+            .len();
+        assert!(
+            compressed_size < plain_size,
+            "{compressed_size} >= {plain_size}"
+        );
     }
 
-    pub fn drop_all(&self) -> Result<()> {
-        let mut file = File::create(&self.history_file)?;
-        Ok(())
-    }
+    #[test]
+    fn test_compressed_cacher_reads_plain_lines() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir
+            .path()
+            .to_str()
+            .unwrap();
 
-    #[cfg(test)]
-    fn sublime_cache() -> String { "~/Library/Caches/Sublime Text/Cache".to_string() }
+        Cacher::new(path)
+            .write_entry(&TestEntry {
+                id: 1,
+                name: "plain".to_string(),
+            })
+            .unwrap();
+        let cacher = Cacher::new_compressed(path);
+        cacher
+            .write_entry(&TestEntry {
+                id: 2,
+                name: "compressed".to_string(),
+            })
+            .unwrap();
 
-    #[cfg(not(test))]
-    fn sublime_cache() -> String {
-        "~/Library/Caches/Sublime Text/Cache".to_string()
-        // crate::sublime_python::get_sublime_cache()
-        //     .unwrap_or("~/Library/Caches/Sublime Text/Cache".to_string())
+        let ids: Vec<i32> = cacher
+            .read_entries::<TestEntry>()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::{
-        fs::File,
-        io::{BufRead, BufReader, Write},
-    };
-
-    use serde::{Deserialize, Serialize};
-    use tempfile::TempDir;
 
-    use super::*;
+    #[test]
+    fn test_validate_history_reports_corrupted_lines() {
+        let temp_dir = TempDir::new().unwrap();
+        let cacher = Cacher::new(
+            temp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        );
+        for id in 0 .. 3 {
+            cacher
+                .write_entry(&TestEntry {
+                    id,
+                    name: format!("entry {id}"),
+                })
+                .unwrap();
+        }
+        assert!(
+            cacher
+                .validate_history()
+                .is_empty()
+        );
 
-    #[derive(Serialize, Deserialize, PartialEq, Debug)]
-    struct TestEntry {
-        id: i32,
-        name: String,
+        let history = std::fs::read_to_string(&cacher.history_file).unwrap();
+        assert!(
+            history
+                .lines()
+                .all(|line| line.contains(" #crc:"))
+        );
+        let tampered = history.replacen("entry 1", "entry X", 1);
+        let mut file = File::create(&cacher.history_file).unwrap();
+        write!(file, "{}", tampered).unwrap();
+        writeln!(file, "{{\"id\":3,\"name\":\"legacy\"}}").unwrap();
+        writeln!(file, "{{not json").unwrap();
+
+        assert_eq!(cacher.validate_history(), vec![1, 4]);
+
+        // A checksum mismatch is reported, but the entry is still read.
+        let names: Vec<String> = cacher
+            .read_entries::<TestEntry>()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "entry 0", "entry X", "entry 2", "legacy"
+            ]
+        );
     }
 
     #[test]
-    fn test_is_sync_and_send() {
-        fn is_sync<T: Sync>() {}
-        fn is_send<T: Send>() {}
+    fn test_trim_to_strategies() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir
+            .path()
+            .to_str()
+            .unwrap();
 
-        is_sync::<Cacher>();
-        is_send::<Cacher>();
+        for strategy in [
+            TrimStrategy::TrimOldest,
+            TrimStrategy::TrimOldestExceptSystem,
+        ] {
+            for mut cacher in [
+                Cacher::new(path),
+                Cacher::new_sqlite(path).unwrap(),
+            ] {
+                cacher.max_history_entries = Some(3);
+                cacher.trim_strategy = strategy;
+
+                cacher
+                    .write_entry(&serde_json::json!({"role": "system", "content": "rules"}))
+                    .unwrap();
+                for index in 0 .. 4 {
+                    cacher
+                        .write_entry(&serde_json::json!({"role": "user", "content": format!("turn {index}")}))
+                        .unwrap();
+                }
+
+                let contents: Vec<String> = cacher
+                    .read_entries::<Value>()
+                    .unwrap()
+                    .iter()
+                    .map(|entry| {
+                        entry["content"]
+                            .as_str()
+                            .unwrap()
+                            .to_string()
+                    })
+                    .collect();
+                let expected = match strategy {
+                    TrimStrategy::TrimOldest => vec!["turn 1", "turn 2", "turn 3"],
+                    TrimStrategy::TrimOldestExceptSystem => vec!["rules", "turn 2", "turn 3"],
+                };
+                assert_eq!(contents, expected, "{strategy}");
+
+                cacher.drop_all().unwrap();
+            }
+        }
     }
 
     #[test]
-    fn test_write_and_read_entries() {
+    fn test_drop_last_and_drop_range() {
         let temp_dir = TempDir::new().unwrap();
-        let history_path = temp_dir
+        let path = temp_dir
             .path()
-            .join("test_history.json");
-        let cacher = Cacher {
-            history_file: history_path
-                .to_string_lossy()
-                .to_string(),
-            current_model_file: "".to_string(),
-            tokens_count_file: "".to_string(),
-        };
-
-        let entry1 = TestEntry {
-            id: 1,
-            name: "Alice".to_string(),
-        };
-        let entry2 = TestEntry {
-            id: 2,
-            name: "Bob".to_string(),
-        };
+            .to_str()
+            .unwrap();
 
-        cacher
-            .write_entry(&entry1)
-            .ok();
-        cacher
-            .write_entry(&entry2)
-            .ok();
+        for cacher in [
+            Cacher::new(path),
+            Cacher::new_sqlite(path).unwrap(),
+        ] {
+            let write_ids = |ids: std::ops::Range<i32>| {
+                for id in ids {
+                    cacher
+                        .write_entry(&TestEntry {
+                            id,
+                            name: format!("entry {id}"),
+                        })
+                        .unwrap();
+                }
+            };
+            let ids = || -> Vec<i32> {
+                cacher
+                    .read_entries::<TestEntry>()
+                    .unwrap()
+                    .iter()
+                    .map(|entry| entry.id)
+                    .collect()
+            };
+
+            write_ids(0 .. 6);
+            cacher.drop_last(2).unwrap();
+            assert_eq!(ids(), vec![0, 1, 2, 3]);
+
+            cacher.drop_last(0).unwrap();
+            assert_eq!(ids(), vec![0, 1, 2, 3]);
+
+            cacher
+                .drop_range(1, 3)
+                .unwrap();
+            assert_eq!(ids(), vec![0, 3]);
+
+            cacher
+                .drop_range(1, 1)
+                .unwrap();
+            cacher
+                .drop_range(5, 10)
+                .unwrap();
+            assert_eq!(ids(), vec![0, 3]);
+            assert!(
+                cacher
+                    .drop_range(2, 1)
+                    .is_err()
+            );
+
+            cacher.drop_last(10).unwrap();
+            assert!(ids().is_empty());
+
+            write_ids(0 .. 3);
+            cacher
+                .drop_range(0, 10)
+                .unwrap();
+            assert!(ids().is_empty());
+        }
+    }
 
-        let file = File::open(&cacher.history_file).unwrap();
-        let reader = BufReader::new(file);
-        let lines: Vec<_> = reader.lines().collect();
-        assert_eq!(lines.len(), 2);
-        assert_eq!(
-            lines[0].as_ref().unwrap(),
-            &serde_json::to_string(&entry1).unwrap()
+    #[test]
+    fn test_trim_to_without_leading_system_message() {
+        let temp_dir = TempDir::new().unwrap();
+        let cacher = Cacher::new(
+            temp_dir
+                .path()
+                .to_str()
+                .unwrap(),
         );
+        for index in 0 .. 5 {
+            cacher
+                .write_entry(&TestEntry {
+                    id: index,
+                    name: format!("entry {index}"),
+                })
+                .unwrap();
+        }
+
+        cacher
+            .trim_to(10, TrimStrategy::TrimOldestExceptSystem)
+            .unwrap();
         assert_eq!(
-            lines[1].as_ref().unwrap(),
-            &serde_json::to_string(&entry2).unwrap()
+            cacher
+                .read_entries::<TestEntry>()
+                .unwrap()
+                .len(),
+            5
         );
 
-        let read_entries: Vec<TestEntry> = cacher.read_entries().unwrap();
-
-        assert_eq!(read_entries.len(), 2);
-        assert_eq!(read_entries[0], entry1);
-        assert_eq!(read_entries[1], entry2);
+        cacher
+            .trim_to(2, TrimStrategy::TrimOldestExceptSystem)
+            .unwrap();
+        let ids: Vec<i32> = cacher
+            .read_entries::<TestEntry>()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.id)
+            .collect();
+        assert_eq!(ids, vec![3, 4]);
     }
 
     #[test]
-    fn test_read_empty_file() {
+    fn test_prune_expired_entries() {
         let temp_dir = TempDir::new().unwrap();
-        let history_path = temp_dir
+        let path = temp_dir
             .path()
-            .join("empty_history.json");
-        let cacher = Cacher {
-            history_file: history_path
-                .to_string_lossy()
-                .to_string(),
-            current_model_file: "".to_string(),
-            tokens_count_file: "".to_string(),
-        };
+            .to_str()
+            .unwrap();
+        let now = unix_timestamp();
+
+        for mut cacher in [
+            Cacher::new(path),
+            Cacher::new_sqlite(path).unwrap(),
+        ] {
+            cacher
+                .write_entry(&serde_json::json!({"name": "legacy"}))
+                .unwrap();
+            cacher
+                .write_entry(&serde_json::json!({"name": "stale", "created_at": now - 120}))
+                .unwrap();
+            cacher
+                .write_entry(&serde_json::json!({"name": "fresh", "created_at": now - 10}))
+                .unwrap();
+
+            assert_eq!(
+                cacher
+                    .read_entries::<Value>()
+                    .unwrap()
+                    .len(),
+                3
+            );
+
+            cacher.max_age_secs = Some(60);
+            let names: Vec<String> = cacher
+                .read_entries::<Value>()
+                .unwrap()
+                .iter()
+                .map(|entry| {
+                    entry["name"]
+                        .as_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect();
+            assert_eq!(names, vec!["legacy", "fresh"]);
+
+            cacher.drop_all().unwrap();
+        }
+    }
 
-        Cacher::create_file_if_not_exists(&cacher.history_file).ok();
+    #[test]
+    fn test_import_json() {
+        let temp_dir = TempDir::new().unwrap();
+        let cacher = Cacher::new(
+            temp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        );
 
-        let read_entries: Vec<TestEntry> = cacher.read_entries().unwrap();
+        let imported = cacher
+            .import_json(
+                r#"[
+                    {"role": "user", "content": "Hi"},
+                    {"role": "assistant", "content": null, "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "read_region_content", "arguments": "{}"}
+                    }]},
+                    {"role": "tool", "content": "done", "tool_call_id": "call_1", "created_at": 42}
+                ]"#,
+            )
+            .unwrap();
+        assert_eq!(imported, 3);
 
-        assert!(read_entries.is_empty());
+        let entries: Vec<CacheEntry> = cacher.read_entries().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[0].content.as_deref(),
+            Some("Hi")
+        );
+        assert!(
+            entries[0]
+                .created_at
+                .is_some()
+        );
+        assert_eq!(entries[2].created_at, Some(42));
+
+        let unknown_role =
+            cacher.import_json(r#"[{"role": "user", "content": "ok"}, {"role": "robot", "content": "Hi"}]"#);
+        assert!(
+            unknown_role
+                .unwrap_err()
+                .to_string()
+                .starts_with("Entry 1 ")
+        );
+        let empty_entry = cacher.import_json(r#"[{"role": "assistant", "content": null}]"#);
+        assert_eq!(
+            empty_entry
+                .unwrap_err()
+                .to_string(),
+            "Entry 0 has neither content nor tool_calls"
+        );
+        assert!(
+            cacher
+                .import_json(r#"{"role": "user"}"#)
+                .is_err()
+        );
+        assert_eq!(
+            cacher
+                .read_entries::<CacheEntry>()
+                .unwrap()
+                .len(),
+            3
+        );
     }
 
     #[test]
-    fn test_partial_or_corrupted_entries() {
+    fn test_search() {
         let temp_dir = TempDir::new().unwrap();
-        let history_path = temp_dir
-            .path()
-            .join("corrupted_history.json");
-        let cacher = Cacher {
-            history_file: history_path
-                .to_string_lossy()
-                .to_string(),
-            current_model_file: "".to_string(),
-            tokens_count_file: "".to_string(),
-        };
+        let cacher = Cacher::new(
+            temp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        );
+        cacher
+            .import_json(
+                r#"[
+                    {"role": "user", "content": "Why does the Parser panic?"},
+                    {"role": "assistant", "content": "The parser panics on empty input."},
+                    {"role": "assistant", "content": null, "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "parser", "arguments": "{}"}
+                    }]},
+                    {"role": "user", "content": "Fix error 404 please"}
+                ]"#,
+            )
+            .unwrap();
 
-        let entry1 = TestEntry {
-            id: 1,
-            name: "Alice".to_string(),
+        let indices = |query: &str, search_type: SearchType| -> Vec<usize> {
+            cacher
+                .search(query, search_type)
+                .unwrap()
+                .into_iter()
+                .map(|(index, _)| index)
+                .collect()
         };
 
-        let valid_line = serde_json::to_string(&entry1).unwrap();
-        let corrupted_line = "{ id: 2, name: Bob }";
+        assert_eq!(
+            indices("PARSER", SearchType::Contains),
+            vec![0, 1]
+        );
+        assert_eq!(
+            indices("the parser", SearchType::StartsWith),
+            vec![1]
+        );
+        assert_eq!(
+            indices(r"error \d{3}", SearchType::Regex),
+            vec![3]
+        );
+        assert!(
+            cacher
+                .search("(", SearchType::Regex)
+                .is_err()
+        );
+        assert_eq!(
+            cacher
+                .search("empty", SearchType::Contains)
+                .unwrap()[0]
+                .1
+                .content
+                .as_deref(),
+            Some("The parser panics on empty input.")
+        );
+    }
 
-        let mut file = File::create(&cacher.history_file).unwrap();
-        writeln!(file, "{}", valid_line).unwrap();
-        writeln!(file, "{}", corrupted_line).unwrap();
+    #[test]
+    fn test_export_markdown() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir
+            .path()
+            .to_str()
+            .unwrap();
+        let out_path = temp_dir
+            .path()
+            .join("chat.md");
+        let cacher = Cacher::new(path);
+
+        for entry in [
+            serde_json::json!({"role": "user", "content": "Fix it", "path": "src/main.rs"}),
+            serde_json::json!({
+                "role": "assistant",
+                "content": "<think></think>Use this:\n    let a = 1;\n    let b = 2;\nDone",
+                "thinking": "pondering",
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "read_region", "arguments": "{\"a\":1}"}
+                }]
+            }),
+            serde_json::json!({"role": "tool", "content": "```rust\n    kept\n```", "tool_call_id": "call_1"}),
+        ] {
+            cacher
+                .write_entry(&entry)
+                .unwrap();
+        }
 
-        let read_entries: Vec<TestEntry> = cacher.read_entries().unwrap();
+        let markdown = cacher
+            .export_markdown(out_path.to_str().unwrap())
+            .unwrap();
 
-        assert_eq!(read_entries.len(), 1);
-        assert_eq!(read_entries[0], entry1);
+        assert_eq!(
+            markdown,
+            "## User\n\n`src/main.rs`\n\nFix it\n\n## \
+             Assistant\n\n<details><summary>Thinking</summary>\n\npondering\n\n</details>\n\nUse \
+             this:\n```\nlet a = 1;\nlet b = 2;\n```\nDone\n\nTool call \
+             `read_region`:\n\n```json\n{\"a\":1}\n```\n\n## Tool\n\n```rust\n    kept\n```\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&out_path).unwrap(),
+            markdown
+        );
     }
 
     use crate::{
@@ -383,13 +2330,14 @@ mod tests {
         let model_path = temp_dir
             .path()
             .join("current_assistant.json");
-        let cacher = Cacher {
-            history_file: "".to_string(),
-            current_model_file: model_path
+        let cacher = Cacher::with_files(
+            "".to_string(),
+            model_path
                 .to_string_lossy()
                 .into_owned(),
-            tokens_count_file: "".to_string(),
-        };
+            "".to_string(),
+            "".to_string(),
+        );
 
         let mut settings = AssistantSettings::default();
 
@@ -471,13 +2419,14 @@ mod tests {
         let history_path = temp_dir
             .path()
             .join("test_history.jl");
-        let cacher = Cacher {
-            history_file: history_path
+        let cacher = Cacher::with_files(
+            history_path
                 .to_string_lossy()
                 .into_owned(),
-            current_model_file: "".to_string(),
-            tokens_count_file: "".to_string(),
-        };
+            "".to_string(),
+            "".to_string(),
+            "".to_string(),
+        );
 
         // Mock JSON entries to write to the file
         let mock_entries = vec![
@@ -523,6 +2472,11 @@ mod tests {
                 scope: None,
                 tool_call_id: None,
                 provider_metadata: None,
+                created_at: None,
+                citations: None,
+                logprobs: None,
+                user: None,
+                finish_reason: None,
             }
         );
 
@@ -538,6 +2492,11 @@ mod tests {
                 scope: None,
                 tool_call_id: None,
                 provider_metadata: None,
+                created_at: None,
+                citations: None,
+                logprobs: None,
+                user: None,
+                finish_reason: None,
             }
         );
 
@@ -561,6 +2520,11 @@ mod tests {
                 scope: None,
                 tool_call_id: None,
                 provider_metadata: None,
+                created_at: None,
+                citations: None,
+                logprobs: None,
+                user: None,
+                finish_reason: None,
             }
         );
 
@@ -575,6 +2539,11 @@ mod tests {
                 scope: None,
                 tool_call_id: Some("call_f4Ixx2ruFvbbqifrMKZ8Cxju".to_string()),
                 provider_metadata: None,
+                created_at: None,
+                citations: None,
+                logprobs: None,
+                user: None,
+                finish_reason: None,
             }
         );
     }
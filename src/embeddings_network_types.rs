@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+
+/// Upper bound of inputs OpenAI accepts in one `/embeddings` call.
+pub(crate) const MAX_EMBEDDING_INPUTS: usize = 2048;
+
+/// Body of an OpenAI compatible `/v1/embeddings` call.
+#[derive(Debug, Serialize)]
+pub(crate) struct EmbeddingsRequest {
+    pub(crate) model: String,
+    pub(crate) input: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct EmbeddingsResponse {
+    pub(crate) data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct EmbeddingData {
+    /// Position of the matching input within the request, items are not guaranteed to come sorted.
+    #[serde(default)]
+    pub(crate) index: usize,
+    pub(crate) embedding: Vec<f32>,
+}
+
+impl EmbeddingsResponse {
+    /// Returns the vectors in the order of the inputs they were computed for.
+    pub(crate) fn into_vectors(mut self) -> Vec<Vec<f32>> {
+        self.data
+            .sort_by_key(|item| item.index);
+        self.data
+            .into_iter()
+            .map(|item| item.embedding)
+            .collect()
+    }
+}
+
+/// Derives the embeddings endpoint from the configured chat url.
+///
+/// `.../chat/completions` becomes `.../embeddings`, a bare host gets `/v1/embeddings` appended.
+pub(crate) fn embeddings_url(base_url: &str) -> String {
+    let trimmed = base_url.trim_end_matches('/');
+    if trimmed.ends_with("/embeddings") {
+        trimmed.to_string()
+    } else if let Some(prefix) = trimmed.strip_suffix("/chat/completions") {
+        format!("{}/embeddings", prefix)
+    } else if trimmed.ends_with("/v1") {
+        format!("{}/embeddings", trimmed)
+    } else {
+        format!("{}/v1/embeddings", trimmed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embeddings_url() {
+        assert_eq!(
+            embeddings_url("https://api.openai.com/v1/chat/completions"),
+            "https://api.openai.com/v1/embeddings"
+        );
+        assert_eq!(
+            embeddings_url("https://api.openai.com/v1/embeddings"),
+            "https://api.openai.com/v1/embeddings"
+        );
+        assert_eq!(
+            embeddings_url("http://localhost:8080/"),
+            "http://localhost:8080/v1/embeddings"
+        );
+        assert_eq!(
+            embeddings_url("http://localhost:8080/v1"),
+            "http://localhost:8080/v1/embeddings"
+        );
+    }
+
+    #[test]
+    fn test_embeddings_response_sorted_by_index() {
+        let response: EmbeddingsResponse = serde_json::from_str(
+            r#"{"object": "list", "data": [
+                {"object": "embedding", "index": 1, "embedding": [0.3, 0.4]},
+                {"object": "embedding", "index": 0, "embedding": [0.1, 0.2]}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            response.into_vectors(),
+            vec![vec![0.1, 0.2], vec![0.3, 0.4]]
+        );
+    }
+}
@@ -0,0 +1,316 @@
+use std::{error::Error, fmt, time::Duration};
+
+use pyo3::pyclass;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// What went wrong with a run, for the callers that react differently to each kind.
+///
+/// `LlmRunner::execute` returns it as is, the network layer inside `anyhow::Error`, get it
+/// back from there with `error.downcast_ref::<LlmError>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LlmError {
+    /// The provider answered with a non-success status.
+    Http {
+        status: u16,
+        message: String,
+        /// How long the provider asked to wait before trying again.
+        retry_after: Option<Duration>,
+    },
+    /// The request didn't get through: no connection, a failed handshake or a dropped stream.
+    Network(String),
+    /// The provider didn't answer in time.
+    Timeout,
+    /// The provider's answer isn't what its api promises.
+    Serde(String),
+    /// The history couldn't be read or written.
+    Cache(String),
+    /// The model kept calling tools past `AssistantSettings::max_tool_call_depth`.
+    ToolDepthExceeded,
+    /// The run was cancelled before the provider answered.
+    Cancelled,
+    /// The circuit breaker is open, the request wasn't sent.
+    CircuitOpen {
+        /// The failure that opened it.
+        last_failure: String,
+    },
+    /// Anything else, mostly settings the request can't be built from.
+    Other(String),
+}
+
+/// The variant of an `LlmError`, set as `kind` on the exceptions raised to Python.
+#[pyclass(eq, eq_int)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LlmErrorKind {
+    Http,
+    Network,
+    Timeout,
+    Serde,
+    Cache,
+    ToolDepthExceeded,
+    Cancelled,
+    CircuitOpen,
+    Other,
+}
+
+impl LlmError {
+    /// Tags `error` with its kind, keeping it as the source.
+    pub(crate) fn from_reqwest(error: reqwest::Error) -> anyhow::Error {
+        let kind = LlmError::from(&error);
+        anyhow::Error::new(error).context(kind)
+    }
+
+    /// A failure of the history store, whatever its cause.
+    pub(crate) fn cache(error: impl fmt::Display) -> Self { LlmError::Cache(error.to_string()) }
+
+    pub fn kind(&self) -> LlmErrorKind {
+        match self {
+            LlmError::Http { .. } => LlmErrorKind::Http,
+            LlmError::Network(_) => LlmErrorKind::Network,
+            LlmError::Timeout => LlmErrorKind::Timeout,
+            LlmError::Serde(_) => LlmErrorKind::Serde,
+            LlmError::Cache(_) => LlmErrorKind::Cache,
+            LlmError::ToolDepthExceeded => LlmErrorKind::ToolDepthExceeded,
+            LlmError::Cancelled => LlmErrorKind::Cancelled,
+            LlmError::CircuitOpen { .. } => LlmErrorKind::CircuitOpen,
+            LlmError::Other(_) => LlmErrorKind::Other,
+        }
+    }
+}
+
+impl From<&reqwest::Error> for LlmError {
+    fn from(error: &reqwest::Error) -> Self {
+        if error.is_timeout() {
+            LlmError::Timeout
+        } else if error.is_decode() {
+            LlmError::Serde(error.source().map_or_else(
+                || error.to_string(),
+                ToString::to_string,
+            ))
+        } else if let Some(status) = error.status() {
+            LlmError::Http {
+                status: status.as_u16(),
+                message: String::new(),
+                retry_after: None,
+            }
+        } else {
+            LlmError::Network(error.to_string())
+        }
+    }
+}
+
+impl From<reqwest::Error> for LlmError {
+    fn from(error: reqwest::Error) -> Self { LlmError::from(&error) }
+}
+
+impl From<serde_json::Error> for LlmError {
+    fn from(error: serde_json::Error) -> Self { LlmError::Serde(error.to_string()) }
+}
+
+impl From<std::io::Error> for LlmError {
+    fn from(error: std::io::Error) -> Self { LlmError::cache(error) }
+}
+
+impl From<anyhow::Error> for LlmError {
+    fn from(error: anyhow::Error) -> Self { LlmError::from(&error) }
+}
+
+impl From<&anyhow::Error> for LlmError {
+    /// The kind the network layer tagged `error` with, otherwise the kind of its first known cause.
+    fn from(error: &anyhow::Error) -> Self {
+        if let Some(llm_error) = error.downcast_ref::<LlmError>() {
+            return llm_error.clone();
+        }
+        error
+            .chain()
+            .find_map(|cause| {
+                if let Some(cause) = cause.downcast_ref::<reqwest::Error>() {
+                    Some(LlmError::from(cause))
+                } else if cause.is::<serde_json::Error>() {
+                    Some(LlmError::Serde(error.to_string()))
+                } else if cause.is::<std::io::Error>() || cause.is::<rusqlite::Error>() {
+                    Some(LlmError::cache(error))
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_else(|| LlmError::Other(error.to_string()))
+    }
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::Http {
+                status,
+                message,
+                retry_after,
+            } => {
+                let status = StatusCode::from_u16(*status).map_or_else(
+                    |_| status.to_string(),
+                    |status| status.to_string(),
+                );
+                write!(
+                    f,
+                    "Request failed with status: {}",
+                    status
+                )?;
+                if !message.is_empty() {
+                    write!(f, ", the error: {}", message)?;
+                }
+                match retry_after {
+                    Some(delay) => {
+                        write!(
+                            f,
+                            ", retry in {}s",
+                            delay.as_secs_f64().ceil()
+                        )
+                    }
+                    None => Ok(()),
+                }
+            }
+            LlmError::Network(message) | LlmError::Other(message) => write!(f, "{}", message),
+            LlmError::Timeout => write!(f, "Request timed out"),
+            LlmError::Serde(message) => {
+                write!(
+                    f,
+                    "Can't parse the response: {}",
+                    message
+                )
+            }
+            LlmError::Cache(message) => {
+                write!(
+                    f,
+                    "Can't access the history: {}",
+                    message
+                )
+            }
+            LlmError::ToolDepthExceeded => write!(f, "Tool call depth exceeded"),
+            LlmError::Cancelled => write!(f, "Request cancelled"),
+            LlmError::CircuitOpen { last_failure } => {
+                write!(
+                    f,
+                    "Circuit open, the provider keeps failing: {}",
+                    last_failure
+                )
+            }
+        }
+    }
+}
+
+impl Error for LlmError {}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_from_sources_picks_the_kind() {
+        let serde_error = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        assert!(matches!(
+            LlmError::from(serde_error),
+            LlmError::Serde(_)
+        ));
+
+        let io_error = std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "denied",
+        );
+        assert_eq!(
+            LlmError::from(io_error),
+            LlmError::Cache("denied".to_string())
+        );
+
+        let wrapped_io: anyhow::Error = Err::<(), _>(std::io::Error::other("disk full"))
+            .context("Writing the history")
+            .unwrap_err();
+        assert_eq!(
+            LlmError::from(wrapped_io),
+            LlmError::Cache("Writing the history".to_string())
+        );
+
+        let sqlite_error = anyhow::Error::new(rusqlite::Error::InvalidQuery);
+        assert_eq!(
+            LlmError::from(sqlite_error).kind(),
+            LlmErrorKind::Cache
+        );
+
+        let tagged = anyhow::Error::new(std::io::Error::other("reset")).context(LlmError::Timeout);
+        assert_eq!(
+            LlmError::from(tagged),
+            LlmError::Timeout
+        );
+
+        assert_eq!(
+            LlmError::from(anyhow::anyhow!(
+                "Bedrock requires `aws_region`"
+            )),
+            LlmError::Other("Bedrock requires `aws_region`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_every_variant_has_its_kind_and_message() {
+        let cases = [
+            (
+                LlmError::Http {
+                    status: 429,
+                    message: "Slow down".to_string(),
+                    retry_after: Some(Duration::from_millis(1500)),
+                },
+                LlmErrorKind::Http,
+                "Request failed with status: 429 Too Many Requests, the error: Slow down, retry in 2s",
+            ),
+            (
+                LlmError::Network("connection refused".to_string()),
+                LlmErrorKind::Network,
+                "connection refused",
+            ),
+            (
+                LlmError::Timeout,
+                LlmErrorKind::Timeout,
+                "Request timed out",
+            ),
+            (
+                LlmError::Serde("EOF".to_string()),
+                LlmErrorKind::Serde,
+                "Can't parse the response: EOF",
+            ),
+            (
+                LlmError::Cache("denied".to_string()),
+                LlmErrorKind::Cache,
+                "Can't access the history: denied",
+            ),
+            (
+                LlmError::ToolDepthExceeded,
+                LlmErrorKind::ToolDepthExceeded,
+                "Tool call depth exceeded",
+            ),
+            (
+                LlmError::Cancelled,
+                LlmErrorKind::Cancelled,
+                "Request cancelled",
+            ),
+            (
+                LlmError::CircuitOpen {
+                    last_failure: "Request timed out".to_string(),
+                },
+                LlmErrorKind::CircuitOpen,
+                "Circuit open, the provider keeps failing: Request timed out",
+            ),
+            (
+                LlmError::Other("bad settings".to_string()),
+                LlmErrorKind::Other,
+                "bad settings",
+            ),
+        ];
+
+        for (error, kind, message) in cases {
+            assert_eq!(error.kind(), kind);
+            assert_eq!(error.to_string(), message);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+/// Image model used when `AssistantSettings.image_model` is not set.
+pub(crate) const DEFAULT_IMAGE_MODEL: &str = "gpt-image-1";
+
+/// Arguments the model passes to the `generate_image` tool.
+#[derive(Debug, Deserialize)]
+pub(crate) struct GenerateImageArguments {
+    pub(crate) prompt: String,
+    #[serde(default)]
+    pub(crate) size: Option<String>,
+    #[serde(default)]
+    pub(crate) quality: Option<String>,
+}
+
+/// Body of an OpenAI compatible `/v1/images/generations` call.
+#[derive(Debug, Serialize)]
+pub(crate) struct ImagesRequest {
+    pub(crate) model: String,
+    pub(crate) prompt: String,
+    pub(crate) n: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) size: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) quality: Option<String>,
+    /// `dall-e-*` models answer with urls unless asked otherwise, `gpt-image-1` rejects the field.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) response_format: Option<String>,
+}
+
+impl ImagesRequest {
+    pub(crate) fn new(model: String, arguments: GenerateImageArguments) -> Self {
+        let response_format = model
+            .starts_with("dall-e")
+            .then(|| "b64_json".to_string());
+        Self {
+            model,
+            prompt: arguments.prompt,
+            n: 1,
+            size: arguments.size,
+            quality: arguments.quality,
+            response_format,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ImagesResponse {
+    pub(crate) data: Vec<ImageData>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ImageData {
+    #[serde(default)]
+    pub(crate) b64_json: Option<String>,
+}
+
+/// Derives the image generation endpoint from the configured chat url.
+///
+/// `.../chat/completions` becomes `.../images/generations`, a bare host gets `/v1/images/generations` appended.
+pub(crate) fn images_url(base_url: &str) -> String {
+    let trimmed = base_url.trim_end_matches('/');
+    if trimmed.ends_with("/images/generations") {
+        trimmed.to_string()
+    } else if let Some(prefix) = trimmed.strip_suffix("/chat/completions") {
+        format!("{}/images/generations", prefix)
+    } else if trimmed.ends_with("/v1") {
+        format!("{}/images/generations", trimmed)
+    } else {
+        format!("{}/v1/images/generations", trimmed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_images_url() {
+        assert_eq!(
+            images_url("https://api.openai.com/v1/chat/completions"),
+            "https://api.openai.com/v1/images/generations"
+        );
+        assert_eq!(
+            images_url("https://api.openai.com/v1/images/generations"),
+            "https://api.openai.com/v1/images/generations"
+        );
+        assert_eq!(
+            images_url("http://localhost:8080/"),
+            "http://localhost:8080/v1/images/generations"
+        );
+    }
+
+    #[test]
+    fn test_images_request_response_format() {
+        let arguments = || {
+            GenerateImageArguments {
+                prompt: "a cat".to_string(),
+                size: Some("1024x1024".to_string()),
+                quality: None,
+            }
+        };
+
+        let dalle = serde_json::to_value(ImagesRequest::new(
+            "dall-e-3".to_string(),
+            arguments(),
+        ))
+        .unwrap();
+        assert_eq!(dalle["response_format"], "b64_json");
+        assert_eq!(dalle["size"], "1024x1024");
+        assert!(dalle.get("quality").is_none());
+
+        let gpt_image = serde_json::to_value(ImagesRequest::new(
+            DEFAULT_IMAGE_MODEL.to_string(),
+            arguments(),
+        ))
+        .unwrap();
+        assert!(
+            gpt_image
+                .get("response_format")
+                .is_none()
+        );
+    }
+}
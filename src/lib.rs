@@ -1,27 +1,63 @@
+mod bedrock_network_types;
 mod cacher;
+mod embeddings_network_types;
+pub mod error;
+mod images_network_types;
+mod logger;
+mod models_network_types;
 mod network_client;
 mod openai_network_types;
 mod provider;
+mod sse;
 pub mod types;
 
-mod logger;
 mod py_worker;
 mod runner;
 pub mod stream_handler;
 mod tools_definition;
+mod trace;
 pub mod worker;
 
+use error::LlmErrorKind;
+use models_network_types::ModelInfo;
 use openai_network_types::Roles;
-use py_worker::{PythonWorker, drop_all, read_all_cache, read_model, write_model, write_to_cache};
+use py_worker::{
+    PythonWorker,
+    add_exceptions,
+    drop_all,
+    drop_last_cache,
+    drop_range_cache,
+    embed,
+    export_cache_markdown,
+    import_cache,
+    list_models,
+    read_all_cache,
+    read_cache_entry,
+    read_model,
+    read_tokens_count,
+    register_tool,
+    search_cache,
+    set_log_level,
+    unregister_tool,
+    update_cache_entry,
+    validate_cache,
+    write_model,
+    write_to_cache,
+};
 use pyo3::prelude::*;
 use types::{
     ApiType,
     AssistantSettings,
+    CircuitBreakerPolicy,
     InputKind,
     PromptMode,
+    RateLimits,
     ReasonEffort,
+    RetryPolicy,
+    StreamOverflow,
     SublimeInputContent,
     SublimeOutputContent,
+    TrimStrategy,
 };
 
 #[pymodule(name = "llm_runner")]
@@ -35,10 +71,35 @@ fn rust_helper(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Roles>()?;
     m.add_class::<ApiType>()?;
     m.add_class::<ReasonEffort>()?;
+    m.add_class::<RetryPolicy>()?;
+    m.add_class::<CircuitBreakerPolicy>()?;
+    m.add_class::<RateLimits>()?;
+    m.add_class::<LlmErrorKind>()?;
+    m.add_class::<TrimStrategy>()?;
+    m.add_class::<StreamOverflow>()?;
+    m.add_class::<ModelInfo>()?;
+    add_exceptions(m)?;
 
     m.add_function(wrap_pyfunction!(read_all_cache, m)?)?;
     m.add_function(wrap_pyfunction!(write_to_cache, m)?)?;
+    m.add_function(wrap_pyfunction!(read_cache_entry, m)?)?;
+    m.add_function(wrap_pyfunction!(update_cache_entry, m)?)?;
+    m.add_function(wrap_pyfunction!(import_cache, m)?)?;
+    m.add_function(wrap_pyfunction!(search_cache, m)?)?;
     m.add_function(wrap_pyfunction!(drop_all, m)?)?;
+    m.add_function(wrap_pyfunction!(drop_last_cache, m)?)?;
+    m.add_function(wrap_pyfunction!(drop_range_cache, m)?)?;
     m.add_function(wrap_pyfunction!(read_model, m)?)?;
+    m.add_function(wrap_pyfunction!(embed, m)?)?;
+    m.add_function(wrap_pyfunction!(list_models, m)?)?;
+    m.add_function(wrap_pyfunction!(validate_cache, m)?)?;
+    m.add_function(wrap_pyfunction!(
+        export_cache_markdown,
+        m
+    )?)?;
+    m.add_function(wrap_pyfunction!(read_tokens_count, m)?)?;
+    m.add_function(wrap_pyfunction!(register_tool, m)?)?;
+    m.add_function(wrap_pyfunction!(unregister_tool, m)?)?;
+    m.add_function(wrap_pyfunction!(set_log_level, m)?)?;
     m.add_function(wrap_pyfunction!(write_model, m)?)
 }
@@ -1,12 +1,121 @@
-use std::fs::File;
+use std::{io::Write, sync::Once};
 
-use fern::Dispatch;
+use anyhow::{Result, anyhow};
+use log::{LevelFilter, Log, Metadata, Record};
 
-#[allow(dead_code)]
-pub fn setup_logger(path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let log_file = File::create(path)?;
+/// Writes the records to stderr, which Sublime Text shows in its console.
+struct StderrLogger;
 
-    Ok(Dispatch::new()
-        .chain(log_file)
-        .apply()?)
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool { metadata.level() <= log::max_level() }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(
+                std::io::stderr(),
+                "[llm_runner {} {}] {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+static INSTALL_LOGGER: Once = Once::new();
+
+/// Sets the most verbose level that gets logged, nothing is logged until it's called.
+///
+/// `level` is one of `off`, `error`, `warn`, `info`, `debug` or `trace`. A logger the host
+/// installed beforehand is kept, otherwise the records go to stderr.
+pub fn set_log_level(level: &str) -> Result<()> {
+    let level = level
+        .parse::<LevelFilter>()
+        .map_err(|_| {
+            anyhow!(
+                "Unknown log level `{}`, expected one of off, error, warn, info, debug, trace",
+                level
+            )
+        })?;
+    INSTALL_LOGGER.call_once(|| {
+        let _ = log::set_logger(&LOGGER);
+    });
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{cacher::Cacher, types::CacheEntry};
+
+    struct CaptureLogger {
+        records: Mutex<Vec<String>>,
+    }
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool { metadata.level() <= log::max_level() }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.records
+                    .lock()
+                    .unwrap()
+                    .push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: CaptureLogger = CaptureLogger {
+        records: Mutex::new(Vec::new()),
+    };
+
+    #[test]
+    fn test_nothing_is_logged_until_level_is_set() {
+        log::set_logger(&CAPTURE).unwrap();
+        let tmp_dir = TempDir::new().unwrap();
+        let cacher = Cacher::new(
+            tmp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        );
+        std::fs::write(&cacher.history_file, "not json\n").unwrap();
+
+        cacher
+            .read_entries::<CacheEntry>()
+            .unwrap();
+        assert!(
+            CAPTURE
+                .records
+                .lock()
+                .unwrap()
+                .is_empty()
+        );
+
+        assert!(set_log_level("verbose").is_err());
+        set_log_level("warn").unwrap();
+        cacher
+            .read_entries::<CacheEntry>()
+            .unwrap();
+        set_log_level("off").unwrap();
+
+        assert!(
+            CAPTURE
+                .records
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|record| record.starts_with("Malformed line skipped: 0"))
+        );
+    }
 }
@@ -0,0 +1,88 @@
+use pyo3::pyclass;
+use serde::Deserialize;
+
+/// One entry of an OpenAI compatible `/v1/models` listing (OpenAI, LM Studio, Ollama, vLLM).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[pyclass]
+pub struct ModelInfo {
+    #[pyo3(get)]
+    pub id: String,
+
+    #[pyo3(get)]
+    #[serde(default)]
+    pub owned_by: Option<String>,
+
+    /// Unix timestamp, local servers tend to omit it.
+    #[pyo3(get)]
+    #[serde(default)]
+    pub created: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ModelsResponse {
+    pub(crate) data: Vec<ModelInfo>,
+}
+
+/// Derives the models endpoint from the configured chat url.
+///
+/// The `/chat/completions` suffix is stripped, `/v1` is appended unless the base already ends with it.
+pub(crate) fn models_url(base_url: &str) -> String {
+    let trimmed = base_url.trim_end_matches('/');
+    let base = trimmed
+        .strip_suffix("/chat/completions")
+        .unwrap_or(trimmed)
+        .trim_end_matches('/');
+    if base.ends_with("/v1") { format!("{}/models", base) } else { format!("{}/v1/models", base) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_models_url() {
+        assert_eq!(
+            models_url("http://localhost:1234/v1/chat/completions"),
+            "http://localhost:1234/v1/models"
+        );
+        assert_eq!(
+            models_url("http://localhost:1234/v1/chat/completions/"),
+            "http://localhost:1234/v1/models"
+        );
+        assert_eq!(
+            models_url("http://localhost:1234/chat/completions"),
+            "http://localhost:1234/v1/models"
+        );
+        assert_eq!(
+            models_url("http://localhost:1234/"),
+            "http://localhost:1234/v1/models"
+        );
+    }
+
+    #[test]
+    fn test_models_response_without_optional_fields() {
+        let response: ModelsResponse = serde_json::from_str(
+            r#"{"object": "list", "data": [
+                {"id": "qwen2.5-coder-7b-instruct", "object": "model", "owned_by": "organization_owner"},
+                {"id": "gpt-4o", "object": "model", "created": 1715367049}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            response.data,
+            vec![
+                ModelInfo {
+                    id: "qwen2.5-coder-7b-instruct".to_string(),
+                    owned_by: Some("organization_owner".to_string()),
+                    created: None,
+                },
+                ModelInfo {
+                    id: "gpt-4o".to_string(),
+                    owned_by: None,
+                    created: Some(1715367049),
+                },
+            ]
+        );
+    }
+}
@@ -118,8 +118,50 @@ pub struct OpenAICompletionRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) parallel_tool_calls: Option<bool>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) stop: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) repetition_penalty: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) min_p: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) top_k: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) response_format: Option<ResponseFormat>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) logprobs: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) top_logprobs: Option<u8>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) n: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) user: Option<String>,
+
+    /// OpenAI reports usage on streams only when asked, in a last chunk with empty `choices`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) stream_options: Option<StreamOptions>,
+
+    /// xAI: queue the completion and return a request id to poll instead of the result.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) deferred: Option<bool>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) tools: Option<Vec<Tool>>,
+
+    /// Only sent along with `tools`, OpenAI rejects it otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tool_choice: Option<ToolChoice>,
 }
 
 impl OpenAICompletionRequest {
@@ -143,9 +185,24 @@ impl OpenAICompletionRequest {
                 .map(|message| OpenAIRequestMessage::from_provider_message(message, settings.api_type)),
         );
 
-        OpenAICompletionRequest {
+        let tools = match settings.api_type {
+            ApiType::OpenAi
+            | ApiType::Mistral
+            | ApiType::AzureOpenAi
+            | ApiType::Groq
+            | ApiType::AzureInference => openai_compat_tools_enabled(settings),
+            ApiType::PlainText => tools_enabled(settings),
+            ApiType::Anthropic
+            | ApiType::OpenAiResponses
+            | ApiType::Google
+            | ApiType::Cohere
+            | ApiType::Ollama
+            | ApiType::Bedrock => None,
+        };
+
+        let mut request = OpenAICompletionRequest {
             messages,
-            stream: settings.stream,
+            stream: settings.stream && !settings.deferred,
             chat_model: settings.chat_model.clone(),
             advertisement: settings.advertisement,
             temperature: settings.temperature,
@@ -155,12 +212,86 @@ impl OpenAICompletionRequest {
             top_p: settings.top_p,
             frequency_penalty: settings.frequency_penalty,
             presence_penalty: settings.presence_penalty,
-            tools: match settings.api_type {
-                ApiType::OpenAi => openai_compat_tools_enabled(settings),
-                ApiType::PlainText => tools_enabled(settings),
-                ApiType::Anthropic | ApiType::OpenAiResponses | ApiType::Google => None,
-            },
+            tool_choice: tools
+                .as_ref()
+                .and(settings.tool_choice.clone()),
+            tools,
             parallel_tool_calls: settings.parallel_tool_calls,
+            seed: settings.seed,
+            stop: settings
+                .stop_sequences
+                .clone(),
+            repetition_penalty: settings.repetition_penalty,
+            min_p: settings.min_p,
+            top_k: settings.top_k,
+            response_format: settings
+                .response_format
+                .clone(),
+            logprobs: settings.logprobs,
+            top_logprobs: settings.top_logprobs,
+            n: settings.n,
+            user: settings.user.clone(),
+            stream_options: (settings.stream && !settings.deferred && settings.api_type == ApiType::OpenAi)
+                .then_some(StreamOptions { include_usage: true }),
+            deferred: settings
+                .deferred
+                .then_some(true),
+        };
+
+        if matches!(
+            settings.api_type,
+            ApiType::OpenAi | ApiType::AzureOpenAi
+        ) && is_reasoning_model(&settings.chat_model)
+        {
+            request.apply_reasoning_model_constraints();
+        }
+
+        request
+    }
+
+    /// o-series models answer 400 to sampling parameters and `max_tokens`, and expect `developer`
+    /// instead of `system`, so such settings are adjusted here with a warning instead of failing.
+    fn apply_reasoning_model_constraints(&mut self) {
+        let mut dropped = Vec::new();
+        for (name, value) in [
+            ("temperature", &mut self.temperature),
+            ("top_p", &mut self.top_p),
+            (
+                "presence_penalty",
+                &mut self.presence_penalty,
+            ),
+            (
+                "frequency_penalty",
+                &mut self.frequency_penalty,
+            ),
+        ] {
+            if value.take().is_some() {
+                dropped.push(name);
+            }
+        }
+        if let Some(max_tokens) = self.max_tokens.take() {
+            dropped.push("max_tokens");
+            self.max_completion_tokens = self
+                .max_completion_tokens
+                .or(Some(max_tokens));
+        }
+        if !dropped.is_empty() {
+            log::warn!(
+                "{} does not support {}, the fields are not sent",
+                self.chat_model,
+                dropped.join(", ")
+            );
+        }
+
+        for message in &mut self.messages {
+            if let OpenAIRequestMessage::OpenAIMessage(
+                message @ OpenAIMessage {
+                    role: Roles::System, ..
+                },
+            ) = message
+            {
+                message.role = Roles::Developer;
+            }
         }
     }
 
@@ -169,34 +300,210 @@ impl OpenAICompletionRequest {
         settings: AssistantSettings,
         cache_entries: Vec<CacheEntry>,
         sublime_inputs: Vec<SublimeInputContent>,
-    ) -> OpenAICompletionRequest {
-        Self::from_conversation(
+    ) -> Result<OpenAICompletionRequest> {
+        settings
+            .validate()
+            .map_err(|errors| anyhow::anyhow!(errors.join("; ")))?;
+        Ok(Self::from_conversation(
             &settings,
             build_conversation(&settings, cache_entries, sublime_inputs),
-        )
+        ))
     }
 }
 
+#[derive(Debug, Serialize)]
+pub(crate) struct StreamOptions {
+    pub(crate) include_usage: bool,
+}
+
+/// `response_format` of chat/completions: JSON mode or structured outputs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    /// The `json_schema` object of structured outputs: `name`, `schema` and optionally `strict`.
+    JsonSchema {
+        #[serde(rename = "json_schema")]
+        schema: Map<String, Value>,
+    },
+}
+
+impl ResponseFormat {
+    /// Reads the settings value: `"json_object"`, `{"type": "json_schema", "json_schema": {...}}`,
+    /// the flat `{"type": "json_schema", "schema": {...}}` (named `response` unless `name` is given)
+    /// or a bare `{"name": ..., "schema": ..., "strict": ...}` object.
+    pub(crate) fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::String(kind) if kind == "text" => Some(Self::Text),
+            Value::String(kind) if kind == "json_object" => Some(Self::JsonObject),
+            Value::Object(mut object) => {
+                match object
+                    .get("type")
+                    .and_then(Value::as_str)
+                {
+                    Some("text") => Some(Self::Text),
+                    Some("json_object") => Some(Self::JsonObject),
+                    Some("json_schema") => {
+                        let schema = match object.remove("json_schema") {
+                            Some(Value::Object(schema)) => schema,
+                            _ if object.contains_key("schema") => {
+                                object.remove("type");
+                                object
+                                    .entry("name")
+                                    .or_insert_with(|| Value::from("response"));
+                                object
+                            }
+                            _ => Map::new(),
+                        };
+                        Some(Self::JsonSchema { schema })
+                    }
+                    Some(_) => None,
+                    None => Some(Self::JsonSchema { schema: object }),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// OpenAI rejects a `json_schema` without a `name`, so it is caught before the request is sent.
+    pub(crate) fn validate(&self) -> Result<()> {
+        match self {
+            Self::JsonSchema { schema }
+                if schema
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .is_none_or(str::is_empty) =>
+            {
+                Err(anyhow::anyhow!(
+                    "response_format json_schema requires a `name`"
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `tool_choice` of chat/completions: whether the model may, must or must not call a tool,
+/// or which one it has to call.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolChoice {
+    None,
+    Auto,
+    Required,
+    /// Forces a call of the function with this name.
+    Specific(String),
+}
+
+impl ToolChoice {
+    /// Reads the settings value: `"none"`, `"auto"`, `"required"`,
+    /// `{"type": "function", "function": {"name": ...}}` or the bare `{"name": ...}`.
+    pub(crate) fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::String(kind) => {
+                match kind.as_str() {
+                    "none" => Some(Self::None),
+                    "auto" => Some(Self::Auto),
+                    "required" => Some(Self::Required),
+                    _ => None,
+                }
+            }
+            Value::Object(object) => {
+                object
+                    .get("function")
+                    .unwrap_or(&Value::Object(object.clone()))
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .map(|name| Self::Specific(name.to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for ToolChoice {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::None => serializer.serialize_str("none"),
+            Self::Auto => serializer.serialize_str("auto"),
+            Self::Required => serializer.serialize_str("required"),
+            Self::Specific(name) => {
+                serde_json::json!({
+                    "type": "function",
+                    "function": {"name": name},
+                })
+                .serialize(serializer)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ToolChoice {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        Self::from_value(value.clone()).ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "unknown tool_choice: {}",
+                value
+            ))
+        })
+    }
+}
+
+/// o1/o3/o4 reasoning models, optionally behind a router prefix like `openai/o3-mini`.
+pub(crate) fn is_reasoning_model(chat_model: &str) -> bool {
+    let model = chat_model
+        .rsplit('/')
+        .next()
+        .unwrap_or(chat_model);
+    ["o1", "o3", "o4"]
+        .iter()
+        .any(|prefix| model.starts_with(prefix))
+}
+
 impl OpenAIRequestMessage {
     fn from_system(content: String, api_type: ApiType) -> Self {
         match api_type {
-            ApiType::OpenAi => Self::OpenAIMessage(OpenAIMessage::from_system(content)),
+            ApiType::OpenAi
+            | ApiType::Mistral
+            | ApiType::AzureOpenAi
+            | ApiType::Groq
+            | ApiType::AzureInference => Self::OpenAIMessage(OpenAIMessage::from_system(content)),
             ApiType::PlainText => {
                 Self::OpenAIPlainTextMessage(OpenAIPlainTextMessage::from_system(
                     content,
                 ))
             }
-            ApiType::Anthropic | ApiType::OpenAiResponses | ApiType::Google => {
+            ApiType::Anthropic
+            | ApiType::OpenAiResponses
+            | ApiType::Google
+            | ApiType::Cohere
+            | ApiType::Ollama
+            | ApiType::Bedrock => {
                 unreachable!("provider-specific request building is handled in crate::provider")
             }
         }
     }
 
-    fn from_provider_message(message: ProviderMessage, api_type: ApiType) -> Self {
+    fn from_provider_message(mut message: ProviderMessage, api_type: ApiType) -> Self {
         match api_type {
-            ApiType::OpenAi => Self::OpenAIMessage(OpenAIMessage::from(message)),
+            ApiType::OpenAi | ApiType::AzureOpenAi | ApiType::Groq => {
+                Self::OpenAIMessage(OpenAIMessage::from(message))
+            }
+            ApiType::Mistral | ApiType::AzureInference => {
+                // Neither Mistral nor the non-OpenAI models behind Azure AI Inference know the `developer` role.
+                if message.role == Roles::Developer {
+                    message.role = Roles::System;
+                }
+                Self::OpenAIMessage(OpenAIMessage::from(message))
+            }
             ApiType::PlainText => Self::OpenAIPlainTextMessage(OpenAIPlainTextMessage::from(message)),
-            ApiType::Anthropic | ApiType::OpenAiResponses | ApiType::Google => {
+            ApiType::Anthropic
+            | ApiType::OpenAiResponses
+            | ApiType::Google
+            | ApiType::Cohere
+            | ApiType::Ollama
+            | ApiType::Bedrock => {
                 unreachable!("provider-specific request building is handled in crate::provider")
             }
         }
@@ -206,6 +513,7 @@ impl OpenAIRequestMessage {
 #[derive(Serialize, Debug, PartialEq, Eq)]
 pub(crate) enum MessageKind {
     SystemMessage,
+    Thinking,
 
     SheetContent,
     CacheEntry,
@@ -220,7 +528,7 @@ impl From<InputKind> for MessageKind {
     fn from(value: InputKind) -> Self {
         match value {
             InputKind::Command => Self::UserCommand,
-            InputKind::ViewSelection => Self::ViewSelection,
+            InputKind::ViewSelection | InputKind::Image | InputKind::Audio => Self::ViewSelection,
             InputKind::BuildOutputPanel | InputKind::LspOutputPanel | InputKind::Terminus => {
                 Self::OutputPaneContent
             }
@@ -236,11 +544,12 @@ impl MessageKind {
     pub(crate) fn weight(&self) -> u8 {
         match self {
             Self::SystemMessage => 0,
-            Self::SheetContent => 1,
-            Self::CacheEntry => 2,
-            Self::OutputPaneContent => 3,
-            Self::ViewSelection => 4,
-            Self::UserCommand | Self::FunctionResult => 5,
+            Self::Thinking => 1,
+            Self::SheetContent => 2,
+            Self::CacheEntry => 3,
+            Self::OutputPaneContent => 4,
+            Self::ViewSelection => 5,
+            Self::UserCommand | Self::FunctionResult => 6,
         }
     }
 }
@@ -282,6 +591,7 @@ impl From<crate::provider::MessageKind> for MessageKind {
     fn from(value: crate::provider::MessageKind) -> Self {
         match value {
             crate::provider::MessageKind::SystemMessage => Self::SystemMessage,
+            crate::provider::MessageKind::Thinking => Self::Thinking,
             crate::provider::MessageKind::SheetContent => Self::SheetContent,
             crate::provider::MessageKind::CacheEntry => Self::CacheEntry,
             crate::provider::MessageKind::OutputPaneContent => Self::OutputPaneContent,
@@ -309,10 +619,19 @@ impl From<CacheEntry> for OpenAIMessage {
 
 impl From<ProviderMessage> for OpenAIMessage {
     fn from(value: ProviderMessage) -> Self {
+        let mut content = vec![MessageContent::from_text(value.content)];
+        content.extend(
+            value
+                .image_url
+                .map(MessageContent::from_image_url),
+        );
+        content.extend(
+            value
+                .input_audio
+                .map(MessageContent::from_input_audio),
+        );
         Self {
-            content: Some(vec![MessageContent::from_text(
-                value.content,
-            )]),
+            content: Some(content),
             role: value.role,
             tool_call_id: value.tool_call_id,
             name: None,
@@ -324,10 +643,30 @@ impl From<ProviderMessage> for OpenAIMessage {
 
 impl From<SublimeInputContent> for OpenAIMessage {
     fn from(value: SublimeInputContent) -> Self {
+        let mut content = vec![MessageContent::from_text(
+            value.combined_content(),
+        )];
+        match value.input_kind {
+            InputKind::Image => {
+                content.extend(
+                    value
+                        .image_data_url()
+                        .ok()
+                        .map(MessageContent::from_image_url),
+                )
+            }
+            InputKind::Audio => {
+                content.extend(
+                    value
+                        .audio_content()
+                        .ok()
+                        .map(MessageContent::from_input_audio),
+                )
+            }
+            _ => {}
+        }
         Self {
-            content: Some(vec![MessageContent::from_text(
-                value.combined_content(),
-            )]),
+            content: Some(content),
             role: if value.tool_id.is_some() { Roles::Tool } else { Roles::User },
             tool_call_id: value.tool_id,
             name: None,
@@ -422,6 +761,20 @@ impl MessageContent {
             content: ContentWrapper::Text(content),
         }
     }
+
+    pub(crate) fn from_image_url(url: String) -> Self {
+        MessageContent {
+            r#type: OpenAIMessageType::ImageUrl,
+            content: ContentWrapper::ImageUrl(ImageContent { url, detail: None }),
+        }
+    }
+
+    pub(crate) fn from_input_audio(audio: AudioContent) -> Self {
+        MessageContent {
+            r#type: OpenAIMessageType::InputAudio,
+            content: ContentWrapper::InputAudio(audio),
+        }
+    }
 }
 
 impl serde::ser::Serialize for MessageContent {
@@ -545,6 +898,63 @@ pub(crate) struct OpenAIResponse {
     pub(crate) created: Option<i64>,
     pub(crate) model: String,
     pub(crate) choices: Vec<Choice>,
+    /// Backend configuration the answer came from, a changed value explains a different answer for the same `seed`.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) system_fingerprint: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) usage: Option<TokenUsage>,
+    /// Perplexity: urls of the sources the answer is grounded on.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) citations: Option<Vec<String>>,
+    /// Perplexity: the sources with their titles, the successor of `citations`.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) search_results: Option<Vec<SearchResult>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct SearchResult {
+    pub(crate) url: String,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) title: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) date: Option<String>,
+}
+
+impl OpenAIResponse {
+    /// Source urls from `citations`, falling back to the ones of `search_results`.
+    pub(crate) fn source_urls(&self) -> Option<Vec<String>> {
+        self.citations
+            .clone()
+            .or_else(|| {
+                self.search_results
+                    .as_ref()
+                    .map(|results| {
+                        results
+                            .iter()
+                            .map(|result| result.url.clone())
+                            .collect()
+                    })
+            })
+            .filter(|urls: &Vec<String>| !urls.is_empty())
+    }
 }
 
 #[derive(Serialize, Debug, PartialEq, Clone)]
@@ -552,6 +962,9 @@ pub(crate) struct Choice {
     pub(crate) index: usize,
     pub(crate) finish_reason: Option<String>,
     pub(crate) message: AssistantMessage,
+    /// Per-token log probabilities, present when the request asked for `logprobs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) logprobs: Option<LogprobsContent>,
 }
 
 impl<'de> serde::de::Deserialize<'de> for Choice {
@@ -565,6 +978,8 @@ impl<'de> serde::de::Deserialize<'de> for Choice {
             #[serde(default)]
             delta: Option<AssistantMessage>,
             finish_reason: Option<String>,
+            #[serde(default)]
+            logprobs: Option<LogprobsContent>,
         }
 
         let temp = TempChoice::deserialize(deserializer)?;
@@ -579,10 +994,58 @@ impl<'de> serde::de::Deserialize<'de> for Choice {
             index: temp.index,
             finish_reason: temp.finish_reason,
             message,
+            logprobs: temp.logprobs,
         })
     }
 }
 
+/// `logprobs` of a chat/completions choice.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub(crate) struct LogprobsContent {
+    /// Absent or `null` when the model refused, the refusal tokens are not kept.
+    #[serde(
+        default,
+        deserialize_with = "null_as_default"
+    )]
+    pub(crate) content: Vec<TokenLogprob>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct TokenLogprob {
+    pub(crate) token: String,
+    pub(crate) logprob: f64,
+    /// UTF-8 bytes of the token, tokens may split a multi-byte character.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) bytes: Option<Vec<u8>>,
+    /// The `top_logprobs` most likely tokens at this position.
+    #[serde(
+        default,
+        deserialize_with = "null_as_default"
+    )]
+    pub(crate) top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct TopLogprob {
+    pub(crate) token: String,
+    pub(crate) logprob: f64,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) bytes: Option<Vec<u8>>,
+}
+
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+    T: Default + Deserialize<'de>, {
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub(crate) struct AssistantMessage {
     pub(crate) role: Roles,
@@ -590,6 +1053,88 @@ pub(crate) struct AssistantMessage {
     pub(crate) tool_calls: Option<Vec<ToolCall>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) provider_metadata: Option<ProviderMetadata>,
+    /// Token usage reported by the provider for the turn that produced this message.
+    #[serde(skip)]
+    pub(crate) usage: Option<TokenUsage>,
+    /// Id the Responses API assigned to the response that produced this message.
+    #[serde(skip)]
+    pub(crate) response_id: Option<String>,
+    /// Source urls the provider grounded this message on (Perplexity).
+    #[serde(skip)]
+    pub(crate) citations: Option<Vec<String>>,
+    /// Token log probabilities of the choice this message came from.
+    #[serde(skip)]
+    pub(crate) logprobs: Option<LogprobsContent>,
+    /// Chain of thought streamed apart from `content` by DeepSeek-style providers.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) reasoning_content: Option<String>,
+    /// The other choices of the same response when more than one was requested with `n`.
+    #[serde(skip)]
+    pub(crate) alternatives: Vec<AssistantMessage>,
+    /// The stream stalled before the provider finished this message.
+    #[serde(skip)]
+    pub(crate) stalled: bool,
+    /// Why the provider stopped generating this message, e.g. `stop` or `length`.
+    #[serde(skip)]
+    pub(crate) finish_reason: Option<String>,
+}
+
+impl AssistantMessage {
+    /// This message followed by its `alternatives`, each one standing on its own.
+    pub(crate) fn into_choices(mut self) -> Vec<AssistantMessage> {
+        let alternatives = std::mem::take(&mut self.alternatives);
+        std::iter::once(self)
+            .chain(alternatives)
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub(crate) struct TokenUsage {
+    pub(crate) prompt_tokens: usize,
+    pub(crate) completion_tokens: usize,
+    pub(crate) total_tokens: usize,
+    /// Seconds spent by the provider on the request, when reported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) total_time: Option<f64>,
+    /// Seconds the request waited in the provider's queue (Groq).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) queue_time: Option<f64>,
+    /// Prompt tokens written to the provider's prompt cache (Anthropic).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cache_creation_input_tokens: Option<usize>,
+    /// Prompt tokens served from the provider's prompt cache (Anthropic).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cache_read_input_tokens: Option<usize>,
+}
+
+impl TokenUsage {
+    /// Adds `other` to this usage, keeping the optional fields unset while neither side reports them.
+    pub(crate) fn accumulate(&mut self, other: &TokenUsage) {
+        fn add<T: Copy + std::ops::Add<Output = T>>(total: Option<T>, other: Option<T>) -> Option<T> {
+            match (total, other) {
+                (Some(total), Some(other)) => Some(total + other),
+                (total, other) => total.or(other),
+            }
+        }
+
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.total_time = add(self.total_time, other.total_time);
+        self.queue_time = add(self.queue_time, other.queue_time);
+        self.cache_creation_input_tokens = add(
+            self.cache_creation_input_tokens,
+            other.cache_creation_input_tokens,
+        );
+        self.cache_read_input_tokens = add(
+            self.cache_read_input_tokens,
+            other.cache_read_input_tokens,
+        );
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -657,6 +1202,11 @@ mod tests {
             path: None,
             scope: None,
             provider_metadata: None,
+            created_at: None,
+            citations: None,
+            logprobs: None,
+            user: None,
+            finish_reason: None,
         }
     }
 
@@ -708,6 +1258,11 @@ mod tests {
             path: None,
             scope: None,
             provider_metadata: None,
+            created_at: None,
+            citations: None,
+            logprobs: None,
+            user: None,
+            finish_reason: None,
         }
     }
 
@@ -782,8 +1337,21 @@ mod tests {
             frequency_penalty: None,
             presence_penalty: Some(0.0),
             tools: None,
+            tool_choice: None,
             parallel_tool_calls: None,
             reasoning_effort: None,
+            seed: None,
+            stop: None,
+            repetition_penalty: None,
+            min_p: None,
+            top_k: None,
+            response_format: None,
+            logprobs: None,
+            user: None,
+            stream_options: None,
+            top_logprobs: None,
+            n: None,
+            deferred: None,
         };
 
         let serialized = serde_json::to_string(&request).unwrap();
@@ -882,9 +1450,22 @@ mod tests {
                     strict: Some(true),
                 }),
             }]),
+            tool_choice: None,
 
             parallel_tool_calls: Some(false),
             reasoning_effort: None,
+            seed: None,
+            stop: None,
+            repetition_penalty: None,
+            min_p: None,
+            top_k: None,
+            response_format: None,
+            logprobs: None,
+            user: None,
+            stream_options: None,
+            top_logprobs: None,
+            n: None,
+            deferred: None,
         };
 
         let serialized = serde_json::to_string(&request).unwrap();
@@ -953,6 +1534,282 @@ mod tests {
         assert_eq!(serialized_json, expected);
     }
 
+    #[test]
+    fn test_reasoning_model_request_drops_unsupported_fields() {
+        let mut settings = dummy_settings(ApiType::OpenAi);
+        settings.temperature = Some(0.7);
+        settings.top_p = Some(1.0);
+        settings.presence_penalty = Some(0.5);
+        settings.frequency_penalty = Some(0.5);
+        settings.max_tokens = Some(2048);
+        settings.reasoning_effort = Some(ReasonEffort::High);
+
+        let request = |chat_model: &str| -> Value {
+            let mut settings = settings.clone();
+            settings.chat_model = chat_model.to_string();
+            serde_json::to_value(
+                OpenAICompletionRequest::create_openai_completion_request(
+                    settings,
+                    vec![],
+                    vec![dummy_sublime_input(
+                        "Hello",
+                        InputKind::Command,
+                    )],
+                )
+                .unwrap(),
+            )
+            .unwrap()
+        };
+
+        let reasoning = request("o3-mini");
+        for field in [
+            "temperature",
+            "top_p",
+            "presence_penalty",
+            "frequency_penalty",
+            "max_tokens",
+        ] {
+            assert!(
+                reasoning.get(field).is_none(),
+                "{field} must not be sent"
+            );
+        }
+        assert_eq!(reasoning["max_completion_tokens"], 2048);
+        assert_eq!(reasoning["reasoning_effort"], "high");
+        assert_eq!(
+            reasoning["messages"][0]["role"],
+            "developer"
+        );
+
+        let standard = request("gpt-4o");
+        assert_eq!(standard["temperature"], 0.7);
+        assert_eq!(standard["top_p"], 1.0);
+        assert_eq!(standard["presence_penalty"], 0.5);
+        assert_eq!(standard["frequency_penalty"], 0.5);
+        assert_eq!(standard["max_tokens"], 2048);
+        assert!(
+            standard
+                .get("max_completion_tokens")
+                .is_none()
+        );
+        assert_eq!(
+            standard["messages"][0]["role"],
+            "system"
+        );
+    }
+
+    #[test]
+    fn test_extended_sampling_params_serialization() {
+        let request = |settings: AssistantSettings| -> Value {
+            serde_json::to_value(
+                OpenAICompletionRequest::create_openai_completion_request(
+                    settings,
+                    vec![],
+                    vec![dummy_sublime_input(
+                        "Hello",
+                        InputKind::Command,
+                    )],
+                )
+                .unwrap(),
+            )
+            .unwrap()
+        };
+        let fields = [
+            "stop",
+            "repetition_penalty",
+            "min_p",
+            "top_k",
+        ];
+
+        let absent = request(dummy_settings(ApiType::OpenAi));
+        for field in fields {
+            assert!(
+                absent.get(field).is_none(),
+                "{field} must be skipped when unset"
+            );
+        }
+
+        let mut settings = dummy_settings(ApiType::OpenAi);
+        settings.stop_sequences = Some(vec![
+            "</s>".to_string(),
+            "\n\n".to_string(),
+        ]);
+        settings.repetition_penalty = Some(1.1);
+        settings.min_p = Some(0.05);
+        settings.top_k = Some(40);
+
+        let present = request(settings);
+        assert_eq!(present["stop"], json!(["</s>", "\n\n"]));
+        assert_eq!(present["repetition_penalty"], 1.1);
+        assert_eq!(present["min_p"], 0.05);
+        assert_eq!(present["top_k"], 40);
+
+        let mut settings = dummy_settings(ApiType::OpenAi);
+        settings.stop_sequences = Some(vec!["END".to_string()]);
+        assert_eq!(
+            request(settings)["stop"],
+            json!(["END"])
+        );
+    }
+
+    #[test]
+    fn test_response_format_parsing_and_serialization() {
+        assert_eq!(
+            ResponseFormat::from_value(json!("json_object")),
+            Some(ResponseFormat::JsonObject)
+        );
+        assert_eq!(
+            ResponseFormat::from_value(json!("yaml")),
+            None
+        );
+
+        let schema = json!({
+            "name": "answer",
+            "strict": true,
+            "schema": {"type": "object", "properties": {"ok": {"type": "boolean"}}}
+        });
+        let bare = ResponseFormat::from_value(schema.clone()).unwrap();
+        let wrapped =
+            ResponseFormat::from_value(json!({"type": "json_schema", "json_schema": schema.clone()}))
+                .unwrap();
+        assert_eq!(bare, wrapped);
+        assert!(bare.validate().is_ok());
+
+        let mut settings = dummy_settings(ApiType::OpenAi);
+        settings.response_format = Some(bare);
+        let request = serde_json::to_value(
+            OpenAICompletionRequest::create_openai_completion_request(
+                settings,
+                vec![],
+                vec![dummy_sublime_input(
+                    "Hello",
+                    InputKind::Command,
+                )],
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            request["response_format"],
+            json!({"type": "json_schema", "json_schema": schema})
+        );
+
+        let unnamed =
+            ResponseFormat::from_value(json!({"type": "json_schema", "json_schema": {"schema": {}}}))
+                .unwrap();
+        assert_eq!(
+            unnamed
+                .validate()
+                .unwrap_err()
+                .to_string(),
+            "response_format json_schema requires a `name`"
+        );
+    }
+
+    #[test]
+    fn test_response_format_variants_roundtrip() {
+        let schema = json!({"type": "object", "properties": {"ok": {"type": "boolean"}}});
+        let flat =
+            ResponseFormat::from_value(json!({"type": "json_schema", "schema": schema.clone()})).unwrap();
+        assert!(flat.validate().is_ok());
+
+        let cases = [
+            (
+                ResponseFormat::Text,
+                json!({"type": "text"}),
+            ),
+            (
+                ResponseFormat::JsonObject,
+                json!({"type": "json_object"}),
+            ),
+            (
+                flat,
+                json!({"type": "json_schema", "json_schema": {"name": "response", "schema": schema}}),
+            ),
+        ];
+        for (format, expected) in cases {
+            assert_eq!(
+                serde_json::to_value(&format).unwrap(),
+                expected
+            );
+            assert_eq!(
+                serde_json::from_value::<ResponseFormat>(expected).unwrap(),
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn test_tool_choice_variants_roundtrip() {
+        let cases = [
+            (ToolChoice::None, json!("none")),
+            (ToolChoice::Auto, json!("auto")),
+            (ToolChoice::Required, json!("required")),
+            (
+                ToolChoice::Specific("apply_patch".to_string()),
+                json!({"type": "function", "function": {"name": "apply_patch"}}),
+            ),
+        ];
+        for (choice, expected) in cases {
+            assert_eq!(
+                serde_json::to_value(&choice).unwrap(),
+                expected
+            );
+            assert_eq!(
+                serde_json::from_value::<ToolChoice>(expected).unwrap(),
+                choice
+            );
+        }
+
+        assert_eq!(
+            ToolChoice::from_value(json!({"name": "apply_patch"})),
+            Some(ToolChoice::Specific(
+                "apply_patch".to_string()
+            ))
+        );
+        assert_eq!(
+            ToolChoice::from_value(json!("sometimes")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_tool_choice_is_sent_only_with_tools() {
+        let mut settings = AssistantSettings {
+            api_type: ApiType::OpenAi,
+            tool_choice: Some(ToolChoice::Required),
+            ..Default::default()
+        };
+
+        settings.tools = Some(true);
+        let request =
+            OpenAICompletionRequest::create_openai_completion_request(settings.clone(), vec![], vec![])
+                .unwrap();
+        assert_eq!(
+            serde_json::to_value(&request).unwrap()["tool_choice"],
+            json!("required")
+        );
+
+        settings.tools = None;
+        let request =
+            OpenAICompletionRequest::create_openai_completion_request(settings, vec![], vec![]).unwrap();
+        assert!(
+            serde_json::to_value(&request)
+                .unwrap()
+                .get("tool_choice")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_is_reasoning_model() {
+        assert!(is_reasoning_model("o1"));
+        assert!(is_reasoning_model("o4-mini"));
+        assert!(is_reasoning_model("openai/o3-mini"));
+        assert!(!is_reasoning_model("gpt-4o"));
+        assert!(!is_reasoning_model("claude-opus-4"));
+    }
+
     #[test]
     fn test_openai_request_serialization_minimal() {
         let request = OpenAICompletionRequest {
@@ -967,8 +1824,21 @@ mod tests {
             frequency_penalty: None,
             presence_penalty: None,
             tools: None,
+            tool_choice: None,
             parallel_tool_calls: None,
             reasoning_effort: None,
+            seed: None,
+            stop: None,
+            repetition_penalty: None,
+            min_p: None,
+            top_k: None,
+            response_format: None,
+            logprobs: None,
+            user: None,
+            stream_options: None,
+            top_logprobs: None,
+            n: None,
+            deferred: None,
         };
 
         let serialized = serde_json::to_string(&request).unwrap();
@@ -992,13 +1862,26 @@ mod tests {
             choices: vec![Choice {
                 index: 0,
                 finish_reason: None,
+                logprobs: None,
                 message: AssistantMessage {
                     role: Roles::Assistant,
                     content: Some("Response text".to_string()),
                     tool_calls: None,
                     provider_metadata: None,
+                    usage: None,
+                    response_id: None,
+                    citations: None,
+                    logprobs: None,
+                    reasoning_content: None,
+                    alternatives: vec![],
+                    stalled: false,
+                    finish_reason: None,
                 },
             }],
+            system_fingerprint: None,
+            usage: None,
+            citations: None,
+            search_results: None,
         };
 
         // Serialize the response directly to JSON
@@ -1045,6 +1928,14 @@ mod tests {
                 },
             }]),
             provider_metadata: None,
+            usage: None,
+            response_id: None,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
         };
 
         let serialized = serde_json::to_string(&assistant_message).unwrap();
@@ -1160,6 +2051,218 @@ mod tests {
                 .content,
             Some("Response text".to_string())
         );
+        assert_eq!(response.system_fingerprint, None);
+    }
+
+    #[test]
+    fn test_openai_response_deserialization_with_system_fingerprint() {
+        let response: OpenAIResponse = serde_json::from_value(json!({
+            "id": "chatcmpl-123",
+            "object": "chat.completion",
+            "created": 1616161616,
+            "model": "gpt-4o",
+            "system_fingerprint": "fp_44709d6fcb",
+            "choices": [{
+                "index": 0,
+                "finish_reason": "stop",
+                "message": {"role": "assistant", "content": "4"}
+            }]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            response
+                .system_fingerprint
+                .as_deref(),
+            Some("fp_44709d6fcb")
+        );
+    }
+
+    #[test]
+    fn test_seed_serialized_only_when_set() {
+        let request = |seed: Option<i64>| -> Value {
+            let mut settings = dummy_settings(ApiType::OpenAi);
+            settings.seed = seed;
+            serde_json::to_value(
+                OpenAICompletionRequest::create_openai_completion_request(
+                    settings,
+                    vec![],
+                    vec![dummy_sublime_input(
+                        "Hello",
+                        InputKind::Command,
+                    )],
+                )
+                .unwrap(),
+            )
+            .unwrap()
+        };
+
+        assert!(
+            request(None)
+                .get("seed")
+                .is_none()
+        );
+        assert_eq!(request(Some(42))["seed"], 42);
+    }
+
+    #[test]
+    fn test_logprobs_request_serialization() {
+        let mut settings = dummy_settings(ApiType::OpenAi);
+        let request = |settings: AssistantSettings| -> Value {
+            serde_json::to_value(
+                OpenAICompletionRequest::create_openai_completion_request(
+                    settings,
+                    vec![],
+                    vec![dummy_sublime_input(
+                        "Hello",
+                        InputKind::Command,
+                    )],
+                )
+                .unwrap(),
+            )
+            .unwrap()
+        };
+
+        let absent = request(settings.clone());
+        assert!(
+            absent
+                .get("logprobs")
+                .is_none()
+        );
+        assert!(
+            absent
+                .get("top_logprobs")
+                .is_none()
+        );
+
+        settings.logprobs = Some(true);
+        settings.top_logprobs = Some(3);
+        let present = request(settings);
+        assert_eq!(present["logprobs"], true);
+        assert_eq!(present["top_logprobs"], 3);
+    }
+
+    #[test]
+    fn test_user_request_serialization() {
+        let mut settings = dummy_settings(ApiType::OpenAi);
+        let request = |settings: AssistantSettings| -> Value {
+            serde_json::to_value(
+                OpenAICompletionRequest::create_openai_completion_request(
+                    settings,
+                    vec![],
+                    vec![dummy_sublime_input(
+                        "Hello",
+                        InputKind::Command,
+                    )],
+                )
+                .unwrap(),
+            )
+            .unwrap()
+        };
+
+        assert!(
+            request(settings.clone())
+                .get("user")
+                .is_none()
+        );
+
+        settings.user = Some("user-1234".to_string());
+        assert_eq!(request(settings)["user"], "user-1234");
+    }
+
+    #[test]
+    fn test_create_request_rejects_out_of_range_settings() {
+        let mut settings = dummy_settings(ApiType::OpenAi);
+        settings.presence_penalty = Some(2.5);
+
+        let error =
+            OpenAICompletionRequest::create_openai_completion_request(settings, vec![], vec![]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`presence_penalty` must be within [-2, 2], got 2.5"
+        );
+    }
+
+    #[test]
+    fn test_openai_response_logprobs_deserialization() {
+        let response: OpenAIResponse = serde_json::from_value(json!({
+            "id": "chatcmpl-9vF1",
+            "object": "chat.completion",
+            "created": 1723544045,
+            "model": "gpt-4o-mini-2024-07-18",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hello!", "refusal": null},
+                "logprobs": {
+                    "content": [
+                        {
+                            "token": "Hello",
+                            "logprob": -0.31725305,
+                            "bytes": [72, 101, 108, 108, 111],
+                            "top_logprobs": [
+                                {"token": "Hello", "logprob": -0.31725305, "bytes": [72, 101, 108, 108, 111]},
+                                {"token": "Hi", "logprob": -1.3190403, "bytes": [72, 105]}
+                            ]
+                        },
+                        {"token": "!", "logprob": -0.02380986, "bytes": [33], "top_logprobs": []}
+                    ],
+                    "refusal": null
+                },
+                "finish_reason": "stop"
+            }]
+        }))
+        .unwrap();
+
+        let logprobs = response.choices[0]
+            .logprobs
+            .as_ref()
+            .unwrap();
+        assert_eq!(logprobs.content.len(), 2);
+        assert_eq!(logprobs.content[0].token, "Hello");
+        assert_eq!(logprobs.content[0].logprob, -0.31725305);
+        assert_eq!(
+            logprobs.content[0].top_logprobs[1],
+            TopLogprob {
+                token: "Hi".to_string(),
+                logprob: -1.3190403,
+                bytes: Some(vec![72, 105]),
+            }
+        );
+        assert!(
+            logprobs.content[1]
+                .top_logprobs
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_openai_response_logprobs_absent_or_null() {
+        let choice = |logprobs: Value| -> Choice {
+            serde_json::from_value(json!({
+                "index": 0,
+                "message": {"role": "assistant", "content": "Sorry"},
+                "logprobs": logprobs,
+                "finish_reason": "stop"
+            }))
+            .unwrap()
+        };
+
+        assert_eq!(choice(Value::Null).logprobs, None);
+        assert_eq!(
+            choice(
+                json!({"content": null, "refusal": [{"token": "Sorry", "logprob": -0.1, "top_logprobs": []}]})
+            )
+            .logprobs,
+            Some(LogprobsContent::default())
+        );
+
+        let without_logprobs: Choice = serde_json::from_value(json!({
+            "index": 0,
+            "message": {"role": "assistant", "content": "Hi"},
+            "finish_reason": "stop"
+        }))
+        .unwrap();
+        assert_eq!(without_logprobs.logprobs, None);
     }
 
     #[test]
@@ -1226,6 +2329,14 @@ mod tests {
                     content: Some("Hello, how can I help?".to_string()),
                     tool_calls: None,
                     provider_metadata: None,
+                    usage: None,
+                    response_id: None,
+                    citations: None,
+                    logprobs: None,
+                    reasoning_content: None,
+                    alternatives: vec![],
+                    stalled: false,
+                    finish_reason: None,
                 }) as Box<dyn std::any::Any>
             } else {
                 // Otherwise, return an OpenAIMessage
@@ -1312,8 +2423,8 @@ mod tests {
         let cache_entries = vec![dummy_cache_entry()];
 
         // We create a few sublime inputs covering all message kinds:
-        // Sheet -> weight 1, BuildOutputPanel -> weight 3, ViewSelection -> weight 4,
-        // Command -> weight 5 (user command)
+        // Sheet -> weight 2, BuildOutputPanel -> weight 4, ViewSelection -> weight 5,
+        // Command -> weight 6 (user command)
         let sublime_inputs = vec![
             dummy_sublime_input("Sheet content", InputKind::Sheet),
             dummy_sublime_input(
@@ -1331,7 +2442,8 @@ mod tests {
             settings,
             cache_entries,
             sublime_inputs,
-        );
+        )
+        .unwrap();
 
         // There should be a system message plus one cache entry plus the four sublime inputs.
         assert_eq!(request.messages.len(), 6);
@@ -1343,7 +2455,7 @@ mod tests {
             .iter()
             .map(message_weight)
             .collect();
-        assert_eq!(weights, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(weights, vec![0, 2, 3, 4, 5, 6]);
 
         // Verify the last message is a UserCommand.
         match request
@@ -1386,7 +2498,8 @@ mod tests {
             settings,
             cache_entries,
             sublime_inputs,
-        );
+        )
+        .unwrap();
 
         assert_eq!(request.messages.len(), 6);
 
@@ -1395,7 +2508,7 @@ mod tests {
             .iter()
             .map(message_weight)
             .collect();
-        assert_eq!(weights, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(weights, vec![0, 2, 3, 4, 5, 6]);
 
         // Verify the last message is a FunctionResult.
         match request
@@ -1434,7 +2547,8 @@ mod tests {
             settings,
             cache_entries,
             sublime_inputs,
-        );
+        )
+        .unwrap();
 
         assert_eq!(request.messages.len(), 6);
 
@@ -1443,7 +2557,7 @@ mod tests {
             .iter()
             .map(message_weight)
             .collect();
-        assert_eq!(weights, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(weights, vec![0, 2, 3, 4, 5, 6]);
 
         // For PlainText branch, messages are built using OpenAIPlainTextMessage.
         // Verify the last message is a UserCommand.
@@ -1486,7 +2600,8 @@ mod tests {
             settings,
             cache_entries,
             sublime_inputs,
-        );
+        )
+        .unwrap();
 
         assert_eq!(request.messages.len(), 6);
 
@@ -1495,7 +2610,7 @@ mod tests {
             .iter()
             .map(message_weight)
             .collect();
-        assert_eq!(weights, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(weights, vec![0, 2, 3, 4, 5, 6]);
 
         // Verify the last message is a FunctionResult.
         match request
@@ -1526,6 +2641,11 @@ mod tests {
                 path: None,
                 scope: None,
                 provider_metadata: None,
+                created_at: None,
+                citations: None,
+                logprobs: None,
+                user: None,
+                finish_reason: None,
             }
         }
         let cache_entries = vec![
@@ -1534,7 +2654,7 @@ mod tests {
             dummy_cache_entry_with_content("cache 3"),
         ];
 
-        // Create sublime inputs with InputKind::Command (which converts to MessageKind::UserCommand, weight 5)
+        // Create sublime inputs with InputKind::Command (which converts to MessageKind::UserCommand, weight 6)
         // to track their original insertion order.
         let sublime_inputs = vec![
             dummy_sublime_input("sublime 1", InputKind::Command),
@@ -1546,16 +2666,17 @@ mod tests {
             settings,
             cache_entries,
             sublime_inputs,
-        );
+        )
+        .unwrap();
 
         // The messages vector now is sorted by their weight.
         // Since there is no system message:
-        // - Cache entries (weight 2) will appear first.
-        // - Sublime inputs (weight 5) come later.
+        // - Cache entries (weight 3) will appear first.
+        // - Sublime inputs (weight 6) come later.
         //
         // Verify that the order of cache entries is preserved, and likewise for sublime inputs.
 
-        // Extract text from cache entry messages (weight 2).
+        // Extract text from cache entry messages (weight 3).
         let cache_texts: Vec<String> = request
             .messages
             .iter()
@@ -1660,7 +2781,7 @@ mod tests {
             dummy_cache_entry_with_role(Roles::Assistant, "cache assistant"),
         ];
 
-        // Sublime input that yields a UserCommand (weight 5).
+        // Sublime input that yields a UserCommand (weight 6).
         let sublime_inputs = vec![dummy_sublime_input(
             "user command",
             InputKind::Command,
@@ -1670,10 +2791,11 @@ mod tests {
             settings,
             cache_entries,
             sublime_inputs,
-        );
+        )
+        .unwrap();
 
-        // Expecting: system message (weight 0), then two cache entries (weight 2),
-        // and finally the sublime input (weight 5).
+        // Expecting: system message (weight 0), then two cache entries (weight 3),
+        // and finally the sublime input (weight 6).
         assert_eq!(request.messages.len(), 4);
 
         // Check system message.
@@ -1729,7 +2851,7 @@ mod tests {
             dummy_cache_entry_with_role(Roles::Assistant, "cache assistant"),
         ];
 
-        // Sublime input that yields a ViewSelection (weight 4).
+        // Sublime input that yields a ViewSelection (weight 5).
         let sublime_inputs = vec![dummy_sublime_input(
             "view selection",
             InputKind::ViewSelection,
@@ -1739,7 +2861,8 @@ mod tests {
             settings,
             cache_entries,
             sublime_inputs,
-        );
+        )
+        .unwrap();
 
         // Expecting: system message, two cache entries, then the sublime input.
         assert_eq!(request.messages.len(), 4);
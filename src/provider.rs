@@ -3,18 +3,30 @@ use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
 use crate::{
+    bedrock_network_types::BedrockConverseRequest,
     openai_network_types::{
         AssistantMessage,
+        AudioContent,
         Function,
         GoogleAssistantPart,
         OpenAICompletionRequest,
         ProviderMetadata,
         Roles,
+        TokenUsage,
         Tool,
         ToolCall,
+        ToolChoice,
+    },
+    tools_definition::{FunctionName, LIST_OPEN_FILES, TOOL_REGISTRY},
+    types::{
+        ApiType,
+        AssistantSettings,
+        CacheEntry,
+        DEFAULT_MAX_IMAGE_BYTES,
+        InputKind,
+        ReasonEffort,
+        SublimeInputContent,
     },
-    tools_definition::FUNCTIONS,
-    types::{ApiType, AssistantSettings, CacheEntry, InputKind, ReasonEffort, SublimeInputContent},
 };
 
 #[derive(Debug, Clone)]
@@ -30,6 +42,10 @@ pub(crate) struct ProviderMessage {
     pub(crate) tool_call_id: Option<String>,
     pub(crate) tool_calls: Option<Vec<ToolCall>>,
     pub(crate) provider_metadata: Option<ProviderMetadata>,
+    /// `data:` url of an attached image, only OpenAI compatible chat payloads send it.
+    pub(crate) image_url: Option<String>,
+    /// Base64 audio attachment, only `ApiType::OpenAi` payloads send it.
+    pub(crate) input_audio: Option<AudioContent>,
     pub(crate) kind: MessageKind,
 }
 
@@ -37,6 +53,8 @@ pub(crate) struct ProviderMessage {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum MessageKind {
     SystemMessage,
+    /// Standalone reasoning of a model like DeepSeek R1, placed right after the system message.
+    Thinking,
     SheetContent,
     CacheEntry,
     OutputPaneContent,
@@ -49,11 +67,12 @@ impl MessageKind {
     pub(crate) fn weight(&self) -> u8 {
         match self {
             Self::SystemMessage => 0,
-            Self::SheetContent => 1,
-            Self::CacheEntry => 2,
-            Self::OutputPaneContent => 3,
-            Self::ViewSelection => 4,
-            Self::UserCommand | Self::FunctionResult => 5,
+            Self::Thinking => 1,
+            Self::SheetContent => 2,
+            Self::CacheEntry => 3,
+            Self::OutputPaneContent => 4,
+            Self::ViewSelection => 5,
+            Self::UserCommand | Self::FunctionResult => 6,
         }
     }
 }
@@ -62,7 +81,7 @@ impl From<InputKind> for MessageKind {
     fn from(value: InputKind) -> Self {
         match value {
             InputKind::Command => Self::UserCommand,
-            InputKind::ViewSelection => Self::ViewSelection,
+            InputKind::ViewSelection | InputKind::Image | InputKind::Audio => Self::ViewSelection,
             InputKind::BuildOutputPanel | InputKind::LspOutputPanel | InputKind::Terminus => {
                 Self::OutputPaneContent
             }
@@ -73,14 +92,22 @@ impl From<InputKind> for MessageKind {
     }
 }
 
-impl From<CacheEntry> for ProviderMessage {
-    fn from(value: CacheEntry) -> Self {
+impl ProviderMessage {
+    /// Cached history turned back into a message, with the stripped reasoning restored for apis that take it.
+    pub(crate) fn from_cache_entry(value: CacheEntry, api_type: ApiType) -> Self {
+        let content = if api_type.replays_thinking() {
+            value.combined_content_with_thinking()
+        } else {
+            value.combined_content()
+        };
         Self {
             role: value.role,
-            content: value.combined_content(),
+            content,
             tool_call_id: value.tool_call_id,
             tool_calls: value.tool_calls,
             provider_metadata: value.provider_metadata,
+            image_url: None,
+            input_audio: None,
             kind: MessageKind::CacheEntry,
         }
     }
@@ -88,12 +115,22 @@ impl From<CacheEntry> for ProviderMessage {
 
 impl From<SublimeInputContent> for ProviderMessage {
     fn from(value: SublimeInputContent) -> Self {
+        let image_url = match value.input_kind {
+            InputKind::Image => value.image_data_url().ok(),
+            _ => None,
+        };
+        let input_audio = match value.input_kind {
+            InputKind::Audio => value.audio_content().ok(),
+            _ => None,
+        };
         Self {
             role: if value.tool_id.is_some() { Roles::Tool } else { Roles::User },
             content: value.combined_content(),
             tool_call_id: value.tool_id,
             tool_calls: None,
             provider_metadata: None,
+            image_url,
+            input_audio,
             kind: MessageKind::from(value.input_kind),
         }
     }
@@ -109,7 +146,7 @@ pub(crate) fn build_conversation(
     messages.extend(
         cache_entries
             .into_iter()
-            .map(ProviderMessage::from),
+            .map(|entry| ProviderMessage::from_cache_entry(entry, settings.api_type)),
     );
     messages.extend(
         sublime_inputs
@@ -255,15 +292,115 @@ pub(crate) fn prepare_payload(
     settings: &AssistantSettings,
     cache_entries: Vec<CacheEntry>,
     sublime_inputs: Vec<SublimeInputContent>,
+) -> Result<String> {
+    settings
+        .validate()
+        .map_err(|errors| anyhow::anyhow!(errors.join("; ")))?;
+    if let Some(response_format) = &settings.response_format {
+        response_format.validate()?;
+    }
+    validate_attachments(settings, &sublime_inputs)?;
+    let payload = prepare_api_payload(settings, cache_entries, sublime_inputs)?;
+    merge_extra_body(settings, payload)
+}
+
+/// Builds a Responses API payload that continues the server side thread of `previous_response_id`,
+/// so only the new inputs are sent instead of the whole history.
+pub(crate) fn prepare_chained_responses_payload(
+    settings: &AssistantSettings,
+    previous_response_id: String,
+    sublime_inputs: Vec<SublimeInputContent>,
+) -> Result<String> {
+    validate_attachments(settings, &sublime_inputs)?;
+    let mut request = OpenAiResponsesRequest::from_conversation(
+        settings,
+        build_conversation(settings, vec![], sublime_inputs),
+    );
+    request.previous_response_id = Some(previous_response_id);
+    merge_extra_body(
+        settings,
+        serde_json::to_string(&request)?,
+    )
+}
+
+fn validate_attachments(settings: &AssistantSettings, sublime_inputs: &[SublimeInputContent]) -> Result<()> {
+    let max_bytes = settings
+        .max_image_bytes
+        .unwrap_or(DEFAULT_MAX_IMAGE_BYTES);
+    sublime_inputs
+        .iter()
+        .try_for_each(|input| {
+            match input.input_kind {
+                InputKind::Image => input.validate_image(max_bytes),
+                InputKind::Audio => input.validate_audio(settings.api_type),
+                _ => Ok(()),
+            }
+        })
+}
+
+fn merge_extra_body(settings: &AssistantSettings, payload: String) -> Result<String> {
+    match &settings.extra_body {
+        Some(extra_body) if !extra_body.is_empty() => {
+            let mut payload = serde_json::from_str::<Value>(&payload)?;
+            if let Some(object) = payload.as_object_mut() {
+                for (key, value) in extra_body {
+                    object
+                        .entry(key.clone())
+                        .or_insert_with(|| value.clone());
+                }
+            }
+            Ok(serde_json::to_string(&payload)?)
+        }
+        _ => Ok(payload),
+    }
+}
+
+fn prepare_api_payload(
+    settings: &AssistantSettings,
+    cache_entries: Vec<CacheEntry>,
+    sublime_inputs: Vec<SublimeInputContent>,
 ) -> Result<String> {
     match settings.api_type {
-        ApiType::OpenAi | ApiType::PlainText => {
+        ApiType::OpenAi | ApiType::PlainText | ApiType::Groq => {
             let request = OpenAICompletionRequest::from_conversation(
                 settings,
                 build_conversation(settings, cache_entries, sublime_inputs),
             );
             Ok(serde_json::to_string(&request)?)
         }
+        ApiType::AzureOpenAi => {
+            // Azure resolves the model from the deployment in the url and rejects a body `model`.
+            let request = OpenAICompletionRequest::from_conversation(
+                settings,
+                build_conversation(settings, cache_entries, sublime_inputs),
+            );
+            let mut request = serde_json::to_value(&request)?;
+            if let Some(object) = request.as_object_mut() {
+                object.remove("model");
+            }
+            Ok(serde_json::to_string(&request)?)
+        }
+        ApiType::AzureInference => {
+            // Non-OpenAI models behind Azure AI Inference only take the basic chat/completions fields.
+            let mut request = OpenAICompletionRequest::from_conversation(
+                settings,
+                build_conversation(settings, cache_entries, sublime_inputs),
+            );
+            request.max_tokens = request.max_tokens.or(request
+                .max_completion_tokens
+                .take());
+            request.reasoning_effort = None;
+            request.parallel_tool_calls = None;
+            request.deferred = None;
+            Ok(serde_json::to_string(&request)?)
+        }
+        ApiType::Mistral => {
+            let request = MistralCompletionRequest::from_conversation(
+                settings,
+                build_conversation(settings, cache_entries, sublime_inputs),
+            );
+            Ok(serde_json::to_string(&request)?)
+        }
         ApiType::OpenAiResponses => {
             let request = OpenAiResponsesRequest::from_conversation(
                 settings,
@@ -278,6 +415,27 @@ pub(crate) fn prepare_payload(
             );
             Ok(serde_json::to_string(&request)?)
         }
+        ApiType::Cohere => {
+            let request = CohereChatRequest::from_conversation(
+                settings,
+                build_conversation(settings, cache_entries, sublime_inputs),
+            );
+            Ok(serde_json::to_string(&request)?)
+        }
+        ApiType::Ollama => {
+            let request = OllamaChatRequest::from_conversation(
+                settings,
+                build_conversation(settings, cache_entries, sublime_inputs),
+            );
+            Ok(serde_json::to_string(&request)?)
+        }
+        ApiType::Bedrock => {
+            let request = BedrockConverseRequest::from_conversation(
+                settings,
+                build_conversation(settings, cache_entries, sublime_inputs),
+            );
+            Ok(serde_json::to_string(&request)?)
+        }
         ApiType::Google => {
             let request = GoogleGenerateContentRequest::from_conversation(
                 settings,
@@ -299,12 +457,14 @@ pub(crate) fn tools_enabled(settings: &AssistantSettings) -> Option<Vec<Tool>> {
         .tools
         .and_then(|enabled| {
             if enabled {
-                Some(
-                    FUNCTIONS
-                        .iter()
-                        .map(|tool| tool.as_ref().clone())
-                        .collect(),
-                )
+                let mut tools = TOOL_REGISTRY.tools_as_vec();
+                if settings
+                    .enable_list_files_tool
+                    .unwrap_or(false)
+                {
+                    tools.push((*LIST_OPEN_FILES).clone());
+                }
+                Some(tools)
             } else {
                 None
             }
@@ -325,7 +485,14 @@ fn normalize_openai_compat_tool(mut tool: Tool) -> Tool {
         function.parameters = Some(normalize_openai_compat_schema_map(
             function.parameters.take().unwrap_or_default(),
         ));
-        function.description = openai_compat_description_for(&function.name);
+        // Registered tools keep the description their host gave them.
+        if function
+            .name
+            .parse::<FunctionName>()
+            .is_ok()
+        {
+            function.description = openai_compat_description_for(&function.name);
+        }
         function.strict = None;
     }
 
@@ -343,11 +510,44 @@ fn openai_compat_description_for(name: &str) -> Option<String> {
                 "List files and directories recursively for a given path.".to_string()
             }
             "read_region_content" => "Read a selected region of a file.".to_string(),
+            "list_open_files" => "List the files open in the editor.".to_string(),
+            "fetch_url" => "Fetch the content of a url.".to_string(),
+            "search_in_files" => "Search files under a directory for lines matching a pattern.".to_string(),
             _ => return None,
         },
     )
 }
 
+/// Mistral speaks the chat/completions dialect, plus a few body fields of its own.
+///
+/// It rejects `parallel_tool_calls` and names the sampling seed `random_seed`.
+#[derive(Debug, Serialize)]
+struct MistralCompletionRequest {
+    #[serde(flatten)]
+    base: OpenAICompletionRequest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    safe_prompt: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    random_seed: Option<i64>,
+}
+
+impl MistralCompletionRequest {
+    fn from_conversation(settings: &AssistantSettings, conversation: ProviderConversation) -> Self {
+        let mut base = OpenAICompletionRequest::from_conversation(settings, conversation);
+        base.parallel_tool_calls = None;
+        if base.tools.is_some() {
+            base.tool_choice = base
+                .tool_choice
+                .or(Some(ToolChoice::Auto));
+        }
+        Self {
+            safe_prompt: settings.safe_prompt,
+            random_seed: base.seed.take(),
+            base,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct OpenAiResponsesRequest {
     model: String,
@@ -365,6 +565,9 @@ struct OpenAiResponsesRequest {
     tools: Option<Vec<ResponsesTool>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     parallel_tool_calls: Option<bool>,
+    /// Chains the request onto a stored response, so `input` carries only the new turn.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_response_id: Option<String>,
 }
 
 impl OpenAiResponsesRequest {
@@ -390,6 +593,7 @@ impl OpenAiResponsesRequest {
                     .collect()
             }),
             parallel_tool_calls: settings.parallel_tool_calls,
+            previous_response_id: None,
         }
     }
 }
@@ -482,7 +686,6 @@ impl ResponsesTool {
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct OpenAiResponsesResponse {
-    #[allow(dead_code)]
     id: Option<String>,
     #[serde(default)]
     output: Vec<ResponsesOutputItem>,
@@ -524,6 +727,14 @@ impl OpenAiResponsesResponse {
             content: if content_parts.is_empty() { None } else { Some(content_parts.join("")) },
             tool_calls: if tool_calls.is_empty() { None } else { Some(tool_calls) },
             provider_metadata: None,
+            usage: None,
+            response_id: self.id,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
         }
     }
 }
@@ -572,6 +783,14 @@ impl OpenAiResponsesStreamState {
             content: if self.text.is_empty() { None } else { Some(self.text) },
             tool_calls: if self.tool_calls.is_empty() { None } else { Some(self.tool_calls) },
             provider_metadata: None,
+            usage: None,
+            response_id: None,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
         }
     }
 }
@@ -592,16 +811,66 @@ struct AnthropicMessagesRequest {
     tools: Option<Vec<AnthropicTool>>,
 }
 
+/// Output cap of the Claude 3 generation, the lowest one Anthropic serves.
+const DEFAULT_ANTHROPIC_MAX_TOKENS: usize = 4096;
+
+/// Anthropic requires `max_tokens`, unset settings fall back to the model's output cap: 8192 from Claude 3.5 on.
+fn anthropic_default_max_tokens(chat_model: &str) -> usize {
+    let model = chat_model
+        .rsplit('/')
+        .next()
+        .unwrap_or(chat_model);
+    if model.starts_with("claude-3-haiku")
+        || model.starts_with("claude-3-sonnet")
+        || model.starts_with("claude-3-opus")
+        || !model.starts_with("claude-")
+    {
+        DEFAULT_ANTHROPIC_MAX_TOKENS
+    } else {
+        8192
+    }
+}
+
+/// Anthropic rejects requests with more `cache_control` breakpoints than this.
+const MAX_ANTHROPIC_CACHE_BREAKPOINTS: usize = 4;
+
 impl AnthropicMessagesRequest {
     fn from_conversation(settings: &AssistantSettings, conversation: ProviderConversation) -> Self {
+        let mut breakpoints = settings
+            .cache_breakpoints
+            .unwrap_or(0)
+            .min(MAX_ANTHROPIC_CACHE_BREAKPOINTS);
+        let mut messages: Vec<AnthropicMessage> = Vec::new();
+        // Walk backwards so the breakpoints land on the last, most context covering, large blocks.
+        for message in conversation
+            .messages
+            .into_iter()
+            .rev()
+        {
+            let is_large = matches!(
+                message.kind,
+                MessageKind::SheetContent | MessageKind::CacheEntry
+            );
+            if let Some(mut anthropic_message) = AnthropicMessage::from_provider_message(message) {
+                if let (true, Some(block)) = (
+                    is_large && breakpoints > 0,
+                    anthropic_message
+                        .content
+                        .last_mut(),
+                ) {
+                    block.set_cache_control(AnthropicCacheControl::ephemeral());
+                    breakpoints -= 1;
+                }
+                messages.push(anthropic_message);
+            }
+        }
+        messages.reverse();
+
         Self {
             model: settings.chat_model.clone(),
-            messages: conversation
-                .messages
-                .into_iter()
-                .filter_map(AnthropicMessage::from_provider_message)
-                .collect(),
-            max_tokens: default_max_output_tokens(settings).unwrap_or(4096),
+            messages: AnthropicMessage::merge_tool_results(messages),
+            max_tokens: default_max_output_tokens(settings)
+                .unwrap_or_else(|| anthropic_default_max_tokens(&settings.chat_model)),
             stream: settings.stream,
             system: conversation.system_message,
             temperature: settings.temperature,
@@ -644,6 +913,35 @@ struct AnthropicMessage {
 }
 
 impl AnthropicMessage {
+    /// Anthropic expects the results of parallel `tool_use` blocks in a single user message.
+    fn merge_tool_results(messages: Vec<Self>) -> Vec<Self> {
+        let is_tool_result = |message: &Self| {
+            message.role == "user"
+                && message
+                    .content
+                    .iter()
+                    .all(|block| {
+                        matches!(
+                            block,
+                            AnthropicContentBlock::ToolResult { .. }
+                        )
+                    })
+        };
+
+        let mut merged: Vec<Self> = Vec::with_capacity(messages.len());
+        for message in messages {
+            match merged.last_mut() {
+                Some(previous) if is_tool_result(previous) && is_tool_result(&message) => {
+                    previous
+                        .content
+                        .extend(message.content)
+                }
+                _ => merged.push(message),
+            }
+        }
+        merged
+    }
+
     fn from_provider_message(message: ProviderMessage) -> Option<Self> {
         match message.role {
             Roles::System | Roles::Developer => None,
@@ -656,6 +954,7 @@ impl AnthropicMessage {
                             .unwrap_or_default(),
                         content: message.content,
                         is_error: false,
+                        cache_control: None,
                     }],
                 })
             }
@@ -664,6 +963,7 @@ impl AnthropicMessage {
                 if !message.content.is_empty() {
                     content.push(AnthropicContentBlock::Text {
                         text: message.content,
+                        cache_control: None,
                     });
                 }
                 if let Some(tool_calls) = message.tool_calls {
@@ -675,6 +975,7 @@ impl AnthropicMessage {
                                     id: call.id,
                                     name: call.function.name,
                                     input: parse_json_object_or_wrap(&call.function.arguments),
+                                    cache_control: None,
                                 }
                             }),
                     );
@@ -689,6 +990,7 @@ impl AnthropicMessage {
                     role: "user".to_string(),
                     content: vec![AnthropicContentBlock::Text {
                         text: message.content,
+                        cache_control: None,
                     }],
                 })
             }
@@ -700,17 +1002,120 @@ impl AnthropicMessage {
 #[serde(tag = "type")]
 enum AnthropicContentBlock {
     #[serde(rename = "text")]
-    Text { text: String },
+    Text {
+        text: String,
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        cache_control: Option<AnthropicCacheControl>,
+    },
     #[serde(rename = "tool_use")]
-    ToolUse { id: String, name: String, input: Map<String, Value> },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Map<String, Value>,
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        cache_control: Option<AnthropicCacheControl>,
+    },
     #[serde(rename = "tool_result")]
-    ToolResult { tool_use_id: String, content: String, is_error: bool },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        is_error: bool,
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        cache_control: Option<AnthropicCacheControl>,
+    },
+}
+
+impl AnthropicContentBlock {
+    fn set_cache_control(&mut self, value: AnthropicCacheControl) {
+        match self {
+            Self::Text { cache_control, .. }
+            | Self::ToolUse { cache_control, .. }
+            | Self::ToolResult { cache_control, .. } => *cache_control = Some(value),
+        }
+    }
+}
+
+/// Prompt caching breakpoint: everything up to the annotated block is cached by Anthropic.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct AnthropicCacheControl {
+    r#type: String,
+}
+
+impl AnthropicCacheControl {
+    fn ephemeral() -> Self {
+        Self {
+            r#type: "ephemeral".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub(crate) struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: usize,
+    #[serde(default)]
+    output_tokens: usize,
+    #[serde(default)]
+    cache_creation_input_tokens: Option<usize>,
+    #[serde(default)]
+    cache_read_input_tokens: Option<usize>,
+}
+
+impl AnthropicUsage {
+    /// `message_delta` repeats the usage with the final output count, absent fields keep their values.
+    pub(crate) fn merge(&mut self, other: AnthropicUsage) {
+        if other.input_tokens > 0 {
+            self.input_tokens = other.input_tokens;
+        }
+        if other.output_tokens > 0 {
+            self.output_tokens = other.output_tokens;
+        }
+        self.cache_creation_input_tokens = other
+            .cache_creation_input_tokens
+            .or(self.cache_creation_input_tokens);
+        self.cache_read_input_tokens = other
+            .cache_read_input_tokens
+            .or(self.cache_read_input_tokens);
+    }
+}
+
+impl From<AnthropicUsage> for TokenUsage {
+    fn from(value: AnthropicUsage) -> Self {
+        // `input_tokens` only counts the uncached part of the prompt.
+        let prompt_tokens = value.input_tokens
+            + value
+                .cache_creation_input_tokens
+                .unwrap_or(0)
+            + value
+                .cache_read_input_tokens
+                .unwrap_or(0);
+        Self {
+            prompt_tokens,
+            completion_tokens: value.output_tokens,
+            total_tokens: prompt_tokens + value.output_tokens,
+            total_time: None,
+            queue_time: None,
+            cache_creation_input_tokens: value.cache_creation_input_tokens,
+            cache_read_input_tokens: value.cache_read_input_tokens,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct AnthropicResponse {
     #[serde(default)]
     content: Vec<AnthropicContentBlock>,
+    #[serde(default)]
+    usage: Option<AnthropicUsage>,
 }
 
 impl AnthropicResponse {
@@ -720,8 +1125,8 @@ impl AnthropicResponse {
 
         for block in self.content {
             match block {
-                AnthropicContentBlock::Text { text } => content_parts.push(text),
-                AnthropicContentBlock::ToolUse { id, name, input } => {
+                AnthropicContentBlock::Text { text, .. } => content_parts.push(text),
+                AnthropicContentBlock::ToolUse { id, name, input, .. } => {
                     tool_calls.push(ToolCall {
                         id,
                         r#type: "function".to_string(),
@@ -741,6 +1146,16 @@ impl AnthropicResponse {
             content: if content_parts.is_empty() { None } else { Some(content_parts.join("")) },
             tool_calls: if tool_calls.is_empty() { None } else { Some(tool_calls) },
             provider_metadata: None,
+            usage: self
+                .usage
+                .map(TokenUsage::from),
+            response_id: None,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
         }
     }
 }
@@ -749,6 +1164,7 @@ impl AnthropicResponse {
 pub(crate) struct AnthropicStreamState {
     pub(crate) text: String,
     pub(crate) tool_calls: Vec<ToolCall>,
+    pub(crate) usage: Option<AnthropicUsage>,
 }
 
 impl AnthropicStreamState {
@@ -758,93 +1174,546 @@ impl AnthropicStreamState {
             content: if self.text.is_empty() { None } else { Some(self.text) },
             tool_calls: if self.tool_calls.is_empty() { None } else { Some(self.tool_calls) },
             provider_metadata: None,
+            usage: self
+                .usage
+                .map(TokenUsage::from),
+            response_id: None,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
         }
     }
 }
 
 #[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct GoogleGenerateContentRequest {
-    contents: Vec<GoogleContent>,
+struct CohereChatRequest {
+    model: String,
+    messages: Vec<CohereMessage>,
+    stream: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
-    system_instruction: Option<GoogleSystemInstruction>,
+    temperature: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    generation_config: Option<GoogleGenerationConfig>,
+    max_tokens: Option<usize>,
+    #[serde(
+        rename = "p",
+        skip_serializing_if = "Option::is_none"
+    )]
+    top_p: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    tools: Option<Vec<GoogleToolDeclaration>>,
+    frequency_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<Tool>>,
 }
 
-impl GoogleGenerateContentRequest {
+impl CohereChatRequest {
     fn from_conversation(settings: &AssistantSettings, conversation: ProviderConversation) -> Self {
+        let mut messages = Vec::new();
+
+        if let Some(system_message) = conversation.system_message {
+            messages.push(CohereMessage::System {
+                content: system_message,
+            });
+        }
+
+        messages.extend(
+            conversation
+                .messages
+                .into_iter()
+                .map(CohereMessage::from_provider_message),
+        );
+
         Self {
-            contents: GoogleContent::from_provider_messages(conversation.messages),
-            system_instruction: conversation
-                .system_message
-                .map(|text| {
-                    GoogleSystemInstruction {
-                        parts: vec![GooglePart::Text { text }],
-                    }
-                }),
-            generation_config: Some(GoogleGenerationConfig {
-                temperature: settings.temperature,
-                top_p: settings.top_p,
-                max_output_tokens: default_max_output_tokens(settings),
-            }),
-            tools: tools_enabled(settings).map(|tools| {
-                vec![GoogleToolDeclaration {
-                    function_declarations: tools
-                        .into_iter()
-                        .filter_map(GoogleFunctionDeclaration::from_openai_tool)
-                        .collect(),
-                }]
-            }),
+            model: settings.chat_model.clone(),
+            messages,
+            stream: settings.stream,
+            temperature: settings.temperature,
+            max_tokens: default_max_output_tokens(settings),
+            top_p: settings.top_p,
+            frequency_penalty: settings.frequency_penalty,
+            presence_penalty: settings.presence_penalty,
+            tools: openai_compat_tools_enabled(settings),
         }
     }
 }
 
 #[derive(Debug, Serialize)]
-struct GoogleSystemInstruction {
-    parts: Vec<GooglePart>,
-}
-
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct GoogleGenerationConfig {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    temperature: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    top_p: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    max_output_tokens: Option<usize>,
+#[serde(tag = "role", rename_all = "snake_case")]
+enum CohereMessage {
+    System {
+        content: String,
+    },
+    User {
+        content: String,
+    },
+    Assistant {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tool_calls: Option<Vec<ToolCall>>,
+    },
+    Tool {
+        tool_call_id: String,
+        content: String,
+    },
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct GoogleToolDeclaration {
-    function_declarations: Vec<GoogleFunctionDeclaration>,
+impl CohereMessage {
+    fn from_provider_message(message: ProviderMessage) -> Self {
+        match message.role {
+            Roles::System | Roles::Developer => {
+                Self::System {
+                    content: message.content,
+                }
+            }
+            Roles::User => {
+                Self::User {
+                    content: message.content,
+                }
+            }
+            Roles::Assistant => {
+                Self::Assistant {
+                    content: if message.content.is_empty() { None } else { Some(message.content) },
+                    tool_calls: message.tool_calls,
+                }
+            }
+            Roles::Tool => {
+                Self::Tool {
+                    tool_call_id: message
+                        .tool_call_id
+                        .unwrap_or_default(),
+                    content: message.content,
+                }
+            }
+        }
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct GoogleFunctionDeclaration {
-    name: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    description: Option<String>,
-    parameters: Map<String, Value>,
+#[derive(Debug, Deserialize)]
+pub(crate) struct CohereResponse {
+    message: CohereResponseMessage,
 }
 
-impl GoogleFunctionDeclaration {
-    fn from_openai_tool(tool: Tool) -> Option<Self> {
-        let function = tool.function?;
-        Some(Self {
-            name: function.name,
-            description: function.description,
-            parameters: normalize_google_schema_map(function.parameters.unwrap_or_default()),
-        })
-    }
+#[derive(Debug, Deserialize)]
+struct CohereResponseMessage {
+    #[serde(default)]
+    content: Vec<CohereContentBlock>,
+    #[serde(default)]
+    tool_calls: Option<Vec<ToolCall>>,
+    /// Reasoning Cohere emits ahead of its tool calls instead of text content.
+    #[serde(default)]
+    tool_plan: Option<String>,
 }
 
-fn normalize_google_schema_map(schema: Map<String, Value>) -> Map<String, Value> {
-    match normalize_openai_compat_schema_value(Value::Object(schema)) {
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum CohereContentBlock {
+    #[serde(rename = "text")]
+    Text { text: String },
+    #[serde(other)]
+    Other,
+}
+
+impl CohereResponse {
+    pub(crate) fn into_assistant_message(self) -> AssistantMessage {
+        let content_parts: Vec<String> = self
+            .message
+            .content
+            .into_iter()
+            .filter_map(|block| {
+                match block {
+                    CohereContentBlock::Text { text } => Some(text),
+                    CohereContentBlock::Other => None,
+                }
+            })
+            .collect();
+
+        AssistantMessage {
+            role: Roles::Assistant,
+            content: if content_parts.is_empty() {
+                self.message
+                    .tool_plan
+                    .filter(|tool_plan| !tool_plan.is_empty())
+            } else {
+                Some(content_parts.join(""))
+            },
+            tool_calls: self
+                .message
+                .tool_calls
+                .filter(|tool_calls| !tool_calls.is_empty()),
+            provider_metadata: None,
+            usage: None,
+            response_id: None,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct CohereStreamState {
+    pub(crate) text: String,
+    pub(crate) tool_calls: Vec<ToolCall>,
+}
+
+impl CohereStreamState {
+    pub(crate) fn into_assistant_message(self) -> AssistantMessage {
+        AssistantMessage {
+            role: Roles::Assistant,
+            content: if self.text.is_empty() { None } else { Some(self.text) },
+            tool_calls: if self.tool_calls.is_empty() { None } else { Some(self.tool_calls) },
+            provider_metadata: None,
+            usage: None,
+            response_id: None,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaChatRequest {
+    model: String,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    options: Map<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<Tool>>,
+}
+
+impl OllamaChatRequest {
+    fn from_conversation(settings: &AssistantSettings, conversation: ProviderConversation) -> Self {
+        let mut messages = Vec::new();
+
+        if let Some(system_message) = conversation.system_message {
+            messages.push(OllamaMessage {
+                role: "system".to_string(),
+                content: system_message,
+                tool_calls: None,
+                tool_name: None,
+            });
+        }
+
+        messages.extend(
+            conversation
+                .messages
+                .into_iter()
+                .map(OllamaMessage::from_provider_message),
+        );
+
+        let mut options = Map::new();
+        if let Some(temperature) = settings.temperature {
+            options.insert(
+                "temperature".to_string(),
+                temperature.into(),
+            );
+        }
+        if let Some(top_p) = settings.top_p {
+            options.insert("top_p".to_string(), top_p.into());
+        }
+        if let Some(num_predict) = default_max_output_tokens(settings) {
+            options.insert(
+                "num_predict".to_string(),
+                num_predict.into(),
+            );
+        }
+        if let Some(num_ctx) = settings.num_ctx {
+            options.insert("num_ctx".to_string(), num_ctx.into());
+        }
+
+        Self {
+            model: settings.chat_model.clone(),
+            messages,
+            stream: settings.stream,
+            options,
+            keep_alive: settings.keep_alive.clone(),
+            tools: openai_compat_tools_enabled(settings),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaMessage {
+    role: String,
+    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<OllamaToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_name: Option<String>,
+}
+
+impl OllamaMessage {
+    fn from_provider_message(message: ProviderMessage) -> Self {
+        let tool_name = (message.role == Roles::Tool).then(|| {
+            extract_ollama_function_name(
+                message
+                    .tool_call_id
+                    .as_deref(),
+            )
+        });
+
+        let role = match message.role {
+            Roles::User => "user",
+            Roles::Assistant => "assistant",
+            Roles::Tool => "tool",
+            Roles::System | Roles::Developer => "system",
+        };
+
+        Self {
+            role: role.to_string(),
+            content: message.content,
+            tool_calls: message
+                .tool_calls
+                .map(|tool_calls| {
+                    tool_calls
+                        .into_iter()
+                        .map(|call| {
+                            OllamaToolCall {
+                                function: OllamaFunctionCall {
+                                    name: call.function.name,
+                                    arguments: parse_json_object_or_wrap(&call.function.arguments),
+                                },
+                            }
+                        })
+                        .collect()
+                }),
+            tool_name,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaToolCall {
+    function: OllamaFunctionCall,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaFunctionCall {
+    name: String,
+    #[serde(default)]
+    arguments: Map<String, Value>,
+}
+
+/// A whole `/api/chat` response, or a single line of its newline-delimited stream.
+#[derive(Debug, Deserialize)]
+pub(crate) struct OllamaChatResponse {
+    #[serde(default)]
+    message: Option<OllamaResponseMessage>,
+    #[serde(default)]
+    pub(crate) done: bool,
+    prompt_eval_count: Option<usize>,
+    eval_count: Option<usize>,
+    /// Nanoseconds.
+    total_duration: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaResponseMessage {
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    tool_calls: Option<Vec<OllamaToolCall>>,
+}
+
+impl OllamaChatResponse {
+    /// Tool calls are numbered from `tool_call_offset`, since Ollama does not assign ids itself.
+    pub(crate) fn into_assistant_message(self, tool_call_offset: usize) -> AssistantMessage {
+        let usage = self.usage();
+        let (content, tool_calls) = match self.message {
+            Some(message) => {
+                (
+                    message.content,
+                    message
+                        .tool_calls
+                        .unwrap_or_default(),
+                )
+            }
+            None => (String::new(), Vec::new()),
+        };
+
+        let tool_calls: Vec<ToolCall> = tool_calls
+            .into_iter()
+            .enumerate()
+            .map(|(index, call)| {
+                ToolCall {
+                    id: build_ollama_tool_call_id(
+                        &call.function.name,
+                        tool_call_offset + index,
+                    ),
+                    r#type: "function".to_string(),
+                    thought_signature: None,
+                    function: Function {
+                        arguments: serde_json::to_string(&call.function.arguments)
+                            .unwrap_or_else(|_| "{}".to_string()),
+                        name: call.function.name,
+                    },
+                }
+            })
+            .collect();
+
+        AssistantMessage {
+            role: Roles::Assistant,
+            content: if content.is_empty() { None } else { Some(content) },
+            tool_calls: if tool_calls.is_empty() { None } else { Some(tool_calls) },
+            provider_metadata: None,
+            usage,
+            response_id: None,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
+        }
+    }
+
+    fn usage(&self) -> Option<TokenUsage> {
+        if !self.done {
+            return None;
+        }
+
+        let prompt_tokens = self
+            .prompt_eval_count
+            .unwrap_or(0);
+        let completion_tokens = self.eval_count.unwrap_or(0);
+        Some(TokenUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            total_time: self
+                .total_duration
+                .map(|nanos| nanos as f64 / 1_000_000_000.0),
+            queue_time: None,
+            cache_creation_input_tokens: None,
+            cache_read_input_tokens: None,
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct OllamaStreamState {
+    pub(crate) text: String,
+    pub(crate) tool_calls: Vec<ToolCall>,
+    pub(crate) usage: Option<TokenUsage>,
+}
+
+impl OllamaStreamState {
+    pub(crate) fn into_assistant_message(self) -> AssistantMessage {
+        AssistantMessage {
+            role: Roles::Assistant,
+            content: if self.text.is_empty() { None } else { Some(self.text) },
+            tool_calls: if self.tool_calls.is_empty() { None } else { Some(self.tool_calls) },
+            provider_metadata: None,
+            usage: self.usage,
+            response_id: None,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GoogleGenerateContentRequest {
+    contents: Vec<GoogleContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<GoogleSystemInstruction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GoogleGenerationConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<GoogleToolDeclaration>>,
+}
+
+impl GoogleGenerateContentRequest {
+    fn from_conversation(settings: &AssistantSettings, conversation: ProviderConversation) -> Self {
+        Self {
+            contents: GoogleContent::from_provider_messages(conversation.messages),
+            system_instruction: conversation
+                .system_message
+                .map(|text| {
+                    GoogleSystemInstruction {
+                        parts: vec![GooglePart::Text { text }],
+                    }
+                }),
+            generation_config: Some(GoogleGenerationConfig {
+                temperature: settings.temperature,
+                top_p: settings.top_p,
+                max_output_tokens: default_max_output_tokens(settings),
+            }),
+            tools: tools_enabled(settings).map(|tools| {
+                vec![GoogleToolDeclaration {
+                    function_declarations: tools
+                        .into_iter()
+                        .filter_map(GoogleFunctionDeclaration::from_openai_tool)
+                        .collect(),
+                }]
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct GoogleSystemInstruction {
+    parts: Vec<GooglePart>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GoogleGenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GoogleToolDeclaration {
+    function_declarations: Vec<GoogleFunctionDeclaration>,
+}
+
+#[derive(Debug, Serialize)]
+struct GoogleFunctionDeclaration {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    parameters: Map<String, Value>,
+}
+
+impl GoogleFunctionDeclaration {
+    fn from_openai_tool(tool: Tool) -> Option<Self> {
+        let function = tool.function?;
+        Some(Self {
+            name: function.name,
+            description: function.description,
+            parameters: normalize_google_schema_map(function.parameters.unwrap_or_default()),
+        })
+    }
+}
+
+fn normalize_google_schema_map(schema: Map<String, Value>) -> Map<String, Value> {
+    match normalize_openai_compat_schema_value(Value::Object(schema)) {
         Value::Object(map) => map,
         _ => Map::new(),
     }
@@ -1243,6 +2112,14 @@ impl GoogleGenerateContentResponse {
             } else {
                 Some(ProviderMetadata::Google { parts: google_parts })
             },
+            usage: None,
+            response_id: None,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
         }
     }
 }
@@ -1261,6 +2138,14 @@ impl GoogleStreamState {
             content: if self.text.is_empty() { None } else { Some(self.text) },
             tool_calls: if self.tool_calls.is_empty() { None } else { Some(self.tool_calls) },
             provider_metadata: self.provider_metadata,
+            usage: None,
+            response_id: None,
+            citations: None,
+            logprobs: None,
+            reasoning_content: None,
+            alternatives: vec![],
+            stalled: false,
+            finish_reason: None,
         }
     }
 }
@@ -1274,7 +2159,7 @@ fn responses_role(role: Roles) -> &'static str {
     }
 }
 
-fn parse_json_object_or_wrap(value: &str) -> Map<String, Value> {
+pub(crate) fn parse_json_object_or_wrap(value: &str) -> Map<String, Value> {
     serde_json::from_str::<Map<String, Value>>(value).unwrap_or_else(|_| {
         Map::from_iter([(
             "result".to_string(),
@@ -1285,6 +2170,12 @@ fn parse_json_object_or_wrap(value: &str) -> Map<String, Value> {
 
 fn build_google_tool_call_id(name: &str, index: usize) -> String { format!("google::{name}::{index}") }
 
+fn build_ollama_tool_call_id(name: &str, index: usize) -> String { format!("ollama::{name}::{index}") }
+
+fn extract_ollama_function_name(tool_call_id: Option<&str>) -> String {
+    extract_google_function_name(tool_call_id)
+}
+
 fn extract_google_function_name(tool_call_id: Option<&str>) -> String {
     tool_call_id
         .and_then(|value| value.split("::").nth(1))
@@ -1310,11 +2201,44 @@ pub(crate) fn google_stream_url(base_url: &str, model: &str, stream: bool) -> St
     format!("{trimmed}/models/{model}{endpoint_suffix}")
 }
 
+/// Builds `{resource}/openai/deployments/{deployment}/chat/completions` when only the resource url and a
+/// deployment are configured, and appends `api-version` unless the url already carries one.
+pub(crate) fn azure_chat_completions_url(
+    base_url: &str,
+    deployment: Option<&str>,
+    api_version: Option<&str>,
+) -> Result<String> {
+    let url = match deployment {
+        Some(deployment) if !base_url.contains("/openai/deployments/") => {
+            format!(
+                "{}/openai/deployments/{deployment}/chat/completions",
+                base_url.trim_end_matches('/')
+            )
+        }
+        _ => base_url.to_string(),
+    };
+    let mut url = reqwest::Url::parse(&url)?;
+
+    if let Some(api_version) = api_version {
+        let has_api_version = url
+            .query_pairs()
+            .any(|(key, _)| key == "api-version");
+        if !has_api_version {
+            url.query_pairs_mut()
+                .append_pair("api-version", api_version);
+        }
+    }
+
+    Ok(url.to_string())
+}
+
 #[cfg(test)]
 mod tests {
+    use base64::{Engine, prelude::BASE64_STANDARD};
     use serde_json::json;
 
     use super::*;
+    use crate::types::{SublimeOutputContent, audio_format, image_mime_type};
 
     fn dummy_settings(api_type: ApiType) -> AssistantSettings {
         let mut assistant = AssistantSettings::default();
@@ -1372,6 +2296,41 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_azure_chat_completions_url() {
+        let url = "https://example.openai.azure.com/openai/deployments/gpt-4o/chat/completions";
+        assert_eq!(
+            azure_chat_completions_url(url, None, Some("2024-10-21")).unwrap(),
+            format!("{url}?api-version=2024-10-21")
+        );
+        assert_eq!(
+            azure_chat_completions_url(
+                &format!("{url}?api-version=2024-06-01"),
+                None,
+                Some("2024-10-21")
+            )
+            .unwrap(),
+            format!("{url}?api-version=2024-06-01")
+        );
+        assert_eq!(
+            azure_chat_completions_url(url, None, None).unwrap(),
+            url
+        );
+        assert_eq!(
+            azure_chat_completions_url(
+                "https://example.openai.azure.com/",
+                Some("gpt-4o"),
+                Some("2024-10-21")
+            )
+            .unwrap(),
+            format!("{url}?api-version=2024-10-21")
+        );
+        assert_eq!(
+            azure_chat_completions_url(url, Some("other"), None).unwrap(),
+            url
+        );
+    }
+
     #[test]
     fn test_google_tool_id_roundtrip() {
         let id = build_google_tool_call_id("read_region_content", 2);
@@ -1382,15 +2341,67 @@ mod tests {
     }
 
     #[test]
-    fn test_prepare_openai_responses_payload() {
-        let settings = dummy_settings(ApiType::OpenAiResponses);
-        let payload = prepare_payload(
-            &settings,
-            vec![],
-            vec![SublimeInputContent {
-                content: Some("hello".to_string()),
-                path: None,
-                scope: None,
+    fn test_prepare_payload_routes_by_api_type() {
+        let payload = |api_type| -> Value {
+            let mut settings = dummy_settings(api_type);
+            settings.tools = None;
+            serde_json::from_str(
+                &prepare_payload(
+                    &settings,
+                    vec![],
+                    vec![SublimeInputContent {
+                        content: Some("hello".to_string()),
+                        path: None,
+                        scope: None,
+                        input_kind: InputKind::Command,
+                        tool_id: None,
+                    }],
+                )
+                .unwrap(),
+            )
+            .unwrap()
+        };
+
+        let open_ai = payload(ApiType::OpenAi);
+        assert_eq!(
+            open_ai["messages"][1]["content"],
+            json!([{"type": "text", "text": "hello"}])
+        );
+
+        let plain_text = payload(ApiType::PlainText);
+        assert_eq!(
+            plain_text["messages"][1]["content"],
+            "hello"
+        );
+
+        let responses = payload(ApiType::OpenAiResponses);
+        assert!(
+            responses
+                .get("messages")
+                .is_none()
+        );
+        assert_eq!(responses["input"][0]["type"], "message");
+
+        let anthropic = payload(ApiType::Anthropic);
+        assert!(
+            anthropic["system"]
+                .as_str()
+                .unwrap()
+                .contains("System role")
+        );
+        assert_eq!(anthropic["messages"][0]["role"], "user");
+    }
+
+    #[test]
+    fn test_prepare_openai_responses_payload() {
+        let settings = dummy_settings(ApiType::OpenAiResponses);
+        let payload = prepare_payload(
+            &settings,
+            vec![],
+            vec![SublimeInputContent {
+                content: Some("hello".to_string()),
+                path: None,
+                scope: None,
                 input_kind: InputKind::Command,
                 tool_id: None,
             }],
@@ -1459,6 +2470,902 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_prepare_anthropic_payload_with_mixed_cache_and_input() {
+        let mut settings = dummy_settings(ApiType::Anthropic);
+        settings.advertisement = true;
+        settings.tools = None;
+
+        let cache_entries = (0 .. 8)
+            .map(|index| {
+                CacheEntry {
+                    content: Some(format!("cache {index}")),
+                    thinking: None,
+                    path: None,
+                    scope: None,
+                    role: if index % 2 == 0 { Roles::User } else { Roles::Assistant },
+                    tool_calls: None,
+                    tool_call_id: None,
+                    provider_metadata: None,
+                    created_at: None,
+                    citations: None,
+                    logprobs: None,
+                    user: None,
+                    finish_reason: None,
+                }
+            })
+            .collect();
+
+        let payload = prepare_payload(
+            &settings,
+            cache_entries,
+            vec![SublimeInputContent {
+                content: Some("command".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::Command,
+                tool_id: None,
+            }],
+        )
+        .unwrap();
+
+        let payload_json: Value = serde_json::from_str(&payload).unwrap();
+        let system = payload_json["system"]
+            .as_str()
+            .unwrap_or_default();
+        assert!(system.starts_with("System role"));
+        assert!(system.contains("https://github.com/sponsors/yaroslavyaroslav"));
+
+        let messages = payload_json["messages"]
+            .as_array()
+            .expect("Expected Anthropic messages array");
+        assert_eq!(messages.len(), 9);
+        assert!(
+            messages
+                .iter()
+                .all(|message| message["role"] == "user" || message["role"] == "assistant")
+        );
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(
+            messages[1]["content"][0],
+            json!({"type": "text", "text": "cache 1"})
+        );
+        assert_eq!(messages[8]["role"], "user");
+        assert_eq!(
+            messages[8]["content"][0]["text"],
+            "command"
+        );
+        assert_eq!(payload_json["max_tokens"], 4096);
+    }
+
+    #[test]
+    fn test_prepare_anthropic_payload_from_default_settings_has_max_tokens() {
+        let mut settings = AssistantSettings {
+            api_type: ApiType::Anthropic,
+            chat_model: "claude-3-opus-20240229".to_string(),
+            ..Default::default()
+        };
+        let input = || {
+            vec![SublimeInputContent {
+                content: Some("hello".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::Command,
+                tool_id: None,
+            }]
+        };
+
+        let payload: Value =
+            serde_json::from_str(&prepare_payload(&settings, vec![], input()).unwrap()).unwrap();
+        assert_eq!(payload["max_tokens"], 4096);
+        assert!(
+            payload
+                .get("max_completion_tokens")
+                .is_none()
+        );
+        assert_eq!(
+            payload["messages"][0]["content"][0]["text"],
+            "hello"
+        );
+
+        settings.chat_model = "claude-sonnet-4-20250514".to_string();
+        let payload: Value =
+            serde_json::from_str(&prepare_payload(&settings, vec![], input()).unwrap()).unwrap();
+        assert_eq!(payload["max_tokens"], 8192);
+
+        settings.max_completion_tokens = Some(2048);
+        let payload: Value =
+            serde_json::from_str(&prepare_payload(&settings, vec![], input()).unwrap()).unwrap();
+        assert_eq!(payload["max_tokens"], 2048);
+        assert!(
+            payload
+                .get("max_completion_tokens")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_thinking_roundtrips_through_cache_entry() {
+        let message: AssistantMessage = serde_json::from_value(json!({
+            "role": "assistant",
+            "content": "<think>Check the borrow first.</think>\nUse `clone`."
+        }))
+        .unwrap();
+        let cache_entry = CacheEntry::from(message);
+        assert_eq!(
+            cache_entry.content.as_deref(),
+            Some("Use `clone`.")
+        );
+        assert_eq!(
+            cache_entry
+                .thinking
+                .as_deref(),
+            Some("Check the borrow first.")
+        );
+        assert_eq!(
+            SublimeOutputContent::from(&cache_entry).content,
+            Some("<think>Check the borrow first.</think>\nUse `clone`.".to_string())
+        );
+
+        let anthropic = ProviderMessage::from_cache_entry(
+            CacheEntry::from(
+                serde_json::from_value::<AssistantMessage>(json!({
+                    "role": "assistant",
+                    "content": "<think>Check the borrow first.</think>\nUse `clone`."
+                }))
+                .unwrap(),
+            ),
+            ApiType::Anthropic,
+        );
+        assert_eq!(
+            anthropic.content,
+            "<think>Check the borrow first.</think>\nUse `clone`."
+        );
+
+        let openai = ProviderMessage::from_cache_entry(cache_entry, ApiType::OpenAi);
+        assert_eq!(openai.content, "Use `clone`.");
+    }
+
+    #[test]
+    fn test_anthropic_response_roundtrips_through_cache_entry() {
+        let response: AnthropicResponse = serde_json::from_value(json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "content": [
+                {"type": "text", "text": "Let me read it."},
+                {"type": "tool_use", "id": "toolu_1", "name": "read_region_content", "input": {"region_id": "a"}}
+            ],
+            "stop_reason": "tool_use"
+        }))
+        .unwrap();
+
+        let cache_entry = CacheEntry::from(response.into_assistant_message());
+        assert_eq!(cache_entry.role, Roles::Assistant);
+        assert_eq!(
+            cache_entry.content.as_deref(),
+            Some("Let me read it.")
+        );
+
+        let message = AnthropicMessage::from_provider_message(ProviderMessage::from_cache_entry(
+            cache_entry,
+            ApiType::Anthropic,
+        ))
+        .unwrap();
+        let message_json = serde_json::to_value(message).unwrap();
+        assert_eq!(
+            message_json,
+            json!({
+                "role": "assistant",
+                "content": [
+                    {"type": "text", "text": "Let me read it."},
+                    {"type": "tool_use", "id": "toolu_1", "name": "read_region_content", "input": {"region_id": "a"}}
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_anthropic_tool_loop_roundtrip_from_openai_shaped_history() {
+        let mut settings = dummy_settings(ApiType::Anthropic);
+        settings.tools = None;
+        let cache_entries: Vec<CacheEntry> = serde_json::from_value(json!([
+            {"role": "user", "content": "Read both files"},
+            {"role": "assistant", "content": null, "tool_calls": [
+                {"id": "toolu_1", "type": "function", "function": {"name": "read_region_content", "arguments": "{\"file_path\":\"a.rs\"}"}},
+                {"id": "toolu_2", "type": "function", "function": {"name": "read_region_content", "arguments": "{\"file_path\":\"b.rs\"}"}}
+            ]},
+            {"role": "tool", "content": "fn a() {}", "tool_call_id": "toolu_1"}
+        ]))
+        .unwrap();
+
+        let payload = prepare_payload(
+            &settings,
+            cache_entries,
+            vec![SublimeInputContent {
+                content: Some("fn b() {}".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::FunctionResult,
+                tool_id: Some("toolu_2".to_string()),
+            }],
+        )
+        .unwrap();
+
+        let payload_json: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(
+            payload_json["messages"],
+            json!([
+                {"role": "user", "content": [{"type": "text", "text": "Read both files"}]},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_1", "name": "read_region_content", "input": {"file_path": "a.rs"}},
+                    {"type": "tool_use", "id": "toolu_2", "name": "read_region_content", "input": {"file_path": "b.rs"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": "fn a() {}", "is_error": false},
+                    {"type": "tool_result", "tool_use_id": "toolu_2", "content": "fn b() {}", "is_error": false}
+                ]}
+            ])
+        );
+
+        let response: AnthropicResponse = serde_json::from_value(json!({
+            "id": "msg_2",
+            "type": "message",
+            "role": "assistant",
+            "content": [
+                {"type": "tool_use", "id": "toolu_3", "name": "create_file", "input": {"file_path": "c.rs"}}
+            ],
+            "stop_reason": "tool_use"
+        }))
+        .unwrap();
+        let message = response.into_assistant_message();
+        assert!(message.content.is_none());
+
+        let tool_calls = message.tool_calls.unwrap();
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].id, "toolu_3");
+        assert_eq!(tool_calls[0].r#type, "function");
+        assert_eq!(
+            tool_calls[0].function.name,
+            "create_file"
+        );
+        assert_eq!(
+            tool_calls[0]
+                .function
+                .arguments,
+            r#"{"file_path":"c.rs"}"#
+        );
+    }
+
+    #[test]
+    fn test_prepare_anthropic_payload_marks_cache_breakpoints() {
+        let mut settings = dummy_settings(ApiType::Anthropic);
+        settings.tools = None;
+        settings.cache_breakpoints = Some(2);
+
+        let cache_entries = || -> Vec<CacheEntry> {
+            (0 .. 3)
+                .map(|index| {
+                    CacheEntry {
+                        content: Some(format!("cache {index}")),
+                        thinking: None,
+                        path: None,
+                        scope: None,
+                        role: Roles::User,
+                        tool_calls: None,
+                        tool_call_id: None,
+                        provider_metadata: None,
+                        created_at: None,
+                        citations: None,
+                        logprobs: None,
+                        user: None,
+                        finish_reason: None,
+                    }
+                })
+                .collect()
+        };
+        let inputs = vec![
+            SublimeInputContent {
+                content: Some("sheet".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::Sheet,
+                tool_id: None,
+            },
+            SublimeInputContent {
+                content: Some("command".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::Command,
+                tool_id: None,
+            },
+        ];
+
+        let payload = prepare_payload(
+            &settings,
+            cache_entries(),
+            inputs.clone(),
+        )
+        .unwrap();
+        let payload_json: Value = serde_json::from_str(&payload).unwrap();
+        let cache_controls: Vec<Value> = payload_json["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["content"][0]["cache_control"].clone())
+            .collect();
+        assert_eq!(
+            cache_controls,
+            vec![
+                Value::Null,
+                Value::Null,
+                json!({"type": "ephemeral"}),
+                json!({"type": "ephemeral"}),
+                Value::Null,
+            ]
+        );
+
+        settings.api_type = ApiType::OpenAi;
+        let payload = prepare_payload(&settings, cache_entries(), inputs).unwrap();
+        assert!(!payload.contains("cache_control"));
+    }
+
+    #[test]
+    fn test_prepare_azure_inference_payload_drops_openai_only_fields() {
+        let mut settings = dummy_settings(ApiType::AzureInference);
+        settings.url = "https://models.inference.ai.azure.com/chat/completions".to_string();
+        settings.chat_model = "Meta-Llama-3.1-405B-Instruct".to_string();
+        settings.max_completion_tokens = Some(1024);
+        settings.reasoning_effort = Some(ReasonEffort::High);
+        settings.parallel_tool_calls = Some(true);
+        settings.temperature = Some(0.5);
+        let payload = prepare_payload(
+            &settings,
+            vec![CacheEntry {
+                content: Some("be terse".to_string()),
+                thinking: None,
+                path: None,
+                scope: None,
+                role: Roles::Developer,
+                tool_calls: None,
+                tool_call_id: None,
+                provider_metadata: None,
+                created_at: None,
+                citations: None,
+                logprobs: None,
+                user: None,
+                finish_reason: None,
+            }],
+            vec![SublimeInputContent {
+                content: Some("hello".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::Command,
+                tool_id: None,
+            }],
+        )
+        .unwrap();
+
+        let payload_json: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(
+            payload_json["model"],
+            "Meta-Llama-3.1-405B-Instruct"
+        );
+        assert_eq!(payload_json["max_tokens"], 1024);
+        assert_eq!(payload_json["temperature"], 0.5);
+        for field in [
+            "max_completion_tokens",
+            "reasoning_effort",
+            "parallel_tool_calls",
+        ] {
+            assert!(
+                payload_json
+                    .get(field)
+                    .is_none(),
+                "{field} must not be sent"
+            );
+        }
+        let roles: Vec<&str> = payload_json["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| {
+                message["role"]
+                    .as_str()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(roles, vec!["system", "system", "user"]);
+    }
+
+    #[test]
+    fn test_list_open_files_tool_is_offered_only_when_enabled() {
+        let offers_list_open_files = |settings: &AssistantSettings| {
+            tools_enabled(settings)
+                .unwrap()
+                .iter()
+                .any(|tool| {
+                    tool.function
+                        .as_ref()
+                        .is_some_and(|function| function.name == "list_open_files")
+                })
+        };
+
+        let mut settings = dummy_settings(ApiType::OpenAi);
+        assert!(!offers_list_open_files(&settings));
+
+        settings.enable_list_files_tool = Some(true);
+        assert!(offers_list_open_files(&settings));
+    }
+
+    #[test]
+    fn test_prepare_mistral_payload_matches_chat_completions_shape() {
+        let mut settings = dummy_settings(ApiType::Mistral);
+        settings.chat_model = "mistral-large-latest".to_string();
+        settings.safe_prompt = Some(true);
+        settings.seed = Some(42);
+        settings.parallel_tool_calls = Some(true);
+        let payload = prepare_payload(
+            &settings,
+            vec![CacheEntry {
+                content: Some("be terse".to_string()),
+                thinking: None,
+                path: None,
+                scope: None,
+                role: Roles::Developer,
+                tool_calls: None,
+                tool_call_id: None,
+                provider_metadata: None,
+                created_at: None,
+                citations: None,
+                logprobs: None,
+                user: None,
+                finish_reason: None,
+            }],
+            vec![SublimeInputContent {
+                content: Some("hello".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::Command,
+                tool_id: None,
+            }],
+        )
+        .unwrap();
+
+        let payload_json: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(
+            payload_json["model"],
+            "mistral-large-latest"
+        );
+        assert_eq!(payload_json["stream"], false);
+        assert_eq!(payload_json["safe_prompt"], true);
+        assert_eq!(payload_json["random_seed"], 42);
+        assert!(
+            payload_json
+                .get("seed")
+                .is_none()
+        );
+        assert!(
+            payload_json
+                .get("parallel_tool_calls")
+                .is_none()
+        );
+        assert_eq!(payload_json["tool_choice"], "auto");
+        assert_eq!(
+            payload_json["messages"][0]["role"],
+            "system"
+        );
+        assert_eq!(
+            payload_json["messages"][1]["role"],
+            "system"
+        );
+        assert_eq!(
+            payload_json["messages"][2],
+            json!({"role": "user", "content": [{"type": "text", "text": "hello"}]})
+        );
+        let tool = &payload_json["tools"][0];
+        assert_eq!(tool["type"], "function");
+        assert!(tool["function"]["name"].is_string());
+        assert!(tool["function"]["parameters"].is_object());
+
+        settings.tools = None;
+        settings.safe_prompt = None;
+        let payload = prepare_payload(&settings, vec![], vec![]).unwrap();
+        let payload_json: Value = serde_json::from_str(&payload).unwrap();
+        assert!(
+            payload_json
+                .get("tools")
+                .is_none()
+        );
+        assert!(
+            payload_json
+                .get("tool_choice")
+                .is_none()
+        );
+        assert!(
+            payload_json
+                .get("safe_prompt")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_prepare_payload_merges_extra_body_without_clobbering() {
+        let mut settings = dummy_settings(ApiType::OpenAi);
+        settings.tools = None;
+        settings.extra_body = Some(Map::from_iter([
+            (
+                "provider".to_string(),
+                json!({"order": ["anthropic", "openai"]}),
+            ),
+            (
+                "transforms".to_string(),
+                json!(["middle-out"]),
+            ),
+            (
+                "model".to_string(),
+                json!("should-not-win"),
+            ),
+        ]));
+
+        let payload: Value =
+            serde_json::from_str(&prepare_payload(&settings, vec![], vec![]).unwrap()).unwrap();
+
+        assert_eq!(payload["model"], "dummy-model");
+        assert_eq!(
+            payload["provider"],
+            json!({"order": ["anthropic", "openai"]})
+        );
+        assert_eq!(
+            payload["transforms"],
+            json!(["middle-out"])
+        );
+        assert!(payload["messages"].is_array());
+    }
+
+    const PIXEL_PNG: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/pixel.png"
+    );
+
+    fn image_input(path: &str) -> SublimeInputContent {
+        SublimeInputContent {
+            content: Some("What is on this picture?".to_string()),
+            path: Some(path.to_string()),
+            scope: None,
+            input_kind: InputKind::Image,
+            tool_id: None,
+        }
+    }
+
+    #[test]
+    fn test_prepare_openai_payload_with_image_input() {
+        let mut settings = dummy_settings(ApiType::OpenAi);
+        settings.tools = None;
+
+        let payload: Value = serde_json::from_str(
+            &prepare_payload(
+                &settings,
+                vec![],
+                vec![image_input(PIXEL_PNG)],
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let content = payload["messages"][1]["content"]
+            .as_array()
+            .unwrap();
+        assert_eq!(content.len(), 2);
+        assert_eq!(content[0]["type"], "text");
+        assert_eq!(
+            content[0]["text"],
+            format!(
+                "Image: `{}`\nWhat is on this picture?",
+                PIXEL_PNG
+            )
+        );
+        assert_eq!(content[1]["type"], "image_url");
+        let expected = format!(
+            "data:image/png;base64,{}",
+            BASE64_STANDARD.encode(std::fs::read(PIXEL_PNG).unwrap())
+        );
+        assert_eq!(content[1]["image_url"]["url"], expected);
+    }
+
+    #[test]
+    fn test_prepare_plain_text_payload_with_image_input_references_path() {
+        let mut settings = dummy_settings(ApiType::PlainText);
+        settings.tools = None;
+
+        let payload: Value = serde_json::from_str(
+            &prepare_payload(
+                &settings,
+                vec![],
+                vec![image_input(PIXEL_PNG)],
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let content = payload["messages"][1]["content"]
+            .as_str()
+            .unwrap();
+        assert!(content.starts_with(&format!("Image: `{}`", PIXEL_PNG)));
+        assert!(!content.contains("data:image"));
+    }
+
+    #[test]
+    fn test_prepare_payload_rejects_oversized_or_unknown_images() {
+        let mut settings = dummy_settings(ApiType::OpenAi);
+        settings.max_image_bytes = Some(8);
+
+        let error = prepare_payload(
+            &settings,
+            vec![],
+            vec![image_input(PIXEL_PNG)],
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("over the 8 bytes limit")
+        );
+
+        settings.max_image_bytes = None;
+        let error = prepare_payload(
+            &settings,
+            vec![],
+            vec![image_input("notes.txt")],
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Unsupported image type")
+        );
+    }
+
+    #[test]
+    fn test_prepare_openai_payload_with_audio_input() {
+        let mut settings = dummy_settings(ApiType::OpenAi);
+        settings.tools = None;
+        let silence_wav = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/silence.wav"
+        );
+        let input = SublimeInputContent {
+            content: Some("Transcribe this".to_string()),
+            path: Some(silence_wav.to_string()),
+            scope: None,
+            input_kind: InputKind::Audio,
+            tool_id: None,
+        };
+
+        let payload: Value =
+            serde_json::from_str(&prepare_payload(&settings, vec![], vec![input.clone()]).unwrap()).unwrap();
+
+        assert_eq!(
+            payload["messages"][1]["content"][1],
+            json!({
+                "type": "input_audio",
+                "input_audio": {
+                    "data": BASE64_STANDARD.encode(std::fs::read(silence_wav).unwrap()),
+                    "format": "wav",
+                },
+            })
+        );
+
+        let error = prepare_payload(
+            &dummy_settings(ApiType::Anthropic),
+            vec![],
+            vec![input],
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("only supported by the `open_ai` api type")
+        );
+    }
+
+    #[test]
+    fn test_audio_format_from_extension() {
+        assert_eq!(audio_format("a/b.wav").unwrap(), "wav");
+        assert_eq!(audio_format("b.MP3").unwrap(), "mp3");
+        assert!(audio_format("b.ogg").is_err());
+    }
+
+    #[test]
+    fn test_image_mime_type_from_extension() {
+        assert_eq!(
+            image_mime_type("a/b.png").unwrap(),
+            "image/png"
+        );
+        assert_eq!(
+            image_mime_type("b.JPG").unwrap(),
+            "image/jpeg"
+        );
+        assert_eq!(
+            image_mime_type("b.jpeg").unwrap(),
+            "image/jpeg"
+        );
+        assert_eq!(
+            image_mime_type("b.webp").unwrap(),
+            "image/webp"
+        );
+        assert!(image_mime_type("b").is_err());
+    }
+
+    #[test]
+    fn test_prepare_cohere_payload_with_tool_roundtrip() {
+        let mut settings = dummy_settings(ApiType::Cohere);
+        settings.chat_model = "command-a-03-2025".to_string();
+        settings.top_p = Some(0.5);
+        let payload = prepare_payload(
+            &settings,
+            vec![
+                CacheEntry {
+                    content: Some("list files".to_string()),
+                    thinking: None,
+                    path: None,
+                    scope: None,
+                    role: Roles::User,
+                    tool_calls: None,
+                    tool_call_id: None,
+                    provider_metadata: None,
+                    created_at: None,
+                    citations: None,
+                    logprobs: None,
+                    user: None,
+                    finish_reason: None,
+                },
+                CacheEntry {
+                    content: None,
+                    thinking: None,
+                    path: None,
+                    scope: None,
+                    role: Roles::Assistant,
+                    tool_calls: Some(vec![ToolCall {
+                        id: "call_1".to_string(),
+                        r#type: "function".to_string(),
+                        thought_signature: None,
+                        function: Function {
+                            name: "get_working_directory_content".to_string(),
+                            arguments: r#"{"directory_path":"."}"#.to_string(),
+                        },
+                    }]),
+                    tool_call_id: None,
+                    provider_metadata: None,
+                    created_at: None,
+                    citations: None,
+                    logprobs: None,
+                    user: None,
+                    finish_reason: None,
+                },
+            ],
+            vec![SublimeInputContent {
+                content: Some("src/".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::FunctionResult,
+                tool_id: Some("call_1".to_string()),
+            }],
+        )
+        .unwrap();
+
+        let payload_json: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(
+            payload_json["model"],
+            "command-a-03-2025"
+        );
+        assert_eq!(payload_json["p"], 0.5);
+        assert!(
+            payload_json
+                .get("top_p")
+                .is_none()
+        );
+
+        let messages = payload_json["messages"]
+            .as_array()
+            .expect("Expected Cohere messages array");
+        assert_eq!(messages[0]["role"], "system");
+        assert_eq!(
+            messages[1],
+            json!({"role": "user", "content": "list files"})
+        );
+        assert_eq!(
+            messages[2],
+            json!({
+                "role": "assistant",
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {
+                        "name": "get_working_directory_content",
+                        "arguments": "{\"directory_path\":\".\"}"
+                    }
+                }]
+            })
+        );
+        assert_eq!(
+            messages[3],
+            json!({"role": "tool", "tool_call_id": "call_1", "content": "src/"})
+        );
+        assert_eq!(
+            payload_json["tools"][0]["type"],
+            "function"
+        );
+    }
+
+    #[test]
+    fn test_parse_cohere_response_into_assistant_message() {
+        let response: CohereResponse = serde_json::from_value(json!({
+            "id": "c14c80c3",
+            "finish_reason": "TOOL_CALL",
+            "message": {
+                "role": "assistant",
+                "tool_plan": "I will read the file.",
+                "content": [{"type": "text", "text": "Reading."}],
+                "tool_calls": [{
+                    "id": "read_region_content_1",
+                    "type": "function",
+                    "function": {
+                        "name": "read_region_content",
+                        "arguments": "{\"region_id\":\"a\"}"
+                    }
+                }]
+            },
+            "usage": {"tokens": {"input_tokens": 10, "output_tokens": 5}}
+        }))
+        .unwrap();
+
+        let message = response.into_assistant_message();
+        assert_eq!(
+            message.content.as_deref(),
+            Some("Reading.")
+        );
+        let tool_calls = message.tool_calls.unwrap();
+        assert_eq!(
+            tool_calls[0].id,
+            "read_region_content_1"
+        );
+        assert_eq!(
+            tool_calls[0]
+                .function
+                .arguments,
+            r#"{"region_id":"a"}"#
+        );
+    }
+
+    #[test]
+    fn test_parse_ollama_final_chunk_into_usage() {
+        let response: OllamaChatResponse = serde_json::from_value(json!({
+            "model": "llama3.2",
+            "message": {"role": "assistant", "content": "Hi"},
+            "done": true,
+            "prompt_eval_count": 26,
+            "eval_count": 290,
+            "total_duration": 2_000_000_000u64
+        }))
+        .unwrap();
+
+        let message = response.into_assistant_message(0);
+        assert_eq!(message.content.as_deref(), Some("Hi"));
+        assert_eq!(
+            message.usage,
+            Some(TokenUsage {
+                prompt_tokens: 26,
+                completion_tokens: 290,
+                total_tokens: 316,
+                total_time: Some(2.0),
+                queue_time: None,
+                cache_creation_input_tokens: None,
+                cache_read_input_tokens: None,
+            })
+        );
+    }
+
     #[test]
     fn test_prepare_google_payload_with_system_instruction() {
         let settings = dummy_settings(ApiType::Google);
@@ -1543,6 +3450,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_prepare_google_payload_maps_history_roles_and_generation_config() {
+        let mut settings = dummy_settings(ApiType::Google);
+        settings.temperature = Some(0.3);
+        settings.top_p = Some(0.9);
+        settings.max_completion_tokens = Some(256);
+        let input = |content: &str, input_kind: InputKind| {
+            SublimeInputContent {
+                content: Some(content.to_string()),
+                path: None,
+                scope: None,
+                input_kind,
+                tool_id: None,
+            }
+        };
+        let cache_entries = vec![
+            CacheEntry::from(input("hi", InputKind::ViewSelection)),
+            CacheEntry::from(input(
+                "hello",
+                InputKind::AssistantResponse,
+            )),
+        ];
+
+        let payload = prepare_payload(
+            &settings,
+            cache_entries,
+            vec![input("ping", InputKind::ViewSelection)],
+        )
+        .unwrap();
+
+        let payload_json: Value = serde_json::from_str(&payload).unwrap();
+        let contents: Vec<(&str, &str)> = payload_json["contents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|content| {
+                (
+                    content["role"]
+                        .as_str()
+                        .unwrap(),
+                    content["parts"][0]["text"]
+                        .as_str()
+                        .unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                ("user", "hi"),
+                ("model", "hello"),
+                ("user", "ping")
+            ]
+        );
+        assert_eq!(
+            payload_json["generationConfig"],
+            json!({"temperature": 0.3, "topP": 0.9, "maxOutputTokens": 256})
+        );
+    }
+
     #[test]
     fn test_prepare_google_payload_uses_camel_case_tool_fields() {
         let settings = dummy_settings(ApiType::Google);
@@ -1565,6 +3532,11 @@ mod tests {
                 }]),
                 tool_call_id: None,
                 provider_metadata: None,
+                created_at: None,
+                citations: None,
+                logprobs: None,
+                user: None,
+                finish_reason: None,
             }],
             vec![SublimeInputContent {
                 content: Some("{\"ok\":true}".to_string()),
@@ -1768,6 +3740,11 @@ mod tests {
                             },
                         ],
                     }),
+                    created_at: None,
+                    citations: None,
+                    logprobs: None,
+                    user: None,
+                    finish_reason: None,
                 },
                 CacheEntry {
                     content: Some("{\"content\":\"one\"}".to_string()),
@@ -1778,6 +3755,11 @@ mod tests {
                     tool_calls: None,
                     tool_call_id: Some("google::read_region_content::1".to_string()),
                     provider_metadata: None,
+                    created_at: None,
+                    citations: None,
+                    logprobs: None,
+                    user: None,
+                    finish_reason: None,
                 },
                 CacheEntry {
                     content: Some("{\"content\":\"two\"}".to_string()),
@@ -1788,6 +3770,11 @@ mod tests {
                     tool_calls: None,
                     tool_call_id: Some("google::read_region_content::3".to_string()),
                     provider_metadata: None,
+                    created_at: None,
+                    citations: None,
+                    logprobs: None,
+                    user: None,
+                    finish_reason: None,
                 },
             ],
             vec![],
@@ -1,17 +1,150 @@
 use std::{
+    collections::HashMap,
+    str::FromStr,
     sync::{Arc, atomic::Ordering},
     thread,
 };
 
-use pyo3::prelude::*;
+use pyo3::{create_exception, exceptions::PyException, prelude::*, sync::GILOnceCell, types::PyDict};
 use tokio::runtime::Runtime;
 
 use crate::{
     cacher::Cacher,
-    types::{AssistantSettings, CacheEntry, PromptMode, SublimeInputContent, SublimeOutputContent},
+    error::LlmError,
+    models_network_types::ModelInfo,
+    tools_definition::TOOL_REGISTRY,
+    types::{
+        AssistantSettings,
+        CacheEntry,
+        PromptMode,
+        RateLimits,
+        SearchType,
+        SublimeInputContent,
+        SublimeOutputContent,
+    },
     worker::OpenAIWorker,
 };
 
+create_exception!(
+    llm_runner,
+    LlmRunnerError,
+    PyException,
+    "A request to the provider failed."
+);
+create_exception!(
+    llm_runner,
+    HttpError,
+    LlmRunnerError,
+    "The provider answered with an error status, args are `(message, status, retry_after)`."
+);
+create_exception!(
+    llm_runner,
+    AuthenticationError,
+    HttpError,
+    "The provider rejected the token."
+);
+create_exception!(
+    llm_runner,
+    RateLimitError,
+    HttpError,
+    "The provider asked to slow down."
+);
+create_exception!(
+    llm_runner,
+    RequestTimeoutError,
+    LlmRunnerError,
+    "The provider didn't answer in time."
+);
+create_exception!(
+    llm_runner,
+    ResponseParseError,
+    LlmRunnerError,
+    "The provider's answer can't be read."
+);
+create_exception!(
+    llm_runner,
+    RequestCancelledError,
+    LlmRunnerError,
+    "The request was cancelled."
+);
+create_exception!(
+    llm_runner,
+    CircuitOpenError,
+    LlmRunnerError,
+    "The provider kept failing, the request wasn't sent until it recovers."
+);
+
+/// Raises the exception class of the kind of failure with its `LlmErrorKind` as `kind`,
+/// `RuntimeError` when it's none of them.
+fn to_py_err(error: anyhow::Error) -> PyErr {
+    let message = error.to_string();
+    let Some(llm_error) = error.downcast_ref::<LlmError>() else {
+        return PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(message);
+    };
+    let py_err = match llm_error {
+        LlmError::Http {
+            status, retry_after, ..
+        } => {
+            let args = (
+                message,
+                *status,
+                retry_after.map(|delay| delay.as_secs_f64()),
+            );
+            match status {
+                401 | 403 => PyErr::new::<AuthenticationError, _>(args),
+                429 => PyErr::new::<RateLimitError, _>(args),
+                _ => PyErr::new::<HttpError, _>(args),
+            }
+        }
+        LlmError::Timeout => PyErr::new::<RequestTimeoutError, _>(message),
+        LlmError::Serde(_) => PyErr::new::<ResponseParseError, _>(message),
+        LlmError::Cancelled => PyErr::new::<RequestCancelledError, _>(message),
+        LlmError::CircuitOpen { .. } => PyErr::new::<CircuitOpenError, _>(message),
+        _ => PyErr::new::<LlmRunnerError, _>(message),
+    };
+    Python::with_gil(|py| {
+        py_err
+            .value(py)
+            .setattr("kind", llm_error.kind())
+            .ok()
+    });
+    py_err
+}
+
+/// Adds the exception classes `to_py_err` raises to the module.
+pub(crate) fn add_exceptions(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add(
+        "LlmRunnerError",
+        py.get_type::<LlmRunnerError>(),
+    )?;
+    m.add("HttpError", py.get_type::<HttpError>())?;
+    m.add(
+        "AuthenticationError",
+        py.get_type::<AuthenticationError>(),
+    )?;
+    m.add(
+        "RateLimitError",
+        py.get_type::<RateLimitError>(),
+    )?;
+    m.add(
+        "RequestTimeoutError",
+        py.get_type::<RequestTimeoutError>(),
+    )?;
+    m.add(
+        "ResponseParseError",
+        py.get_type::<ResponseParseError>(),
+    )?;
+    m.add(
+        "RequestCancelledError",
+        py.get_type::<RequestCancelledError>(),
+    )?;
+    m.add(
+        "CircuitOpenError",
+        py.get_type::<CircuitOpenError>(),
+    )
+}
+
 #[pyclass(name = "Worker")]
 #[derive(Clone, Debug)]
 pub struct PythonWorker {
@@ -21,6 +154,12 @@ pub struct PythonWorker {
     #[pyo3(get)]
     pub proxy: Option<String>,
 
+    #[pyo3(get)]
+    pub ca_cert_path: Option<String>,
+
+    #[pyo3(get)]
+    pub danger_accept_invalid_certs: bool,
+
     worker: Arc<OpenAIWorker>,
 }
 
@@ -59,20 +198,115 @@ impl FunctionHandler {
     }
 }
 
+/// Event loop the async tool handlers share, run forever by a daemon thread of its own.
+static TOOL_EVENT_LOOP: GILOnceCell<PyObject> = GILOnceCell::new();
+
+/// Tool handler for an `async def` callback: every call schedules the coroutine on the dedicated
+/// tool event loop and waits for its result, so neither the GIL nor a loop of the host is held meanwhile.
+/// A raising coroutine hands its error back to the model as the tool result.
+struct AsyncFunctionHandler {
+    func: Arc<dyn Fn((String, String)) -> String + Send + Sync + 'static>,
+}
+
+impl AsyncFunctionHandler {
+    fn new(obj: PyObject) -> Self {
+        let func = Arc::new(
+            move |args: (String, String)| -> String {
+                Python::with_gil(|py| Self::run_coroutine(py, &obj, args)).unwrap_or_else(|error| {
+                    format!(
+                        "Async function handler failed: {}",
+                        error
+                    )
+                })
+            },
+        );
+        Self { func }
+    }
+
+    fn run_coroutine(py: Python<'_>, obj: &PyObject, args: (String, String)) -> PyResult<String> {
+        let coroutine = obj.call1(py, args)?;
+        let event_loop = Self::event_loop(py)?;
+        let future = py
+            .import("asyncio")?
+            .call_method1(
+                "run_coroutine_threadsafe",
+                (coroutine, event_loop),
+            )?;
+        // `concurrent.futures.Future.result` releases the GIL while it waits for the loop thread.
+        future
+            .call_method0("result")?
+            .extract::<String>()
+    }
+
+    fn event_loop(py: Python<'_>) -> PyResult<&PyObject> {
+        TOOL_EVENT_LOOP.get_or_try_init(py, || {
+            let event_loop = py
+                .import("asyncio")?
+                .call_method0("new_event_loop")?;
+            let kwargs = PyDict::new(py);
+            kwargs.set_item(
+                "target",
+                event_loop.getattr("run_forever")?,
+            )?;
+            kwargs.set_item("daemon", true)?;
+            kwargs.set_item("name", "llm_runner-tools")?;
+            py.import("threading")?
+                .getattr("Thread")?
+                .call((), Some(&kwargs))?
+                .call_method0("start")?;
+            Ok(event_loop.unbind())
+        })
+    }
+
+    fn is_coroutine_function(obj: &PyObject) -> bool {
+        Python::with_gil(|py| {
+            py.import("inspect")
+                .and_then(|inspect| inspect.call_method1("iscoroutinefunction", (obj,)))
+                .and_then(|ret| ret.extract::<bool>())
+                .unwrap_or(false)
+        })
+    }
+}
+
+/// Picks the async handler for coroutine functions and the sync one for everything else.
+fn function_handler_for(obj: PyObject) -> Arc<dyn Fn((String, String)) -> String + Send + Sync + 'static> {
+    if AsyncFunctionHandler::is_coroutine_function(&obj) {
+        AsyncFunctionHandler::new(obj).func
+    } else {
+        FunctionHandler::new(obj).func
+    }
+}
+
 #[pymethods]
 impl PythonWorker {
     #[new]
-    #[pyo3(signature = (window_id, path, proxy=None))]
-    fn new(window_id: usize, path: String, proxy: Option<String>) -> Self {
+    #[pyo3(signature = (window_id, path, proxy=None, ca_cert_path=None, danger_accept_invalid_certs=false))]
+    fn new(
+        window_id: usize,
+        path: String,
+        proxy: Option<String>,
+        ca_cert_path: Option<String>,
+        danger_accept_invalid_certs: bool,
+    ) -> Self {
         PythonWorker {
             window_id,
             proxy: proxy.clone(),
-            worker: Arc::new(OpenAIWorker::new(
-                window_id, path, proxy,
-            )),
+            ca_cert_path: ca_cert_path.clone(),
+            danger_accept_invalid_certs,
+            worker: Arc::new(
+                OpenAIWorker::new(window_id, path, proxy).with_tls(
+                    ca_cert_path,
+                    danger_accept_invalid_certs,
+                ),
+            ),
         }
     }
 
+    /// `handler` gets the plain text of the answer, or with `stream_events` in the settings every stream
+    /// event as a JSON string, e.g. `{"event":"text","data":"Hi"}`, the last one being `{"event":"done"}`,
+    /// or `{"event":"error","data":{"kind":"http","message":"...","status":429,"retry_after":7.0}}` when
+    /// the run failed. `error_handler` gets that same error event, without `stream_events` an
+    /// `LlmRunner error: ...` line.
     #[allow(clippy::too_many_arguments)]
     #[pyo3(signature = (view_id, prompt_mode, contents, assistant_settings, handler, error_handler, function_handler))]
     fn run(
@@ -97,7 +331,8 @@ impl PythonWorker {
                         assistant_settings,
                         TextHandler::new(handler).func,
                         TextHandler::new(error_handler).func,
-                        FunctionHandler::new(function_handler).func,
+                        function_handler_for(function_handler),
+                        vec![],
                     )
                     .await
             })
@@ -108,6 +343,16 @@ impl PythonWorker {
 
     pub fn cancel(&mut self) { self.worker.cancel() }
 
+    pub fn pause(&mut self) { self.worker.pause() }
+
+    pub fn resume(&mut self) { self.worker.resume() }
+
+    pub fn last_request_id(&self) -> Option<String> { self.worker.last_request_id() }
+
+    pub fn last_rate_limits(&self) -> Option<RateLimits> { self.worker.last_rate_limits() }
+
+    pub fn token_count(&self) -> usize { self.worker.token_count() }
+
     pub fn is_alive(&self) -> bool {
         self.worker
             .is_alive
@@ -117,6 +362,7 @@ impl PythonWorker {
     #[allow(clippy::too_many_arguments)]
     fn run_sync(
         &mut self,
+        py: Python<'_>,
         view_id: usize,
         prompt_mode: PromptMode,
         contents: Vec<SublimeInputContent>,
@@ -124,24 +370,29 @@ impl PythonWorker {
         handler: PyObject,
         error_handler: PyObject,
         function_handler: PyObject,
-    ) -> PyResult<()> {
+    ) -> PyResult<Vec<String>> {
         let rt = Runtime::new().expect("Failed to create runtime");
         let worker_clone = self.worker.clone();
-        let _ = rt.block_on(async move {
-            worker_clone
-                .run(
-                    view_id,
-                    contents,
-                    prompt_mode,
-                    assistant_settings,
-                    TextHandler::new(handler).func,
-                    TextHandler::new(error_handler).func,
-                    FunctionHandler::new(function_handler).func,
-                )
-                .await
+        let function_handler = function_handler_for(function_handler);
+        // Handlers take the GIL from other threads, so it can't be held while blocking on the run.
+        let choices = py.allow_threads(|| {
+            rt.block_on(async move {
+                worker_clone
+                    .run(
+                        view_id,
+                        contents,
+                        prompt_mode,
+                        assistant_settings,
+                        TextHandler::new(handler).func,
+                        TextHandler::new(error_handler).func,
+                        function_handler,
+                        vec![],
+                    )
+                    .await
+            })
         });
 
-        Ok(())
+        choices.map_err(to_py_err)
     }
 }
 
@@ -173,6 +424,68 @@ pub fn write_to_cache(path: &str, content: SublimeInputContent) -> PyResult<()>
     Ok(())
 }
 
+/// Replaces the history entry at `index` with `content`, keeping the time it was first cached.
+#[pyfunction]
+#[pyo3(signature = (path, index, content))]
+pub fn update_cache_entry(path: &str, index: usize, content: SublimeInputContent) -> PyResult<()> {
+    let cacher = Cacher::new(path);
+    let mut entry = CacheEntry::from(content);
+    if let Ok(Some(existing)) = cacher.read_entry::<CacheEntry>(index) {
+        entry.created_at = existing
+            .created_at
+            .or(entry.created_at);
+    }
+    cacher
+        .write_entry_at(index, &entry)
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("{}", e)))
+}
+
+#[pyfunction]
+#[pyo3(signature = (path, index))]
+pub fn read_cache_entry(path: &str, index: usize) -> PyResult<Option<SublimeOutputContent>> {
+    let cacher = Cacher::new(path);
+    let entry = cacher
+        .read_entry::<CacheEntry>(index)
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("{}", e)))?;
+
+    Ok(entry
+        .as_ref()
+        .map(SublimeOutputContent::from))
+}
+
+#[pyfunction]
+#[allow(unused)]
+#[pyo3(signature = (path, query, mode="contains"))]
+pub fn search_cache(path: &str, query: &str, mode: &str) -> PyResult<Vec<(usize, SublimeOutputContent)>> {
+    let search_type = SearchType::from_str(mode).map_err(|_| {
+        PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Unknown search mode: {}", mode))
+    })?;
+    let cacher = Cacher::new(path);
+    let matches = cacher
+        .search(query, search_type)
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("{}", e)))?;
+
+    Ok(matches
+        .iter()
+        .map(|(index, entry)| {
+            (
+                *index,
+                SublimeOutputContent::from(entry),
+            )
+        })
+        .collect())
+}
+
+#[pyfunction]
+#[allow(unused)]
+#[pyo3(signature = (path, json_str))]
+pub fn import_cache(path: &str, json_str: &str) -> PyResult<usize> {
+    let cacher = Cacher::new(path);
+    cacher
+        .import_json(json_str)
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("{}", e)))
+}
+
 #[pyfunction]
 #[allow(unused)]
 #[pyo3(signature = (path))]
@@ -185,16 +498,113 @@ pub fn read_model(path: &str) -> PyResult<AssistantSettings> {
     Ok(model)
 }
 
+/// Cumulative `prompt_tokens`, `completion_tokens` and `total_tokens` spent in the cache at `path`.
+#[pyfunction]
+#[allow(unused)]
+#[pyo3(signature = (path))]
+pub fn read_tokens_count(path: &str) -> PyResult<Option<HashMap<String, usize>>> {
+    let cacher = Cacher::new(path);
+    Ok(cacher
+        .read_tokens_count()
+        .map(|usage| {
+            HashMap::from([
+                (
+                    "prompt_tokens".to_string(),
+                    usage.prompt_tokens,
+                ),
+                (
+                    "completion_tokens".to_string(),
+                    usage.completion_tokens,
+                ),
+                (
+                    "total_tokens".to_string(),
+                    usage.total_tokens,
+                ),
+            ])
+        }))
+}
+
 #[pyfunction]
 #[allow(unused)]
 #[pyo3(signature = (path, model))]
 pub fn write_model(path: &str, model: AssistantSettings) -> PyResult<()> {
-    println!("path in rust: {}", path);
     let cacher = Cacher::new(path);
     cacher.write_model::<AssistantSettings>(&model);
     Ok(())
 }
 
+#[pyfunction]
+#[allow(unused)]
+#[pyo3(signature = (path, model_settings, texts))]
+pub fn embed(
+    py: Python<'_>,
+    path: &str,
+    model_settings: AssistantSettings,
+    texts: Vec<String>,
+) -> PyResult<Vec<Vec<f32>>> {
+    let rt = Runtime::new().expect("Failed to create runtime");
+    let worker = OpenAIWorker::new(0, path.to_string(), None);
+    py.allow_threads(|| rt.block_on(worker.embed(&model_settings, texts)))
+        .map_err(to_py_err)
+}
+
+#[pyfunction]
+#[allow(unused)]
+#[pyo3(signature = (model_settings))]
+pub fn list_models(py: Python<'_>, model_settings: AssistantSettings) -> PyResult<Vec<ModelInfo>> {
+    let rt = Runtime::new().expect("Failed to create runtime");
+    let worker = OpenAIWorker::new(0, String::new(), None);
+    // Other Python threads keep running while the request is in flight.
+    py.allow_threads(|| rt.block_on(worker.list_models(&model_settings)))
+        .map_err(to_py_err)
+}
+
+/// Sets the most verbose level the runner logs to the console at, it logs nothing until then.
+#[pyfunction]
+#[pyo3(signature = (level))]
+pub fn set_log_level(level: &str) -> PyResult<()> {
+    crate::logger::set_log_level(level)
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("{}", e)))
+}
+
+#[pyfunction]
+#[allow(unused)]
+#[pyo3(signature = (path, out_path))]
+pub fn export_cache_markdown(path: &str, out_path: &str) -> PyResult<String> {
+    let cacher = Cacher::new(path);
+    cacher
+        .export_markdown(out_path)
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("{}", e)))
+}
+
+#[pyfunction]
+#[allow(unused)]
+#[pyo3(signature = (path))]
+pub fn validate_cache(path: &str) -> PyResult<Vec<usize>> {
+    let cacher = Cacher::new(path);
+    Ok(cacher.validate_history())
+}
+
+#[pyfunction]
+#[allow(unused)]
+#[pyo3(signature = (path, n))]
+pub fn drop_last_cache(path: &str, n: usize) -> PyResult<()> {
+    let cacher = Cacher::new(path);
+    cacher
+        .drop_last(n)
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("{}", e)))
+}
+
+#[pyfunction]
+#[allow(unused)]
+#[pyo3(signature = (path, start, end))]
+pub fn drop_range_cache(path: &str, start: usize, end: usize) -> PyResult<()> {
+    let cacher = Cacher::new(path);
+    cacher
+        .drop_range(start, end)
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("{}", e)))
+}
+
 #[pyfunction]
 #[allow(unused)]
 #[pyo3(signature = (path))]
@@ -204,6 +614,25 @@ pub fn drop_all(path: &str) -> PyResult<()> {
     Ok(())
 }
 
+#[pyfunction]
+#[allow(unused)]
+#[pyo3(signature = (name, description, schema_json, strict=true))]
+pub fn register_tool(name: &str, description: &str, schema_json: &str, strict: bool) -> PyResult<()> {
+    let parameters = serde_json::from_str(schema_json)
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("{}", e)))?;
+    TOOL_REGISTRY
+        .register(name, description, parameters, strict)
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("{}", e)))
+}
+
+#[pyfunction]
+#[allow(unused)]
+#[pyo3(signature = (name))]
+pub fn unregister_tool(name: &str) -> PyResult<()> {
+    TOOL_REGISTRY.unregister(name);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -217,5 +646,46 @@ mod tests {
         is_send::<PythonWorker>();
         is_send::<PyObject>();
     }
+
+    // Needs an embedded interpreter, run with `cargo test --no-default-features`.
+    #[cfg(not(feature = "extension-module"))]
+    #[test]
+    fn test_async_function_handler_returns_result_or_error() {
+        pyo3::prepare_freethreaded_python();
+        let (succeeding, raising) = Python::with_gil(|py| {
+            let module = PyModule::from_code(
+                py,
+                c"async def succeeding(name, args):\n    return name + ':' + args\n\nasync def raising(name, args):\n    raise ValueError('tool broke')\n",
+                c"tools.py",
+                c"tools",
+            )
+            .unwrap();
+            (
+                module
+                    .getattr("succeeding")
+                    .unwrap()
+                    .unbind(),
+                module
+                    .getattr("raising")
+                    .unwrap()
+                    .unbind(),
+            )
+        });
+
+        let handler = function_handler_for(succeeding);
+        assert_eq!(
+            handler(("echo".to_string(), "{}".to_string())),
+            "echo:{}"
+        );
+
+        let handler = function_handler_for(raising);
+        let result = thread::spawn(move || handler(("echo".to_string(), "{}".to_string())))
+            .join()
+            .unwrap();
+        assert_eq!(
+            result,
+            "Async function handler failed: ValueError: tool broke"
+        );
+    }
     // This code tested on Python's side
 }
@@ -1,15 +1,39 @@
-use std::sync::{Arc, atomic::AtomicBool};
+use std::{
+    collections::HashMap,
+    sync::{Arc, atomic::AtomicBool},
+};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use futures_util::future::join_all;
+use regex::{Regex, RegexBuilder};
+use serde_json::{Value, json};
 use tokio::sync::{Mutex, mpsc::Sender};
+use walkdir::WalkDir;
 
 use crate::{
     cacher::Cacher,
+    error::LlmError,
     network_client::NetworkClient,
-    openai_network_types::ToolCall,
-    types::{AssistantSettings, CacheEntry, InputKind, SublimeInputContent},
+    openai_network_types::{AssistantMessage, ToolCall},
+    stream_handler::StreamEvent,
+    tools_definition::{FunctionName, MAX_SEARCH_MATCHES, SearchInFilesArguments},
+    types::{
+        ApiType,
+        AssistantSettings,
+        CacheEntry,
+        DEFAULT_MAX_TOOL_CALL_DEPTH,
+        InputKind,
+        SublimeInputContent,
+    },
 };
 
+/// What a function handler returns to leave a built-in tool, like `fetch_url`, to the runner.
+const BUILTIN_HANDLER: &str = "__builtin__";
+
+/// Follow-up prompt for an answer whose stream stalled halfway.
+const RESUME_PROMPT: &str =
+    "Your previous answer was cut off. Continue it exactly where it stopped, without repeating anything.";
+
 #[allow(unused, dead_code)]
 #[derive(Clone, Debug)]
 pub struct LlmRunner;
@@ -21,35 +45,98 @@ impl LlmRunner {
         cacher: Arc<Mutex<Cacher>>,
         contents: Vec<SublimeInputContent>,
         assistant_settings: AssistantSettings,
-        sender: Arc<Mutex<Sender<String>>>,
+        sender: Arc<Mutex<Sender<StreamEvent>>>,
         function_handler: Arc<dyn Fn((String, String)) -> String + Send + Sync + 'static>,
         cancel_flag: Arc<AtomicBool>,
         store: bool,
-    ) -> Result<()> {
-        let cache_entries: Vec<CacheEntry> = cacher
-            .lock()
-            .await
-            .read_entries()?;
+        depth: usize,
+    ) -> Result<Vec<String>, LlmError> {
+        let cache_entries: Vec<CacheEntry> = {
+            let mut cacher = cacher.lock().await;
+            cacher.max_age_secs = assistant_settings.cache_ttl;
+            cacher.max_history_entries = assistant_settings.max_history_entries;
+            cacher.trim_strategy = assistant_settings
+                .trim_strategy
+                .unwrap_or_default();
+            cacher
+                .read_entries()
+                .map_err(LlmError::cache)?
+        };
 
-        if store {
+        let cache_entry = |entry: CacheEntry| {
+            CacheEntry {
+                user: assistant_settings
+                    .user
+                    .clone(),
+                ..entry
+            }
+        };
+
+        if store && !assistant_settings.dry_run {
             for entry in &contents {
                 if entry.input_kind != InputKind::Sheet {
                     cacher
                         .lock()
                         .await
-                        .write_entry(&CacheEntry::from(entry.clone()))
+                        .write_entry(&cache_entry(CacheEntry::from(
+                            entry.clone(),
+                        )))
                         .ok();
                 }
             }
         }
 
-        let payload = provider.prepare_payload(
+        let previous_response_id = if assistant_settings.api_type == ApiType::OpenAiResponses {
+            cacher
+                .lock()
+                .await
+                .read_response_id()
+        } else {
+            None
+        };
+
+        // The conversation a stalled answer gets resumed against.
+        let resume_history = assistant_settings
+            .resume_on_stall
+            .unwrap_or(false)
+            .then(|| cache_entries.clone());
+
+        let payload = match previous_response_id {
+            Some(previous_response_id) => {
+                provider.prepare_chained_payload(
+                    assistant_settings.clone(),
+                    previous_response_id,
+                    contents.clone(),
+                )?
+            }
+            None => {
+                provider.prepare_payload(
+                    assistant_settings.clone(),
+                    cache_entries,
+                    contents.clone(),
+                )?
+            }
+        };
+
+        let request = provider.prepare_request(
             assistant_settings.clone(),
-            cache_entries,
-            contents.clone(),
+            payload.clone(),
         )?;
 
-        let request = provider.prepare_request(assistant_settings.clone(), payload)?;
+        if assistant_settings.dry_run {
+            log::debug!(
+                "Dry run, {} {} not sent",
+                request.method(),
+                request.url()
+            );
+            sender
+                .lock()
+                .await
+                .send(payload.clone().into())
+                .await
+                .ok();
+            return Ok(vec![payload]);
+        }
 
         // TODO: To make type to cast conditional to support various of protocols
         let result = provider
@@ -61,23 +148,122 @@ impl LlmRunner {
             )
             .await;
 
+        let result = match (result, resume_history) {
+            (Ok(message), Some(history)) if message.stalled && message.tool_calls.is_none() => {
+                Ok(LlmRunner::resume_stalled(
+                    &provider,
+                    &assistant_settings,
+                    history,
+                    &contents,
+                    message,
+                    Arc::clone(&sender),
+                    Arc::clone(&cancel_flag),
+                )
+                .await)
+            }
+            (result, _) => result,
+        };
+
+        // A clean stop goes without saying, any other reason tells why the answer ends where it does.
+        if let Some(finish_reason) = result
+            .as_ref()
+            .ok()
+            .filter(|message| message.tool_calls.is_none())
+            .and_then(|message| {
+                message
+                    .finish_reason
+                    .as_deref()
+            })
+            .filter(|finish_reason| *finish_reason != "stop")
+        {
+            sender
+                .lock()
+                .await
+                .send(StreamEvent::Finished {
+                    finish_reason: finish_reason.to_string(),
+                })
+                .await
+                .ok();
+        }
+
+        if let Some(usage) = result
+            .as_ref()
+            .ok()
+            .and_then(|message| message.usage.as_ref())
+        {
+            cacher
+                .lock()
+                .await
+                .append_tokens_count(usage)
+                .ok();
+        }
+
+        if store {
+            // A turn made through another api is missing from the server side thread, so stop chaining.
+            let response_id = result
+                .as_ref()
+                .ok()
+                .and_then(|message| message.response_id.as_deref())
+                .filter(|_| assistant_settings.api_type == ApiType::OpenAiResponses);
+            let cacher = cacher.lock().await;
+            match response_id {
+                Some(response_id) => {
+                    cacher
+                        .write_response_id(response_id)
+                        .ok()
+                }
+                None if result.is_ok() => cacher.drop_response_id().ok(),
+                None => None,
+            };
+        }
+
         if let Some(tool_calls) = result
             .as_ref()
             .ok()
             .and_then(|message| message.tool_calls.clone())
         {
+            for tool_call in &tool_calls {
+                sender
+                    .lock()
+                    .await
+                    .send(StreamEvent::FunctionCallEnd {
+                        name: tool_call
+                            .function
+                            .name
+                            .clone(),
+                        arguments: LlmRunner::compact_arguments(&tool_call.function.arguments),
+                    })
+                    .await
+                    .ok();
+            }
+
+            // Some models keep calling tools forever, the dangling call is left out of the history.
+            if depth
+                >= assistant_settings
+                    .max_tool_call_depth
+                    .unwrap_or(DEFAULT_MAX_TOOL_CALL_DEPTH)
+            {
+                return Err(LlmError::ToolDepthExceeded);
+            }
+
             if let Ok(ref message) = result {
                 cacher
                     .lock()
                     .await
-                    .write_entry(&CacheEntry::from(message.clone()))
+                    .write_entry(&cache_entry(CacheEntry::from(
+                        message.clone(),
+                    )))
                     .ok();
             }
 
             let content = LlmRunner::handle_function_call(
+                &provider,
+                &assistant_settings,
+                &cacher,
                 tool_calls,
                 Arc::clone(&function_handler),
-            );
+            )
+            .await;
 
             Box::pin(Self::execute(
                 provider,
@@ -88,40 +274,241 @@ impl LlmRunner {
                 function_handler,
                 cancel_flag,
                 true,
+                depth + 1,
             ))
             .await
         } else if store {
-            cacher
-                .lock()
-                .await
-                .write_entry(&CacheEntry::from(result?))
+            let choices = result?.into_choices();
+            let cacher = cacher.lock().await;
+            for choice in &choices {
+                cacher
+                    .write_entry(&cache_entry(CacheEntry::from(
+                        choice.clone(),
+                    )))
+                    .map_err(LlmError::cache)?;
+            }
+            Ok(Self::choice_contents(choices))
         } else {
-            result.map(|_| ())
+            result
+                .map(|message| Self::choice_contents(message.into_choices()))
+                .map_err(LlmError::from)
         }
     }
 
-    fn handle_function_call(
+    /// The text of every choice, an alternative with no text stays in its place as an empty one.
+    fn choice_contents(choices: Vec<AssistantMessage>) -> Vec<String> {
+        choices
+            .into_iter()
+            .map(|choice| {
+                choice
+                    .content
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Asks for the rest of an answer whose stream stalled, replaying the partial text as the
+    /// assistant's own turn, and merges both parts into the one message that gets cached.
+    ///
+    /// Falls back to the partial answer when the follow-up request fails.
+    async fn resume_stalled(
+        provider: &NetworkClient,
+        assistant_settings: &AssistantSettings,
+        mut history: Vec<CacheEntry>,
+        contents: &[SublimeInputContent],
+        partial: AssistantMessage,
+        sender: Arc<Mutex<Sender<StreamEvent>>>,
+        cancel_flag: Arc<AtomicBool>,
+    ) -> AssistantMessage {
+        history.extend(
+            contents
+                .iter()
+                .cloned()
+                .map(CacheEntry::from),
+        );
+        history.push(CacheEntry::from(partial.clone()));
+        let settings = AssistantSettings {
+            stream: false,
+            ..assistant_settings.clone()
+        };
+        let prompt = SublimeInputContent {
+            content: Some(RESUME_PROMPT.to_string()),
+            path: None,
+            scope: None,
+            input_kind: InputKind::Command,
+            tool_id: None,
+        };
+
+        let continuation = match provider
+            .prepare_payload(settings.clone(), history, vec![prompt])
+            .and_then(|payload| provider.prepare_request(settings.clone(), payload))
+        {
+            Ok(request) => {
+                provider
+                    .execute_request(
+                        settings,
+                        request,
+                        Arc::clone(&sender),
+                        cancel_flag,
+                    )
+                    .await
+            }
+            Err(error) => Err(error),
+        };
+
+        match continuation {
+            Ok(continuation) => {
+                let usage = match (partial.usage, continuation.usage) {
+                    (Some(mut usage), Some(other)) => {
+                        usage.accumulate(&other);
+                        Some(usage)
+                    }
+                    (usage, other) => usage.or(other),
+                };
+                AssistantMessage {
+                    content: Some(format!(
+                        "{}{}",
+                        partial
+                            .content
+                            .unwrap_or_default(),
+                        continuation
+                            .content
+                            .unwrap_or_default()
+                    )),
+                    usage,
+                    stalled: false,
+                    finish_reason: continuation.finish_reason,
+                    ..partial
+                }
+            }
+            Err(error) => {
+                log::warn!(
+                    "Resuming the stalled answer failed: {}",
+                    error
+                );
+                sender
+                    .lock()
+                    .await
+                    .send(StreamEvent::Stalled)
+                    .await
+                    .ok();
+                partial
+            }
+        }
+    }
+
+    /// The arguments as compact JSON, `{}` when the model sent none, untouched when they don't parse.
+    fn compact_arguments(arguments: &str) -> String {
+        match arguments.trim() {
+            "" => "{}".to_string(),
+            arguments => {
+                serde_json::from_str::<serde_json::Value>(arguments)
+                    .map(|value| value.to_string())
+                    .unwrap_or_else(|_| arguments.to_string())
+            }
+        }
+    }
+
+    async fn handle_function_call(
+        provider: &NetworkClient,
+        assistant_settings: &AssistantSettings,
+        cacher: &Arc<Mutex<Cacher>>,
         tool_calls: Vec<ToolCall>,
         function_handler: Arc<dyn Fn((String, String)) -> String + Send + Sync + 'static>,
     ) -> Vec<SublimeInputContent> {
-        tool_calls
-            .iter()
-            .map(|tool_call| {
+        // Concurrency is opt-in, results come back in the order the model made the calls either way.
+        if assistant_settings.parallel_tool_calls == Some(true) {
+            return join_all(
+                tool_calls
+                    .into_iter()
+                    .map(|tool_call| {
+                        LlmRunner::pick_function(
+                            provider,
+                            assistant_settings,
+                            cacher,
+                            tool_call,
+                            Arc::clone(&function_handler),
+                        )
+                    }),
+            )
+            .await;
+        }
+
+        let mut content = Vec::with_capacity(tool_calls.len());
+        for tool_call in tool_calls {
+            content.push(
                 LlmRunner::pick_function(
-                    tool_call.clone(),
+                    provider,
+                    assistant_settings,
+                    cacher,
+                    tool_call,
                     Arc::clone(&function_handler),
                 )
-            })
-            .collect::<Vec<_>>()
+                .await,
+            );
+        }
+        content
     }
 
-    fn pick_function(
+    async fn pick_function(
+        provider: &NetworkClient,
+        assistant_settings: &AssistantSettings,
+        cacher: &Arc<Mutex<Cacher>>,
         tool: ToolCall,
         function_handler: Arc<dyn Fn((String, String)) -> String + Send + Sync + 'static>,
     ) -> SublimeInputContent {
         let name = tool.function.name.clone();
         let args = tool.function.arguments;
-        let response = function_handler((name, args));
+        // Image generation is served by the runner itself, a refusal goes back to the model as the result.
+        let response = if name == FunctionName::GenerateImage.to_string() {
+            LlmRunner::generate_image(
+                provider,
+                assistant_settings,
+                cacher,
+                &tool.id,
+                &args,
+            )
+            .await
+            .unwrap_or_else(|error| format!("Image generation failed: {}", error))
+        } else {
+            // A host handler may take its time, keep it off the runtime's worker threads.
+            let handler_args = args.clone();
+            let response = tokio::task::spawn_blocking(move || function_handler((name, handler_args)))
+                .await
+                .unwrap_or_else(|error| format!("Function handler failed: {}", error));
+
+            // The host hands the tools the runner can serve itself back to it.
+            match tool
+                .function
+                .name
+                .parse::<FunctionName>()
+            {
+                Ok(FunctionName::FetchUrl) if response == BUILTIN_HANDLER => {
+                    provider
+                        .fetch_url(&args)
+                        .await
+                        .unwrap_or_else(|error| format!("Fetching the url failed: {}", error))
+                }
+                Ok(FunctionName::SearchInFiles) if response == BUILTIN_HANDLER => {
+                    tokio::task::spawn_blocking(move || LlmRunner::search_in_files(&args))
+                        .await
+                        .map_err(anyhow::Error::from)
+                        .and_then(|result| result)
+                        .unwrap_or_else(|error| format!("Searching in files failed: {}", error))
+                }
+                _ => response,
+            }
+        };
+        let response = match LlmRunner::validate_tool_result(
+            assistant_settings
+                .tool_schemas
+                .as_ref(),
+            &tool.function.name,
+            &response,
+        ) {
+            Ok(()) => response,
+            Err(error) => error.to_string(),
+        };
 
         SublimeInputContent {
             content: Some(response),
@@ -131,11 +518,162 @@ impl LlmRunner {
             scope: None,
         }
     }
+
+    /// Checks a tool result against the schema registered for the tool in `tool_schemas`,
+    /// a tool without one passes as is.
+    fn validate_tool_result(
+        tool_schemas: Option<&HashMap<String, Value>>,
+        name: &str,
+        result: &str,
+    ) -> Result<()> {
+        let Some(schema) = tool_schemas.and_then(|schemas| schemas.get(name)) else {
+            return Ok(());
+        };
+        let validator = jsonschema::validator_for(schema).map_err(|error| {
+            anyhow!(
+                "Schema of `{}` tool is invalid: {}",
+                name,
+                error
+            )
+        })?;
+        let result: Value = serde_json::from_str(result).map_err(|error| {
+            anyhow!(
+                "Result of `{}` tool is not JSON: {}",
+                name,
+                error
+            )
+        })?;
+
+        let errors: Vec<String> = validator
+            .iter_errors(&result)
+            .map(|error| format!("{} at `{}`", error, error.instance_path))
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Result of `{}` tool doesn't match its schema: {}",
+                name,
+                errors.join("; ")
+            ))
+        }
+    }
+
+    /// Greps the text files under `directory_path` for the `search_in_files` tool.
+    ///
+    /// Hidden files and directories are skipped, so are the files that aren't UTF-8.
+    fn search_in_files(args: &str) -> Result<String> {
+        let arguments: SearchInFilesArguments = serde_json::from_str(args)?;
+        let pattern = LlmRunner::search_pattern(
+            &arguments.pattern,
+            arguments.case_sensitive,
+        )?;
+
+        let files = WalkDir::new(&arguments.directory_path)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.depth() == 0
+                    || !entry
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with('.')
+            })
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file());
+
+        let mut matches = Vec::new();
+        let mut truncated = false;
+        'files: for entry in files {
+            let Ok(text) = std::fs::read_to_string(entry.path()) else {
+                continue;
+            };
+            for (index, line) in text.lines().enumerate() {
+                if !pattern.is_match(line) {
+                    continue;
+                }
+                if matches.len() == MAX_SEARCH_MATCHES {
+                    truncated = true;
+                    break 'files;
+                }
+                matches.push(json!({
+                    "file": entry.path().to_string_lossy(),
+                    "line": index + 1,
+                    "content": line,
+                }));
+            }
+        }
+
+        Ok(json!({"matches": matches, "truncated": truncated}).to_string())
+    }
+
+    /// A pattern using regex only syntax, an escape, anchor, class, repetition or alternation,
+    /// is a regular expression. Otherwise `*` and `?` make it a glob and the rest matches literally.
+    fn search_pattern(pattern: &str, case_sensitive: bool) -> Result<Regex> {
+        let source = if pattern.contains(['\\', '^', '$', '[', '{', '+', '|']) {
+            pattern.to_string()
+        } else if pattern.contains(['*', '?']) {
+            pattern
+                .chars()
+                .map(|char| {
+                    match char {
+                        '*' => ".*".to_string(),
+                        '?' => ".".to_string(),
+                        char => regex::escape(&char.to_string()),
+                    }
+                })
+                .collect()
+        } else {
+            regex::escape(pattern)
+        };
+
+        Ok(RegexBuilder::new(&source)
+            .case_insensitive(!case_sensitive)
+            .build()?)
+    }
+
+    /// Saves the generated image under the cacher path and returns the file path.
+    async fn generate_image(
+        provider: &NetworkClient,
+        assistant_settings: &AssistantSettings,
+        cacher: &Arc<Mutex<Cacher>>,
+        tool_id: &str,
+        args: &str,
+    ) -> Result<String> {
+        let image = provider
+            .generate_image(assistant_settings, args)
+            .await?;
+
+        let images_dir = cacher
+            .lock()
+            .await
+            .images_dir();
+        std::fs::create_dir_all(&images_dir)?;
+        let file_name: String = tool_id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        let path = images_dir.join(format!("{}.png", file_name));
+        std::fs::write(&path, image)?;
+
+        Ok(path
+            .to_string_lossy()
+            .into_owned())
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
     use super::*;
+    use crate::error::LlmErrorKind;
 
     #[test]
     fn test_is_sync_and_send() {
@@ -145,4 +683,727 @@ mod tests {
         is_sync::<LlmRunner>();
         is_send::<LlmRunner>();
     }
+
+    async fn execute_greeting(cacher: Cacher, url: String) -> Result<Vec<String>, LlmError> {
+        let settings = AssistantSettings {
+            api_type: ApiType::OpenAi,
+            url,
+            stream: false,
+            ..Default::default()
+        };
+        let (tx, _rx) = mpsc::channel(10);
+        LlmRunner::execute(
+            NetworkClient::new(None, 1, None, None).unwrap(),
+            Arc::new(Mutex::new(cacher)),
+            vec![SublimeInputContent {
+                content: Some("Greet me".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::ViewSelection,
+                tool_id: None,
+            }],
+            settings,
+            Arc::new(Mutex::new(tx)),
+            Arc::new(|_| String::new()),
+            Arc::new(AtomicBool::new(false)),
+            true,
+            0,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_streams_payload_without_sending_it() {
+        let mock_server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::any())
+            .respond_with(wiremock::ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let settings = AssistantSettings {
+            api_type: ApiType::OpenAi,
+            url: mock_server.uri(),
+            chat_model: "gpt-4o".to_string(),
+            dry_run: true,
+            ..Default::default()
+        };
+        let cacher = Arc::new(Mutex::new(Cacher::in_memory()));
+        let (tx, mut rx) = mpsc::channel(10);
+        let result = LlmRunner::execute(
+            NetworkClient::new(None, 1, None, None).unwrap(),
+            Arc::clone(&cacher),
+            vec![SublimeInputContent {
+                content: Some("Greet me".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::ViewSelection,
+                tool_id: None,
+            }],
+            settings,
+            Arc::new(Mutex::new(tx)),
+            Arc::new(|_| String::new()),
+            Arc::new(AtomicBool::new(false)),
+            true,
+            0,
+        )
+        .await
+        .unwrap();
+
+        let streamed = rx.recv().await.unwrap();
+        assert_eq!(rx.recv().await, None);
+        let payload: serde_json::Value = serde_json::from_str(streamed.text().unwrap()).unwrap();
+        assert_eq!(payload["model"], "gpt-4o");
+        assert!(
+            payload
+                .to_string()
+                .contains("Greet me")
+        );
+        assert_eq!(
+            result,
+            vec![
+                streamed
+                    .text()
+                    .unwrap()
+                    .to_string()
+            ]
+        );
+        assert!(
+            cacher
+                .lock()
+                .await
+                .read_entries::<CacheEntry>()
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            mock_server
+                .received_requests()
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_reports_unreadable_history_as_cache_error() {
+        let mock_server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::method("POST"))
+            .respond_with(
+                wiremock::ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "id": "chatcmpl-1",
+                    "object": "chat.completion",
+                    "created": 1,
+                    "model": "gpt-4o",
+                    "choices": [{
+                        "index": 0,
+                        "message": { "role": "assistant", "content": "Hi" },
+                        "finish_reason": "stop"
+                    }]
+                })),
+            )
+            .mount(&mock_server)
+            .await;
+        // The answer would be cached in a directory that is a file.
+        let not_a_dir = tempfile::NamedTempFile::new().unwrap();
+        let cacher = Cacher::new(
+            not_a_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        );
+
+        let error = execute_greeting(cacher, mock_server.uri())
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(error, LlmError::Cache(_)),
+            "Expected a cache error, got: {:?}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_reports_refused_connection_as_network_error() {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!(
+            "http://{}/v1/chat/completions",
+            listener.local_addr().unwrap()
+        );
+        drop(listener);
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+
+        let error = execute_greeting(
+            Cacher::new(
+                tmp_dir
+                    .path()
+                    .to_str()
+                    .unwrap(),
+            ),
+            url,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(
+            matches!(error, LlmError::Network(_)),
+            "Expected a network error, got: {:?}",
+            error
+        );
+        assert_eq!(error.kind(), LlmErrorKind::Network);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_stream_is_resumed_into_one_cache_entry() {
+        // First connection streams a chunk and stalls, the second answers the resume request.
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!(
+            "http://{}",
+            listener.local_addr().unwrap()
+        );
+        let server = tokio::spawn(async move {
+            let (mut stalled, _) = listener
+                .accept()
+                .await
+                .unwrap();
+            read_request(&mut stalled).await;
+            let chunk = "data: {\"model\":\"some_model\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"\
+                         assistant\",\"content\":\"Hello\"}}]}\n\n";
+            stalled
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\ntransfer-encoding: \
+                         chunked\r\n\r\n{:x}\r\n{chunk}\r\n",
+                        chunk.len()
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+
+            let (mut resumed, _) = listener
+                .accept()
+                .await
+                .unwrap();
+            let body = read_request(&mut resumed).await;
+            let response = serde_json::json!({
+                "model": "some_model",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": " world"},
+                    "finish_reason": "stop"
+                }]
+            })
+            .to_string();
+            resumed
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: \
+                         {}\r\n\r\n{response}",
+                        response.len()
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            drop(stalled);
+            body
+        });
+
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let cacher = Arc::new(Mutex::new(Cacher::new(
+            tmp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        )));
+        let settings = AssistantSettings {
+            api_type: ApiType::OpenAi,
+            url,
+            stream: true,
+            timeout: 1,
+            resume_on_stall: Some(true),
+            ..Default::default()
+        };
+
+        let (tx, mut rx) = mpsc::channel(10);
+        LlmRunner::execute(
+            NetworkClient::new(None, 1, None, None).unwrap(),
+            Arc::clone(&cacher),
+            vec![SublimeInputContent {
+                content: Some("Greet me".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::ViewSelection,
+                tool_id: None,
+            }],
+            settings,
+            Arc::new(Mutex::new(tx)),
+            Arc::new(|_| String::new()),
+            Arc::new(AtomicBool::new(false)),
+            true,
+            0,
+        )
+        .await
+        .unwrap();
+
+        let mut output = vec![];
+        while let Ok(chunk) = rx.try_recv() {
+            output.push(chunk);
+        }
+        assert_eq!(
+            output,
+            vec![
+                StreamEvent::from("Hello"),
+                StreamEvent::from(" world")
+            ]
+        );
+
+        let resume_request: serde_json::Value = serde_json::from_slice(&server.await.unwrap()).unwrap();
+        let messages = resume_request["messages"]
+            .as_array()
+            .unwrap();
+        assert_eq!(
+            messages[messages.len() - 2]["role"],
+            "assistant"
+        );
+        assert_eq!(
+            messages[messages.len() - 1]["content"][0]["text"],
+            RESUME_PROMPT
+        );
+
+        let entries = cacher
+            .lock()
+            .await
+            .read_entries::<CacheEntry>()
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[1].content.as_deref(),
+            Some("Hello world")
+        );
+    }
+
+    /// Runs four tools sleeping 100, 80, 60 and 40 ms, returns the time taken and the result order.
+    async fn run_slow_tool_calls(settings: &AssistantSettings) -> (Duration, Vec<String>) {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let cacher = Arc::new(Mutex::new(Cacher::new(
+            tmp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        )));
+        let tool_calls: Vec<ToolCall> = (0 .. 4)
+            .map(|index| {
+                ToolCall {
+                    id: format!("call_{index}"),
+                    r#type: "function".to_string(),
+                    thought_signature: None,
+                    function: crate::openai_network_types::Function {
+                        name: "slow_tool".to_string(),
+                        arguments: format!("{{\"delay_ms\": {}}}", 100 - index * 20),
+                    },
+                }
+            })
+            .collect();
+        let function_handler = Arc::new(|(_, args): (String, String)| {
+            let args: serde_json::Value = serde_json::from_str(&args).unwrap();
+            std::thread::sleep(Duration::from_millis(
+                args["delay_ms"]
+                    .as_u64()
+                    .unwrap(),
+            ));
+            args.to_string()
+        });
+
+        let started = std::time::Instant::now();
+        let content = LlmRunner::handle_function_call(
+            &NetworkClient::new(None, 10, None, None).unwrap(),
+            settings,
+            &cacher,
+            tool_calls,
+            function_handler,
+        )
+        .await;
+        let elapsed = started.elapsed();
+
+        let tool_ids = content
+            .into_iter()
+            .map(|input| input.tool_id.unwrap())
+            .collect();
+        (elapsed, tool_ids)
+    }
+
+    #[tokio::test]
+    async fn test_parallel_tool_calls_run_concurrently_in_call_order() {
+        let settings = AssistantSettings {
+            parallel_tool_calls: Some(true),
+            ..Default::default()
+        };
+
+        let (elapsed, tool_ids) = run_slow_tool_calls(&settings).await;
+
+        assert!(
+            elapsed < Duration::from_millis(250),
+            "tool calls took {elapsed:?}"
+        );
+        assert_eq!(
+            tool_ids,
+            vec!["call_0", "call_1", "call_2", "call_3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls_run_serially_when_parallel_tool_calls_is_unset() {
+        let (elapsed, tool_ids) = run_slow_tool_calls(&AssistantSettings::default()).await;
+
+        assert!(
+            elapsed >= Duration::from_millis(280),
+            "tool calls took {elapsed:?}"
+        );
+        assert_eq!(
+            tool_ids,
+            vec!["call_0", "call_1", "call_2", "call_3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_truncated_answer_reports_and_caches_finish_reason() {
+        let mock_server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::method("POST"))
+            .respond_with(
+                wiremock::ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "model": "some_model",
+                    "choices": [{
+                        "index": 0,
+                        "message": {"role": "assistant", "content": "Once upon"},
+                        "finish_reason": "length"
+                    }]
+                })),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let cacher = Arc::new(Mutex::new(Cacher::new(
+            tmp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        )));
+        let settings = AssistantSettings {
+            api_type: ApiType::OpenAi,
+            url: mock_server.uri(),
+            stream: false,
+            ..Default::default()
+        };
+
+        let (tx, mut rx) = mpsc::channel(10);
+        LlmRunner::execute(
+            NetworkClient::new(None, 10, None, None).unwrap(),
+            Arc::clone(&cacher),
+            vec![SublimeInputContent {
+                content: Some("Tell a story".to_string()),
+                path: None,
+                scope: None,
+                input_kind: InputKind::ViewSelection,
+                tool_id: None,
+            }],
+            settings,
+            Arc::new(Mutex::new(tx)),
+            Arc::new(|_| String::new()),
+            Arc::new(AtomicBool::new(false)),
+            true,
+            0,
+        )
+        .await
+        .unwrap();
+
+        let mut output = vec![];
+        while let Ok(chunk) = rx.try_recv() {
+            output.push(chunk);
+        }
+        assert_eq!(
+            output.last(),
+            Some(&StreamEvent::Finished {
+                finish_reason: "length".to_string()
+            })
+        );
+
+        let entries = cacher
+            .lock()
+            .await
+            .read_entries::<CacheEntry>()
+            .unwrap();
+        assert_eq!(
+            entries[1]
+                .finish_reason
+                .as_deref(),
+            Some("length")
+        );
+    }
+
+    fn weather_schemas() -> HashMap<String, Value> {
+        HashMap::from([(
+            "get_weather".to_string(),
+            serde_json::json!({
+                "type": "object",
+                "properties": {"temperature": {"type": "number"}},
+                "required": ["temperature"]
+            }),
+        )])
+    }
+
+    #[test]
+    fn test_validate_tool_result_accepts_matching_result() {
+        let schemas = weather_schemas();
+
+        assert!(
+            LlmRunner::validate_tool_result(
+                Some(&schemas),
+                "get_weather",
+                r#"{"temperature": 21.5}"#
+            )
+            .is_ok()
+        );
+        // Tools without a schema aren't checked at all.
+        assert!(
+            LlmRunner::validate_tool_result(
+                Some(&schemas),
+                "read_region_content",
+                "plain text"
+            )
+            .is_ok()
+        );
+        assert!(LlmRunner::validate_tool_result(None, "get_weather", "plain text").is_ok());
+    }
+
+    #[test]
+    fn test_validate_tool_result_describes_mismatch() {
+        let schemas = weather_schemas();
+
+        let error = LlmRunner::validate_tool_result(
+            Some(&schemas),
+            "get_weather",
+            r#"{"temperature": "warm"}"#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.starts_with("Result of `get_weather` tool doesn't match its schema:"));
+        assert!(
+            error.contains("/temperature"),
+            "{error}"
+        );
+
+        let error = LlmRunner::validate_tool_result(Some(&schemas), "get_weather", "sunny")
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("Result of `get_weather` tool is not JSON:"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_tool_result_is_replaced_with_error() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let cacher = Arc::new(Mutex::new(Cacher::new(
+            tmp_dir
+                .path()
+                .to_str()
+                .unwrap(),
+        )));
+        let settings = AssistantSettings {
+            tool_schemas: Some(weather_schemas()),
+            ..Default::default()
+        };
+
+        let content = LlmRunner::pick_function(
+            &NetworkClient::new(None, 10, None, None).unwrap(),
+            &settings,
+            &cacher,
+            ToolCall {
+                id: "call_0".to_string(),
+                r#type: "function".to_string(),
+                thought_signature: None,
+                function: crate::openai_network_types::Function {
+                    name: "get_weather".to_string(),
+                    arguments: "{}".to_string(),
+                },
+            },
+            Arc::new(|_| r#"{"humidity": 40}"#.to_string()),
+        )
+        .await;
+
+        assert_eq!(
+            content.tool_id.as_deref(),
+            Some("call_0")
+        );
+        assert!(
+            content
+                .content
+                .unwrap()
+                .starts_with("Result of `get_weather` tool doesn't match its schema:")
+        );
+    }
+
+    fn search(directory: &std::path::Path, pattern: &str, case_sensitive: bool) -> Value {
+        let args = json!({
+            "pattern": pattern,
+            "directory_path": directory.to_str().unwrap(),
+            "case_sensitive": case_sensitive,
+        });
+        serde_json::from_str(&LlmRunner::search_in_files(&args.to_string()).unwrap()).unwrap()
+    }
+
+    fn matched_lines(result: &Value) -> Vec<(String, u64)> {
+        result["matches"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|found| {
+                let file = std::path::Path::new(
+                    found["file"]
+                        .as_str()
+                        .unwrap(),
+                );
+                (
+                    file.file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned(),
+                    found["line"]
+                        .as_u64()
+                        .unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_search_in_files_matches_literal_glob_and_regex() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let src = tmp_dir.path().join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir_all(tmp_dir.path().join(".git")).unwrap();
+        std::fs::write(
+            src.join("lib.rs"),
+            "fn main() {}\nfn parse_args(args: &[String]) {}\n// TODO: parse (more)\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tmp_dir
+                .path()
+                .join("notes.txt"),
+            "Parse later\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tmp_dir
+                .path()
+                .join(".git")
+                .join("config"),
+            "parse\n",
+        )
+        .unwrap();
+
+        let result = search(tmp_dir.path(), "parse", true);
+        assert_eq!(
+            matched_lines(&result),
+            vec![
+                ("lib.rs".to_string(), 2),
+                ("lib.rs".to_string(), 3)
+            ]
+        );
+        assert_eq!(
+            result["matches"][1]["content"],
+            "// TODO: parse (more)"
+        );
+        assert_eq!(result["truncated"], false);
+
+        assert_eq!(
+            matched_lines(&search(tmp_dir.path(), "PARSE", false)),
+            vec![
+                ("notes.txt".to_string(), 1),
+                ("lib.rs".to_string(), 2),
+                ("lib.rs".to_string(), 3)
+            ]
+        );
+        assert_eq!(
+            matched_lines(&search(
+                tmp_dir.path(),
+                "fn *(args*)",
+                true
+            )),
+            vec![("lib.rs".to_string(), 2)]
+        );
+        assert_eq!(
+            matched_lines(&search(
+                tmp_dir.path(),
+                r"^fn \w+\(\)",
+                true
+            )),
+            vec![("lib.rs".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_search_in_files_caps_matches() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            tmp_dir
+                .path()
+                .join("many.txt"),
+            "needle\n".repeat(MAX_SEARCH_MATCHES + 5),
+        )
+        .unwrap();
+
+        let result = search(tmp_dir.path(), "needle", true);
+        assert_eq!(
+            result["matches"]
+                .as_array()
+                .unwrap()
+                .len(),
+            MAX_SEARCH_MATCHES
+        );
+        assert_eq!(result["truncated"], true);
+    }
+
+    /// Reads one HTTP request off `stream` and returns its body.
+    async fn read_request(stream: &mut TcpStream) -> Vec<u8> {
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let read = tokio::time::timeout(
+                Duration::from_secs(5),
+                stream.read(&mut buffer),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            request.extend_from_slice(&buffer[.. read]);
+            let Some(head_end) = request
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+            else {
+                continue;
+            };
+            let head = String::from_utf8_lossy(&request[.. head_end]).to_lowercase();
+            let length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .and_then(|value| {
+                    value
+                        .trim()
+                        .parse::<usize>()
+                        .ok()
+                })
+                .unwrap_or(0);
+            if request.len() >= head_end + 4 + length || read == 0 {
+                return request[head_end + 4 ..].to_vec();
+            }
+        }
+    }
 }
@@ -0,0 +1,197 @@
+use futures_util::{Stream, StreamExt, stream};
+
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// One server-sent event, `event` is `message` unless the server named it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SseEvent {
+    pub(crate) event: String,
+    pub(crate) data: String,
+}
+
+/// Splits a byte stream into server-sent events.
+///
+/// Some proxies split a multi-byte character between two events, so the data of an event
+/// is decoded only up to its last complete UTF-8 sequence and the rest opens the next event.
+#[derive(Debug, Default)]
+pub(crate) struct SseDecoder {
+    buffer: Vec<u8>,
+    started: bool,
+    event: Option<String>,
+    data: Vec<u8>,
+    /// Bytes of a character the previous event's data broke off in the middle of.
+    pending: Vec<u8>,
+}
+
+impl SseDecoder {
+    /// Feeds the next chunk of the stream and returns the events it completed.
+    pub(crate) fn push(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        self.buffer
+            .extend_from_slice(bytes);
+        if !self.started {
+            if self.buffer.len() < BOM.len() && BOM.starts_with(&self.buffer) {
+                return vec![];
+            }
+            self.started = true;
+            if self.buffer.starts_with(BOM) {
+                self.buffer
+                    .drain(.. BOM.len());
+            }
+        }
+
+        let mut events = Vec::new();
+        while let Some(end) = self
+            .buffer
+            .iter()
+            .position(|byte| *byte == b'\n')
+        {
+            let mut line: Vec<u8> = self
+                .buffer
+                .drain(..= end)
+                .collect();
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+
+            if line.is_empty() {
+                events.extend(self.dispatch());
+            } else {
+                self.add_line(&line);
+            }
+        }
+        events
+    }
+
+    fn add_line(&mut self, line: &[u8]) {
+        let (field, value) = match line
+            .iter()
+            .position(|byte| *byte == b':')
+        {
+            // A line starting with a colon is a comment.
+            Some(0) => return,
+            Some(colon) => {
+                let value = &line[colon + 1 ..];
+                (
+                    &line[.. colon],
+                    value
+                        .strip_prefix(b" ")
+                        .unwrap_or(value),
+                )
+            }
+            None => (line, &[][..]),
+        };
+
+        match field {
+            b"event" => self.event = Some(String::from_utf8_lossy(value).into_owned()),
+            b"data" => {
+                self.data
+                    .extend_from_slice(value);
+                self.data.push(b'\n');
+            }
+            _ => {}
+        }
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self
+            .event
+            .take()
+            .filter(|event| !event.is_empty())
+            .unwrap_or_else(|| "message".to_string());
+        let mut data = std::mem::take(&mut self.data);
+        if data.is_empty() {
+            return None;
+        }
+        data.pop();
+
+        let mut bytes = std::mem::take(&mut self.pending);
+        bytes.extend(data);
+        Some(SseEvent {
+            event,
+            data: self.decode(bytes),
+        })
+    }
+
+    fn decode(&mut self, mut bytes: Vec<u8>) -> String {
+        if let Err(error) = std::str::from_utf8(&bytes) {
+            // No `error_len` means the bytes end halfway through a character rather than on an invalid one.
+            if error.error_len().is_none() {
+                self.pending = bytes.split_off(error.valid_up_to());
+            }
+        }
+        String::from_utf8(bytes)
+            .unwrap_or_else(|error| String::from_utf8_lossy(error.as_bytes()).into_owned())
+    }
+}
+
+/// Server-sent events of a response body stream.
+pub(crate) fn sse_events<S, B, E>(body: S) -> impl Stream<Item = Result<SseEvent, E>> + Unpin
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>, {
+    let mut decoder = SseDecoder::default();
+    body.flat_map(move |chunk| {
+        stream::iter(match chunk {
+            Ok(bytes) => {
+                decoder
+                    .push(bytes.as_ref())
+                    .into_iter()
+                    .map(Ok)
+                    .collect()
+            }
+            Err(error) => vec![Err(error)],
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoder_parses_named_events_and_multiline_data() {
+        let mut decoder = SseDecoder::default();
+
+        let events = decoder.push(b"\xEF\xBB\xBF: keep-alive\r\nevent: message_start\r\ndata: {\"a\":\r\ndata:1}\r\n\r\ndata: [DONE]\n");
+        assert_eq!(
+            events,
+            vec![SseEvent {
+                event: "message_start".to_string(),
+                data: "{\"a\":\n1}".to_string(),
+            }]
+        );
+
+        assert_eq!(
+            decoder.push(b"\n"),
+            vec![SseEvent {
+                event: "message".to_string(),
+                data: "[DONE]".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_decoder_carries_split_character_into_next_event() {
+        let mut decoder = SseDecoder::default();
+        let emoji = "😀".as_bytes();
+
+        let mut first = b"data: {\"content\":\"Hi ".to_vec();
+        first.extend_from_slice(&emoji[.. 2]);
+        first.extend_from_slice(b"\n\n");
+        let mut second = b"data: ".to_vec();
+        second.extend_from_slice(&emoji[2 ..]);
+        second.extend_from_slice(b"\"}\n\n");
+
+        let events: Vec<String> = decoder
+            .push(&first)
+            .into_iter()
+            .chain(decoder.push(&second))
+            .map(|event| event.data)
+            .collect();
+        assert_eq!(
+            events,
+            vec!["{\"content\":\"Hi ", "😀\"}"]
+        );
+    }
+}
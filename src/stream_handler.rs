@@ -1,17 +1,502 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 
-use tokio::sync::mpsc::Receiver;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc::{Receiver, Sender, UnboundedReceiver},
+    time::{Instant, MissedTickBehavior, interval, sleep, timeout},
+};
+
+use crate::error::{LlmError, LlmErrorKind};
+
+/// How often held back chunks check whether the stream got resumed.
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// `AssistantSettings::stream_coalesce_ms` when unset.
+pub const DEFAULT_COALESCE_WINDOW_MS: u64 = 30;
+/// `AssistantSettings::stream_coalesce_bytes` when unset.
+pub const DEFAULT_COALESCE_BYTES: usize = 512;
+
+/// How long and how much text the tokens are gathered for before the handler gets them as one,
+/// so a fast provider doesn't call into Python for every few characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Coalesce {
+    pub window: Duration,
+    pub max_bytes: usize,
+}
+
+impl Coalesce {
+    /// `None` when the window is zero, every token then goes to the handler on its own.
+    pub fn new(window_ms: Option<u64>, max_bytes: Option<usize>) -> Option<Self> {
+        let window = Duration::from_millis(window_ms.unwrap_or(DEFAULT_COALESCE_WINDOW_MS));
+        (!window.is_zero()).then(|| {
+            Self {
+                window,
+                max_bytes: max_bytes.unwrap_or(DEFAULT_COALESCE_BYTES),
+            }
+        })
+    }
+}
+
+/// One item of the stream a run sends towards the handler.
+///
+/// The handler gets it as a JSON string, e.g. `{"event":"text","data":"Hel"}`,
+/// `{"event":"function_call_start","data":"read_file"}`,
+/// `{"event":"finished","data":{"finish_reason":"length"}}`,
+/// `{"event":"error","data":{"kind":"http","message":"...","status":429,"retry_after":7.0}}`
+/// or `{"event":"done"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(
+    tag = "event",
+    content = "data",
+    rename_all = "snake_case"
+)]
+pub enum StreamEvent {
+    /// A piece of the answer.
+    Text(String),
+    /// A piece of the reasoning the model streams ahead of the answer.
+    Thinking(String),
+    /// The model started calling the function of this name.
+    FunctionCallStart(String),
+    /// The next piece of the arguments JSON of the call to `name`.
+    FunctionCallArguments { name: String, delta: String },
+    /// The arguments of the call to `name` are complete, compacted when they are valid JSON.
+    FunctionCallEnd { name: String, arguments: String },
+    /// The provider went quiet mid answer and the request was given up.
+    Stalled,
+    /// The request failed and goes out again, `attempt` of at most `max` retries.
+    Retrying { attempt: u8, max: u8 },
+    /// A deferred completion isn't ready yet, sent every so often while it's polled for.
+    Pending,
+    /// The run got cancelled.
+    Aborted,
+    /// The answer stopped for a reason other than a natural end, e.g. `length`.
+    Finished { finish_reason: String },
+    /// The run failed, `status` and `retry_after` (in seconds) are set for `Http` failures.
+    Error { kind: LlmErrorKind, message: String, status: Option<u16>, retry_after: Option<f64> },
+    /// The run is over, nothing follows.
+    Done,
+}
+
+impl StreamEvent {
+    pub fn to_json(&self) -> String { serde_json::to_string(self).expect("a stream event always serializes") }
+
+    pub fn from_json(json: &str) -> Option<Self> { serde_json::from_str(json).ok() }
+
+    /// The text of a `Text`, `None` for the other events.
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            StreamEvent::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// The string the handler got for this event before the events were typed, `None` for
+    /// the events that had none.
+    #[deprecated(note = "handlers should read the JSON events, this goes away in the next release")]
+    pub fn legacy_text(&self) -> Option<String> {
+        match self {
+            StreamEvent::Text(text) => Some(text.clone()),
+            StreamEvent::Thinking(_)
+            | StreamEvent::FunctionCallArguments { .. }
+            | StreamEvent::FunctionCallEnd { .. }
+            | StreamEvent::Retrying { .. }
+            | StreamEvent::Pending
+            | StreamEvent::Finished { .. }
+            | StreamEvent::Error { .. }
+            | StreamEvent::Done => None,
+            StreamEvent::FunctionCallStart(name) => Some(format!("- {name}\n")),
+            StreamEvent::Stalled => Some("\n[STALLED]".to_string()),
+            StreamEvent::Aborted => Some("\n[ABORTED]".to_string()),
+        }
+    }
+}
+
+impl From<String> for StreamEvent {
+    fn from(text: String) -> Self { StreamEvent::Text(text) }
+}
+
+impl From<&str> for StreamEvent {
+    fn from(text: &str) -> Self { StreamEvent::Text(text.to_string()) }
+}
+
+impl From<&LlmError> for StreamEvent {
+    fn from(error: &LlmError) -> Self {
+        let (status, retry_after) = match error {
+            LlmError::Http {
+                status, retry_after, ..
+            } => {
+                (
+                    Some(*status),
+                    retry_after.map(|delay| delay.as_secs_f64()),
+                )
+            }
+            _ => (None, None),
+        };
+        StreamEvent::Error {
+            kind: error.kind(),
+            message: error.to_string(),
+            status,
+            retry_after,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct StreamHandler {}
 
 impl StreamHandler {
+    /// Hands every event to `emit_fn` as JSON.
+    ///
+    /// With `coalesce` the tokens that come in within its window are joined into one, which
+    /// goes out early once it grows to `max_bytes`, and right away with any other event.
+    ///
+    /// While `pause_flag` is set the events are held back, and go out once it clears with
+    /// the held tokens joined into one. A stream that ends paused waits for the resume.
     pub async fn handle_stream_with(
-        mut rx: Receiver<String>,
+        rx: Receiver<StreamEvent>,
         emit_fn: Arc<dyn Fn(String) + Send + Sync + 'static>,
+        pause_flag: Arc<AtomicBool>,
+        coalesce: Option<Coalesce>,
     ) {
-        while let Some(data) = rx.recv().await {
+        Self::drive_counting(
+            rx,
+            emit_fn,
+            pause_flag,
+            coalesce,
+            |_| {},
+        )
+        .await;
+    }
+
+    /// Hands every event to each of the `sinks` in turn, e.g. one updating the view and
+    /// another logging the answer to a file.
+    pub async fn fan_out(rx: Receiver<StreamEvent>, sinks: Vec<Arc<dyn Fn(String) + Send + Sync + 'static>>) {
+        Self::handle_stream_with(
+            rx,
+            Self::broadcast(sinks),
+            Arc::new(AtomicBool::new(false)),
+            None,
+        )
+        .await
+    }
+
+    /// A single emit function calling all of `sinks` with a copy of the event.
+    pub fn broadcast(
+        sinks: Vec<Arc<dyn Fn(String) + Send + Sync + 'static>>,
+    ) -> Arc<dyn Fn(String) + Send + Sync + 'static> {
+        Arc::new(move |data: String| {
+            for sink in &sinks {
+                sink(data.clone());
+            }
+        })
+    }
+
+    /// Hands every event to `emit_fn` and appends it to `file_path`, one JSON per line, so the
+    /// stream of several runs adds up in one file.
+    pub async fn pipe_to_file(
+        mut rx: Receiver<StreamEvent>,
+        emit_fn: Arc<dyn Fn(String) + Send + Sync + 'static>,
+        file_path: &str,
+    ) -> Result<()> {
+        let mut file = Self::open_stream_log(file_path).await?;
+        while let Some(event) = rx.recv().await {
+            let data = event.to_json();
+            let line = format!("{}\n", data);
             emit_fn(data);
+            file.write_all(line.as_bytes())
+                .await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// The file half of `pipe_to_file`, for the events some other handler emits.
+    pub(crate) async fn append_to_file(mut rx: UnboundedReceiver<String>, file_path: &str) -> Result<()> {
+        let mut file = Self::open_stream_log(file_path).await?;
+        while let Some(data) = rx.recv().await {
+            file.write_all(format!("{}\n", data).as_bytes())
+                .await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+
+    async fn open_stream_log(file_path: &str) -> Result<File> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_path)
+            .await?)
+    }
+
+    /// Same as `handle_stream_with`, returns the number of whitespace-delimited tokens emitted.
+    pub async fn handle_stream_counting(
+        rx: Receiver<StreamEvent>,
+        emit_fn: Arc<dyn Fn(String) + Send + Sync + 'static>,
+        pause_flag: Arc<AtomicBool>,
+        coalesce: Option<Coalesce>,
+    ) -> usize {
+        Self::drive_counting(
+            rx,
+            emit_fn,
+            pause_flag,
+            coalesce,
+            |_| {},
+        )
+        .await
+    }
+
+    /// Same as `handle_stream_with`, fires `on_token` with the number of tokens emitted so far
+    /// after each event.
+    pub async fn handle_stream_with_callback(
+        rx: Receiver<StreamEvent>,
+        emit_fn: Arc<dyn Fn(String) + Send + Sync + 'static>,
+        on_token: Arc<dyn Fn(usize) + Send + Sync + 'static>,
+    ) {
+        Self::drive_counting(
+            rx,
+            emit_fn,
+            Arc::new(AtomicBool::new(false)),
+            None,
+            |count| on_token(count),
+        )
+        .await;
+    }
+
+    async fn drive_counting(
+        mut rx: Receiver<StreamEvent>,
+        emit_fn: Arc<dyn Fn(String) + Send + Sync + 'static>,
+        pause_flag: Arc<AtomicBool>,
+        coalesce: Option<Coalesce>,
+        on_token: impl Fn(usize),
+    ) -> usize {
+        let mut counter = TokenCounter::default();
+        let mut emit = |event: StreamEvent| {
+            if let Some(text) = event.text() {
+                counter.feed(text);
+            }
+            emit_fn(event.to_json());
+            on_token(counter.count);
+        };
+        let mut held = HeldEvents::default();
+
+        loop {
+            let next = if held.is_empty() {
+                rx.recv().await
+            } else {
+                // A resume or the end of the window has to flush the held events even when
+                // no new one comes in.
+                let wait = match (coalesce, held.since) {
+                    (Some(coalesce), Some(since)) if !pause_flag.load(Ordering::SeqCst) => {
+                        (since + coalesce.window).saturating_duration_since(Instant::now())
+                    }
+                    _ => PAUSE_POLL_INTERVAL,
+                };
+                match timeout(wait, rx.recv()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        if !pause_flag.load(Ordering::SeqCst) {
+                            held.take()
+                                .into_iter()
+                                .for_each(&mut emit);
+                        }
+                        continue;
+                    }
+                }
+            };
+            let Some(event) = next else {
+                break;
+            };
+
+            let is_token = matches!(
+                event,
+                StreamEvent::Text(_) | StreamEvent::Thinking(_) | StreamEvent::FunctionCallArguments { .. }
+            );
+            held.push(event);
+            let flush = match coalesce {
+                _ if pause_flag.load(Ordering::SeqCst) => false,
+                Some(coalesce) if is_token => {
+                    held.text_len() >= coalesce.max_bytes
+                        || held
+                            .since
+                            .is_some_and(|since| since.elapsed() >= coalesce.window)
+                }
+                _ => true,
+            };
+            if flush {
+                held.take()
+                    .into_iter()
+                    .for_each(&mut emit);
+            }
+        }
+
+        while !held.is_empty() && pause_flag.load(Ordering::SeqCst) {
+            sleep(PAUSE_POLL_INTERVAL).await;
+        }
+        held.take()
+            .into_iter()
+            .for_each(&mut emit);
+        counter.count
+    }
+
+    /// Same as `handle_stream_with`, but batches the tokens into fewer `emit_fn` calls.
+    ///
+    /// The batch goes out every `flush_interval_ms`, at the end of a sentence, right before
+    /// any other event and once the channel closes.
+    pub async fn handle_stream_with_buffer(
+        mut rx: Receiver<StreamEvent>,
+        emit_fn: Arc<dyn Fn(String) + Send + Sync + 'static>,
+        flush_interval_ms: u64,
+    ) {
+        let mut buffer = String::new();
+        let mut ticker = interval(Duration::from_millis(
+            flush_interval_ms.max(1),
+        ));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let flush = |buffer: &mut String| {
+            if !buffer.is_empty() {
+                emit_fn(StreamEvent::Text(std::mem::take(buffer)).to_json());
+            }
+        };
+
+        loop {
+            tokio::select! {
+                event = rx.recv() => {
+                    match event {
+                        None => break,
+                        Some(StreamEvent::Text(text)) => {
+                            buffer.push_str(&text);
+                            if Self::ends_sentence(&buffer) {
+                                flush(&mut buffer);
+                            }
+                        }
+                        Some(event) => {
+                            flush(&mut buffer);
+                            emit_fn(event.to_json());
+                        }
+                    }
+                }
+                _ = ticker.tick() => flush(&mut buffer),
+            }
+        }
+
+        flush(&mut buffer);
+    }
+
+    /// Forwards the events of `rx` to `tx` without ever keeping the sending side waiting: while
+    /// `tx` is full the events pile up with back to back tokens joined into one, and go out as
+    /// room frees up.
+    pub async fn relay_coalescing(mut rx: Receiver<StreamEvent>, tx: Sender<StreamEvent>) {
+        let mut held = HeldEvents::default();
+        loop {
+            tokio::select! {
+                biased;
+                event = rx.recv() => {
+                    match event {
+                        Some(event) => held.push(event),
+                        None => break,
+                    }
+                }
+                permit = tx.reserve(), if !held.is_empty() => {
+                    match (permit, held.pop_front()) {
+                        (Ok(permit), Some(event)) => permit.send(event),
+                        // The handler is gone, nobody is left to coalesce for.
+                        _ => return,
+                    }
+                }
+            }
+        }
+
+        for event in held.take() {
+            if tx.send(event).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    fn ends_sentence(text: &str) -> bool {
+        [". ", "! ", "? "]
+            .iter()
+            .any(|end| text.ends_with(end))
+    }
+}
+
+/// Events held back while the stream is paused or being coalesced, back to back tokens
+/// joined into one.
+#[derive(Debug, Default)]
+struct HeldEvents {
+    events: Vec<StreamEvent>,
+    /// When the first of the held events came in.
+    since: Option<Instant>,
+}
+
+impl HeldEvents {
+    fn push(&mut self, event: StreamEvent) {
+        self.since
+            .get_or_insert_with(Instant::now);
+        match (self.events.last_mut(), event) {
+            (Some(StreamEvent::Text(held)), StreamEvent::Text(text))
+            | (Some(StreamEvent::Thinking(held)), StreamEvent::Thinking(text)) => held.push_str(&text),
+            (
+                Some(StreamEvent::FunctionCallArguments {
+                    name: held_name,
+                    delta: held,
+                }),
+                StreamEvent::FunctionCallArguments { name, delta },
+            ) if *held_name == name => held.push_str(&delta),
+            (_, event) => self.events.push(event),
+        }
+    }
+
+    fn is_empty(&self) -> bool { self.events.is_empty() }
+
+    fn text_len(&self) -> usize {
+        self.events
+            .iter()
+            .filter_map(StreamEvent::text)
+            .map(str::len)
+            .sum()
+    }
+
+    fn pop_front(&mut self) -> Option<StreamEvent> {
+        let event = (!self.events.is_empty()).then(|| self.events.remove(0));
+        if self.events.is_empty() {
+            self.since = None;
+        }
+        event
+    }
+
+    fn take(&mut self) -> Vec<StreamEvent> {
+        self.since = None;
+        std::mem::take(&mut self.events)
+    }
+}
+
+/// Counts whitespace-delimited tokens of a text that comes in chunks, a word split
+/// between two chunks is counted once.
+#[derive(Debug, Default)]
+struct TokenCounter {
+    count: usize,
+    in_word: bool,
+}
+
+impl TokenCounter {
+    fn feed(&mut self, chunk: &str) {
+        for char in chunk.chars() {
+            let in_word = !char.is_whitespace();
+            if in_word && !self.in_word {
+                self.count += 1;
+            }
+            self.in_word = in_word;
         }
     }
 }
@@ -28,4 +513,510 @@ mod tests {
         is_sync::<StreamHandler>();
         is_send::<StreamHandler>();
     }
+
+    #[test]
+    fn test_stream_event_json_round_trip() {
+        let events = [
+            StreamEvent::from("Hi \"there\""),
+            StreamEvent::Thinking("Hmm".to_string()),
+            StreamEvent::FunctionCallStart("read_file".to_string()),
+            StreamEvent::FunctionCallArguments {
+                name: "read_file".to_string(),
+                delta: "{\"pa".to_string(),
+            },
+            StreamEvent::FunctionCallEnd {
+                name: "read_file".to_string(),
+                arguments: "{}".to_string(),
+            },
+            StreamEvent::Stalled,
+            StreamEvent::Retrying { attempt: 1, max: 2 },
+            StreamEvent::Pending,
+            StreamEvent::Aborted,
+            StreamEvent::Finished {
+                finish_reason: "length".to_string(),
+            },
+            StreamEvent::from(&LlmError::Http {
+                status: 429,
+                message: String::new(),
+                retry_after: Some(Duration::from_secs(7)),
+            }),
+            StreamEvent::Done,
+        ];
+        let json: Vec<String> = events
+            .iter()
+            .map(StreamEvent::to_json)
+            .collect();
+
+        assert_eq!(
+            json,
+            vec![
+                r#"{"event":"text","data":"Hi \"there\""}"#,
+                r#"{"event":"thinking","data":"Hmm"}"#,
+                r#"{"event":"function_call_start","data":"read_file"}"#,
+                r#"{"event":"function_call_arguments","data":{"name":"read_file","delta":"{\"pa"}}"#,
+                r#"{"event":"function_call_end","data":{"name":"read_file","arguments":"{}"}}"#,
+                r#"{"event":"stalled"}"#,
+                r#"{"event":"retrying","data":{"attempt":1,"max":2}}"#,
+                r#"{"event":"pending"}"#,
+                r#"{"event":"aborted"}"#,
+                r#"{"event":"finished","data":{"finish_reason":"length"}}"#,
+                r#"{"event":"error","data":{"kind":"http","message":"Request failed with status: 429 Too Many Requests, retry in 7s","status":429,"retry_after":7.0}}"#,
+                r#"{"event":"done"}"#,
+            ]
+        );
+        for (json, event) in json.iter().zip(events) {
+            assert_eq!(
+                StreamEvent::from_json(json),
+                Some(event)
+            );
+        }
+        assert_eq!(StreamEvent::from_json("Hi"), None);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_legacy_text_matches_the_baseline_strings() {
+        let events = [
+            StreamEvent::from("Hel"),
+            StreamEvent::Thinking("Hmm".to_string()),
+            StreamEvent::FunctionCallStart("read_file".to_string()),
+            StreamEvent::FunctionCallArguments {
+                name: "read_file".to_string(),
+                delta: "{}".to_string(),
+            },
+            StreamEvent::FunctionCallEnd {
+                name: "read_file".to_string(),
+                arguments: "{}".to_string(),
+            },
+            StreamEvent::Retrying { attempt: 1, max: 2 },
+            StreamEvent::Pending,
+            StreamEvent::Stalled,
+            StreamEvent::Aborted,
+            StreamEvent::Finished {
+                finish_reason: "length".to_string(),
+            },
+            StreamEvent::from(&LlmError::Timeout),
+            StreamEvent::Done,
+        ];
+        let legacy: Vec<String> = events
+            .iter()
+            .filter_map(StreamEvent::legacy_text)
+            .collect();
+
+        // Only the strings the handler got before the events were typed.
+        assert_eq!(
+            legacy,
+            vec![
+                "Hel",
+                "- read_file\n",
+                "\n[STALLED]",
+                "\n[ABORTED]"
+            ]
+        );
+    }
+
+    type EmitFn = Arc<dyn Fn(String) + Send + Sync + 'static>;
+
+    /// An emit function decoding what it's handed back into events.
+    fn collecting_emit_fn() -> (
+        Arc<std::sync::Mutex<Vec<StreamEvent>>>,
+        EmitFn,
+    ) {
+        let emitted = Arc::new(std::sync::Mutex::new(vec![]));
+        let emitted_clone = Arc::clone(&emitted);
+        (
+            emitted,
+            Arc::new(move |data: String| {
+                emitted_clone
+                    .lock()
+                    .unwrap()
+                    .push(StreamEvent::from_json(&data).expect("emitted data is a stream event"))
+            }),
+        )
+    }
+
+    fn tokens(texts: &[&str]) -> Vec<StreamEvent> {
+        texts
+            .iter()
+            .map(|text| StreamEvent::from(*text))
+            .collect()
+    }
+
+    fn text_of(events: &[StreamEvent]) -> String {
+        events
+            .iter()
+            .filter_map(StreamEvent::text)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_paused_stream_delivers_held_chunks_on_resume() {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let (emitted, emit_fn) = collecting_emit_fn();
+        let pause_flag = Arc::new(AtomicBool::new(false));
+        let handler = tokio::spawn(StreamHandler::handle_stream_with(
+            rx,
+            emit_fn,
+            Arc::clone(&pause_flag),
+            None,
+        ));
+
+        tx.send("Hello".into())
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(20)).await;
+        pause_flag.store(true, Ordering::SeqCst);
+        for token in [",", " world"] {
+            tx.send(token.into())
+                .await
+                .unwrap();
+        }
+        sleep(PAUSE_POLL_INTERVAL * 3).await;
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            tokens(&["Hello"])
+        );
+
+        pause_flag.store(false, Ordering::SeqCst);
+        sleep(PAUSE_POLL_INTERVAL * 3).await;
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            tokens(&["Hello", ", world"])
+        );
+
+        tx.send("!".into())
+            .await
+            .unwrap();
+        drop(tx);
+        handler.await.unwrap();
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            tokens(&["Hello", ", world", "!"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_ended_while_paused_waits_for_resume() {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let (emitted, emit_fn) = collecting_emit_fn();
+        let pause_flag = Arc::new(AtomicBool::new(true));
+
+        for event in [
+            StreamEvent::from("held"),
+            StreamEvent::from(" back"),
+            StreamEvent::Aborted,
+            StreamEvent::Done,
+        ] {
+            tx.send(event).await.unwrap();
+        }
+        drop(tx);
+        let handler = tokio::spawn(StreamHandler::handle_stream_with(
+            rx,
+            emit_fn,
+            Arc::clone(&pause_flag),
+            None,
+        ));
+        sleep(PAUSE_POLL_INTERVAL * 3).await;
+        assert!(!handler.is_finished());
+        assert!(
+            emitted
+                .lock()
+                .unwrap()
+                .is_empty()
+        );
+
+        pause_flag.store(false, Ordering::SeqCst);
+        handler.await.unwrap();
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            vec![
+                StreamEvent::from("held back"),
+                StreamEvent::Aborted,
+                StreamEvent::Done
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_stream_joins_rapid_tokens() {
+        let (tx, rx) = tokio::sync::mpsc::channel(200);
+        let (emitted, emit_fn) = collecting_emit_fn();
+        let handler = tokio::spawn(StreamHandler::handle_stream_with(
+            rx,
+            emit_fn,
+            Arc::new(AtomicBool::new(false)),
+            Coalesce::new(Some(30), Some(512)),
+        ));
+
+        for i in 0 .. 100 {
+            tx.send(format!("{i} ").into())
+                .await
+                .unwrap();
+        }
+        tx.send(StreamEvent::Stalled)
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            emitted.lock().unwrap().last(),
+            Some(&StreamEvent::Stalled)
+        );
+
+        drop(tx);
+        handler.await.unwrap();
+        let emitted = emitted.lock().unwrap();
+        assert!(
+            emitted.len() < 10,
+            "{} handler calls",
+            emitted.len()
+        );
+        assert_eq!(
+            text_of(&emitted),
+            (0 .. 100)
+                .map(|i| format!("{i} "))
+                .collect::<String>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_relay_coalescing_never_keeps_sender_waiting() {
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let (relay_tx, mut relay_rx) = tokio::sync::mpsc::channel(1);
+        let relay = tokio::spawn(StreamHandler::relay_coalescing(
+            rx, relay_tx,
+        ));
+
+        // Nobody reads the relayed side meanwhile.
+        timeout(Duration::from_secs(1), async {
+            for i in 0 .. 100 {
+                tx.send(format!("{i} ").into())
+                    .await
+                    .unwrap();
+            }
+            tx.send(StreamEvent::Done)
+                .await
+                .unwrap();
+        })
+        .await
+        .unwrap();
+        drop(tx);
+
+        let mut relayed = vec![];
+        while let Some(event) = relay_rx.recv().await {
+            relayed.push(event);
+        }
+        relay.await.unwrap();
+        assert!(
+            relayed.len() < 10,
+            "{} events",
+            relayed.len()
+        );
+        assert_eq!(relayed.last(), Some(&StreamEvent::Done));
+        assert_eq!(
+            text_of(&relayed),
+            (0 .. 100)
+                .map(|i| format!("{i} "))
+                .collect::<String>()
+        );
+    }
+
+    #[test]
+    fn test_zero_coalesce_window_turns_it_off() {
+        assert_eq!(Coalesce::new(Some(0), None), None);
+        assert_eq!(
+            Coalesce::new(None, None),
+            Some(Coalesce {
+                window: Duration::from_millis(DEFAULT_COALESCE_WINDOW_MS),
+                max_bytes: DEFAULT_COALESCE_BYTES,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipe_to_file_appends_stream_of_every_run() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let file_path = tmp_dir
+            .path()
+            .join("stream.log");
+        let file_path = file_path.to_str().unwrap();
+
+        for texts in [vec!["Hel", "lo"], vec![" again"]] {
+            let (tx, rx) = tokio::sync::mpsc::channel(10);
+            let (emitted, emit_fn) = collecting_emit_fn();
+            for text in &texts {
+                tx.send((*text).into())
+                    .await
+                    .unwrap();
+            }
+            drop(tx);
+
+            StreamHandler::pipe_to_file(rx, emit_fn, file_path)
+                .await
+                .unwrap();
+            assert_eq!(*emitted.lock().unwrap(), tokens(&texts));
+        }
+
+        let content = std::fs::read_to_string(file_path).unwrap();
+        let logged: Vec<StreamEvent> = content
+            .lines()
+            .map(|line| StreamEvent::from_json(line).unwrap())
+            .collect();
+        assert_eq!(logged, tokens(&["Hel", "lo", " again"]));
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_hands_every_chunk_to_each_sink() {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let (ui_emitted, ui_sink) = collecting_emit_fn();
+        let (log_emitted, log_sink) = collecting_emit_fn();
+
+        let events = vec![
+            StreamEvent::from("Hello"),
+            StreamEvent::from(", "),
+            StreamEvent::from("world"),
+            StreamEvent::Done,
+        ];
+        for event in &events {
+            tx.send(event.clone())
+                .await
+                .unwrap();
+        }
+        drop(tx);
+        StreamHandler::fan_out(rx, vec![ui_sink, log_sink]).await;
+
+        let ui_emitted = ui_emitted.lock().unwrap();
+        assert_eq!(*ui_emitted, events);
+        assert_eq!(
+            *ui_emitted,
+            *log_emitted.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_counting_stream_counts_words_split_across_chunks() {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let (emitted, emit_fn) = collecting_emit_fn();
+
+        for token in [
+            "Hel", "lo, ", "wor", "ld", "\n\n", "again ",
+        ] {
+            tx.send(token.into())
+                .await
+                .unwrap();
+        }
+        tx.send(StreamEvent::Done)
+            .await
+            .unwrap();
+        drop(tx);
+
+        assert_eq!(
+            StreamHandler::handle_stream_counting(
+                rx,
+                emit_fn,
+                Arc::new(AtomicBool::new(false)),
+                None,
+            )
+            .await,
+            3
+        );
+        assert_eq!(
+            text_of(&emitted.lock().unwrap()),
+            "Hello, world\n\nagain "
+        );
+    }
+
+    #[tokio::test]
+    async fn test_callback_stream_reports_cumulative_count_per_chunk() {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let (_, emit_fn) = collecting_emit_fn();
+        let counts = Arc::new(std::sync::Mutex::new(vec![]));
+        let counts_clone = Arc::clone(&counts);
+
+        for token in ["One", " two", "", " three four"] {
+            tx.send(token.into())
+                .await
+                .unwrap();
+        }
+        drop(tx);
+        StreamHandler::handle_stream_with_callback(
+            rx,
+            emit_fn,
+            Arc::new(move |count| {
+                counts_clone
+                    .lock()
+                    .unwrap()
+                    .push(count)
+            }),
+        )
+        .await;
+
+        assert_eq!(
+            *counts.lock().unwrap(),
+            vec![1, 2, 2, 4]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffered_stream_batches_tokens() {
+        let (tx, rx) = tokio::sync::mpsc::channel(100);
+        let (emitted, emit_fn) = collecting_emit_fn();
+        let text: String = ('a' ..= 'z')
+            .cycle()
+            .take(100)
+            .collect();
+
+        let producer = async move {
+            for char in text.chars() {
+                tx.send(char.to_string().into())
+                    .await
+                    .unwrap();
+            }
+        };
+        tokio::join!(
+            producer,
+            StreamHandler::handle_stream_with_buffer(rx, emit_fn, 50)
+        );
+
+        let emitted = emitted.lock().unwrap();
+        assert!(
+            emitted.len() < 10,
+            "emitted {} times",
+            emitted.len()
+        );
+        assert_eq!(
+            text_of(&emitted),
+            ('a' ..= 'z')
+                .cycle()
+                .take(100)
+                .collect::<String>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffered_stream_flushes_at_sentence_end() {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let (emitted, emit_fn) = collecting_emit_fn();
+
+        for token in [
+            "Hi", "!", " ", "How", " are", " you", "?",
+        ] {
+            tx.send(token.into())
+                .await
+                .unwrap();
+        }
+        tx.send(StreamEvent::Stalled)
+            .await
+            .unwrap();
+        drop(tx);
+        StreamHandler::handle_stream_with_buffer(rx, emit_fn, 60_000).await;
+
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            vec![
+                StreamEvent::from("Hi! "),
+                StreamEvent::from("How are you?"),
+                StreamEvent::Stalled
+            ]
+        );
+    }
 }
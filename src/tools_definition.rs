@@ -1,7 +1,12 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 
+use anyhow::{Result, anyhow};
 use once_cell::sync::Lazy;
-use serde_json::json;
+use serde::Deserialize;
+use serde_json::{Value, json};
 use strum_macros::{Display, EnumString};
 
 use crate::openai_network_types::{FunctionToCall, Tool};
@@ -14,6 +19,10 @@ pub enum FunctionName {
     ReplaceTextForWholeFile,
     ReadRegionContent,
     GetWorkingDirectoryContent,
+    GenerateImage,
+    ListOpenFiles,
+    FetchUrl,
+    SearchInFiles,
 }
 
 pub static FUNCTIONS: Lazy<Vec<Arc<Tool>>> = Lazy::new(|| {
@@ -23,9 +32,105 @@ pub static FUNCTIONS: Lazy<Vec<Arc<Tool>>> = Lazy::new(|| {
         Arc::new((*APPLY_PATCH).clone()),
         Arc::new((*READ_REGION_CONTENT).clone()),
         Arc::new((*GET_WORKING_DIRECTORY_CONTENT).clone()),
+        Arc::new((*GENERATE_IMAGE).clone()),
+        Arc::new((*FETCH_URL).clone()),
+        Arc::new((*SEARCH_IN_FILES).clone()),
     ]
 });
 
+/// Tools registered by the host at runtime, offered to the model next to `FUNCTIONS`.
+pub static TOOL_REGISTRY: Lazy<DynamicToolRegistry> = Lazy::new(DynamicToolRegistry::default);
+
+#[derive(Debug, Default)]
+pub struct DynamicToolRegistry {
+    tools: RwLock<HashMap<String, Tool>>,
+}
+
+impl DynamicToolRegistry {
+    /// Registers a function tool, replacing a previously registered one of the same name.
+    ///
+    /// Built-in tool names are reserved and `parameters` has to be a JSON schema object.
+    pub fn register(&self, name: &str, description: &str, parameters: Value, strict: bool) -> Result<()> {
+        if name.is_empty()
+            || name.len() > 64
+            || !name
+                .chars()
+                .all(|char| char.is_ascii_alphanumeric() || char == '_' || char == '-')
+        {
+            return Err(anyhow!(
+                "Tool name `{}` must be 1-64 characters of [a-zA-Z0-9_-]",
+                name
+            ));
+        }
+
+        if name
+            .parse::<FunctionName>()
+            .is_ok()
+        {
+            return Err(anyhow!("Tool `{}` is built in", name));
+        }
+
+        let Value::Object(parameters) = parameters else {
+            return Err(anyhow!(
+                "Parameters of tool `{}` must be a JSON object",
+                name
+            ));
+        };
+
+        let tool = Tool {
+            r#type: "function".to_string(),
+            function: Some(FunctionToCall {
+                name: name.to_string(),
+                description: Some(description.to_string()),
+                parameters: Some(parameters),
+                strict: Some(strict),
+            }),
+        };
+
+        self.tools
+            .write()
+            .map_err(|_| anyhow!("Tool registry lock poisoned"))?
+            .insert(name.to_string(), tool);
+        Ok(())
+    }
+
+    pub fn unregister(&self, name: &str) {
+        if let Ok(mut tools) = self.tools.write() {
+            tools.remove(name);
+        }
+    }
+
+    /// Built-in tools first, then the registered ones ordered by name.
+    pub fn tools_as_vec(&self) -> Vec<Tool> {
+        let mut registered: Vec<Tool> = self
+            .tools
+            .read()
+            .map(|tools| {
+                tools
+                    .values()
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        registered.sort_by(|lhs, rhs| {
+            lhs.function
+                .as_ref()
+                .map(|function| &function.name)
+                .cmp(
+                    &rhs.function
+                        .as_ref()
+                        .map(|function| &function.name),
+                )
+        });
+
+        FUNCTIONS
+            .iter()
+            .map(|tool| tool.as_ref().clone())
+            .chain(registered)
+            .collect()
+    }
+}
+
 #[allow(dead_code)]
 pub static WEB_SEARCH: Lazy<Tool> = Lazy::new(|| {
     Tool {
@@ -232,3 +337,250 @@ pub static READ_REGION_CONTENT: Lazy<Tool> = Lazy::new(|| {
         }),
     }
 });
+
+pub static GENERATE_IMAGE: Lazy<Tool> = Lazy::new(|| {
+    Tool {
+        r#type: "function".to_string(),
+        function: Some(FunctionToCall {
+            name: FunctionName::GenerateImage.to_string(),
+            description: Some(
+                r#"Generate a PNG image from a text prompt.
+                Returns the path of the saved file, or the reason the image was refused."#
+                    .to_string(),
+            ),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "prompt": {
+                        "type": "string",
+                        "description": "Detailed description of the image to generate."
+                    },
+                    "size": {
+                        "type": ["string", "null"],
+                        "enum": ["1024x1024", "1536x1024", "1024x1536", "1792x1024", "1024x1792", null],
+                        "description": "Image dimensions, null for the model default."
+                    },
+                    "quality": {
+                        "type": ["string", "null"],
+                        "enum": ["low", "medium", "high", "standard", "hd", null],
+                        "description": "Rendering quality, null for the model default."
+                    }
+                },
+                "required": ["prompt", "size", "quality"],
+                "additionalProperties": false
+            })
+            .as_object()
+            .cloned(),
+            strict: Some(true),
+        }),
+    }
+});
+
+/// Body bytes `fetch_url` returns when the model leaves `max_bytes` out.
+pub(crate) const DEFAULT_FETCH_MAX_BYTES: usize = 65536;
+
+/// Arguments the model calls `fetch_url` with.
+#[derive(Debug, Deserialize)]
+pub(crate) struct FetchUrlArguments {
+    pub(crate) url: String,
+    #[serde(default)]
+    pub(crate) max_bytes: Option<usize>,
+}
+
+pub static FETCH_URL: Lazy<Tool> = Lazy::new(|| {
+    Tool {
+        r#type: "function".to_string(),
+        function: Some(FunctionToCall {
+            name: FunctionName::FetchUrl.to_string(),
+            description: Some(
+                r#"Fetch a web page or any other resource over HTTP GET.
+                Returns the beginning of the response body as text."#
+                    .to_string(),
+            ),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "url": {
+                        "type": "string",
+                        "description": "Absolute http(s) URL to fetch."
+                    },
+                    "max_bytes": {
+                        "type": ["integer", "null"],
+                        "description": "Most bytes of the body to return, null for 65536."
+                    }
+                },
+                "required": ["url", "max_bytes"],
+                "additionalProperties": false
+            })
+            .as_object()
+            .cloned(),
+            strict: Some(true),
+        }),
+    }
+});
+
+/// Matches `search_in_files` returns at most.
+pub(crate) const MAX_SEARCH_MATCHES: usize = 100;
+
+/// Arguments the model calls `search_in_files` with.
+#[derive(Debug, Deserialize)]
+pub(crate) struct SearchInFilesArguments {
+    pub(crate) pattern: String,
+    pub(crate) directory_path: String,
+    #[serde(default)]
+    pub(crate) case_sensitive: bool,
+}
+
+pub static SEARCH_IN_FILES: Lazy<Tool> = Lazy::new(|| {
+    Tool {
+        r#type: "function".to_string(),
+        function: Some(FunctionToCall {
+            name: FunctionName::SearchInFiles.to_string(),
+            description: Some(
+                r#"Search the text files under a directory for lines matching a pattern, like `grep -rn`.
+                The pattern is a literal string, a glob with `*` and `?`, or a regular expression.
+                Returns a JSON object with up to 100 `matches` of `file`, `line` and `content`."#
+                    .to_string(),
+            ),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "pattern": {
+                        "type": "string",
+                        "description": "Text, glob or regular expression to look for in each line."
+                    },
+                    "directory_path": {
+                        "type": "string",
+                        "description": "The path of the directory to search (use `.` for project root)."
+                    },
+                    "case_sensitive": {
+                        "type": "boolean",
+                        "description": "Whether letter case has to match."
+                    }
+                },
+                "required": ["pattern", "directory_path", "case_sensitive"],
+                "additionalProperties": false
+            })
+            .as_object()
+            .cloned(),
+            strict: Some(true),
+        }),
+    }
+});
+
+/// Offered on top of `FUNCTIONS` only when `enable_list_files_tool` is set.
+pub static LIST_OPEN_FILES: Lazy<Tool> = Lazy::new(|| {
+    Tool {
+        r#type: "function".to_string(),
+        function: Some(FunctionToCall {
+            name: FunctionName::ListOpenFiles.to_string(),
+            description: Some(
+                r#"List the files currently open in the editor panes.
+                Returns one path per line, unsaved buffers are listed by their tab name."#
+                    .to_string(),
+            ),
+            parameters: json!({
+                "type": "object",
+                "properties": {},
+                "required": [],
+                "additionalProperties": false
+            })
+            .as_object()
+            .cloned(),
+            strict: Some(true),
+        }),
+    }
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_merges_registered_tools_after_builtins() {
+        let registry = DynamicToolRegistry::default();
+        registry
+            .register(
+                "lookup_symbol",
+                "Find a symbol definition",
+                json!({"type": "object", "properties": {"symbol": {"type": "string"}}}),
+                false,
+            )
+            .unwrap();
+
+        let tools = registry.tools_as_vec();
+        assert_eq!(tools.len(), FUNCTIONS.len() + 1);
+        let function = tools
+            .last()
+            .and_then(|tool| tool.function.as_ref())
+            .unwrap();
+        assert_eq!(function.name, "lookup_symbol");
+        assert_eq!(function.strict, Some(false));
+
+        registry.unregister("lookup_symbol");
+        assert_eq!(
+            registry.tools_as_vec().len(),
+            FUNCTIONS.len()
+        );
+    }
+
+    #[test]
+    fn test_registry_rejects_invalid_tools() {
+        let registry = DynamicToolRegistry::default();
+
+        assert!(
+            registry
+                .register(
+                    "apply_patch",
+                    "Shadow",
+                    json!({"type": "object"}),
+                    true
+                )
+                .is_err()
+        );
+        assert!(
+            registry
+                .register(
+                    "has space",
+                    "Bad name",
+                    json!({"type": "object"}),
+                    true
+                )
+                .is_err()
+        );
+        assert!(
+            registry
+                .register(
+                    "not_an_object",
+                    "Bad schema",
+                    json!("string"),
+                    true
+                )
+                .is_err()
+        );
+        assert_eq!(
+            registry.tools_as_vec().len(),
+            FUNCTIONS.len()
+        );
+    }
+
+    #[test]
+    fn test_list_open_files_tool_serialization() {
+        let tool = serde_json::to_value(&*LIST_OPEN_FILES).unwrap();
+
+        assert_eq!(tool["type"], "function");
+        assert_eq!(
+            tool["function"]["name"],
+            "list_open_files"
+        );
+        assert_eq!(tool["function"]["strict"], true);
+        assert_eq!(
+            tool["function"]["parameters"],
+            json!({"type": "object", "properties": {}, "required": [], "additionalProperties": false})
+        );
+        assert_eq!(
+            "list_open_files".parse::<FunctionName>(),
+            Ok(FunctionName::ListOpenFiles)
+        );
+    }
+}
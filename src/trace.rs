@@ -0,0 +1,225 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use reqwest::{
+    Request,
+    header::{AUTHORIZATION, CONTENT_ENCODING, HeaderName, PROXY_AUTHORIZATION},
+};
+use serde_json::Value;
+
+use crate::sse::SseEvent;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Headers the providers carry their keys in, the AWS session token of a signed Bedrock request too.
+const SECRET_HEADERS: [&str; 4] = [
+    "x-api-key",
+    "api-key",
+    "x-goog-api-key",
+    "x-amz-security-token",
+];
+
+static DUMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A file in `AssistantSettings.debug_dump_dir` holding one request and the raw response to it.
+///
+/// The token is replaced with `[REDACTED]` wherever it shows up, same as the auth headers
+/// and any `token` field of the payload.
+#[derive(Debug)]
+pub(crate) struct TraceDump {
+    path: PathBuf,
+    token: Option<String>,
+}
+
+impl TraceDump {
+    /// Creates the dump file and writes `request` into it.
+    pub(crate) fn start(dir: &str, request: &Request, token: Option<&str>) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_millis();
+        let path = Path::new(dir).join(format!(
+            "{}-{}.log",
+            millis,
+            DUMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let dump = Self {
+            path,
+            token: token
+                .filter(|token| !token.is_empty())
+                .map(str::to_string),
+        };
+
+        let mut text = format!(
+            "{} {}\n",
+            request.method(),
+            request.url()
+        );
+        for (name, value) in request.headers() {
+            let value = if Self::is_secret_header(name) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            text.push_str(&format!("{}: {}\n", name, value));
+        }
+        text.push('\n');
+        if let Some(body) = request
+            .body()
+            .and_then(|body| body.as_bytes())
+        {
+            let gzipped = request
+                .headers()
+                .get(CONTENT_ENCODING)
+                .is_some_and(|encoding| encoding == "gzip");
+            let body = match gzipped {
+                true => Self::gunzip(body)?,
+                false => String::from_utf8_lossy(body).into_owned(),
+            };
+            text.push_str(&Self::redact_payload(&body));
+        }
+        text.push_str("\n\n");
+
+        File::create(&dump.path)?.write_all(dump.redact(&text).as_bytes())?;
+        Ok(dump)
+    }
+
+    /// Appends a received server-sent event as it came over the wire.
+    pub(crate) fn record_event(&self, event: &SseEvent) {
+        let data = event
+            .data
+            .lines()
+            .map(|line| format!("data: {}\n", line))
+            .collect::<String>();
+        self.append(&format!(
+            "event: {}\n{}\n",
+            event.event, data
+        ));
+    }
+
+    /// Appends a response body that came in one piece.
+    pub(crate) fn record_body(&self, body: &Value) { self.append(&format!("{}\n", body)); }
+
+    fn append(&self, text: &str) {
+        let result = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(self.redact(text).as_bytes()));
+        if let Err(error) = result {
+            log::warn!(
+                "Can't write the trace to {}: {}",
+                self.path.display(),
+                error
+            );
+        }
+    }
+
+    fn redact(&self, text: &str) -> String {
+        match &self.token {
+            Some(token) => text.replace(token.as_str(), REDACTED),
+            None => text.to_string(),
+        }
+    }
+
+    fn gunzip(bytes: &[u8]) -> Result<String> {
+        let mut body = String::new();
+        flate2::read::GzDecoder::new(bytes).read_to_string(&mut body)?;
+        Ok(body)
+    }
+
+    fn is_secret_header(name: &HeaderName) -> bool {
+        name == AUTHORIZATION || name == PROXY_AUTHORIZATION || SECRET_HEADERS.contains(&name.as_str())
+    }
+
+    fn redact_payload(body: &str) -> String {
+        fn redact_tokens(value: &mut Value) {
+            match value {
+                Value::Object(map) => {
+                    for (key, value) in map.iter_mut() {
+                        if key == "token" {
+                            *value = Value::String(REDACTED.to_string());
+                        } else {
+                            redact_tokens(value);
+                        }
+                    }
+                }
+                Value::Array(values) => {
+                    values
+                        .iter_mut()
+                        .for_each(redact_tokens)
+                }
+                _ => {}
+            }
+        }
+
+        match serde_json::from_str::<Value>(body) {
+            Ok(mut json) => {
+                redact_tokens(&mut json);
+                serde_json::to_string_pretty(&json).unwrap_or_else(|_| body.to_string())
+            }
+            Err(_) => body.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_dump_holds_payload_but_not_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().join("dumps");
+        let mut request = reqwest::Client::new()
+            .post("https://api.example.com/v1/chat/completions")
+            .header(
+                AUTHORIZATION,
+                HeaderValue::from_static("Bearer sk-secret"),
+            )
+            .header("x-api-key", "sk-secret")
+            .header("x-amz-security-token", "session-secret")
+            .body(r#"{"model":"gpt-test","messages":[{"role":"user","content":"Hello sk-secret"}],"token":"other-secret"}"#)
+            .build()
+            .unwrap();
+        request.headers_mut().insert(
+            "x-trace",
+            HeaderValue::from_static("kept"),
+        );
+
+        let dump = TraceDump::start(
+            dir_path.to_str().unwrap(),
+            &request,
+            Some("sk-secret"),
+        )
+        .unwrap();
+        dump.record_event(&SseEvent {
+            event: "message".to_string(),
+            data: r#"{"choices":[{"delta":{"content":"Hi"}}]}"#.to_string(),
+        });
+
+        let files: Vec<_> = fs::read_dir(&dir_path)
+            .unwrap()
+            .collect();
+        assert_eq!(files.len(), 1);
+        let text = fs::read_to_string(&dump.path).unwrap();
+        assert!(text.starts_with("POST https://api.example.com/v1/chat/completions\n"));
+        assert!(text.contains("\"model\": \"gpt-test\""));
+        assert!(text.contains("Hello [REDACTED]"));
+        assert!(text.contains("authorization: [REDACTED]"));
+        assert!(text.contains("x-api-key: [REDACTED]"));
+        assert!(text.contains("x-amz-security-token: [REDACTED]"));
+        assert!(text.contains("x-trace: kept"));
+        assert!(text.contains("event: message\ndata: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n"));
+        assert!(!text.contains("sk-secret"));
+        assert!(!text.contains("other-secret"));
+        assert!(!text.contains("session-secret"));
+    }
+}
@@ -1,11 +1,29 @@
-use std::{collections::HashMap, str::FromStr};
-
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use base64::{Engine, prelude::BASE64_STANDARD};
 use pyo3::{FromPyObject, pyclass, pymethods};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use strum_macros::{Display, EnumString};
 
-use crate::openai_network_types::{AssistantMessage, ProviderMetadata, Roles, ToolCall};
+pub use crate::openai_network_types::{ResponseFormat, ToolChoice};
+use crate::{
+    cacher::unix_timestamp,
+    openai_network_types::{
+        AssistantMessage,
+        AudioContent,
+        LogprobsContent,
+        ProviderMetadata,
+        Roles,
+        ToolCall,
+    },
+};
 
 #[allow(unused)]
 #[pyclass(eq, eq_int)]
@@ -18,7 +36,7 @@ pub enum PromptMode {
     // OutputPanel, // TODO: review is it necessary
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub(crate) struct CacheEntry {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -50,6 +68,26 @@ pub(crate) struct CacheEntry {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) provider_metadata: Option<ProviderMetadata>,
+
+    /// Unix timestamp of the moment the entry was cached, absent in entries written before it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) created_at: Option<i64>,
+
+    /// Source urls of an assistant answer, kept for display only and never sent back to a provider.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) citations: Option<Vec<String>>,
+
+    /// Token log probabilities of an assistant answer, kept for display only like `citations`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) logprobs: Option<LogprobsContent>,
+
+    /// `AssistantSettings.user` the entry was exchanged under.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) user: Option<String>,
+
+    /// Why the provider stopped generating an assistant answer, e.g. `stop` or `length`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) finish_reason: Option<String>,
 }
 
 impl From<SublimeInputContent> for CacheEntry {
@@ -74,6 +112,11 @@ impl From<SublimeInputContent> for CacheEntry {
             tool_calls: None,
             tool_call_id: content.tool_id,
             provider_metadata: None,
+            created_at: Some(unix_timestamp()),
+            citations: None,
+            logprobs: None,
+            user: None,
+            finish_reason: None,
         }
     }
 }
@@ -87,6 +130,10 @@ impl From<AssistantMessage> for CacheEntry {
         } else {
             (None, None)
         };
+        let thinking = content
+            .reasoning_content
+            .filter(|reasoning| !reasoning.is_empty())
+            .or(thinking);
 
         CacheEntry {
             content: t_content,
@@ -97,25 +144,26 @@ impl From<AssistantMessage> for CacheEntry {
             tool_calls: content.tool_calls,
             tool_call_id: None,
             provider_metadata: content.provider_metadata,
+            created_at: Some(unix_timestamp()),
+            citations: content.citations,
+            logprobs: content.logprobs,
+            user: None,
+            finish_reason: content.finish_reason,
         }
     }
 }
 
 impl CacheEntry {
+    /// Moves the `<think>` block out of `content`, tags included, and returns its text.
+    ///
+    /// Entries cached before kept an empty `<think></think>` in place, readers still handle it.
     fn extract_thinking_part(content: &mut String) -> Option<String> {
-        let re = Regex::new(r"(?s)<think>(.*?)</think>").ok()?;
-        re.captures(&content.clone())
-            .and_then(|caps| {
-                let thinking_part = caps
-                    .get(1)
-                    .map(|m| m.as_str().to_string());
-                if let Some(thinking) = &thinking_part {
-                    *content = content
-                        .replace(&thinking.to_string(), "") // keep tags in place
-                        .to_string();
-                }
-                thinking_part.map(|s| s.to_string())
-            })
+        let re = Regex::new(r"(?s)<think>(.*?)</think>\s*").ok()?;
+        let (block, thinking) = re
+            .captures(content)
+            .map(|caps| (caps[0].to_string(), caps[1].to_string()))?;
+        *content = content.replacen(&block, "", 1);
+        Some(thinking)
     }
 
     pub(crate) fn combined_content(&self) -> String {
@@ -125,6 +173,99 @@ impl CacheEntry {
             (..) => "".to_string(),
         }
     }
+
+    /// `combined_content` preceded by the reasoning `extract_thinking_part` stripped, back in `<think>` tags.
+    pub(crate) fn combined_content_with_thinking(&self) -> String {
+        match self
+            .thinking
+            .as_deref()
+            .map(str::trim)
+            .filter(|thinking| !thinking.is_empty())
+        {
+            Some(thinking) => {
+                format!(
+                    "<think>{}</think>\n{}",
+                    thinking,
+                    self.combined_content()
+                )
+            }
+            None => self.combined_content(),
+        }
+    }
+
+    /// Renders the entry as a `## <Role>` section of an exported conversation.
+    pub(crate) fn to_markdown(&self) -> String {
+        let mut sections = vec![format!("## {}", self.role)];
+
+        if let Some(path) = &self.path {
+            sections.push(format!("`{}`", path));
+        }
+        if let Some(thinking) = self
+            .thinking
+            .as_deref()
+            .map(str::trim)
+            .filter(|thinking| !thinking.is_empty())
+        {
+            sections.push(format!(
+                "<details><summary>Thinking</summary>\n\n{}\n\n</details>",
+                thinking
+            ));
+        }
+        if let Some(content) = self
+            .content
+            .as_deref()
+            .map(|content| content.replace("<think></think>", ""))
+            .filter(|content| !content.trim().is_empty())
+        {
+            sections.push(Self::fence_code(
+                content.trim_matches('\n'),
+            ));
+        }
+        for tool_call in self
+            .tool_calls
+            .iter()
+            .flatten()
+        {
+            sections.push(format!(
+                "Tool call `{}`:\n\n```json\n{}\n```",
+                tool_call.function.name, tool_call.function.arguments
+            ));
+        }
+
+        sections.join("\n\n")
+    }
+
+    /// Fences runs of 4-space indented lines, content that already has fences is left as is.
+    fn fence_code(content: &str) -> String {
+        if content.contains("```") {
+            return content.to_string();
+        }
+
+        let mut output: Vec<String> = Vec::new();
+        let mut code: Vec<&str> = Vec::new();
+        let flush = |output: &mut Vec<String>, code: &mut Vec<&str>| {
+            if !code.is_empty() {
+                output.push("```".to_string());
+                output.extend(
+                    code.drain(..)
+                        .map(|line| line[4 ..].to_string()),
+                );
+                output.push("```".to_string());
+            }
+        };
+
+        for line in content.lines() {
+            if line.starts_with("    ") {
+                code.push(line);
+            } else {
+                flush(&mut output, &mut code);
+                output.push(line.to_string());
+            }
+        }
+        flush(&mut output, &mut code);
+
+        output.join("\n")
+    }
 }
 
 #[pyclass(eq, eq_int)]
@@ -139,6 +280,10 @@ pub enum InputKind {
     Sheet,
     FunctionResult,
     AssistantResponse,
+    /// An image file at `path`, sent as an `image_url` part next to the optional `content` text.
+    Image,
+    /// A wav or mp3 file at `path`, sent as an `input_audio` part, OpenAI only.
+    Audio,
 }
 
 #[pyclass(eq, eq_int)]
@@ -153,6 +298,239 @@ pub enum ReasonEffort {
     High,
 }
 
+/// Which entries `Cacher::trim_to` drops once the history outgrows `max_history_entries`.
+#[pyclass(eq, eq_int)]
+#[derive(EnumString, Display, Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrimStrategy {
+    #[default]
+    #[strum(serialize = "trim_oldest")]
+    TrimOldest,
+    /// Same as `TrimOldest`, but a system message heading the history is kept.
+    #[strum(serialize = "trim_oldest_except_system")]
+    TrimOldestExceptSystem,
+}
+
+/// What a run does once the handler can't keep up and the stream channel is full.
+#[pyclass(eq, eq_int)]
+#[derive(EnumString, Display, Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamOverflow {
+    /// The response reading waits for the handler.
+    #[default]
+    #[strum(serialize = "block")]
+    Block,
+    /// The response reading goes on, the text waiting for the handler is joined into fewer events.
+    #[strum(serialize = "coalesce")]
+    Coalesce,
+}
+
+/// How `Cacher::search` matches the query against entry content, always case-insensitive.
+#[derive(EnumString, Display, Debug, Clone, Copy, Default, PartialEq)]
+pub enum SearchType {
+    #[default]
+    #[strum(serialize = "contains")]
+    Contains,
+    #[strum(serialize = "regex")]
+    Regex,
+    #[strum(serialize = "starts_with")]
+    StartsWith,
+}
+
+/// Static AWS credentials used to SigV4-sign Bedrock requests instead of the `AWS_*` environment.
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Takes precedence over `AssistantSettings.aws_region`.
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default)]
+    pub session_token: Option<String>,
+}
+
+impl std::fmt::Debug for AwsCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AwsCredentials")
+            .field("access_key_id", &self.access_key_id)
+            .field("secret_access_key", &"<redacted>")
+            .field("region", &self.region)
+            .field(
+                "session_token",
+                &self
+                    .session_token
+                    .as_ref()
+                    .map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+impl AwsCredentials {
+    fn from_dict(dict: &HashMap<String, RustyEnum>) -> Option<Self> {
+        let string = |key: &str| {
+            match dict.get(key) {
+                Some(RustyEnum::String(value)) => Some(value.clone()),
+                _ => None,
+            }
+        };
+
+        Some(Self {
+            access_key_id: string("access_key_id")?,
+            secret_access_key: string("secret_access_key")?,
+            region: string("region"),
+            session_token: string("session_token"),
+        })
+    }
+}
+
+/// How `NetworkClient` retries requests answered with 429 or 503.
+#[pyclass]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, the first one included.
+    #[pyo3(get)]
+    pub max_attempts: u8,
+
+    #[pyo3(get)]
+    pub base_delay_ms: u64,
+
+    #[pyo3(get)]
+    pub max_delay_ms: u64,
+
+    /// Randomize each delay between zero and its exponential value.
+    #[pyo3(get)]
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 1000,
+            max_delay_ms: 30_000,
+            jitter: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the attempt following `attempt` (1-based): `base * 2^(attempt - 1)`, capped by `max_delay_ms`.
+    pub(crate) fn delay_for(&self, attempt: u8) -> Duration {
+        let exponent = u32::from(attempt.saturating_sub(1)).min(16);
+        let delay_ms = self
+            .base_delay_ms
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay_ms);
+
+        if self.jitter && delay_ms > 0 {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.subsec_nanos())
+                .unwrap_or_default();
+            Duration::from_millis(u64::from(nanos) % (delay_ms + 1))
+        } else {
+            Duration::from_millis(delay_ms)
+        }
+    }
+
+    fn from_dict(dict: &HashMap<String, RustyEnum>) -> Self {
+        let mut policy = Self::default();
+
+        if let Some(RustyEnum::Int(value)) = dict.get("max_attempts") {
+            policy.max_attempts = (*value).clamp(1, u8::MAX as usize) as u8;
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("base_delay_ms") {
+            policy.base_delay_ms = *value as u64;
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("max_delay_ms") {
+            policy.max_delay_ms = *value as u64;
+        }
+
+        if let Some(RustyEnum::Bool(value)) = dict.get("jitter") {
+            policy.jitter = *value;
+        }
+
+        policy
+    }
+}
+
+/// When `NetworkClient` stops sending requests to a provider that keeps failing.
+///
+/// Only outages count: 5xx and 429 answers, connection failures and timeouts.
+#[pyclass]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub struct CircuitBreakerPolicy {
+    /// Consecutive failures that open the breaker.
+    #[pyo3(get)]
+    pub failure_threshold: u32,
+
+    /// How long the breaker stays open before it lets a single probe through.
+    #[pyo3(get)]
+    pub reset_timeout_ms: u64,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            reset_timeout_ms: 30_000,
+        }
+    }
+}
+
+impl CircuitBreakerPolicy {
+    pub(crate) fn reset_timeout(&self) -> Duration { Duration::from_millis(self.reset_timeout_ms) }
+
+    fn from_dict(dict: &HashMap<String, RustyEnum>) -> Self {
+        let mut policy = Self::default();
+
+        if let Some(RustyEnum::Int(value)) = dict.get("failure_threshold") {
+            policy.failure_threshold = (*value).clamp(1, u32::MAX as usize) as u32;
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("reset_timeout_ms") {
+            policy.reset_timeout_ms = *value as u64;
+        }
+
+        policy
+    }
+}
+
+/// Quota the provider reported in the headers of its latest response.
+///
+/// OpenAI sends `x-ratelimit-*` headers and Anthropic `anthropic-ratelimit-*` ones, whatever
+/// the provider left out stays `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[pyclass]
+pub struct RateLimits {
+    #[pyo3(get)]
+    pub limit_requests: Option<u64>,
+
+    #[pyo3(get)]
+    pub remaining_requests: Option<u64>,
+
+    #[pyo3(get)]
+    pub limit_tokens: Option<u64>,
+
+    #[pyo3(get)]
+    pub remaining_tokens: Option<u64>,
+
+    /// When the request quota refills, as the provider wrote it (`6m0s` or an RFC 3339 date).
+    #[pyo3(get)]
+    pub reset_requests: Option<String>,
+
+    /// When the token quota refills, as the provider wrote it.
+    #[pyo3(get)]
+    pub reset_tokens: Option<String>,
+
+    /// Seconds the provider asked to wait before the next request, from `Retry-After`.
+    #[pyo3(get)]
+    pub retry_after_secs: Option<f64>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[pyclass]
 pub struct SublimeOutputContent {
@@ -164,16 +542,26 @@ pub struct SublimeOutputContent {
 
     #[pyo3(get)]
     pub path: Option<String>,
+
+    /// Source urls to render as footnotes of an assistant answer.
+    #[pyo3(get)]
+    pub citations: Option<Vec<String>>,
+
+    /// Token log probabilities of an assistant answer as a JSON string.
+    #[pyo3(get)]
+    pub logprobs: Option<String>,
 }
 
 impl From<&CacheEntry> for SublimeOutputContent {
     fn from(content: &CacheEntry) -> Self {
         let output_contnt = if let Some(mut tmp) = content.content.clone() {
             if let Some(thinking) = &content.thinking {
-                tmp = tmp.replace(
-                    "<think></think>",
-                    &format!("<think>{}</think>", thinking),
-                );
+                let block = format!("<think>{}</think>", thinking);
+                tmp = if tmp.contains("<think></think>") {
+                    tmp.replace("<think></think>", &block)
+                } else {
+                    format!("{}\n{}", block, tmp)
+                };
             }
             Some(tmp)
         } else {
@@ -183,6 +571,11 @@ impl From<&CacheEntry> for SublimeOutputContent {
             content: output_contnt,
             role: content.role,
             path: content.path.clone(),
+            citations: content.citations.clone(),
+            logprobs: content
+                .logprobs
+                .as_ref()
+                .and_then(|logprobs| serde_json::to_string(logprobs).ok()),
         }
     }
 }
@@ -225,14 +618,162 @@ impl SublimeInputContent {
     }
 
     pub(crate) fn combined_content(&self) -> String {
-        match (&self.path, &self.content) {
-            (Some(path), Some(content)) => format!("Path: `{}`\n{}", path, content),
-            (None, Some(content)) => content.clone(),
+        match (
+            self.input_kind,
+            &self.path,
+            &self.content,
+        ) {
+            (InputKind::Image, Some(path), Some(content)) => format!("Image: `{}`\n{}", path, content),
+            (InputKind::Image, Some(path), None) => format!("Image: `{}`", path),
+            (InputKind::Audio, Some(path), Some(content)) => format!("Audio: `{}`\n{}", path, content),
+            (InputKind::Audio, Some(path), None) => format!("Audio: `{}`", path),
+            (_, Some(path), Some(content)) => format!("Path: `{}`\n{}", path, content),
+            (_, None, Some(content)) => content.clone(),
             (..) => "".to_string(),
         }
     }
 }
 
+impl SublimeInputContent {
+    /// Checks that an `Image` input points to a readable image of a known type within `max_bytes`,
+    /// without reading the file itself.
+    pub(crate) fn validate_image(&self, max_bytes: usize) -> Result<()> {
+        let path = self
+            .path
+            .as_deref()
+            .ok_or_else(|| anyhow!("Image input requires a `path`"))?;
+        image_mime_type(path)?;
+        let size = std::fs::metadata(path)
+            .map_err(|err| {
+                anyhow!(
+                    "Failed to read image `{}`: {}",
+                    path,
+                    err
+                )
+            })?
+            .len();
+        if size > max_bytes as u64 {
+            return Err(anyhow!(
+                "Image `{}` is {} bytes, over the {} bytes limit",
+                path,
+                size,
+                max_bytes
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads the image at `path` into a `data:image/...;base64,` url.
+    pub(crate) fn image_data_url(&self) -> Result<String> {
+        let path = self
+            .path
+            .as_deref()
+            .ok_or_else(|| anyhow!("Image input requires a `path`"))?;
+        let mime_type = image_mime_type(path)?;
+        let bytes = std::fs::read(path).map_err(|err| {
+            anyhow!(
+                "Failed to read image `{}`: {}",
+                path,
+                err
+            )
+        })?;
+        Ok(format!(
+            "data:{};base64,{}",
+            mime_type,
+            BASE64_STANDARD.encode(bytes)
+        ))
+    }
+}
+
+impl SublimeInputContent {
+    /// Checks that an `Audio` input points to a readable file of a known format.
+    pub(crate) fn validate_audio(&self, api_type: ApiType) -> Result<()> {
+        if api_type != ApiType::OpenAi {
+            return Err(anyhow!(
+                "Audio input is only supported by the `open_ai` api type, not `{}`",
+                api_type
+            ));
+        }
+        let path = self
+            .path
+            .as_deref()
+            .ok_or_else(|| anyhow!("Audio input requires a `path`"))?;
+        audio_format(path)?;
+        std::fs::metadata(path).map_err(|err| {
+            anyhow!(
+                "Failed to read audio `{}`: {}",
+                path,
+                err
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Reads the audio file at `path` into a base64 `input_audio` payload.
+    pub(crate) fn audio_content(&self) -> Result<AudioContent> {
+        let path = self
+            .path
+            .as_deref()
+            .ok_or_else(|| anyhow!("Audio input requires a `path`"))?;
+        let format = audio_format(path)?;
+        let bytes = std::fs::read(path).map_err(|err| {
+            anyhow!(
+                "Failed to read audio `{}`: {}",
+                path,
+                err
+            )
+        })?;
+        Ok(AudioContent {
+            data: BASE64_STANDARD.encode(bytes),
+            format: Some(format.to_string()),
+        })
+    }
+}
+
+/// Detects the `input_audio` format from the file extension.
+pub(crate) fn audio_format(path: &str) -> Result<&'static str> {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("wav") => Ok("wav"),
+        Some("mp3") => Ok("mp3"),
+        _ => {
+            Err(anyhow!(
+                "Unsupported audio type: `{}`",
+                path
+            ))
+        }
+    }
+}
+
+/// Largest image attachment sent when `AssistantSettings.max_image_bytes` is not set.
+pub(crate) const DEFAULT_MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+/// Tool call rounds a run may take before it's considered stuck in a loop.
+pub(crate) const DEFAULT_MAX_TOOL_CALL_DEPTH: usize = 5;
+
+/// Detects the image MIME type from the file extension.
+pub(crate) fn image_mime_type(path: &str) -> Result<&'static str> {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("png") => Ok("image/png"),
+        Some("jpg" | "jpeg") => Ok("image/jpeg"),
+        Some("gif") => Ok("image/gif"),
+        Some("webp") => Ok("image/webp"),
+        _ => {
+            Err(anyhow!(
+                "Unsupported image type: `{}`",
+                path
+            ))
+        }
+    }
+}
+
 #[pyclass]
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AssistantSettings {
@@ -292,123 +833,703 @@ pub struct AssistantSettings {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub parallel_tool_calls: Option<bool>,
 
+    /// Azure OpenAI only: value of the `api-version` query parameter.
     #[pyo3(get)]
-    pub timeout: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
 
+    /// Azure OpenAI only: deployment name, used to build the endpoint from a bare resource `url`.
     #[pyo3(get)]
-    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub azure_deployment: Option<String>,
 
+    /// Ollama only: context window size, sent as `options.num_ctx`.
     #[pyo3(get)]
-    pub advertisement: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<usize>,
 
+    /// Ollama only: how long the model stays loaded after the request, e.g. `"5m"`.
     #[pyo3(get)]
-    pub api_type: ApiType,
-}
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<String>,
 
-#[pyclass(eq, eq_int)]
-#[derive(EnumString, Display, Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
-#[serde(rename_all = "snake_case")]
-pub enum ApiType {
-    #[strum(serialize = "open_ai")]
-    OpenAi,
-    #[strum(serialize = "plain_text")]
-    PlainText,
-    #[strum(
-        serialize = "anthropic",
-        serialize = "antropic"
-    )]
-    Anthropic,
-    #[strum(
-        serialize = "open_ai_responses",
-        serialize = "responses"
-    )]
-    OpenAiResponses,
-    #[strum(serialize = "google")]
-    Google,
-}
+    /// Bedrock only: AWS region used for SigV4 signing, falls back to `AWS_REGION`/`AWS_DEFAULT_REGION`.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aws_region: Option<String>,
 
-#[derive(FromPyObject, Clone)]
-pub enum RustyEnum {
-    Bool(bool),
-    Int(usize),
-    Float(f64),
-    String(String),
-}
+    /// Bedrock only: SigV4 service name, `bedrock` unless overridden.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aws_service: Option<String>,
 
-#[pymethods]
-impl AssistantSettings {
-    #[new]
-    #[pyo3(signature = (dict))]
-    pub fn new(dict: HashMap<String, RustyEnum>) -> Self {
-        let mut default = AssistantSettings::default();
+    /// Bedrock only: credentials to sign with instead of the `AWS_*` environment, never persisted.
+    #[serde(default, skip_serializing)]
+    pub aws_auth: Option<AwsCredentials>,
 
-        if let Some(RustyEnum::String(value)) = dict.get("name") {
-            default.name = value.clone();
-        }
+    /// Sampling seed for reproducible outputs, sent as `random_seed` to Mistral.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
 
-        if let Some(RustyEnum::String(value)) = dict
-            .get("output_mode")
-            .or(dict.get("prompt_mode"))
-        {
-            let deprecated_value = if value == "panel" { "view" } else { value };
-            default.output_mode = PromptMode::from_str(deprecated_value).unwrap_or(PromptMode::Phantom);
-        }
+    /// Extra HTTP headers sent with every request, e.g. OpenRouter's `HTTP-Referer` and `X-Title`.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_headers: Option<HashMap<String, String>>,
 
-        if let Some(RustyEnum::String(value)) = dict.get("token") {
-            default.token = Some(value.clone());
-        }
-        if let Some(RustyEnum::String(value)) = dict.get("chat_model") {
-            default.chat_model = value.clone();
-        }
+    /// Extra top-level body fields, e.g. OpenRouter's `provider` and `transforms`; never override known fields.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_body: Option<Map<String, Value>>,
 
-        if let Some(RustyEnum::String(value)) = dict.get("url") {
-            default.url = value.clone();
-        }
+    /// Retry behaviour on 429/500/502/503 and connection failures, `RetryPolicy::default()` when unset.
+    /// The flat `max_retries` and `retry_base_delay_ms` keys set it too, with jitter on.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_policy: Option<RetryPolicy>,
 
-        if let Some(RustyEnum::String(value)) = dict.get("assistant_role") {
-            default.assistant_role = Some(value.clone());
-        }
+    /// Fast-fails requests while the provider is down, off when unset.
+    /// `circuit_breaker: true` turns it on with `CircuitBreakerPolicy::default()`.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<CircuitBreakerPolicy>,
 
-        if let Some(RustyEnum::String(value)) = dict.get("reasoning_effort") {
-            default.reasoning_effort = ReasonEffort::from_str(value).ok();
-        }
+    /// Proxy for this assistant's requests, overriding the one the worker was created with.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_url: Option<String>,
 
-        if let Some(RustyEnum::Float(value)) = dict.get("temperature") {
-            default.temperature = Some(*value);
-        }
+    /// Basic auth user for the proxy.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_user: Option<String>,
 
-        if let Some(RustyEnum::Int(value)) = dict.get("max_tokens") {
-            if !dict.contains_key("max_completion_tokens") {
-                default.max_tokens = Some(*value);
-            }
-        }
+    /// Basic auth password for the proxy.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_pass: Option<String>,
+
+    /// Proxy for https requests, `proxy_url` then proxies only the plain http ones.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_https_url: Option<String>,
+
+    /// PEM bundle trusted on top of the built-in roots, overriding the worker's one.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_cert_path: Option<String>,
+
+    /// Skips the server certificate checks, overriding the worker's choice.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub danger_accept_invalid_certs: Option<bool>,
+
+    /// Seconds to wait for a connection to be established, unbounded when unset.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<usize>,
+
+    /// Seconds to wait for each read from an established connection, unbounded when unset.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_timeout: Option<usize>,
+
+    /// xAI only: request a deferred completion and poll for it instead of streaming.
+    #[pyo3(get)]
+    #[serde(default)]
+    pub deferred: bool,
+
+    /// Seconds a history entry is kept, older entries are pruned on the next read.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_ttl: Option<u64>,
+
+    /// Anthropic only: number of trailing sheet and history blocks marked as prompt cache breakpoints.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_breakpoints: Option<usize>,
+
+    /// Upper bound of the cached history, the oldest entries are dropped past it.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_history_entries: Option<usize>,
+
+    /// Which entries go once `max_history_entries` is exceeded, `trim_oldest` when unset.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trim_strategy: Option<TrimStrategy>,
+
+    /// Endpoint of the `generate_image` tool, derived from `url` when unset.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub images_url: Option<String>,
+
+    /// Model the `generate_image` tool asks for, `gpt-image-1` when unset.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_model: Option<String>,
+
+    /// Sequences that end the completion (OpenAI accepts up to 4), a single string or a list in the settings.
+    #[pyo3(get)]
+    #[serde(
+        alias = "stop",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub stop_sequences: Option<Vec<String>>,
+
+    /// Together/Fireworks/vLLM style multiplicative penalty for repeated tokens.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repetition_penalty: Option<f64>,
+
+    /// Drops tokens less likely than this fraction of the top token, not an OpenAI parameter.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f64>,
+
+    /// Samples from the `k` most likely tokens only, not an OpenAI parameter.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+
+    /// JSON mode (`"json_object"`) or an OpenAI structured outputs `json_schema` with `name`, `schema` and `strict`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+
+    /// Return the log probabilities of the generated tokens.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+
+    /// Most likely alternatives (0-20) returned for every token, requires `logprobs`.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u8>,
+
+    /// Largest image attachment accepted, in bytes. Defaults to `DEFAULT_MAX_IMAGE_BYTES`.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_image_bytes: Option<usize>,
+
+    /// Number of independent completions to request, every choice is answered and cached.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<usize>,
+
+    /// End-user id sent as `user` for OpenAI abuse monitoring, also stamped on the cached entries.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// Tool call rounds allowed in a single run. Defaults to `DEFAULT_MAX_TOOL_CALL_DEPTH`.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tool_call_depth: Option<usize>,
+
+    /// Whether the model may (`"auto"`), must (`"required"`) or must not (`"none"`) call a tool, or `{"name": ...}` of the one it has to call.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+
+    /// When a stream stalls, ask for the rest of the answer in a follow-up request instead of stopping at `[STALLED]`.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume_on_stall: Option<bool>,
+
+    /// JSON schemas by tool name, a tool result that doesn't match its schema goes back to the model as an error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_schemas: Option<HashMap<String, Value>>,
+
+    /// Offers the model the `list_open_files` tool next to the built-in ones.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enable_list_files_tool: Option<bool>,
+
+    /// Directory every request and its raw response are dumped to, secrets redacted.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug_dump_dir: Option<String>,
+
+    /// File every streamed chunk is appended to, one per line, for debugging.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_stream_path: Option<String>,
+
+    /// Gzip request bodies over `COMPRESS_REQUEST_MIN_BYTES`, only for servers known to accept `Content-Encoding: gzip`.
+    #[pyo3(get)]
+    #[serde(default)]
+    pub compress_requests: bool,
+
+    /// Milliseconds the streamed tokens are gathered for before the handler gets them as one, 30 when unset, 0 hands each one over right away.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_coalesce_ms: Option<u64>,
+
+    /// Gathered text that goes to the handler before `stream_coalesce_ms` is up, 512 when unset.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_coalesce_bytes: Option<usize>,
+
+    /// Hands the handler the `StreamEvent` JSON strings instead of the plain text and the old
+    /// `\n[ABORTED]` like markers. Off for this release, so wrappers built on the plain strings keep working.
+    #[pyo3(get)]
+    #[serde(default)]
+    pub stream_events: bool,
+
+    /// Builds the request and streams its JSON payload to the handler instead of sending it.
+    #[pyo3(get)]
+    #[serde(default)]
+    pub dry_run: bool,
+
+    /// Events the stream buffers ahead of the handler, `DEFAULT_STREAM_CHANNEL_CAPACITY` when unset.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_channel_capacity: Option<usize>,
+
+    /// What the stream does once the handler falls `stream_channel_capacity` events behind, `block` when unset.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_overflow: Option<StreamOverflow>,
+
+    /// Mistral only: prepend Mistral's guardrail prompt to the conversation.
+    #[pyo3(get)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub safe_prompt: Option<bool>,
+
+    #[pyo3(get)]
+    pub timeout: usize,
+
+    #[pyo3(get)]
+    pub stream: bool,
+
+    #[pyo3(get)]
+    pub advertisement: bool,
+
+    #[pyo3(get)]
+    pub api_type: ApiType,
+}
+
+#[pyclass(eq, eq_int)]
+#[derive(EnumString, Display, Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiType {
+    #[strum(serialize = "open_ai")]
+    OpenAi,
+    #[strum(serialize = "plain_text")]
+    PlainText,
+    #[strum(
+        serialize = "anthropic",
+        serialize = "antropic"
+    )]
+    Anthropic,
+    #[strum(
+        serialize = "open_ai_responses",
+        serialize = "responses"
+    )]
+    OpenAiResponses,
+    #[strum(
+        serialize = "google",
+        serialize = "gemini"
+    )]
+    Google,
+    #[strum(serialize = "mistral")]
+    Mistral,
+    #[strum(
+        serialize = "azure_open_ai",
+        serialize = "azure"
+    )]
+    AzureOpenAi,
+    #[strum(serialize = "cohere")]
+    Cohere,
+    #[strum(serialize = "ollama")]
+    Ollama,
+    #[strum(serialize = "bedrock")]
+    Bedrock,
+    #[strum(serialize = "groq")]
+    Groq,
+    #[strum(
+        serialize = "azure_inference",
+        serialize = "github_models"
+    )]
+    AzureInference,
+}
+
+impl ApiType {
+    /// Apis that get the cached reasoning back inline: Anthropic extended thinking.
+    pub(crate) fn replays_thinking(&self) -> bool { matches!(self, Self::Anthropic) }
+}
+
+#[derive(FromPyObject, Clone)]
+pub enum RustyEnum {
+    Bool(bool),
+    Int(usize),
+    Float(f64),
+    String(String),
+    List(Vec<String>),
+    Dict(HashMap<String, RustyEnum>),
+}
+
+impl From<RustyEnum> for Value {
+    fn from(value: RustyEnum) -> Self {
+        match value {
+            RustyEnum::Bool(value) => Value::Bool(value),
+            RustyEnum::Int(value) => Value::from(value),
+            RustyEnum::Float(value) => Value::from(value),
+            RustyEnum::String(value) => Value::String(value),
+            RustyEnum::List(value) => Value::from(value),
+            RustyEnum::Dict(value) => {
+                Value::Object(
+                    value
+                        .into_iter()
+                        .map(|(key, value)| (key, value.into()))
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+#[pymethods]
+impl AssistantSettings {
+    #[new]
+    #[pyo3(signature = (dict))]
+    pub fn new(dict: HashMap<String, RustyEnum>) -> Self {
+        let mut default = AssistantSettings::default();
+
+        if let Some(RustyEnum::String(value)) = dict.get("name") {
+            default.name = value.clone();
+        }
+
+        if let Some(RustyEnum::String(value)) = dict
+            .get("output_mode")
+            .or(dict.get("prompt_mode"))
+        {
+            let deprecated_value = if value == "panel" { "view" } else { value };
+            default.output_mode = PromptMode::from_str(deprecated_value).unwrap_or(PromptMode::Phantom);
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("token") {
+            default.token = Some(value.clone());
+        }
+        if let Some(RustyEnum::String(value)) = dict.get("chat_model") {
+            default.chat_model = value.clone();
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("url") {
+            default.url = value.clone();
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("assistant_role") {
+            default.assistant_role = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("reasoning_effort") {
+            default.reasoning_effort = ReasonEffort::from_str(value).ok();
+        }
+
+        if let Some(RustyEnum::Float(value)) = dict.get("temperature") {
+            default.temperature = Some(*value);
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("max_tokens") {
+            if !dict.contains_key("max_completion_tokens") {
+                default.max_tokens = Some(*value);
+            }
+        }
 
         if let Some(RustyEnum::Int(value)) = dict.get("max_completion_tokens") {
             default.max_completion_tokens = Some(*value);
         }
 
-        if let Some(RustyEnum::Int(value)) = dict.get("timeout") {
-            default.timeout = *value;
+        if let Some(RustyEnum::Int(value)) = dict.get("timeout") {
+            default.timeout = *value;
+        }
+
+        if let Some(RustyEnum::Float(value)) = dict.get("top_p") {
+            default.top_p = Some(*value);
+        }
+
+        if let Some(RustyEnum::Float(value)) = dict.get("frequency_penalty") {
+            default.frequency_penalty = Some(*value);
+        }
+
+        if let Some(RustyEnum::Float(value)) = dict.get("presence_penalty") {
+            default.presence_penalty = Some(*value);
+        }
+
+        if let Some(RustyEnum::Bool(value)) = dict.get("tools") {
+            default.tools = Some(*value);
+        }
+
+        if let Some(RustyEnum::Bool(value)) = dict.get("parallel_tool_calls") {
+            default.parallel_tool_calls = Some(*value);
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("api_version") {
+            default.api_version = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("azure_deployment") {
+            default.azure_deployment = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("num_ctx") {
+            default.num_ctx = Some(*value);
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("keep_alive") {
+            default.keep_alive = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("aws_region") {
+            default.aws_region = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("aws_service") {
+            default.aws_service = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::Dict(value)) = dict.get("aws_auth") {
+            default.aws_auth = AwsCredentials::from_dict(value);
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("seed") {
+            default.seed = Some(*value as i64);
+        }
+
+        if let Some(RustyEnum::Dict(value)) = dict.get("extra_headers") {
+            default.extra_headers = Some(
+                value
+                    .iter()
+                    .filter_map(|(key, value)| {
+                        match value {
+                            RustyEnum::String(value) => Some((key.clone(), value.clone())),
+                            _ => None,
+                        }
+                    })
+                    .collect(),
+            );
+        }
+
+        if let Some(RustyEnum::Dict(value)) = dict.get("extra_body") {
+            default.extra_body = Some(
+                value
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone().into()))
+                    .collect(),
+            );
+        }
+
+        if let Some(RustyEnum::Dict(value)) = dict.get("retry_policy") {
+            default.retry_policy = Some(RetryPolicy::from_dict(value));
+        }
+
+        match dict.get("circuit_breaker") {
+            Some(RustyEnum::Dict(value)) => {
+                default.circuit_breaker = Some(CircuitBreakerPolicy::from_dict(value))
+            }
+            Some(RustyEnum::Bool(true)) => default.circuit_breaker = Some(CircuitBreakerPolicy::default()),
+            _ => {}
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("max_retries") {
+            let policy = default
+                .retry_policy
+                .get_or_insert_with(|| {
+                    RetryPolicy {
+                        jitter: true,
+                        ..RetryPolicy::default()
+                    }
+                });
+            policy.max_attempts = (*value).min(u8::MAX as usize - 1) as u8 + 1;
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("retry_base_delay_ms") {
+            let policy = default
+                .retry_policy
+                .get_or_insert_with(|| {
+                    RetryPolicy {
+                        jitter: true,
+                        ..RetryPolicy::default()
+                    }
+                });
+            policy.base_delay_ms = *value as u64;
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("proxy_url") {
+            default.proxy_url = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("proxy_user") {
+            default.proxy_user = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("proxy_pass") {
+            default.proxy_pass = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("proxy_https_url") {
+            default.proxy_https_url = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("ca_cert_path") {
+            default.ca_cert_path = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::Bool(value)) = dict.get("danger_accept_invalid_certs") {
+            default.danger_accept_invalid_certs = Some(*value);
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("connect_timeout") {
+            default.connect_timeout = Some(*value);
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("read_timeout") {
+            default.read_timeout = Some(*value);
+        }
+
+        if let Some(RustyEnum::Bool(value)) = dict.get("deferred") {
+            default.deferred = *value;
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("cache_ttl") {
+            default.cache_ttl = Some(*value as u64);
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("cache_breakpoints") {
+            default.cache_breakpoints = Some(*value);
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("max_history_entries") {
+            default.max_history_entries = Some(*value);
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("trim_strategy") {
+            default.trim_strategy = TrimStrategy::from_str(value).ok();
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("images_url") {
+            default.images_url = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("image_model") {
+            default.image_model = Some(value.clone());
+        }
+
+        match dict
+            .get("stop_sequences")
+            .or_else(|| dict.get("stop"))
+        {
+            Some(RustyEnum::String(value)) => default.stop_sequences = Some(vec![value.clone()]),
+            Some(RustyEnum::List(value)) => default.stop_sequences = Some(value.clone()),
+            _ => {}
+        }
+
+        if let Some(RustyEnum::Float(value)) = dict.get("repetition_penalty") {
+            default.repetition_penalty = Some(*value);
+        }
+
+        if let Some(RustyEnum::Float(value)) = dict.get("min_p") {
+            default.min_p = Some(*value);
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("top_k") {
+            default.top_k = Some(*value);
+        }
+
+        if let Some(value) = dict.get("response_format") {
+            default.response_format = ResponseFormat::from_value(value.clone().into());
         }
 
-        if let Some(RustyEnum::Float(value)) = dict.get("top_p") {
-            default.top_p = Some(*value);
+        if let Some(RustyEnum::Bool(value)) = dict.get("logprobs") {
+            default.logprobs = Some(*value);
         }
 
-        if let Some(RustyEnum::Float(value)) = dict.get("frequency_penalty") {
-            default.frequency_penalty = Some(*value);
+        if let Some(RustyEnum::Int(value)) = dict.get("top_logprobs") {
+            default.top_logprobs = u8::try_from(*value).ok();
         }
 
-        if let Some(RustyEnum::Float(value)) = dict.get("presence_penalty") {
-            default.presence_penalty = Some(*value);
+        if let Some(RustyEnum::Int(value)) = dict.get("max_image_bytes") {
+            default.max_image_bytes = Some(*value);
         }
 
-        if let Some(RustyEnum::Bool(value)) = dict.get("tools") {
-            default.tools = Some(*value);
+        if let Some(RustyEnum::Int(value)) = dict.get("n") {
+            default.n = Some(*value);
         }
 
-        if let Some(RustyEnum::Bool(value)) = dict.get("parallel_tool_calls") {
-            default.parallel_tool_calls = Some(*value);
+        if let Some(RustyEnum::String(value)) = dict.get("user") {
+            default.user = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("max_tool_call_depth") {
+            default.max_tool_call_depth = Some(*value);
+        }
+
+        if let Some(value) = dict.get("tool_choice") {
+            default.tool_choice = ToolChoice::from_value(value.clone().into());
+        }
+
+        if let Some(RustyEnum::Bool(value)) = dict.get("resume_on_stall") {
+            default.resume_on_stall = Some(*value);
+        }
+
+        if let Some(RustyEnum::Dict(schemas)) = dict.get("tool_schemas") {
+            default.tool_schemas = Some(
+                schemas
+                    .iter()
+                    .map(|(name, schema)| {
+                        (
+                            name.clone(),
+                            Value::from(schema.clone()),
+                        )
+                    })
+                    .collect(),
+            );
+        }
+
+        if let Some(RustyEnum::Bool(value)) = dict.get("enable_list_files_tool") {
+            default.enable_list_files_tool = Some(*value);
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("debug_dump_dir") {
+            default.debug_dump_dir = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("log_stream_path") {
+            default.log_stream_path = Some(value.clone());
+        }
+
+        if let Some(RustyEnum::Bool(value)) = dict.get("compress_requests") {
+            default.compress_requests = *value;
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("stream_coalesce_ms") {
+            default.stream_coalesce_ms = Some(*value as u64);
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("stream_coalesce_bytes") {
+            default.stream_coalesce_bytes = Some(*value);
+        }
+
+        if let Some(RustyEnum::Bool(value)) = dict.get("stream_events") {
+            default.stream_events = *value;
+        }
+
+        if let Some(RustyEnum::Bool(value)) = dict.get("dry_run") {
+            default.dry_run = *value;
+        }
+
+        if let Some(RustyEnum::Int(value)) = dict.get("stream_channel_capacity") {
+            default.stream_channel_capacity = Some(*value);
+        }
+
+        if let Some(RustyEnum::String(value)) = dict.get("stream_overflow") {
+            default.stream_overflow = StreamOverflow::from_str(value).ok();
+        }
+
+        if let Some(RustyEnum::Bool(value)) = dict
+            .get("safe_prompt")
+            .or(dict.get("safe_mode"))
+        {
+            default.safe_prompt = Some(*value);
         }
 
         if let Some(RustyEnum::Bool(value)) = dict.get("stream") {
@@ -420,7 +1541,13 @@ impl AssistantSettings {
         }
 
         if let Some(RustyEnum::String(value)) = dict.get("api_type") {
-            default.api_type = ApiType::from_str(value).unwrap_or(ApiType::PlainText);
+            default.api_type = ApiType::from_str(value).unwrap_or_else(|_| {
+                log::warn!(
+                    "Unknown api_type `{}` falls back to `plain_text`, this fallback is deprecated",
+                    value
+                );
+                ApiType::PlainText
+            });
         }
 
         default
@@ -431,6 +1558,60 @@ impl AssistantSettings {
     }
 }
 
+impl AssistantSettings {
+    /// Checks the sampling parameters against the ranges OpenAI documents, reporting every offending field.
+    pub(crate) fn validate(&self) -> std::result::Result<(), Vec<String>> {
+        let ranges = [
+            (
+                "temperature",
+                self.temperature,
+                0.0,
+                2.0,
+            ),
+            ("top_p", self.top_p, 0.0, 1.0),
+            (
+                "frequency_penalty",
+                self.frequency_penalty,
+                -2.0,
+                2.0,
+            ),
+            (
+                "presence_penalty",
+                self.presence_penalty,
+                -2.0,
+                2.0,
+            ),
+        ];
+        let mut errors: Vec<String> = ranges
+            .into_iter()
+            .filter_map(|(name, value, min, max)| {
+                value
+                    .filter(|value| !(min ..= max).contains(value))
+                    .map(|value| {
+                        format!(
+                            "`{}` must be within [{}, {}], got {}",
+                            name, min, max, value
+                        )
+                    })
+            })
+            .collect();
+
+        for (name, value) in [
+            ("max_tokens", self.max_tokens),
+            (
+                "max_completion_tokens",
+                self.max_completion_tokens,
+            ),
+        ] {
+            if value == Some(0) {
+                errors.push(format!("`{}` must be at least 1", name));
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
 impl Default for AssistantSettings {
     fn default() -> Self {
         Self {
@@ -450,6 +1631,58 @@ impl Default for AssistantSettings {
             tools: None,
             timeout: 10,
             parallel_tool_calls: None,
+            api_version: None,
+            azure_deployment: None,
+            num_ctx: None,
+            keep_alive: None,
+            aws_region: None,
+            aws_service: None,
+            aws_auth: None,
+            seed: None,
+            extra_headers: None,
+            extra_body: None,
+            retry_policy: None,
+            circuit_breaker: None,
+            proxy_url: None,
+            proxy_user: None,
+            proxy_pass: None,
+            proxy_https_url: None,
+            ca_cert_path: None,
+            danger_accept_invalid_certs: None,
+            connect_timeout: None,
+            read_timeout: None,
+            deferred: false,
+            cache_ttl: None,
+            cache_breakpoints: None,
+            max_history_entries: None,
+            trim_strategy: None,
+            images_url: None,
+            image_model: None,
+            stop_sequences: None,
+            repetition_penalty: None,
+            min_p: None,
+            top_k: None,
+            response_format: None,
+            logprobs: None,
+            top_logprobs: None,
+            max_image_bytes: None,
+            n: None,
+            user: None,
+            max_tool_call_depth: None,
+            tool_choice: None,
+            resume_on_stall: None,
+            tool_schemas: None,
+            enable_list_files_tool: None,
+            debug_dump_dir: None,
+            log_stream_path: None,
+            compress_requests: false,
+            stream_coalesce_ms: None,
+            stream_coalesce_bytes: None,
+            stream_events: false,
+            dry_run: false,
+            stream_channel_capacity: None,
+            stream_overflow: None,
+            safe_prompt: None,
             stream: true,
             advertisement: true,
             api_type: ApiType::PlainText,
@@ -457,6 +1690,133 @@ impl Default for AssistantSettings {
     }
 }
 
+/// Builds `AssistantSettings` from Rust code, starting from the defaults.
+///
+/// `url` and `model` have no meaningful default and must be set before `build`.
+#[derive(Debug, Clone, Default)]
+pub struct AssistantSettingsBuilder {
+    settings: AssistantSettings,
+    url: Option<String>,
+    chat_model: Option<String>,
+}
+
+impl AssistantSettings {
+    pub fn builder() -> AssistantSettingsBuilder { AssistantSettingsBuilder::default() }
+}
+
+impl AssistantSettingsBuilder {
+    pub fn url(&mut self, value: impl Into<String>) -> &mut Self {
+        self.url = Some(value.into());
+        self
+    }
+
+    pub fn model(&mut self, value: impl Into<String>) -> &mut Self {
+        self.chat_model = Some(value.into());
+        self
+    }
+
+    pub fn name(&mut self, value: impl Into<String>) -> &mut Self {
+        self.settings.name = value.into();
+        self
+    }
+
+    pub fn api_type(&mut self, value: ApiType) -> &mut Self {
+        self.settings.api_type = value;
+        self
+    }
+
+    pub fn output_mode(&mut self, value: PromptMode) -> &mut Self {
+        self.settings.output_mode = value;
+        self
+    }
+
+    pub fn token(&mut self, value: impl Into<String>) -> &mut Self {
+        self.settings.token = Some(value.into());
+        self
+    }
+
+    pub fn assistant_role(&mut self, value: impl Into<String>) -> &mut Self {
+        self.settings.assistant_role = Some(value.into());
+        self
+    }
+
+    pub fn temperature(&mut self, value: f64) -> &mut Self {
+        self.settings.temperature = Some(value);
+        self
+    }
+
+    pub fn top_p(&mut self, value: f64) -> &mut Self {
+        self.settings.top_p = Some(value);
+        self
+    }
+
+    pub fn max_tokens(&mut self, value: usize) -> &mut Self {
+        self.settings.max_tokens = Some(value);
+        self
+    }
+
+    pub fn max_completion_tokens(&mut self, value: usize) -> &mut Self {
+        self.settings
+            .max_completion_tokens = Some(value);
+        self
+    }
+
+    pub fn reasoning_effort(&mut self, value: ReasonEffort) -> &mut Self {
+        self.settings.reasoning_effort = Some(value);
+        self
+    }
+
+    pub fn seed(&mut self, value: i64) -> &mut Self {
+        self.settings.seed = Some(value);
+        self
+    }
+
+    pub fn tools(&mut self, value: bool) -> &mut Self {
+        self.settings.tools = Some(value);
+        self
+    }
+
+    pub fn parallel_tool_calls(&mut self, value: bool) -> &mut Self {
+        self.settings
+            .parallel_tool_calls = Some(value);
+        self
+    }
+
+    pub fn stream(&mut self, value: bool) -> &mut Self {
+        self.settings.stream = value;
+        self
+    }
+
+    pub fn timeout(&mut self, value: usize) -> &mut Self {
+        self.settings.timeout = value;
+        self
+    }
+
+    pub fn advertisement(&mut self, value: bool) -> &mut Self {
+        self.settings.advertisement = value;
+        self
+    }
+
+    pub fn build(&self) -> Result<AssistantSettings> {
+        let url = self
+            .url
+            .clone()
+            .filter(|url| !url.is_empty())
+            .ok_or_else(|| anyhow!("AssistantSettings requires `url`"))?;
+        let chat_model = self
+            .chat_model
+            .clone()
+            .filter(|chat_model| !chat_model.is_empty())
+            .ok_or_else(|| anyhow!("AssistantSettings requires `chat_model`"))?;
+
+        Ok(AssistantSettings {
+            url,
+            chat_model,
+            ..self.settings.clone()
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -483,6 +1843,258 @@ mod tests {
         is_send::<PromptMode>();
     }
 
+    #[test]
+    fn test_new_parses_extra_headers_and_body() {
+        let settings = AssistantSettings::new(HashMap::from([
+            (
+                "extra_headers".to_string(),
+                RustyEnum::Dict(HashMap::from([(
+                    "X-Title".to_string(),
+                    RustyEnum::String("Sublime Text".to_string()),
+                )])),
+            ),
+            (
+                "extra_body".to_string(),
+                RustyEnum::Dict(HashMap::from([(
+                    "provider".to_string(),
+                    RustyEnum::Dict(HashMap::from([(
+                        "allow_fallbacks".to_string(),
+                        RustyEnum::Bool(false),
+                    )])),
+                )])),
+            ),
+        ]));
+
+        assert_eq!(
+            settings
+                .extra_headers
+                .unwrap()
+                .get("X-Title")
+                .map(String::as_str),
+            Some("Sublime Text")
+        );
+        assert_eq!(
+            settings.extra_body.unwrap()["provider"]["allow_fallbacks"],
+            Value::Bool(false)
+        );
+    }
+
+    #[test]
+    fn test_retry_policy_backoff_is_exponential_and_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay_ms: 1000,
+            max_delay_ms: 3000,
+            jitter: false,
+        };
+
+        assert_eq!(
+            policy.delay_for(1),
+            Duration::from_millis(1000)
+        );
+        assert_eq!(
+            policy.delay_for(2),
+            Duration::from_millis(2000)
+        );
+        assert_eq!(
+            policy.delay_for(3),
+            Duration::from_millis(3000)
+        );
+        assert!(
+            RetryPolicy {
+                jitter: true,
+                ..policy
+            }
+            .delay_for(2)
+                <= Duration::from_millis(2000)
+        );
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "retry_policy".to_string(),
+            RustyEnum::Dict(HashMap::from([
+                (
+                    "max_attempts".to_string(),
+                    RustyEnum::Int(5),
+                ),
+                (
+                    "jitter".to_string(),
+                    RustyEnum::Bool(true),
+                ),
+            ])),
+        )]));
+        assert_eq!(
+            settings.retry_policy,
+            Some(RetryPolicy {
+                max_attempts: 5,
+                jitter: true,
+                ..RetryPolicy::default()
+            })
+        );
+
+        let settings = AssistantSettings::new(HashMap::from([
+            (
+                "max_retries".to_string(),
+                RustyEnum::Int(4),
+            ),
+            (
+                "retry_base_delay_ms".to_string(),
+                RustyEnum::Int(250),
+            ),
+        ]));
+        assert_eq!(
+            settings.retry_policy,
+            Some(RetryPolicy {
+                max_attempts: 5,
+                base_delay_ms: 250,
+                jitter: true,
+                ..RetryPolicy::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_circuit_breaker_is_off_unless_set() {
+        assert_eq!(
+            AssistantSettings::default().circuit_breaker,
+            None
+        );
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "circuit_breaker".to_string(),
+            RustyEnum::Bool(true),
+        )]));
+        assert_eq!(
+            settings.circuit_breaker,
+            Some(CircuitBreakerPolicy::default())
+        );
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "circuit_breaker".to_string(),
+            RustyEnum::Dict(HashMap::from([(
+                "failure_threshold".to_string(),
+                RustyEnum::Int(2),
+            )])),
+        )]));
+        assert_eq!(
+            settings.circuit_breaker,
+            Some(CircuitBreakerPolicy {
+                failure_threshold: 2,
+                ..CircuitBreakerPolicy::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_new_parses_tool_schemas() {
+        let settings = AssistantSettings::new(HashMap::from([(
+            "tool_schemas".to_string(),
+            RustyEnum::Dict(HashMap::from([(
+                "get_weather".to_string(),
+                RustyEnum::Dict(HashMap::from([(
+                    "type".to_string(),
+                    RustyEnum::String("object".to_string()),
+                )])),
+            )])),
+        )]));
+        assert_eq!(
+            settings.tool_schemas,
+            Some(HashMap::from([(
+                "get_weather".to_string(),
+                serde_json::json!({"type": "object"}),
+            )]))
+        );
+    }
+
+    #[test]
+    fn test_new_parses_tool_choice() {
+        let settings = AssistantSettings::new(HashMap::from([(
+            "tool_choice".to_string(),
+            RustyEnum::String("none".to_string()),
+        )]));
+        assert_eq!(
+            settings.tool_choice,
+            Some(ToolChoice::None)
+        );
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "tool_choice".to_string(),
+            RustyEnum::Dict(HashMap::from([(
+                "name".to_string(),
+                RustyEnum::String("apply_patch".to_string()),
+            )])),
+        )]));
+        assert_eq!(
+            settings.tool_choice,
+            Some(ToolChoice::Specific(
+                "apply_patch".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_assistant_settings_builder() {
+        let settings = AssistantSettings::builder()
+            .url("http://localhost:1234/v1/chat/completions")
+            .model("qwen2.5-coder")
+            .api_type(ApiType::OpenAi)
+            .token("secret")
+            .temperature(0.2)
+            .max_completion_tokens(2048)
+            .stream(false)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            settings.url,
+            "http://localhost:1234/v1/chat/completions"
+        );
+        assert_eq!(settings.chat_model, "qwen2.5-coder");
+        assert_eq!(settings.api_type, ApiType::OpenAi);
+        assert_eq!(
+            settings.token.as_deref(),
+            Some("secret")
+        );
+        assert_eq!(settings.temperature, Some(0.2));
+        assert_eq!(
+            settings.max_completion_tokens,
+            Some(2048)
+        );
+        assert!(!settings.stream);
+        assert_eq!(
+            settings.timeout,
+            AssistantSettings::default().timeout
+        );
+    }
+
+    #[test]
+    fn test_assistant_settings_builder_requires_url_and_model() {
+        let missing_url = AssistantSettings::builder()
+            .model("gpt-4o")
+            .build();
+        assert_eq!(
+            missing_url
+                .unwrap_err()
+                .to_string(),
+            "AssistantSettings requires `url`"
+        );
+
+        let missing_model = AssistantSettings::builder()
+            .url("https://api.openai.com/v1/chat/completions")
+            .build();
+        assert_eq!(
+            missing_model
+                .unwrap_err()
+                .to_string(),
+            "AssistantSettings requires `chat_model`"
+        );
+
+        let empty_model = AssistantSettings::builder()
+            .url("https://api.openai.com/v1/chat/completions")
+            .model("")
+            .build();
+        assert!(empty_model.is_err());
+    }
+
     #[test]
     fn test_new_api_type_aliases_parse() {
         let settings = AssistantSettings::new(HashMap::from([(
@@ -505,5 +2117,138 @@ mod tests {
             RustyEnum::String("google".to_string()),
         )]));
         assert_eq!(settings.api_type, ApiType::Google);
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "api_type".to_string(),
+            RustyEnum::String("gemini".to_string()),
+        )]));
+        assert_eq!(settings.api_type, ApiType::Google);
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "api_type".to_string(),
+            RustyEnum::String("mistral".to_string()),
+        )]));
+        assert_eq!(settings.api_type, ApiType::Mistral);
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "api_type".to_string(),
+            RustyEnum::String("azure".to_string()),
+        )]));
+        assert_eq!(settings.api_type, ApiType::AzureOpenAi);
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "api_type".to_string(),
+            RustyEnum::String("cohere".to_string()),
+        )]));
+        assert_eq!(settings.api_type, ApiType::Cohere);
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "api_type".to_string(),
+            RustyEnum::String("ollama".to_string()),
+        )]));
+        assert_eq!(settings.api_type, ApiType::Ollama);
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "api_type".to_string(),
+            RustyEnum::String("bedrock".to_string()),
+        )]));
+        assert_eq!(settings.api_type, ApiType::Bedrock);
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "api_type".to_string(),
+            RustyEnum::String("groq".to_string()),
+        )]));
+        assert_eq!(settings.api_type, ApiType::Groq);
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "api_type".to_string(),
+            RustyEnum::String("github_models".to_string()),
+        )]));
+        assert_eq!(
+            settings.api_type,
+            ApiType::AzureInference
+        );
+    }
+
+    #[test]
+    fn test_new_unknown_api_type_falls_back_to_plain_text() {
+        let settings = AssistantSettings::new(HashMap::from([(
+            "api_type".to_string(),
+            RustyEnum::String("responses".to_string()),
+        )]));
+        assert_eq!(
+            settings.api_type,
+            ApiType::OpenAiResponses
+        );
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "api_type".to_string(),
+            RustyEnum::String("open_ai_legacy".to_string()),
+        )]));
+        assert_eq!(settings.api_type, ApiType::PlainText);
+    }
+
+    #[test]
+    fn test_validate_accepts_boundaries() {
+        let mut settings = AssistantSettings::default();
+        assert_eq!(settings.validate(), Ok(()));
+
+        settings.temperature = Some(2.0);
+        settings.top_p = Some(0.0);
+        settings.frequency_penalty = Some(-2.0);
+        settings.presence_penalty = Some(2.0);
+        settings.max_tokens = Some(1);
+        settings.max_completion_tokens = Some(1);
+        assert_eq!(settings.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_reports_every_out_of_range_field() {
+        let settings = AssistantSettings {
+            temperature: Some(2.1),
+            top_p: Some(-0.1),
+            frequency_penalty: Some(-2.5),
+            presence_penalty: Some(3.0),
+            max_tokens: Some(0),
+            ..Default::default()
+        };
+
+        let errors = settings
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "`temperature` must be within [0, 2], got 2.1",
+                "`top_p` must be within [0, 1], got -0.1",
+                "`frequency_penalty` must be within [-2, 2], got -2.5",
+                "`presence_penalty` must be within [-2, 2], got 3",
+                "`max_tokens` must be at least 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_new_parses_stop_sequences() {
+        let settings = AssistantSettings::new(HashMap::from([(
+            "stop_sequences".to_string(),
+            RustyEnum::List(vec!["END".to_string()]),
+        )]));
+        assert_eq!(
+            settings.stop_sequences,
+            Some(vec!["END".to_string()])
+        );
+
+        let settings = AssistantSettings::new(HashMap::from([(
+            "stop".to_string(),
+            RustyEnum::String("</s>".to_string()),
+        )]));
+        assert_eq!(
+            settings.stop_sequences,
+            Some(vec!["</s>".to_string()])
+        );
+
+        let settings = AssistantSettings::new(HashMap::new());
+        assert_eq!(settings.stop_sequences, None);
     }
 }
@@ -1,22 +1,28 @@
 use std::sync::{
     Arc,
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
 use anyhow::Result;
+use futures_util::future::join_all;
 use tokio::{
     join,
-    sync::{Mutex, mpsc},
+    sync::{Mutex, Notify, mpsc},
 };
 
 use crate::{
     cacher::Cacher,
-    network_client::NetworkClient,
+    error::LlmError,
+    models_network_types::ModelInfo,
+    network_client::{CircuitBreaker, ConnectionPoolConfig, NetworkClient, ProxyConfig, TlsConfig},
     runner::LlmRunner,
-    stream_handler::StreamHandler,
-    types::{AssistantSettings, PromptMode, SublimeInputContent},
+    stream_handler::{Coalesce, StreamEvent, StreamHandler},
+    types::{AssistantSettings, PromptMode, RateLimits, StreamOverflow, SublimeInputContent},
 };
 
+/// Events a run buffers ahead of the handler unless `stream_channel_capacity` says otherwise.
+pub const DEFAULT_STREAM_CHANNEL_CAPACITY: usize = 64;
+
 #[allow(unused, dead_code)]
 #[derive(Clone, Debug)]
 pub struct OpenAIWorker {
@@ -27,11 +33,28 @@ pub struct OpenAIWorker {
     pub(crate) contents: Vec<SublimeInputContent>,
     pub(crate) assistant_settings: Option<AssistantSettings>,
     pub(crate) proxy: Option<String>,
+    /// PEM bundle trusted on top of the built-in roots, for TLS intercepting proxies.
+    pub(crate) ca_cert_path: Option<String>,
+    pub(crate) danger_accept_invalid_certs: bool,
     pub(crate) cacher_path: String,
+    /// The temporary directory a `fork_at` worker keeps its history in.
+    fork_dir: Option<Arc<tempfile::TempDir>>,
 
     cacher: Arc<Mutex<Cacher>>,
     cancel_signal: Arc<AtomicBool>,
+    /// Holds the streamed chunks back from the handler while set.
+    pause_signal: Arc<AtomicBool>,
+    /// Wakes the in-flight request of the current run as soon as `cancel` is called.
+    cancel_notify: Arc<Notify>,
     pub(crate) is_alive: Arc<AtomicBool>,
+    last_request_id: Arc<std::sync::Mutex<Option<String>>>,
+    last_rate_limits: Arc<std::sync::Mutex<Option<RateLimits>>>,
+    /// Whitespace-delimited tokens streamed to the handler over all the runs.
+    pub(crate) token_count: Arc<AtomicUsize>,
+    /// Shared with every `NetworkClient` this worker creates, so failures add up across runs.
+    circuit_breaker: CircuitBreaker,
+    /// Workers with equal pool and client settings share one `reqwest::Client`.
+    pool_config: ConnectionPoolConfig,
 }
 
 impl OpenAIWorker {
@@ -43,13 +66,81 @@ impl OpenAIWorker {
             contents: vec![],
             assistant_settings: None,
             proxy,
+            ca_cert_path: None,
+            danger_accept_invalid_certs: false,
             cacher_path: path.clone(),
+            fork_dir: None,
             cacher: Arc::new(Mutex::new(Cacher::new(&path))),
             cancel_signal: Arc::new(AtomicBool::new(false)),
+            pause_signal: Arc::new(AtomicBool::new(false)),
+            cancel_notify: Arc::new(Notify::new()),
             is_alive: Arc::new(AtomicBool::new(false)),
+            last_request_id: Arc::new(std::sync::Mutex::new(None)),
+            last_rate_limits: Arc::new(std::sync::Mutex::new(None)),
+            token_count: Arc::new(AtomicUsize::new(0)),
+            circuit_breaker: CircuitBreaker::default(),
+            pool_config: ConnectionPoolConfig::default(),
         }
     }
 
+    /// Trusts the certificates of `ca_cert_path` on top of the built-in roots, or skips the
+    /// certificate checks altogether, for every client this worker creates.
+    pub fn with_tls(mut self, ca_cert_path: Option<String>, danger_accept_invalid_certs: bool) -> Self {
+        self.ca_cert_path = ca_cert_path;
+        self.danger_accept_invalid_certs = danger_accept_invalid_certs;
+        self
+    }
+
+    /// A worker with the same settings whose history starts as the entries of this one before
+    /// `entry_index` and lives in a fresh temporary directory, to take the conversation elsewhere from there.
+    ///
+    /// The fork has its own cancel, pause and request tracking, only the circuit breaker is shared.
+    /// Its directory is removed once the fork and every clone of it are dropped.
+    pub async fn fork_at(&self, entry_index: usize) -> Result<Self> {
+        let fork_dir = tempfile::Builder::new()
+            .prefix("llm_runner_fork_")
+            .tempdir()?;
+        let cacher_path = fork_dir
+            .path()
+            .to_string_lossy()
+            .into_owned();
+
+        let fork_cacher = Cacher::new(&cacher_path);
+        {
+            let cacher = self.cacher.lock().await;
+            for (_, entry) in cacher
+                .read_indexed_entries::<serde_json::Value>()?
+                .into_iter()
+                .take_while(|(num, _)| *num < entry_index)
+            {
+                fork_cacher.write_entry(&entry)?;
+            }
+            if let Ok(model) = cacher.read_model::<serde_json::Value>() {
+                fork_cacher.write_model(&model)?;
+            }
+        }
+
+        Ok(Self {
+            cacher_path,
+            fork_dir: Some(Arc::new(fork_dir)),
+            cacher: Arc::new(Mutex::new(fork_cacher)),
+            cancel_signal: Arc::new(AtomicBool::new(false)),
+            pause_signal: Arc::new(AtomicBool::new(false)),
+            cancel_notify: Arc::new(Notify::new()),
+            is_alive: Arc::new(AtomicBool::new(false)),
+            last_request_id: Arc::new(std::sync::Mutex::new(None)),
+            last_rate_limits: Arc::new(std::sync::Mutex::new(None)),
+            token_count: Arc::new(AtomicUsize::new(0)),
+            ..self.clone()
+        })
+    }
+
+    /// Streams the answer to `handler` and `extra_sinks` and returns the text of its every choice,
+    /// more than one when `n` asks for alternatives to pick from.
+    ///
+    /// A failure goes to `error_handler` as the `error` event JSON with `stream_events` on, as
+    /// an `LlmRunner error: ...` line otherwise. A cancelled run returns `LlmError::Cancelled`
+    /// without bothering `error_handler`, the stream has already told about it with `Aborted`.
     #[allow(clippy::too_many_arguments)]
     pub async fn run(
         &self,
@@ -60,50 +151,382 @@ impl OpenAIWorker {
         handler: Arc<dyn Fn(String) + Send + Sync + 'static>,
         error_handler: Arc<dyn Fn(String) + Send + Sync + 'static>,
         function_handler: Arc<dyn Fn((String, String)) -> String + Send + Sync + 'static>,
-    ) -> Result<()> {
+        mut extra_sinks: Vec<Arc<dyn Fn(String) + Send + Sync + 'static>>,
+    ) -> Result<Vec<String>> {
+        let provider = match self.network_client(&assistant_settings) {
+            Ok(provider) => provider,
+            Err(e) => {
+                error_handler(error_report(
+                    &LlmError::from(&e),
+                    assistant_settings.stream_events,
+                ));
+                return Err(e);
+            }
+        };
+
         self.is_alive
             .store(true, Ordering::SeqCst);
 
-        let provider = NetworkClient::new(
-            self.proxy.clone(),
-            assistant_settings.timeout,
+        log::debug!(
+            "Running the request of view {}",
+            view_id
         );
+        let capacity = assistant_settings
+            .stream_channel_capacity
+            .unwrap_or(DEFAULT_STREAM_CHANNEL_CAPACITY)
+            .max(1);
+        let (tx, rx) = mpsc::channel(capacity);
+        // With `Coalesce` the relay keeps draining the response side while the handler lags behind.
+        let (rx, relay) = match assistant_settings
+            .stream_overflow
+            .unwrap_or_default()
+        {
+            StreamOverflow::Block => (rx, None),
+            StreamOverflow::Coalesce => {
+                let (relay_tx, relay_rx) = mpsc::channel(capacity);
+                (relay_rx, Some((rx, relay_tx)))
+            }
+        };
+        let relay_fut = async {
+            if let Some((rx, relay_tx)) = relay {
+                StreamHandler::relay_coalescing(rx, relay_tx).await
+            }
+        };
+
+        let stream_log = assistant_settings
+            .log_stream_path
+            .clone()
+            .map(|path| {
+                let (log_tx, log_rx) = mpsc::unbounded_channel();
+                extra_sinks.push(Arc::new(move |data| {
+                    log_tx.send(data).ok();
+                }));
+                (path, log_rx)
+            });
+        let log_fut = async {
+            let Some((path, log_rx)) = stream_log else {
+                return;
+            };
+            if let Err(e) = StreamHandler::append_to_file(log_rx, &path).await {
+                log::warn!(
+                    "Can't log the stream to {}: {}",
+                    path,
+                    e
+                );
+            }
+        };
 
-        let (tx, rx) = mpsc::channel(view_id);
+        let stream_events = assistant_settings.stream_events;
+        let coalesce = Coalesce::new(
+            assistant_settings.stream_coalesce_ms,
+            assistant_settings.stream_coalesce_bytes,
+        );
 
         let store = match prompt_mode {
             PromptMode::View => true,
             PromptMode::Phantom => false,
         };
 
-        let result_fut = LlmRunner::execute(
-            provider,
-            Arc::clone(&self.cacher),
-            contents,
-            assistant_settings,
-            Arc::new(Mutex::new(tx)),
-            Arc::clone(&function_handler),
-            Arc::clone(&self.cancel_signal),
-            store,
-        );
+        let sender = Arc::new(Mutex::new(tx));
+        let result_fut = async {
+            let result = LlmRunner::execute(
+                provider,
+                Arc::clone(&self.cacher),
+                contents,
+                assistant_settings,
+                Arc::clone(&sender),
+                Arc::clone(&function_handler),
+                Arc::clone(&self.cancel_signal),
+                store,
+                0,
+            )
+            .await;
+            let last = match &result {
+                Ok(_) => StreamEvent::Done,
+                Err(e) => StreamEvent::from(e),
+            };
+            sender
+                .lock()
+                .await
+                .send(last)
+                .await
+                .ok();
+            // The handler stops once the last sender is gone.
+            drop(sender);
+            result
+        };
 
-        let handler_fut = StreamHandler::handle_stream_with(rx, handler);
+        #[allow(deprecated)]
+        let handler: Arc<dyn Fn(String) + Send + Sync + 'static> = match stream_events {
+            false => {
+                Arc::new(move |json: String| {
+                    if let Some(text) = StreamEvent::from_json(&json).and_then(|event| event.legacy_text()) {
+                        handler(text)
+                    }
+                })
+            }
+            true => handler,
+        };
+        let handler = match extra_sinks.is_empty() {
+            true => handler,
+            false => {
+                StreamHandler::broadcast(
+                    std::iter::once(handler)
+                        .chain(extra_sinks)
+                        .collect(),
+                )
+            }
+        };
+        // A slow handler blocks the thread it's called on, so it gets a task of its own.
+        let handler_task = tokio::spawn(StreamHandler::handle_stream_counting(
+            rx,
+            handler,
+            Arc::clone(&self.pause_signal),
+            coalesce,
+        ));
 
-        let (runner_result, _) = join!(result_fut, handler_fut);
+        let (runner_result, ..) = join!(result_fut, relay_fut, log_fut);
+        let emitted_tokens = match handler_task.await {
+            Ok(emitted_tokens) => emitted_tokens,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        };
+        self.token_count
+            .fetch_add(emitted_tokens, Ordering::SeqCst);
 
-        if let Err(e) = &runner_result {
-            error_handler(format!("LlmRunner error: {}", e));
+        // What a cancelled run streamed stays in the history, but it's no answer to return.
+        let runner_result = match runner_result {
+            Ok(_)
+                if self
+                    .cancel_signal
+                    .load(Ordering::SeqCst) =>
+            {
+                Err(LlmError::Cancelled)
+            }
+            result => result,
+        };
+        match &runner_result {
+            Ok(_) | Err(LlmError::Cancelled) => {}
+            Err(e) => error_handler(error_report(e, stream_events)),
         }
 
         self.is_alive
             .store(false, Ordering::SeqCst);
 
-        runner_result
+        runner_result.map_err(anyhow::Error::from)
+    }
+
+    /// Runs every request concurrently, each on its own task with an in-memory history, so none
+    /// of them reads or extends the history of this worker or of one another.
+    ///
+    /// `handler` and `error_handler` get the view id of the request along with the data. The results
+    /// come in the order of `requests`.
+    ///
+    /// Each task has its own cancel, pause and request tracking; `cancel` on this worker cancels
+    /// them all, and their streamed tokens add up to `token_count` once the batch is over.
+    pub async fn run_batch(
+        &self,
+        requests: Vec<(
+            usize,
+            Vec<SublimeInputContent>,
+            PromptMode,
+            AssistantSettings,
+        )>,
+        handler: Arc<dyn Fn(usize, String) + Send + Sync + 'static>,
+        error_handler: Arc<dyn Fn(usize, String) + Send + Sync + 'static>,
+        function_handler: Arc<dyn Fn((String, String)) -> String + Send + Sync + 'static>,
+    ) -> Vec<Result<()>> {
+        self.is_alive
+            .store(true, Ordering::SeqCst);
+
+        let workers: Vec<Self> = requests
+            .iter()
+            .map(|_| {
+                Self {
+                    cacher: Arc::new(Mutex::new(Cacher::in_memory())),
+                    cancel_signal: Arc::new(AtomicBool::new(false)),
+                    pause_signal: Arc::new(AtomicBool::new(false)),
+                    cancel_notify: Arc::new(Notify::new()),
+                    is_alive: Arc::new(AtomicBool::new(false)),
+                    last_request_id: Arc::new(std::sync::Mutex::new(None)),
+                    last_rate_limits: Arc::new(std::sync::Mutex::new(None)),
+                    token_count: Arc::new(AtomicUsize::new(0)),
+                    ..self.clone()
+                }
+            })
+            .collect();
+
+        let cancel_link = {
+            let cancel_signal = Arc::clone(&self.cancel_signal);
+            let cancel_notify = Arc::clone(&self.cancel_notify);
+            let workers = workers.clone();
+            tokio::spawn(async move {
+                let cancelled = cancel_notify.notified();
+                tokio::pin!(cancelled);
+                cancelled.as_mut().enable();
+                if !cancel_signal.load(Ordering::SeqCst) {
+                    cancelled.await;
+                }
+                workers
+                    .iter()
+                    .for_each(Self::cancel);
+            })
+        };
+
+        let tasks = requests
+            .into_iter()
+            .zip(workers.clone())
+            .map(
+                |((view_id, contents, prompt_mode, assistant_settings), worker)| {
+                    let handler = Arc::clone(&handler);
+                    let error_handler = Arc::clone(&error_handler);
+                    let function_handler = Arc::clone(&function_handler);
+                    tokio::spawn(async move {
+                        worker
+                            .run(
+                                view_id,
+                                contents,
+                                prompt_mode,
+                                assistant_settings,
+                                Arc::new(move |data| handler(view_id, data)),
+                                Arc::new(move |data| error_handler(view_id, data)),
+                                function_handler,
+                                vec![],
+                            )
+                            .await
+                            .map(|_| ())
+                    })
+                },
+            );
+        let results = join_all(tasks)
+            .await
+            .into_iter()
+            .map(|result| result.map_err(anyhow::Error::from)?)
+            .collect();
+        cancel_link.abort();
+        self.token_count.fetch_add(
+            workers
+                .iter()
+                .map(Self::token_count)
+                .sum(),
+            Ordering::SeqCst,
+        );
+
+        self.is_alive
+            .store(false, Ordering::SeqCst);
+
+        results
+    }
+
+    /// Embeds `texts` through the same proxy and connection pool the chat requests use.
+    pub async fn embed(
+        &self,
+        assistant_settings: &AssistantSettings,
+        texts: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>> {
+        self.network_client(assistant_settings)?
+            .execute_embeddings_request(assistant_settings, texts)
+            .await
+    }
+
+    /// Lists the models available at `assistant_settings.url` through the configured proxy.
+    pub async fn list_models(&self, assistant_settings: &AssistantSettings) -> Result<Vec<ModelInfo>> {
+        self.network_client(assistant_settings)?
+            .list_models(assistant_settings)
+            .await
+    }
+
+    fn network_client(&self, assistant_settings: &AssistantSettings) -> Result<NetworkClient> {
+        let proxy = assistant_settings
+            .proxy_url
+            .clone()
+            .or_else(|| self.proxy.clone())
+            .map(|url| {
+                ProxyConfig {
+                    url,
+                    https_url: assistant_settings
+                        .proxy_https_url
+                        .clone(),
+                    user: assistant_settings
+                        .proxy_user
+                        .clone(),
+                    pass: assistant_settings
+                        .proxy_pass
+                        .clone(),
+                }
+            });
+        let tls = TlsConfig {
+            ca_cert_path: assistant_settings
+                .ca_cert_path
+                .clone()
+                .or_else(|| self.ca_cert_path.clone()),
+            danger_accept_invalid_certs: assistant_settings
+                .danger_accept_invalid_certs
+                .unwrap_or(self.danger_accept_invalid_certs),
+        };
+        let mut provider = NetworkClient::with_shared_client(
+            proxy,
+            tls,
+            assistant_settings.timeout,
+            assistant_settings.connect_timeout,
+            assistant_settings.read_timeout,
+            self.pool_config,
+        )?;
+        provider.last_request_id = Arc::clone(&self.last_request_id);
+        provider.last_rate_limits = Arc::clone(&self.last_rate_limits);
+        provider.circuit_breaker = self.circuit_breaker.clone();
+        provider.cancel_notify = Arc::clone(&self.cancel_notify);
+        Ok(provider)
     }
 
     pub fn cancel(&self) {
         self.cancel_signal
             .store(true, Ordering::SeqCst);
+        self.cancel_notify
+            .notify_waiters();
+        // A paused run would otherwise wait for the resume to finish.
+        self.resume();
+    }
+
+    /// Holds the streamed chunks back from the handler without stopping the request.
+    pub fn pause(&self) {
+        self.pause_signal
+            .store(true, Ordering::SeqCst);
+    }
+
+    /// Hands the chunks held since `pause` to the handler at once and goes on streaming.
+    pub fn resume(&self) {
+        self.pause_signal
+            .store(false, Ordering::SeqCst);
+    }
+
+    /// Request id of the latest provider response (`x-groq-request-id` or `x-request-id`).
+    pub fn last_request_id(&self) -> Option<String> {
+        self.last_request_id
+            .lock()
+            .ok()
+            .and_then(|request_id| request_id.clone())
+    }
+
+    /// Quota the provider reported in the latest response that carried rate-limit headers.
+    pub fn last_rate_limits(&self) -> Option<RateLimits> {
+        self.last_rate_limits
+            .lock()
+            .ok()
+            .and_then(|rate_limits| rate_limits.clone())
+    }
+
+    /// Whitespace-delimited tokens streamed to the handler since the worker was created.
+    pub fn token_count(&self) -> usize {
+        self.token_count
+            .load(Ordering::SeqCst)
+    }
+}
+
+/// What `error_handler` gets for a failed run.
+fn error_report(error: &LlmError, stream_events: bool) -> String {
+    match stream_events {
+        true => StreamEvent::from(error).to_json(),
+        false => format!("LlmRunner error: {}", error),
     }
 }
 
@@ -119,4 +542,582 @@ mod tests {
         is_sync::<OpenAIWorker>();
         is_send::<OpenAIWorker>();
     }
+
+    #[test]
+    fn test_workers_share_client_for_equal_settings() {
+        let first = OpenAIWorker::new(1, "/tmp/first".to_string(), None);
+        let second = OpenAIWorker::new(2, "/tmp/second".to_string(), None);
+        let settings = AssistantSettings::default();
+
+        assert!(Arc::ptr_eq(
+            &first
+                .network_client(&settings)
+                .unwrap()
+                .client,
+            &second
+                .network_client(&settings)
+                .unwrap()
+                .client,
+        ));
+
+        let proxied = OpenAIWorker::new(
+            3,
+            "/tmp/proxied".to_string(),
+            Some("http://127.0.0.1:3128".to_string()),
+        );
+        assert!(!Arc::ptr_eq(
+            &first
+                .network_client(&settings)
+                .unwrap()
+                .client,
+            &proxied
+                .network_client(&settings)
+                .unwrap()
+                .client,
+        ));
+    }
+
+    #[tokio::test(
+        flavor = "multi_thread",
+        worker_threads = 2
+    )]
+    async fn test_slow_handler_does_not_stall_coalescing_stream() {
+        use wiremock::{Mock, MockServer, ResponseTemplate, matchers::method};
+
+        let mut body = String::new();
+        for i in 0 .. 40 {
+            body.push_str(&format!(
+                "data: {{\"model\":\"gpt-4o\",\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"w{i} \
+                 \"}}}}]}}\n\n"
+            ));
+        }
+        body.push_str("data: [DONE]\n\n");
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-type", "text/event-stream")
+                    .set_body_string(body),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let worker = OpenAIWorker::new(
+            1,
+            tmp_dir
+                .path()
+                .to_str()
+                .unwrap()
+                .to_string(),
+            None,
+        );
+        let settings = AssistantSettings {
+            api_type: crate::types::ApiType::OpenAi,
+            url: mock_server.uri(),
+            stream: true,
+            stream_coalesce_ms: Some(0),
+            stream_channel_capacity: Some(2),
+            stream_overflow: Some(StreamOverflow::Coalesce),
+            stream_events: true,
+            ..Default::default()
+        };
+
+        let handled = Arc::new(std::sync::Mutex::new(vec![]));
+        let handled_clone = Arc::clone(&handled);
+        let started = std::time::Instant::now();
+        worker
+            .run(
+                1,
+                vec![],
+                PromptMode::View,
+                settings,
+                Arc::new(move |data| {
+                    std::thread::sleep(std::time::Duration::from_millis(25));
+                    handled_clone
+                        .lock()
+                        .unwrap()
+                        .push(data)
+                }),
+                Arc::new(|_| {}),
+                Arc::new(|_| "".to_string()),
+                vec![],
+            )
+            .await
+            .unwrap();
+
+        // Blocking on the handler would take 40 calls, a second at least.
+        assert!(started.elapsed() < std::time::Duration::from_millis(500));
+        let handled = handled.lock().unwrap();
+        assert!(
+            handled.len() < 10,
+            "{} handler calls",
+            handled.len()
+        );
+        let text: String = handled
+            .iter()
+            .filter_map(|data| StreamEvent::from_json(data))
+            .filter_map(|event| {
+                event
+                    .text()
+                    .map(str::to_string)
+            })
+            .collect();
+        assert_eq!(
+            text,
+            (0 .. 40)
+                .map(|i| format!("w{i} "))
+                .collect::<String>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fork_diverges_after_fork_point() {
+        use wiremock::{Mock, MockServer, ResponseTemplate, matchers::method};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "model": "some_model",
+                    "choices": [{
+                        "index": 0,
+                        "message": {"role": "assistant", "content": "Pong"},
+                        "finish_reason": "stop"
+                    }]
+                })),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let worker = OpenAIWorker::new(
+            1,
+            tmp_dir
+                .path()
+                .to_str()
+                .unwrap()
+                .to_string(),
+            None,
+        );
+        let settings = AssistantSettings {
+            api_type: crate::types::ApiType::OpenAi,
+            url: mock_server.uri(),
+            stream: false,
+            ..Default::default()
+        };
+        async fn ask(worker: &OpenAIWorker, settings: &AssistantSettings, question: &str) {
+            worker
+                .run(
+                    1,
+                    vec![SublimeInputContent {
+                        content: Some(question.to_string()),
+                        path: None,
+                        scope: None,
+                        input_kind: crate::types::InputKind::ViewSelection,
+                        tool_id: None,
+                    }],
+                    PromptMode::View,
+                    settings.clone(),
+                    Arc::new(|_| {}),
+                    Arc::new(|_| {}),
+                    Arc::new(|_| "".to_string()),
+                    vec![],
+                )
+                .await
+                .unwrap();
+        }
+        let history = |worker: &OpenAIWorker| {
+            let cacher = Cacher::new(&worker.cacher_path);
+            cacher
+                .read_entries::<crate::types::CacheEntry>()
+                .unwrap()
+                .into_iter()
+                .map(|entry| {
+                    entry
+                        .content
+                        .unwrap_or_default()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        ask(&worker, &settings, "Ping").await;
+        assert_eq!(history(&worker), vec!["Ping", "Pong"]);
+
+        let fork = worker
+            .fork_at(1)
+            .await
+            .unwrap();
+        assert_ne!(fork.cacher_path, worker.cacher_path);
+        assert_eq!(history(&fork), vec!["Ping"]);
+
+        ask(&fork, &settings, "Ping again").await;
+        assert_eq!(history(&worker), vec!["Ping", "Pong"]);
+        assert_eq!(
+            history(&fork),
+            vec!["Ping", "Ping again", "Pong"]
+        );
+
+        // The history of the fork goes away with the fork.
+        let fork_path = fork.cacher_path.clone();
+        drop(fork);
+        assert!(!std::path::Path::new(&fork_path).exists());
+    }
+
+    #[tokio::test]
+    async fn test_run_batch_runs_every_request() {
+        use wiremock::{Mock, MockServer, ResponseTemplate, matchers::method};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({
+                        "model": "some_model",
+                        "choices": [{
+                            "index": 0,
+                            "message": {"role": "assistant", "content": "Pong"},
+                            "finish_reason": "stop"
+                        }]
+                    }))
+                    .set_delay(std::time::Duration::from_millis(200)),
+            )
+            .expect(5)
+            .mount(&mock_server)
+            .await;
+
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let worker = OpenAIWorker::new(
+            1,
+            tmp_dir
+                .path()
+                .to_str()
+                .unwrap()
+                .to_string(),
+            None,
+        );
+        let settings = AssistantSettings {
+            api_type: crate::types::ApiType::OpenAi,
+            url: mock_server.uri(),
+            stream: false,
+            stream_events: true,
+            ..Default::default()
+        };
+
+        let streamed = Arc::new(std::sync::Mutex::new(vec![]));
+        let streamed_clone = Arc::clone(&streamed);
+        let started = std::time::Instant::now();
+        let results = worker
+            .run_batch(
+                (1 ..= 5)
+                    .map(|view_id| {
+                        (
+                            view_id,
+                            vec![],
+                            PromptMode::View,
+                            settings.clone(),
+                        )
+                    })
+                    .collect(),
+                Arc::new(move |view_id, data| {
+                    streamed_clone
+                        .lock()
+                        .unwrap()
+                        .push((view_id, data))
+                }),
+                Arc::new(|_, _| {}),
+                Arc::new(|_| "".to_string()),
+            )
+            .await;
+
+        assert_eq!(results.len(), 5);
+        assert!(
+            results
+                .iter()
+                .all(Result::is_ok)
+        );
+        assert!(started.elapsed() < std::time::Duration::from_millis(800));
+        let mut view_ids: Vec<usize> = streamed
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, data)| StreamEvent::from_json(data) == Some(StreamEvent::from("Pong")))
+            .map(|(view_id, _)| *view_id)
+            .collect();
+        view_ids.sort();
+        assert_eq!(view_ids, vec![1, 2, 3, 4, 5]);
+        assert_eq!(worker.token_count(), 5);
+        // The histories stay in memory.
+        assert!(
+            std::fs::read_dir(tmp_dir.path())
+                .unwrap()
+                .next()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel_stops_every_run_of_a_batch() {
+        use wiremock::{Mock, MockServer, ResponseTemplate, matchers::method};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({
+                        "model": "some_model",
+                        "choices": [{
+                            "index": 0,
+                            "message": {"role": "assistant", "content": "Pong"},
+                            "finish_reason": "stop"
+                        }]
+                    }))
+                    .set_delay(std::time::Duration::from_secs(5)),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let worker = OpenAIWorker::new(
+            1,
+            tmp_dir
+                .path()
+                .to_str()
+                .unwrap()
+                .to_string(),
+            None,
+        );
+        let settings = AssistantSettings {
+            api_type: crate::types::ApiType::OpenAi,
+            url: mock_server.uri(),
+            stream: false,
+            ..Default::default()
+        };
+
+        let canceller = worker.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            canceller.cancel();
+        });
+        let started = std::time::Instant::now();
+        let results = worker
+            .run_batch(
+                (1 ..= 3)
+                    .map(|view_id| {
+                        (
+                            view_id,
+                            vec![],
+                            PromptMode::View,
+                            settings.clone(),
+                        )
+                    })
+                    .collect(),
+                Arc::new(|_, _| {}),
+                Arc::new(|_, _| {}),
+                Arc::new(|_| "".to_string()),
+            )
+            .await;
+
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        assert_eq!(results.len(), 3);
+        assert_eq!(worker.token_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_run_returns_cancelled_error() {
+        use wiremock::{Mock, MockServer, ResponseTemplate, matchers::method};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({
+                        "model": "some_model",
+                        "choices": [{
+                            "index": 0,
+                            "message": {"role": "assistant", "content": "Pong"},
+                            "finish_reason": "stop"
+                        }]
+                    }))
+                    .set_delay(std::time::Duration::from_secs(5)),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let worker = OpenAIWorker::new(
+            1,
+            tmp_dir
+                .path()
+                .to_str()
+                .unwrap()
+                .to_string(),
+            None,
+        );
+        let settings = AssistantSettings {
+            api_type: crate::types::ApiType::OpenAi,
+            url: mock_server.uri(),
+            stream: false,
+            stream_events: true,
+            ..Default::default()
+        };
+
+        let canceller = worker.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            canceller.cancel();
+        });
+        let streamed = Arc::new(std::sync::Mutex::new(vec![]));
+        let errors = Arc::new(std::sync::Mutex::new(vec![]));
+        let result = {
+            let streamed = Arc::clone(&streamed);
+            let errors = Arc::clone(&errors);
+            worker
+                .run(
+                    1,
+                    vec![],
+                    PromptMode::View,
+                    settings,
+                    Arc::new(move |data| {
+                        streamed
+                            .lock()
+                            .unwrap()
+                            .push(data)
+                    }),
+                    Arc::new(move |data| {
+                        errors
+                            .lock()
+                            .unwrap()
+                            .push(data)
+                    }),
+                    Arc::new(|_| "".to_string()),
+                    vec![],
+                )
+                .await
+        };
+
+        assert_eq!(
+            result
+                .unwrap_err()
+                .downcast_ref::<LlmError>(),
+            Some(&LlmError::Cancelled)
+        );
+        assert!(
+            errors
+                .lock()
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            streamed
+                .lock()
+                .unwrap()
+                .contains(&StreamEvent::Aborted.to_json())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_consecutive_runs_reuse_client() {
+        use wiremock::{Mock, MockServer, ResponseTemplate, matchers::method};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "model": "some_model",
+                    "choices": [{
+                        "index": 0,
+                        "message": {"role": "assistant", "content": "Pong"},
+                        "finish_reason": "stop"
+                    }]
+                })),
+            )
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let worker = OpenAIWorker::new(
+            1,
+            tmp_dir
+                .path()
+                .to_str()
+                .unwrap()
+                .to_string(),
+            None,
+        );
+        let mut settings = AssistantSettings {
+            api_type: crate::types::ApiType::OpenAi,
+            url: mock_server.uri(),
+            stream: false,
+            ..Default::default()
+        };
+        // No other test builds a client with this read timeout.
+        settings.read_timeout = Some(4_242);
+
+        let mut clients = vec![];
+        for _ in 0 .. 2 {
+            worker
+                .run(
+                    1,
+                    vec![],
+                    PromptMode::View,
+                    settings.clone(),
+                    Arc::new(|_| {}),
+                    Arc::new(|_| {}),
+                    Arc::new(|_| String::new()),
+                    vec![],
+                )
+                .await
+                .unwrap();
+            clients.push(
+                worker
+                    .network_client(&settings)
+                    .unwrap()
+                    .client,
+            );
+        }
+
+        assert!(Arc::ptr_eq(&clients[0], &clients[1]));
+    }
+
+    #[tokio::test]
+    async fn test_run_reports_unusable_proxy_instead_of_going_direct() {
+        let worker = OpenAIWorker::new(
+            1,
+            "/tmp/bad_proxy".to_string(),
+            Some("ftp://proxy.local:21".to_string()),
+        );
+        let errors = Arc::new(std::sync::Mutex::new(vec![]));
+        let errors_clone = Arc::clone(&errors);
+
+        let result = worker
+            .run(
+                1,
+                vec![],
+                PromptMode::View,
+                AssistantSettings::default(),
+                Arc::new(|_| {}),
+                Arc::new(move |error| {
+                    errors_clone
+                        .lock()
+                        .unwrap()
+                        .push(error)
+                }),
+                Arc::new(|_| String::new()),
+                vec![],
+            )
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec!["LlmRunner error: Unsupported proxy scheme `ftp` in `ftp://proxy.local:21`".to_string()]
+        );
+        assert!(
+            !worker
+                .is_alive
+                .load(Ordering::SeqCst)
+        );
+    }
 }
@@ -1,6 +1,9 @@
 import asyncio
+import json
 import os
+import threading
 import time
+from http.server import BaseHTTPRequestHandler, HTTPServer
 from typing import List
 
 import pytest
@@ -12,6 +15,19 @@ from llm_runner import (
     Worker,  # type: ignore
     ReasonEffort,  # type: ignore
     ApiType,  # type: ignore
+    TrimStrategy,  # type: ignore
+    import_cache,  # type: ignore
+    list_models,  # type: ignore
+    LlmErrorKind,  # type: ignore
+    AuthenticationError,  # type: ignore
+    RateLimitError,  # type: ignore
+    ResponseParseError,  # type: ignore
+    read_all_cache,  # type: ignore
+    read_cache_entry,  # type: ignore
+    read_tokens_count,  # type: ignore
+    register_tool,  # type: ignore
+    unregister_tool,  # type: ignore
+    update_cache_entry,  # type: ignore
 )
 
 
@@ -72,6 +88,26 @@ def test_assistant_settings():
     assert settings.api_type == ApiType.OpenAi
 
 
+def test_assistant_settings_extended_sampling():
+    settings = AssistantSettings(
+        {
+            'name': 'Local',
+            'chat_model': 'qwen2.5-coder',
+            'stop': ['</s>', '###'],
+            'repetition_penalty': 1.1,
+            'min_p': 0.05,
+            'top_k': 40,
+        }
+    )
+
+    assert settings.stop_sequences == ['</s>', '###']
+    assert settings.repetition_penalty == 1.1
+    assert settings.min_p == 0.05
+    assert settings.top_k == 40
+    assert AssistantSettings({'stop': '</s>'}).stop_sequences == ['</s>']
+    assert AssistantSettings({'stop_sequences': ['END']}).stop_sequences == ['END']
+
+
 def test_assistant_settings_real():
     dicttt = {
         'advertisement': False,
@@ -98,6 +134,63 @@ def test_assistant_settings_real():
     assert settings.api_type == ApiType.OpenAi
 
 
+def test_assistant_settings_history_limit():
+    settings = AssistantSettings(
+        {
+            'name': 'Limited',
+            'chat_model': 'gpt-4o-mini',
+            'max_history_entries': 20,
+            'trim_strategy': 'trim_oldest_except_system',
+        }
+    )
+
+    assert settings.max_history_entries == 20
+    assert settings.trim_strategy == TrimStrategy.TrimOldestExceptSystem
+
+
+def test_import_cache(tmp_path):
+    imported = import_cache(
+        str(tmp_path),
+        '[{"role": "user", "content": "Hi"}, {"role": "assistant", "content": "Hello"}]',
+    )
+
+    assert imported == 2
+    assert [entry.content for entry in read_all_cache(str(tmp_path))] == ['Hi', 'Hello']
+
+    with pytest.raises(ValueError):
+        import_cache(str(tmp_path), '[{"role": "assistant", "content": null}]')
+
+
+def test_update_cache_entry(tmp_path):
+    import_cache(
+        str(tmp_path),
+        '[{"role": "user", "content": "Hi"}, {"role": "assistant", "content": "Hello"}]',
+    )
+
+    update_cache_entry(str(tmp_path), 0, SublimeInputContent(InputKind.ViewSelection, 'Hey'))
+
+    assert read_cache_entry(str(tmp_path), 0).content == 'Hey'
+    assert read_cache_entry(str(tmp_path), 1).content == 'Hello'
+    assert read_cache_entry(str(tmp_path), 2) is None
+    with pytest.raises(RuntimeError):
+        update_cache_entry(str(tmp_path), 2, SublimeInputContent(InputKind.ViewSelection, 'late'))
+
+
+def test_read_tokens_count_without_usage(tmp_path):
+    assert read_tokens_count(str(tmp_path)) is None
+
+
+def test_register_tool_validates_schema():
+    register_tool('lookup_symbol', 'Find a symbol definition', '{"type": "object", "properties": {}}')
+    unregister_tool('lookup_symbol')
+
+    with pytest.raises(ValueError):
+        register_tool('apply_patch', 'Shadows a built-in', '{"type": "object"}')
+
+    with pytest.raises(ValueError):
+        register_tool('broken', 'Not JSON', '{')
+
+
 def test_assistant_settings_supports_new_provider_types():
     anthropic = AssistantSettings(
         {
@@ -176,6 +269,7 @@ def test_python_worker_plain_run():
         'url': 'https://api.openai.com/v1/chat/completions',
         'token': os.getenv('OPENAI_API_KEY'),
         'stream': False,
+        'stream_events': True,
         'advertisement': False,
     }
 
@@ -186,6 +280,7 @@ def test_python_worker_plain_run():
     time.sleep(2)
 
     assert some_list
+    assert json.loads(some_list[-1]) == {'event': 'done'}
 
 
 def test_python_worker_sse_run():
@@ -334,3 +429,213 @@ async def test_python_worker_sse_function_run_cancel():
     with open(f'{PATH}chat_history.jl', 'w') as _:
         # Opening the file with 'w' mode truncates the file, clearing its contents
         pass
+
+
+def test_python_worker_awaits_async_function_handler(tmp_path):
+    responses = [
+        {
+            'model': 'some_model',
+            'choices': [
+                {
+                    'index': 0,
+                    'message': {
+                        'role': 'assistant',
+                        'content': None,
+                        'tool_calls': [
+                            {
+                                'id': 'call_1',
+                                'type': 'function',
+                                'function': {'name': 'lookup', 'arguments': '{}'},
+                            }
+                        ],
+                    },
+                    'finish_reason': 'tool_calls',
+                }
+            ],
+        },
+        {
+            'model': 'some_model',
+            'choices': [
+                {
+                    'index': 0,
+                    'message': {'role': 'assistant', 'content': 'Done'},
+                    'finish_reason': 'stop',
+                }
+            ],
+        },
+    ]
+    requests: List[dict] = []
+
+    class Handler(BaseHTTPRequestHandler):
+        def do_POST(self):
+            length = int(self.headers['Content-Length'])
+            requests.append(json.loads(self.rfile.read(length)))
+            body = json.dumps(responses[min(len(requests), len(responses)) - 1]).encode()
+            self.send_response(200)
+            self.send_header('Content-Type', 'application/json')
+            self.send_header('Content-Length', str(len(body)))
+            self.end_headers()
+            self.wfile.write(body)
+
+        def log_message(self, *args):
+            pass
+
+    server = HTTPServer(('127.0.0.1', 0), Handler)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+
+    awaited: List[str] = []
+
+    async def async_function_handler(name: str, args: str) -> str:
+        await asyncio.sleep(0.01)
+        awaited.append(name)
+        return 'Async success'
+
+    settings = AssistantSettings(
+        {
+            'name': 'TEST',
+            'chat_model': 'some_model',
+            'api_type': 'open_ai',
+            'url': f'http://127.0.0.1:{server.server_port}/v1/chat/completions',
+            'token': 'dummy-token',
+            'stream': False,
+            'advertisement': False,
+        }
+    )
+    contents = SublimeInputContent(InputKind.ViewSelection, 'Call the lookup function')
+
+    worker = Worker(window_id=102, path=str(tmp_path))
+    worker.run_sync(
+        1,
+        PromptMode.Phantom,
+        [contents],
+        settings,
+        lambda _: None,
+        lambda _: None,
+        async_function_handler,
+    )
+    server.shutdown()
+
+    assert awaited == ['lookup']
+    tool_result = requests[1]['messages'][-1]
+    assert tool_result['role'] == 'tool'
+    assert tool_result['content'][0]['text'] == 'Async success'
+
+
+@pytest.mark.parametrize(
+    'status, headers, body, exception, kind',
+    [
+        (
+            401,
+            {},
+            b'{"error": {"message": "Incorrect API key provided"}}',
+            AuthenticationError,
+            LlmErrorKind.Http,
+        ),
+        (
+            429,
+            {'Retry-After': '7'},
+            b'{"error": {"message": "Rate limit reached"}}',
+            RateLimitError,
+            LlmErrorKind.Http,
+        ),
+        (200, {}, b'{"data": [', ResponseParseError, LlmErrorKind.Serde),
+    ],
+)
+def test_list_models_raises_typed_errors(status, headers, body, exception, kind):
+    class Handler(BaseHTTPRequestHandler):
+        def do_GET(self):
+            self.send_response(status)
+            self.send_header('Content-Type', 'application/json')
+            self.send_header('Content-Length', str(len(body)))
+            for name, value in headers.items():
+                self.send_header(name, value)
+            self.end_headers()
+            self.wfile.write(body)
+
+        def log_message(self, *args):
+            pass
+
+    server = HTTPServer(('127.0.0.1', 0), Handler)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    settings = AssistantSettings(
+        {
+            'name': 'TEST',
+            'chat_model': 'some_model',
+            'url': f'http://127.0.0.1:{server.server_port}/v1/chat/completions',
+            'token': 'dummy-token',
+        }
+    )
+
+    with pytest.raises(exception) as error:
+        list_models(settings)
+    server.shutdown()
+
+    assert error.value.kind == kind
+    if status == 429:
+        assert error.value.args[1:] == (429, 7.0)
+
+
+@pytest.mark.parametrize(
+    'status, headers, exception',
+    [
+        (401, {}, AuthenticationError),
+        (429, {'Retry-After': '7'}, RateLimitError),
+    ],
+)
+def test_run_sync_raises_typed_errors(tmp_path, status, headers, exception):
+    body = b'{"error": {"message": "Request rejected"}}'
+
+    class Handler(BaseHTTPRequestHandler):
+        def do_POST(self):
+            self.rfile.read(int(self.headers['Content-Length']))
+            self.send_response(status)
+            self.send_header('Content-Type', 'application/json')
+            self.send_header('Content-Length', str(len(body)))
+            for name, value in headers.items():
+                self.send_header(name, value)
+            self.end_headers()
+            self.wfile.write(body)
+
+        def log_message(self, *args):
+            pass
+
+    server = HTTPServer(('127.0.0.1', 0), Handler)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    settings = AssistantSettings(
+        {
+            'name': 'TEST',
+            'chat_model': 'some_model',
+            'api_type': 'open_ai',
+            'url': f'http://127.0.0.1:{server.server_port}/v1/chat/completions',
+            'token': 'dummy-token',
+            'stream': False,
+            'advertisement': False,
+            'max_retries': 0,
+            'stream_events': True,
+        }
+    )
+    contents = SublimeInputContent(InputKind.ViewSelection, 'Hi')
+    errors: List[str] = []
+
+    worker = Worker(window_id=103, path=str(tmp_path))
+    with pytest.raises(exception) as error:
+        worker.run_sync(
+            1,
+            PromptMode.View,
+            [contents],
+            settings,
+            lambda _: None,
+            errors.append,
+            lambda _name, _args: '',
+        )
+    server.shutdown()
+
+    assert error.value.kind == LlmErrorKind.Http
+    assert error.value.args[1] == status
+    event = json.loads(errors[0])
+    assert event['event'] == 'error'
+    assert event['data']['kind'] == 'http'
+    assert event['data']['status'] == status
+    if status == 429:
+        assert error.value.args[2] == 7.0
+        assert event['data']['retry_after'] == 7.0
@@ -7,7 +7,12 @@ use std::{
 };
 
 use common::mocks::{RecordedSequentialResponder, SequentialResponder, SseEvent, sse_response};
-use llm_runner::{types::*, worker::*};
+use llm_runner::{
+    error::{LlmError, LlmErrorKind},
+    stream_handler::StreamEvent,
+    types::*,
+    worker::*,
+};
 // use reqwest::header::CONTENT_TYPE;
 use serde_json::{Value, json};
 use tempfile::TempDir;
@@ -16,7 +21,7 @@ use wiremock::{
     Mock,
     MockServer,
     ResponseTemplate,
-    matchers::{method, path},
+    matchers::{body_partial_json, header, method, path},
 };
 
 #[tokio::test]
@@ -63,6 +68,9 @@ async fn test_run_chact_method_with_mock_server() {
     assistant_settings.token = Some("dummy-token".to_string());
     assistant_settings.chat_model = "some_model".to_string();
     assistant_settings.stream = false;
+    assistant_settings.stream_events = true;
+    let stream_log = format!("{}/stream.log", tmp_dir);
+    assistant_settings.log_stream_path = Some(stream_log.clone());
 
     let prompt_mode = PromptMode::View;
 
@@ -74,15 +82,31 @@ async fn test_run_chact_method_with_mock_server() {
         tool_id: None,
     };
 
+    let output = Arc::new(Mutex::new(vec![]));
+    let output_clone = Arc::clone(&output);
+    let logged = Arc::new(Mutex::new(vec![]));
+    let logged_clone = Arc::clone(&logged);
+
     let result = worker
         .run(
             1,
             vec![contents],
             prompt_mode,
             assistant_settings,
-            Arc::new(|_| {}),
+            Arc::new(move |s| {
+                output_clone
+                    .lock()
+                    .unwrap()
+                    .push(s)
+            }),
             Arc::new(|_| {}),
             Arc::new(|_| "".to_string()),
+            vec![Arc::new(move |s| {
+                logged_clone
+                    .lock()
+                    .unwrap()
+                    .push(s)
+            })],
         )
         .await;
 
@@ -91,6 +115,25 @@ async fn test_run_chact_method_with_mock_server() {
         "Expected Ok, got Err: {:?}",
         result
     );
+    assert_eq!(
+        *output.lock().unwrap(),
+        vec![
+            StreamEvent::from("Some Content").to_json(),
+            StreamEvent::Done.to_json()
+        ]
+    );
+    assert_eq!(
+        *output.lock().unwrap(),
+        *logged.lock().unwrap()
+    );
+    assert_eq!(
+        fs::read_to_string(&stream_log).unwrap(),
+        format!(
+            "{}\n{}\n",
+            StreamEvent::from("Some Content").to_json(),
+            StreamEvent::Done.to_json()
+        )
+    );
     assert!(fs::remove_dir_all(tmp_dir).is_ok())
 }
 
@@ -144,6 +187,7 @@ async fn test_run_tool_method_with_mock_server() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "".to_string()),
+            vec![],
         )
         .await;
 
@@ -155,6 +199,246 @@ async fn test_run_tool_method_with_mock_server() {
     assert!(fs::remove_dir_all(tmp_dir).is_ok())
 }
 
+#[tokio::test]
+async fn test_run_stops_at_max_tool_call_depth() {
+    let tmp_dir = TempDir::new().unwrap();
+    let worker = OpenAIWorker::new(
+        1,
+        tmp_dir
+            .path()
+            .to_str()
+            .unwrap()
+            .to_string(),
+        None,
+    );
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({
+                "model": "some_model",
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "tool_calls": [{
+                            "id": "call_1",
+                            "type": "function",
+                            "function": {
+                                "name": "read_region_content",
+                                "arguments": "{\"file_path\": \"/a\", \"region\": {\"a\": 1, \"b\": 2}}"
+                            }
+                        }]
+                    },
+                    "finish_reason": "tool_calls"
+                }]
+            })),
+        )
+        .expect(3)
+        .mount(&mock_server)
+        .await;
+
+    let assistant_settings = AssistantSettings {
+        url: mock_server.uri(),
+        token: Some("dummy-token".to_string()),
+        chat_model: "some_model".to_string(),
+        stream: false,
+        tools: Some(true),
+        api_type: ApiType::OpenAi,
+        max_tool_call_depth: Some(2),
+        ..Default::default()
+    };
+
+    let calls = Arc::new(Mutex::new(0));
+    let calls_clone = Arc::clone(&calls);
+    let result = worker
+        .run(
+            1,
+            vec![test_view_selection_input(
+                "Loop forever",
+            )],
+            PromptMode::View,
+            assistant_settings,
+            Arc::new(|_| {}),
+            Arc::new(|_| {}),
+            Arc::new(move |_| {
+                *calls_clone.lock().unwrap() += 1;
+                "Success".to_string()
+            }),
+            vec![],
+        )
+        .await;
+
+    let error = result.unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<LlmError>(),
+        Some(&LlmError::ToolDepthExceeded)
+    );
+    assert_eq!(
+        error.to_string(),
+        "Tool call depth exceeded"
+    );
+    assert_eq!(*calls.lock().unwrap(), 2);
+}
+
+#[tokio::test]
+async fn test_run_fetches_url_for_builtin_handler() {
+    let tmp_dir = TempDir::new().unwrap();
+    let worker = OpenAIWorker::new(
+        1,
+        tmp_dir
+            .path()
+            .to_str()
+            .unwrap()
+            .to_string(),
+        None,
+    );
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/page"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_string("<html><body>Rust 1.85 is out</body></html>"),
+        )
+        .mount(&mock_server)
+        .await;
+    let responder = RecordedSequentialResponder::new(vec![
+        ResponseTemplate::new(200).set_body_json(json!({
+            "model": "some_model",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {
+                            "name": "fetch_url",
+                            "arguments": json!({"url": format!("{}/page", mock_server.uri()), "max_bytes": 31}).to_string()
+                        }
+                    }]
+                },
+                "finish_reason": "tool_calls"
+            }]
+        })),
+        ResponseTemplate::new(200).set_body_json(json!({
+            "model": "some_model",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Rust 1.85 is out."},
+                "finish_reason": "stop"
+            }]
+        })),
+    ]);
+    Mock::given(method("POST"))
+        .respond_with(responder.clone())
+        .expect(2)
+        .mount(&mock_server)
+        .await;
+
+    let assistant_settings = AssistantSettings {
+        url: mock_server.uri(),
+        token: Some("dummy-token".to_string()),
+        chat_model: "some_model".to_string(),
+        stream: false,
+        tools: Some(true),
+        api_type: ApiType::OpenAi,
+        ..Default::default()
+    };
+
+    worker
+        .run(
+            1,
+            vec![test_view_selection_input("What's new?")],
+            PromptMode::View,
+            assistant_settings,
+            Arc::new(|_| {}),
+            Arc::new(|_| {}),
+            Arc::new(|_| "__builtin__".to_string()),
+            vec![],
+        )
+        .await
+        .unwrap();
+
+    let request_bodies = responder.recorded_json_bodies();
+    let tool_message = as_array(&request_bodies[1], "messages")
+        .iter()
+        .find(|message| message["role"] == "tool")
+        .unwrap();
+    assert_eq!(tool_message["tool_call_id"], "call_1");
+    assert_eq!(
+        tool_message["content"][0]["text"],
+        "<html><body>Rust 1.85 is out</body></html>"[.. 31]
+    );
+}
+
+#[tokio::test]
+async fn test_run_through_authenticated_proxy() {
+    let temp_dir = TempDir::new().unwrap();
+    let worker = OpenAIWorker::new(
+        1,
+        temp_dir
+            .path()
+            .to_string_lossy()
+            .into_owned(),
+        None,
+    );
+
+    // The mock server plays the proxy: plain http requests reach it in absolute form.
+    let proxy_server = MockServer::start().await;
+    let _mock = Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .and(header(
+            "proxy-authorization",
+            "Basic dXNlcjpwYXNz",
+        ))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({
+                "model": "some_model",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "Proxied"},
+                    "finish_reason": "stop"
+                }]
+            })),
+        )
+        .expect(1)
+        .mount(&proxy_server)
+        .await;
+
+    let settings = AssistantSettings {
+        url: "http://llm.invalid/v1/chat/completions".to_string(),
+        token: Some("dummy-token".to_string()),
+        chat_model: "some_model".to_string(),
+        stream: false,
+        proxy_url: Some(proxy_server.uri()),
+        proxy_user: Some("user".to_string()),
+        proxy_pass: Some("pass".to_string()),
+        ..Default::default()
+    };
+
+    let result = worker
+        .run(
+            1,
+            vec![test_view_selection_input("Hello")],
+            PromptMode::View,
+            settings,
+            Arc::new(|_| {}),
+            Arc::new(|_| {}),
+            Arc::new(|_| "".to_string()),
+            vec![],
+        )
+        .await;
+
+    assert!(
+        result.is_ok(),
+        "Expected Ok, got Err: {:?}",
+        result
+    );
+}
+
 #[tokio::test]
 async fn test_error_handler_called_on_http_failure() {
     // Setup temporary cache folder.
@@ -180,6 +464,7 @@ async fn test_error_handler_called_on_http_failure() {
     assistant_settings.token = Some("dummy-token".to_string());
     assistant_settings.chat_model = "some_model".to_string();
     assistant_settings.stream = false;
+    assistant_settings.stream_events = true;
 
     // Create an error accumulator.
     let errors: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
@@ -189,8 +474,14 @@ async fn test_error_handler_called_on_http_failure() {
         guard.push(msg);
     });
 
-    // A normal handler that does nothing.
-    let normal_handler = Arc::new(|_s: String| {});
+    let streamed = Arc::new(Mutex::new(vec![]));
+    let streamed_clone = Arc::clone(&streamed);
+    let normal_handler = Arc::new(move |data: String| {
+        streamed_clone
+            .lock()
+            .unwrap()
+            .push(data)
+    });
 
     let contents = vec![SublimeInputContent {
         content: Some("trigger error".to_string()),
@@ -209,6 +500,7 @@ async fn test_error_handler_called_on_http_failure() {
             normal_handler,
             error_handler,
             Arc::new(|_| "".to_string()),
+            vec![],
         )
         .await;
 
@@ -226,104 +518,326 @@ async fn test_error_handler_called_on_http_failure() {
         !errs.is_empty(),
         "Expected error_handler to be called when LlmRunner fails"
     );
-    assert!(
-        errs.iter()
-            .any(|msg| msg.contains("LlmRunner error")),
-        "Expected error message to contain 'LlmRunner error'"
+    // With `stream_events` on the error handler gets the same typed event the stream ends with.
+    assert!(matches!(
+        StreamEvent::from_json(&errs[0]),
+        Some(StreamEvent::Error {
+            kind: LlmErrorKind::Http,
+            status: Some(500),
+            ..
+        })
+    ));
+    assert_eq!(
+        streamed
+            .lock()
+            .unwrap()
+            .last(),
+        Some(&errs[0])
     );
 
     let _ = fs::remove_dir_all(tmp_dir);
 }
 
 #[tokio::test]
-async fn test_error_handler_not_called_on_success() {
-    // Setup temporary cache folder.
-    let tmp_dir = TempDir::new()
-        .unwrap()
-        .into_path()
-        .to_str()
-        .unwrap()
-        .to_string();
-    let worker = OpenAIWorker::new(1, tmp_dir.clone(), None);
+async fn test_handler_gets_plain_strings_by_default() {
+    let tmp_dir = TempDir::new().unwrap();
+    let worker = OpenAIWorker::new(
+        1,
+        tmp_dir
+            .path()
+            .to_str()
+            .unwrap()
+            .to_string(),
+        None,
+    );
 
-    // Start a mock server that returns a successful response.
     let mock_server = MockServer::start().await;
-    let endpoint = "/openai/endpoint";
-    let _mock = Mock::given(method("POST"))
-        .and(path(endpoint))
+    Mock::given(method("POST"))
         .respond_with(
             ResponseTemplate::new(200).set_body_json(json!({
                 "model": "some_model",
-                "id": "some_id",
-                "created": 367123,
                 "choices": [{
                     "index": 0,
-                    "message": {
-                        "role": "assistant",
-                        "content": "Success content",
-                        "refusal": null
-                    },
-                    "logprobs": null,
-                    "finish_reason": "stop"
+                    "message": {"role": "assistant", "content": "Cut sh"},
+                    "finish_reason": "length"
                 }]
             })),
         )
         .mount(&mock_server)
         .await;
 
-    let mut assistant_settings = AssistantSettings::default();
-    assistant_settings.url = format!("{}{}", mock_server.uri(), endpoint);
-    assistant_settings.token = Some("dummy-token".to_string());
-    assistant_settings.chat_model = "some_model".to_string();
-    assistant_settings.stream = false;
-
-    // Create an error accumulator.
-    let errors: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
-    let errors_clone = errors.clone();
-    let error_handler = Arc::new(move |msg: String| {
-        let mut guard = errors_clone.lock().unwrap();
-        guard.push(msg);
-    });
-
-    // A normal handler that does nothing.
-    let normal_handler = Arc::new(|_s: String| {});
-
-    let contents = vec![SublimeInputContent {
-        content: Some("test success".to_string()),
-        path: Some("dummy".to_string()),
-        scope: Some("dummy".to_string()),
-        input_kind: InputKind::ViewSelection,
-        tool_id: None,
-    }];
+    let assistant_settings = AssistantSettings {
+        url: mock_server.uri(),
+        stream: false,
+        ..Default::default()
+    };
 
-    let result = worker
+    let output = Arc::new(Mutex::new(vec![]));
+    let output_clone = Arc::clone(&output);
+    worker
         .run(
             1,
-            contents,
+            vec![test_view_selection_input("Hi")],
             PromptMode::View,
             assistant_settings,
-            normal_handler,
-            error_handler,
+            Arc::new(move |s| {
+                output_clone
+                    .lock()
+                    .unwrap()
+                    .push(s)
+            }),
+            Arc::new(|_| {}),
             Arc::new(|_| "".to_string()),
+            vec![],
         )
-        .await;
+        .await
+        .unwrap();
 
-    // Expect a successful result.
-    assert!(
-        result.is_ok(),
-        "Expected Ok result for successful endpoint"
-    );
+    assert_eq!(*output.lock().unwrap(), vec!["Cut sh"]);
+}
 
-    let errs = errors.lock().unwrap().clone();
-    assert!(
-        errs.is_empty(),
-        "Expected error_handler to not be called when LlmRunner succeeds"
+#[tokio::test]
+async fn test_error_handler_called_on_mid_stream_error_chunk() {
+    let temp_dir = TempDir::new().unwrap();
+    let worker = OpenAIWorker::new(
+        1,
+        temp_dir
+            .path()
+            .to_string_lossy()
+            .into_owned(),
+        None,
     );
 
-    let _ = fs::remove_dir_all(tmp_dir);
-}
-
-#[test]
+    let mock_server = MockServer::start().await;
+    let endpoint = "/openai/endpoint";
+    let _mock = Mock::given(method("POST"))
+        .and(path(endpoint))
+        .respond_with(sse_response(vec![
+            SseEvent::data(json!({
+                "model": "some_model",
+                "choices": [{
+                    "index": 0,
+                    "delta": {"role": "assistant", "content": "Half an"},
+                    "finish_reason": null
+                }]
+            })),
+            SseEvent::data(json!({
+                "error": {
+                    "message": "Rate limit reached for requests",
+                    "type": "rate_limit_error"
+                }
+            })),
+        ]))
+        .mount(&mock_server)
+        .await;
+
+    let errors: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+    let errors_clone = errors.clone();
+    let error_handler = Arc::new(move |msg: String| {
+        errors_clone
+            .lock()
+            .unwrap()
+            .push(msg);
+    });
+
+    let result = worker
+        .run(
+            1,
+            vec![test_view_selection_input(
+                "trigger error",
+            )],
+            PromptMode::View,
+            test_stream_settings(
+                format!("{}{}", mock_server.uri(), endpoint),
+                ApiType::OpenAi,
+            ),
+            Arc::new(|_s: String| {}),
+            error_handler,
+            Arc::new(|_| "".to_string()),
+            vec![],
+        )
+        .await;
+
+    assert!(result.is_err());
+    let errs = errors.lock().unwrap().clone();
+    assert!(
+        errs.iter()
+            .any(|msg| msg.contains("Rate limit reached for requests")),
+        "Expected the stream error to reach error_handler: {errs:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_error_handler_not_called_on_success() {
+    // Setup temporary cache folder.
+    let tmp_dir = TempDir::new()
+        .unwrap()
+        .into_path()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let worker = OpenAIWorker::new(1, tmp_dir.clone(), None);
+
+    // Start a mock server that returns a successful response.
+    let mock_server = MockServer::start().await;
+    let endpoint = "/openai/endpoint";
+    let _mock = Mock::given(method("POST"))
+        .and(path(endpoint))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({
+                "model": "some_model",
+                "id": "some_id",
+                "created": 367123,
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": "Success content",
+                        "refusal": null
+                    },
+                    "logprobs": null,
+                    "finish_reason": "stop"
+                }]
+            })),
+        )
+        .mount(&mock_server)
+        .await;
+
+    let mut assistant_settings = AssistantSettings::default();
+    assistant_settings.url = format!("{}{}", mock_server.uri(), endpoint);
+    assistant_settings.token = Some("dummy-token".to_string());
+    assistant_settings.chat_model = "some_model".to_string();
+    assistant_settings.stream = false;
+
+    // Create an error accumulator.
+    let errors: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+    let errors_clone = errors.clone();
+    let error_handler = Arc::new(move |msg: String| {
+        let mut guard = errors_clone.lock().unwrap();
+        guard.push(msg);
+    });
+
+    // A normal handler that does nothing.
+    let normal_handler = Arc::new(|_s: String| {});
+
+    let contents = vec![SublimeInputContent {
+        content: Some("test success".to_string()),
+        path: Some("dummy".to_string()),
+        scope: Some("dummy".to_string()),
+        input_kind: InputKind::ViewSelection,
+        tool_id: None,
+    }];
+
+    let result = worker
+        .run(
+            1,
+            contents,
+            PromptMode::View,
+            assistant_settings,
+            normal_handler,
+            error_handler,
+            Arc::new(|_| "".to_string()),
+            vec![],
+        )
+        .await;
+
+    // Expect a successful result.
+    assert!(
+        result.is_ok(),
+        "Expected Ok result for successful endpoint"
+    );
+
+    let errs = errors.lock().unwrap().clone();
+    assert!(
+        errs.is_empty(),
+        "Expected error_handler to not be called when LlmRunner succeeds"
+    );
+    assert_eq!(worker.token_count(), 2);
+
+    let _ = fs::remove_dir_all(tmp_dir);
+}
+
+#[tokio::test]
+async fn test_run_returns_every_choice_and_streams_the_first() {
+    let tmp_dir = TempDir::new().unwrap();
+    let worker = OpenAIWorker::new(
+        1,
+        tmp_dir
+            .path()
+            .to_string_lossy()
+            .into_owned(),
+        None,
+    );
+
+    let mock_server = MockServer::start().await;
+    let _mock = Mock::given(method("POST"))
+        .and(body_partial_json(json!({"n": 2})))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({
+                "model": "some_model",
+                "choices": [
+                    {
+                        "index": 0,
+                        "message": {"role": "assistant", "content": "First take"},
+                        "finish_reason": "stop"
+                    },
+                    {
+                        "index": 1,
+                        "message": {"role": "assistant", "content": "Second take"},
+                        "finish_reason": "stop"
+                    }
+                ]
+            })),
+        )
+        .mount(&mock_server)
+        .await;
+
+    let assistant_settings = AssistantSettings {
+        url: mock_server.uri(),
+        chat_model: "some_model".to_string(),
+        stream: false,
+        n: Some(2),
+        stream_events: true,
+        ..Default::default()
+    };
+
+    let output = Arc::new(Mutex::new(vec![]));
+    let output_clone = Arc::clone(&output);
+    let choices = worker
+        .run(
+            1,
+            vec![test_view_selection_input(
+                "Two takes, please",
+            )],
+            PromptMode::View,
+            assistant_settings,
+            Arc::new(move |s| {
+                output_clone
+                    .lock()
+                    .unwrap()
+                    .push(s)
+            }),
+            Arc::new(|_| {}),
+            Arc::new(|_| "".to_string()),
+            vec![],
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        choices,
+        vec!["First take", "Second take"]
+    );
+    assert_eq!(
+        *output.lock().unwrap(),
+        vec![
+            StreamEvent::from("First take").to_json(),
+            StreamEvent::Done.to_json()
+        ]
+    );
+}
+
+#[test]
 #[ignore = "It's llm local server depndant, so should be skipped by default"]
 async fn test_server_local_completion() {
     let tmp_dir = TempDir::new()
@@ -363,6 +877,7 @@ async fn test_server_local_completion() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "".to_string()),
+            vec![],
         )
         .await;
 
@@ -409,6 +924,7 @@ fn remote_settings(url: &str, token: String, model: &str, api_type: ApiType) ->
     assistant_settings.token = Some(token);
     assistant_settings.chat_model = model.to_string();
     assistant_settings.stream = true;
+    assistant_settings.stream_events = true;
     assistant_settings.api_type = api_type;
     assistant_settings
 }
@@ -448,6 +964,7 @@ async fn test_server_remote_completion() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "".to_string()),
+            vec![],
         )
         .await;
 
@@ -499,6 +1016,7 @@ async fn test_server_remote_complerion_cancelled() {
         }),
         Arc::new(|_| {}),
         Arc::new(|_| "".to_string()),
+        vec![],
     );
 
     worker.cancel();
@@ -508,11 +1026,16 @@ async fn test_server_remote_complerion_cancelled() {
     let output_final = output.lock().unwrap();
 
     assert!(
-        result.is_ok(),
-        "Expected Ok, got Err: {:?}",
+        matches!(
+            result
+                .as_ref()
+                .map_err(|e| e.downcast_ref::<LlmError>()),
+            Err(Some(LlmError::Cancelled))
+        ),
+        "Expected a cancelled run, got: {:?}",
         result
     );
-    assert!(output_final.contains(&"\n[ABORTED]".to_string()))
+    assert!(output_final.contains(&StreamEvent::Aborted.to_json()))
 }
 
 #[test]
@@ -552,6 +1075,7 @@ async fn test_server_remote_fucntion_call() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "Success".to_string()),
+            vec![],
         )
         .await;
 
@@ -603,6 +1127,7 @@ async fn test_server_remote_fucntion_call_parallel() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "Success".to_string()),
+            vec![],
         )
         .await;
 
@@ -644,6 +1169,7 @@ async fn test_server_remote_anthropic_completion() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "".to_string()),
+            vec![],
         )
         .await;
 
@@ -686,6 +1212,7 @@ async fn test_server_remote_anthropic_function_call() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "Success".to_string()),
+            vec![],
         )
         .await;
 
@@ -731,6 +1258,7 @@ async fn test_server_remote_anthropic_function_call_parallel() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "Success".to_string()),
+            vec![],
         )
         .await;
 
@@ -772,6 +1300,7 @@ async fn test_server_remote_google_completion() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "".to_string()),
+            vec![],
         )
         .await;
 
@@ -814,6 +1343,7 @@ async fn test_server_remote_google_function_call() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "Success".to_string()),
+            vec![],
         )
         .await;
 
@@ -859,6 +1389,7 @@ async fn test_server_remote_google_function_call_parallel() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "Success".to_string()),
+            vec![],
         )
         .await;
 
@@ -900,6 +1431,7 @@ async fn test_server_remote_together_completion() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "".to_string()),
+            vec![],
         )
         .await;
 
@@ -944,6 +1476,7 @@ async fn test_server_remote_together_function_call() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "Success".to_string()),
+            vec![],
         )
         .await;
 
@@ -989,6 +1522,7 @@ async fn test_server_remote_together_function_call_parallel() {
             Arc::new(|_| {}),
             Arc::new(|_| {}),
             Arc::new(|_| "Success".to_string()),
+            vec![],
         )
         .await;
 
@@ -1009,12 +1543,39 @@ fn test_view_selection_input(content: &str) -> SublimeInputContent {
     }
 }
 
+/// The text of the token events a handler got, in the order they came.
+fn streamed_text(streamed: &[String]) -> String {
+    streamed
+        .iter()
+        .filter_map(|data| StreamEvent::from_json(data))
+        .filter_map(|event| {
+            event
+                .text()
+                .map(str::to_string)
+        })
+        .collect()
+}
+
+fn streamed_function_calls(streamed: &[String]) -> Vec<String> {
+    streamed
+        .iter()
+        .filter_map(|data| StreamEvent::from_json(data))
+        .filter_map(|event| {
+            match event {
+                StreamEvent::FunctionCallStart(name) => Some(name),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
 fn test_stream_settings(url: String, api_type: ApiType) -> AssistantSettings {
     let mut settings = AssistantSettings::default();
     settings.url = url;
     settings.token = Some("dummy-token".to_string());
     settings.chat_model = "some_model".to_string();
     settings.stream = true;
+    settings.stream_events = true;
     settings.tools = Some(true);
     settings.parallel_tool_calls = Some(false);
     settings.api_type = api_type;
@@ -1180,6 +1741,7 @@ async fn test_worker_anthropic_streaming_tool_roundtrip_preserves_tool_input_ind
                     .push(payload.clone());
                 format!("tool-result for {}", payload.0)
             }),
+            vec![],
         )
         .await;
 
@@ -1252,11 +1814,11 @@ async fn test_worker_anthropic_streaming_tool_roundtrip_preserves_tool_input_ind
         "tool-result for get_working_directory_content"
     );
 
-    let streamed_output = streamed
-        .lock()
-        .unwrap()
-        .join("");
-    assert!(streamed_output.contains("- get_working_directory_content\n"));
+    let streamed_output = streamed_text(&streamed.lock().unwrap());
+    assert_eq!(
+        streamed_function_calls(&streamed.lock().unwrap()),
+        vec!["get_working_directory_content"]
+    );
     assert!(streamed_output.contains("Directory listing ready."));
 }
 
@@ -1362,6 +1924,7 @@ async fn test_worker_openai_responses_streaming_function_call_roundtrip() {
                     .push(payload.clone());
                 "workspace listing".to_string()
             }),
+            vec![],
         )
         .await;
 
@@ -1430,12 +1993,12 @@ async fn test_worker_openai_responses_streaming_function_call_roundtrip() {
         "workspace listing"
     );
 
-    let streamed_output = streamed
-        .lock()
-        .unwrap()
-        .join("");
+    let streamed_output = streamed_text(&streamed.lock().unwrap());
     assert!(streamed_output.contains("Let me call a tool. "));
-    assert!(streamed_output.contains("- get_working_directory_content\n"));
+    assert_eq!(
+        streamed_function_calls(&streamed.lock().unwrap()),
+        vec!["get_working_directory_content"]
+    );
     assert!(streamed_output.contains("Directory listing ready."));
 }
 
@@ -1605,6 +2168,7 @@ async fn test_worker_openai_streaming_function_call_roundtrip() {
                     .push(payload.clone());
                 "workspace listing".to_string()
             }),
+            vec![],
         )
         .await;
 
@@ -1669,13 +2233,39 @@ async fn test_worker_openai_streaming_function_call_roundtrip() {
         "workspace listing"
     );
 
-    let streamed_output = streamed
+    let streamed_output = streamed_text(&streamed.lock().unwrap());
+    assert_eq!(
+        streamed_output,
+        "Let me call a tool. Directory listing ready."
+    );
+    assert_eq!(
+        streamed_function_calls(&streamed.lock().unwrap()),
+        vec!["get_working_directory_content"]
+    );
+    let events: Vec<StreamEvent> = streamed
         .lock()
         .unwrap()
-        .join("");
+        .iter()
+        .filter_map(|data| StreamEvent::from_json(data))
+        .collect();
+    let streamed_arguments: String = events
+        .iter()
+        .filter_map(|event| {
+            match event {
+                StreamEvent::FunctionCallArguments { delta, .. } => Some(delta.as_str()),
+                _ => None,
+            }
+        })
+        .collect();
     assert_eq!(
-        streamed_output,
-        "Let me call a tool. - get_working_directory_content\nDirectory listing ready."
+        streamed_arguments,
+        "{\"directory_path\":\".\",\"respect_gitignore\":true}"
+    );
+    assert!(
+        events.contains(&StreamEvent::FunctionCallEnd {
+            name: "get_working_directory_content".to_string(),
+            arguments: "{\"directory_path\":\".\",\"respect_gitignore\":true}".to_string(),
+        })
     );
 }
 
@@ -1759,6 +2349,7 @@ data: [DONE]
                     .push(payload.clone());
                 "workspace listing".to_string()
             }),
+            vec![],
         )
         .await;
 
@@ -1901,6 +2492,7 @@ async fn test_worker_openai_responses_streaming_multiple_tool_calls_roundtrip()
                     other => panic!("Unexpected function: {other}"),
                 }
             }),
+            vec![],
         )
         .await;
 
@@ -1976,15 +2568,10 @@ async fn test_worker_openai_responses_streaming_multiple_tool_calls_roundtrip()
         "selection contents"
     );
 
-    let streamed_output = streamed
-        .lock()
-        .unwrap()
-        .join("");
+    let streamed_output = streamed_text(&streamed.lock().unwrap());
     assert!(streamed_output.contains("Let me call two tools. "));
     assert_eq!(
-        streamed_output
-            .matches("- ")
-            .count(),
+        streamed_function_calls(&streamed.lock().unwrap()).len(),
         2,
         "Each streamed tool call should emit exactly one UI marker",
     );
@@ -1992,7 +2579,7 @@ async fn test_worker_openai_responses_streaming_multiple_tool_calls_roundtrip()
 }
 
 #[tokio::test]
-async fn test_worker_google_streaming_regression_mixed_text_and_function_call_roundtrip() {
+async fn test_worker_openai_responses_named_sse_events_replay() {
     let temp_dir = TempDir::new().unwrap();
     let worker = OpenAIWorker::new(
         1,
@@ -2003,55 +2590,251 @@ async fn test_worker_google_streaming_regression_mixed_text_and_function_call_ro
         None,
     );
 
+    let completed_output = json!([
+        {
+            "id": "msg_1",
+            "type": "message",
+            "status": "completed",
+            "role": "assistant",
+            "content": [{"type": "output_text", "text": "Checking both.", "annotations": []}]
+        },
+        {
+            "id": "fc_1",
+            "type": "function_call",
+            "status": "completed",
+            "call_id": "call_a",
+            "name": "get_working_directory_content",
+            "arguments": "{\"directory_path\":\".\"}"
+        },
+        {
+            "id": "fc_2",
+            "type": "function_call",
+            "status": "completed",
+            "call_id": "call_b",
+            "name": "read_region_content",
+            "arguments": "{\"region_id\":\"selection\"}"
+        }
+    ]);
+
     let mock_server = MockServer::start().await;
-    let endpoint = "/models/some_model:streamGenerateContent";
+    let endpoint = "/responses";
     let responder = RecordedSequentialResponder::new(vec![
         sse_response(vec![
-            SseEvent::data(json!({
-                "candidates": [{
-                    "content": {
-                        "role": "model",
-                        "parts": [{
-                            "text": "I'll inspect "
-                        }]
-                    }
-                }]
-            })),
-            SseEvent::data(json!({
-                "candidates": [{
-                    "content": {
-                        "role": "model",
-                        "parts": [
-                            {
-                                "text": "I'll inspect the workspace. "
-                            },
-                            {
-                                "thoughtSignature": "sig_123",
-                                "functionCall": {
-                                    "name": "get_working_directory_content",
-                                    "args": {
-                                        "directory_path": "."
-                                    }
-                                }
-                            }
-                        ]
-                    }
-                }]
-            })),
-            SseEvent::data(json!({
-                "candidates": [{
-                    "content": {
-                        "role": "model",
-                        "parts": [
-                            {
-                                "text": "I'll inspect the workspace. "
-                            },
-                            {
-                                "thoughtSignature": "sig_123",
-                                "functionCall": {
-                                    "name": "get_working_directory_content",
-                                    "args": {
-                                        "directory_path": "."
+            SseEvent::named(
+                "response.created",
+                json!({"type": "response.created", "sequence_number": 0, "response": {"id": "resp_1", "status": "in_progress", "output": []}}),
+            ),
+            SseEvent::named(
+                "response.in_progress",
+                json!({"type": "response.in_progress", "sequence_number": 1, "response": {"id": "resp_1", "status": "in_progress", "output": []}}),
+            ),
+            SseEvent::named(
+                "response.output_item.added",
+                json!({"type": "response.output_item.added", "sequence_number": 2, "output_index": 0, "item": {"id": "msg_1", "type": "message", "status": "in_progress", "role": "assistant", "content": []}}),
+            ),
+            SseEvent::named(
+                "response.content_part.added",
+                json!({"type": "response.content_part.added", "sequence_number": 3, "item_id": "msg_1", "output_index": 0, "content_index": 0, "part": {"type": "output_text", "text": "", "annotations": []}}),
+            ),
+            SseEvent::named(
+                "response.output_text.delta",
+                json!({"type": "response.output_text.delta", "sequence_number": 4, "item_id": "msg_1", "output_index": 0, "content_index": 0, "delta": "Checking"}),
+            ),
+            SseEvent::named(
+                "response.output_text.delta",
+                json!({"type": "response.output_text.delta", "sequence_number": 5, "item_id": "msg_1", "output_index": 0, "content_index": 0, "delta": " both."}),
+            ),
+            SseEvent::named(
+                "response.output_text.done",
+                json!({"type": "response.output_text.done", "sequence_number": 6, "item_id": "msg_1", "output_index": 0, "content_index": 0, "text": "Checking both."}),
+            ),
+            SseEvent::named(
+                "response.output_item.added",
+                json!({"type": "response.output_item.added", "sequence_number": 7, "output_index": 1, "item": {"id": "fc_1", "type": "function_call", "status": "in_progress", "call_id": "call_a", "name": "get_working_directory_content", "arguments": ""}}),
+            ),
+            SseEvent::named(
+                "response.output_item.added",
+                json!({"type": "response.output_item.added", "sequence_number": 8, "output_index": 2, "item": {"id": "fc_2", "type": "function_call", "status": "in_progress", "call_id": "call_b", "name": "read_region_content", "arguments": ""}}),
+            ),
+            SseEvent::named(
+                "response.function_call_arguments.delta",
+                json!({"type": "response.function_call_arguments.delta", "sequence_number": 9, "item_id": "fc_1", "output_index": 1, "delta": "{\"directory_path\":"}),
+            ),
+            SseEvent::named(
+                "response.function_call_arguments.delta",
+                json!({"type": "response.function_call_arguments.delta", "sequence_number": 10, "item_id": "fc_2", "output_index": 2, "delta": "{\"region_id\":"}),
+            ),
+            SseEvent::named(
+                "response.function_call_arguments.delta",
+                json!({"type": "response.function_call_arguments.delta", "sequence_number": 11, "item_id": "fc_1", "output_index": 1, "delta": "\".\"}"}),
+            ),
+            SseEvent::named(
+                "response.function_call_arguments.delta",
+                json!({"type": "response.function_call_arguments.delta", "sequence_number": 12, "item_id": "fc_2", "output_index": 2, "delta": "\"selection\"}"}),
+            ),
+            SseEvent::named(
+                "response.completed",
+                json!({"type": "response.completed", "sequence_number": 13, "response": {"id": "resp_1", "status": "completed", "output": completed_output}}),
+            ),
+        ]),
+        sse_response(vec![
+            SseEvent::named(
+                "response.output_text.delta",
+                json!({"type": "response.output_text.delta", "sequence_number": 0, "item_id": "msg_2", "output_index": 0, "content_index": 0, "delta": "Done."}),
+            ),
+            SseEvent::named(
+                "response.completed",
+                json!({"type": "response.completed", "sequence_number": 1, "response": {"id": "resp_2", "status": "completed", "output": [{"id": "msg_2", "type": "message", "role": "assistant", "content": [{"type": "output_text", "text": "Done."}]}]}}),
+            ),
+        ]),
+    ]);
+
+    Mock::given(method("POST"))
+        .and(path(endpoint))
+        .respond_with(responder.clone())
+        .mount(&mock_server)
+        .await;
+
+    let streamed = Arc::new(Mutex::new(Vec::<String>::new()));
+    let streamed_clone = Arc::clone(&streamed);
+    let function_calls = Arc::new(Mutex::new(
+        Vec::<(String, String)>::new(),
+    ));
+    let function_calls_clone = Arc::clone(&function_calls);
+
+    let result = worker
+        .run(
+            1,
+            vec![test_view_selection_input(
+                "Look around.",
+            )],
+            PromptMode::View,
+            test_stream_settings(
+                format!("{}{}", mock_server.uri(), endpoint),
+                ApiType::OpenAiResponses,
+            ),
+            Arc::new(move |chunk| {
+                streamed_clone
+                    .lock()
+                    .unwrap()
+                    .push(chunk)
+            }),
+            Arc::new(|_| {}),
+            Arc::new(move |payload| {
+                function_calls_clone
+                    .lock()
+                    .unwrap()
+                    .push(payload.clone());
+                format!("result of {}", payload.0)
+            }),
+            vec![],
+        )
+        .await;
+
+    assert!(
+        result.is_ok(),
+        "Expected Ok, got Err: {:?}",
+        result
+    );
+
+    assert_eq!(
+        function_calls
+            .lock()
+            .unwrap()
+            .as_slice(),
+        &[
+            (
+                "get_working_directory_content".to_string(),
+                r#"{"directory_path":"."}"#.to_string(),
+            ),
+            (
+                "read_region_content".to_string(),
+                r#"{"region_id":"selection"}"#.to_string(),
+            ),
+        ],
+    );
+
+    let request_bodies = responder.recorded_json_bodies();
+    assert_eq!(request_bodies.len(), 2);
+    assert_eq!(
+        request_bodies[1]["previous_response_id"],
+        "resp_1"
+    );
+    let second_input = as_array(&request_bodies[1], "input");
+    assert_eq!(second_input.len(), 2);
+    assert_eq!(second_input[0]["call_id"], "call_a");
+    assert_eq!(
+        second_input[0]["output"],
+        "result of get_working_directory_content"
+    );
+    assert_eq!(second_input[1]["call_id"], "call_b");
+
+    let streamed_output = streamed_text(&streamed.lock().unwrap());
+    assert!(streamed_output.starts_with("Checking both."));
+    assert!(streamed_output.ends_with("Done."));
+}
+
+#[tokio::test]
+async fn test_worker_google_streaming_regression_mixed_text_and_function_call_roundtrip() {
+    let temp_dir = TempDir::new().unwrap();
+    let worker = OpenAIWorker::new(
+        1,
+        temp_dir
+            .path()
+            .to_string_lossy()
+            .into_owned(),
+        None,
+    );
+
+    let mock_server = MockServer::start().await;
+    let endpoint = "/models/some_model:streamGenerateContent";
+    let responder = RecordedSequentialResponder::new(vec![
+        sse_response(vec![
+            SseEvent::data(json!({
+                "candidates": [{
+                    "content": {
+                        "role": "model",
+                        "parts": [{
+                            "text": "I'll inspect "
+                        }]
+                    }
+                }]
+            })),
+            SseEvent::data(json!({
+                "candidates": [{
+                    "content": {
+                        "role": "model",
+                        "parts": [
+                            {
+                                "text": "I'll inspect the workspace. "
+                            },
+                            {
+                                "thoughtSignature": "sig_123",
+                                "functionCall": {
+                                    "name": "get_working_directory_content",
+                                    "args": {
+                                        "directory_path": "."
+                                    }
+                                }
+                            }
+                        ]
+                    }
+                }]
+            })),
+            SseEvent::data(json!({
+                "candidates": [{
+                    "content": {
+                        "role": "model",
+                        "parts": [
+                            {
+                                "text": "I'll inspect the workspace. "
+                            },
+                            {
+                                "thoughtSignature": "sig_123",
+                                "functionCall": {
+                                    "name": "get_working_directory_content",
+                                    "args": {
+                                        "directory_path": "."
                                     }
                                 }
                             }
@@ -2151,6 +2934,7 @@ async fn test_worker_google_streaming_regression_mixed_text_and_function_call_ro
                     .push(payload.clone());
                 r#"{"entries":["src","tests"]}"#.to_string()
             }),
+            vec![],
         )
         .await;
 
@@ -2231,19 +3015,14 @@ async fn test_worker_google_streaming_regression_mixed_text_and_function_call_ro
         }),
     );
 
-    let streamed_output = streamed
-        .lock()
-        .unwrap()
-        .join("");
+    let streamed_output = streamed_text(&streamed.lock().unwrap());
     assert_eq!(
         streamed_output,
-        "I'll inspect the workspace. - get_working_directory_content\nDirectory listing ready."
+        "I'll inspect the workspace. Directory listing ready."
     );
     assert_eq!(
-        streamed_output
-            .matches("- get_working_directory_content\n")
-            .count(),
-        1,
+        streamed_function_calls(&streamed.lock().unwrap()),
+        vec!["get_working_directory_content"],
         "Tool marker should not be emitted again when Google re-sends the same functionCall",
     );
 }
@@ -2343,6 +3122,7 @@ async fn test_worker_openai_responses_non_streaming_multiple_tool_calls_roundtri
                     other => panic!("Unexpected tool call: {other}"),
                 }
             }),
+            vec![],
         )
         .await;
 
@@ -2377,59 +3157,522 @@ async fn test_worker_openai_responses_non_streaming_multiple_tool_calls_roundtri
         "Expected exactly two API requests"
     );
 
+    assert!(
+        request_bodies[0]
+            .get("previous_response_id")
+            .is_none(),
+        "The first turn has nothing to chain onto"
+    );
+    assert_eq!(
+        request_bodies[1]["previous_response_id"],
+        "resp_1"
+    );
+
     let second_input = as_array(&request_bodies[1], "input");
     assert_eq!(
         second_input.len(),
-        6,
-        "Expected user message, assistant text, two function_call items, and two function_call_output items",
+        2,
+        "Chained request should carry only the two function_call_output items",
     );
 
-    assert_eq!(second_input[1]["type"], "message");
-    assert_eq!(second_input[1]["role"], "assistant");
     assert_eq!(
-        second_input[1]["content"][0]["text"],
-        "I need two tools."
+        second_input[0]["type"],
+        "function_call_output"
+    );
+    assert_eq!(second_input[0]["call_id"], "call_1");
+    assert_eq!(
+        second_input[0]["output"],
+        r#"{"entries":["src","tests"]}"#
     );
 
-    assert_eq!(second_input[2]["type"], "function_call");
-    assert_eq!(second_input[2]["call_id"], "call_1");
     assert_eq!(
-        second_input[2]["name"],
+        second_input[1]["type"],
+        "function_call_output"
+    );
+    assert_eq!(second_input[1]["call_id"], "call_2");
+    assert_eq!(
+        second_input[1]["output"],
+        r#"{"content":"pub mod stream_handler;"}"#
+    );
+
+    assert_eq!(
+        fs::read_to_string(
+            temp_dir
+                .path()
+                .join("last_response_id.json")
+        )
+        .unwrap()
+        .trim(),
+        "\"resp_2\""
+    );
+}
+
+#[tokio::test]
+async fn test_worker_ollama_ndjson_streaming_tool_roundtrip_writes_tokens_count() {
+    let temp_dir = TempDir::new().unwrap();
+    let worker = OpenAIWorker::new(
+        1,
+        temp_dir
+            .path()
+            .to_string_lossy()
+            .into_owned(),
+        None,
+    );
+
+    let ndjson = |lines: Vec<Value>| {
+        let body: String = lines
+            .iter()
+            .map(|line| format!("{line}\n"))
+            .collect();
+        ResponseTemplate::new(200)
+            .insert_header("content-type", "application/x-ndjson")
+            .set_body_string(body)
+    };
+
+    let mock_server = MockServer::start().await;
+    let endpoint = "/api/chat";
+    let responder = RecordedSequentialResponder::new(vec![
+        ndjson(vec![
+            json!({
+                "model": "llama3.2",
+                "message": {
+                    "role": "assistant",
+                    "content": "",
+                    "tool_calls": [{
+                        "function": {
+                            "name": "get_working_directory_content",
+                            "arguments": {"directory_path": "."}
+                        }
+                    }]
+                },
+                "done": false
+            }),
+            json!({
+                "model": "llama3.2",
+                "message": {"role": "assistant", "content": ""},
+                "done": true,
+                "done_reason": "stop",
+                "prompt_eval_count": 40,
+                "eval_count": 12,
+                "total_duration": 1_500_000_000u64
+            }),
+        ]),
+        ndjson(vec![
+            json!({"model": "llama3.2", "message": {"role": "assistant", "content": "Listing "}, "done": false}),
+            json!({"model": "llama3.2", "message": {"role": "assistant", "content": "ready."}, "done": false}),
+            json!({
+                "model": "llama3.2",
+                "message": {"role": "assistant", "content": ""},
+                "done": true,
+                "prompt_eval_count": 60,
+                "eval_count": 3
+            }),
+        ]),
+    ]);
+
+    Mock::given(method("POST"))
+        .and(path(endpoint))
+        .respond_with(responder.clone())
+        .mount(&mock_server)
+        .await;
+
+    let streamed = Arc::new(Mutex::new(Vec::<String>::new()));
+    let streamed_clone = Arc::clone(&streamed);
+
+    let mut settings = test_stream_settings(
+        format!("{}{}", mock_server.uri(), endpoint),
+        ApiType::Ollama,
+    );
+    settings.num_ctx = Some(8192);
+    settings.keep_alive = Some("10m".to_string());
+
+    let result = worker
+        .run(
+            1,
+            vec![test_view_selection_input(
+                "List the files.",
+            )],
+            PromptMode::View,
+            settings,
+            Arc::new(move |chunk| {
+                streamed_clone
+                    .lock()
+                    .unwrap()
+                    .push(chunk)
+            }),
+            Arc::new(|_| {}),
+            Arc::new(|payload| format!("tool-result for {}", payload.0)),
+            vec![],
+        )
+        .await;
+
+    assert!(
+        result.is_ok(),
+        "Expected Ok, got Err: {:?}",
+        result
+    );
+
+    let request_bodies = responder.recorded_json_bodies();
+    assert_eq!(request_bodies.len(), 2);
+    assert_eq!(
+        request_bodies[0]["options"]["num_ctx"],
+        8192
+    );
+    assert_eq!(request_bodies[0]["keep_alive"], "10m");
+
+    let second_messages = as_array(&request_bodies[1], "messages");
+    let assistant = second_messages
+        .iter()
+        .find(|message| message["role"] == "assistant")
+        .expect("Expected assistant tool call message");
+    assert_eq!(
+        assistant["tool_calls"][0]["function"]["arguments"],
+        json!({"directory_path": "."})
+    );
+    let tool = second_messages
+        .iter()
+        .find(|message| message["role"] == "tool")
+        .expect("Expected tool result message");
+    assert_eq!(
+        tool["tool_name"],
         "get_working_directory_content"
     );
     assert_eq!(
-        second_input[2]["arguments"],
-        r#"{"directory_path":".","respect_gitignore":true}"#
+        tool["content"],
+        "tool-result for get_working_directory_content"
     );
 
-    assert_eq!(second_input[3]["type"], "function_call");
-    assert_eq!(second_input[3]["call_id"], "call_2");
+    let streamed_output = streamed_text(&streamed.lock().unwrap());
     assert_eq!(
-        second_input[3]["name"],
-        "read_region_content"
+        streamed_function_calls(&streamed.lock().unwrap()),
+        vec!["get_working_directory_content"]
     );
+    assert!(streamed_output.contains("Listing ready."));
+
+    let tokens_count: Value = serde_json::from_str(
+        &fs::read_to_string(
+            temp_dir
+                .path()
+                .join("tokens_count.json"),
+        )
+        .unwrap(),
+    )
+    .unwrap();
     assert_eq!(
-        second_input[3]["arguments"],
-        r#"{"file_path":"src/lib.rs","region":{"a":0,"b":5}}"#
+        tokens_count,
+        json!({"prompt_tokens": 100, "completion_tokens": 15, "total_tokens": 115, "total_time": 1.5})
+    );
+}
+
+#[tokio::test]
+async fn test_worker_openai_generate_image_tool_roundtrip() {
+    let temp_dir = TempDir::new().unwrap();
+    let worker = OpenAIWorker::new(
+        1,
+        temp_dir
+            .path()
+            .to_string_lossy()
+            .into_owned(),
+        None,
+    );
+
+    let mock_server = MockServer::start().await;
+    let endpoint = "/v1/chat/completions";
+    let responder = RecordedSequentialResponder::new(vec![
+        ResponseTemplate::new(200).set_body_json(json!({
+            "id": "chatcmpl_1",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "some_model",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [
+                        {
+                            "id": "call_ok",
+                            "type": "function",
+                            "function": {
+                                "name": "generate_image",
+                                "arguments": "{\"prompt\":\"a red square\",\"size\":\"1024x1024\",\"quality\":null}"
+                            }
+                        },
+                        {
+                            "id": "call_refused",
+                            "type": "function",
+                            "function": {
+                                "name": "generate_image",
+                                "arguments": "{\"prompt\":\"something forbidden\",\"size\":null,\"quality\":null}"
+                            }
+                        }
+                    ]
+                },
+                "finish_reason": "tool_calls"
+            }]
+        })),
+        ResponseTemplate::new(200).set_body_json(json!({
+            "id": "chatcmpl_2",
+            "object": "chat.completion",
+            "created": 2,
+            "model": "some_model",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": "Here is your image."
+                },
+                "finish_reason": "stop"
+            }]
+        })),
+    ]);
+
+    Mock::given(method("POST"))
+        .and(path(endpoint))
+        .respond_with(responder.clone())
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/v1/images/generations"))
+        .and(body_partial_json(json!({
+            "model": "gpt-image-1",
+            "prompt": "a red square",
+            "size": "1024x1024"
+        })))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({
+                "created": 1,
+                "data": [{"b64_json": "iVBORw0KGgo="}]
+            })),
+        )
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/v1/images/generations"))
+        .and(body_partial_json(
+            json!({"prompt": "something forbidden"}),
+        ))
+        .respond_with(
+            ResponseTemplate::new(400).set_body_json(json!({
+                "error": {
+                    "message": "Your request was rejected as a result of our safety system.",
+                    "type": "invalid_request_error",
+                    "code": "content_policy_violation"
+                }
+            })),
+        )
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let function_calls = Arc::new(Mutex::new(
+        Vec::<(String, String)>::new(),
+    ));
+    let function_calls_clone = Arc::clone(&function_calls);
+    let mut settings = test_stream_settings(
+        format!("{}{}", mock_server.uri(), endpoint),
+        ApiType::OpenAi,
+    );
+    settings.stream = false;
+
+    let result = worker
+        .run(
+            1,
+            vec![test_view_selection_input(
+                "Draw two pictures.",
+            )],
+            PromptMode::View,
+            settings,
+            Arc::new(|_| {}),
+            Arc::new(|_| {}),
+            Arc::new(move |payload| {
+                function_calls_clone
+                    .lock()
+                    .unwrap()
+                    .push(payload);
+                "unexpected".to_string()
+            }),
+            vec![],
+        )
+        .await;
+
+    assert!(
+        result.is_ok(),
+        "Expected Ok, got Err: {:?}",
+        result
+    );
+    assert!(
+        function_calls
+            .lock()
+            .unwrap()
+            .is_empty(),
+        "generate_image must not reach the plugin function handler"
     );
 
+    let image_path = temp_dir
+        .path()
+        .join("images")
+        .join("call_ok.png");
     assert_eq!(
-        second_input[4]["type"],
-        "function_call_output"
+        fs::read(&image_path).unwrap(),
+        b"\x89PNG\r\n\x1a\n"
     );
-    assert_eq!(second_input[4]["call_id"], "call_1");
+
+    let request_bodies = responder.recorded_json_bodies();
+    let second_messages = as_array(&request_bodies[1], "messages");
     assert_eq!(
-        second_input[4]["output"],
-        r#"{"entries":["src","tests"]}"#
+        second_messages[2]["tool_call_id"],
+        "call_ok"
+    );
+    assert_eq!(
+        second_messages[2]["content"][0]["text"],
+        image_path
+            .to_string_lossy()
+            .as_ref()
+    );
+    assert_eq!(
+        second_messages[3]["tool_call_id"],
+        "call_refused"
+    );
+    assert!(
+        second_messages[3]["content"][0]["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("Image generation failed: ")
+    );
+    assert!(
+        second_messages[3]["content"][0]["text"]
+            .as_str()
+            .unwrap()
+            .contains("rejected as a result of our safety system")
+    );
+}
+
+#[tokio::test]
+async fn test_worker_openai_json_schema_response_format() {
+    let temp_dir = TempDir::new().unwrap();
+    let worker = OpenAIWorker::new(
+        1,
+        temp_dir
+            .path()
+            .to_string_lossy()
+            .into_owned(),
+        None,
+    );
+
+    let mock_server = MockServer::start().await;
+    let endpoint = "/openai/endpoint";
+    let responder = RecordedSequentialResponder::new(vec![sse_response(vec![
+        SseEvent::data(json!({
+            "created": 1,
+            "choices": [{"delta": {"role": "assistant", "content": "{\"ok\":"}, "finish_reason": null, "index": 0}],
+            "id": "chatcmpl_1",
+            "model": "some_model",
+            "object": "chat.completion.chunk"
+        })),
+        SseEvent::data(json!({
+            "created": 1,
+            "choices": [{"delta": {"content": " true}"}, "finish_reason": "stop", "index": 0}],
+            "id": "chatcmpl_1",
+            "model": "some_model",
+            "object": "chat.completion.chunk"
+        })),
+    ])]);
+    Mock::given(method("POST"))
+        .and(path(endpoint))
+        .respond_with(responder.clone())
+        .mount(&mock_server)
+        .await;
+
+    let schema = json!({
+        "name": "answer",
+        "strict": true,
+        "schema": {"type": "object", "properties": {"ok": {"type": "boolean"}}, "required": ["ok"]}
+    });
+    let mut settings = test_stream_settings(
+        format!("{}{}", mock_server.uri(), endpoint),
+        ApiType::OpenAi,
     );
+    settings.tools = None;
+    settings.response_format = Some(ResponseFormat::JsonSchema {
+        schema: schema
+            .as_object()
+            .cloned()
+            .unwrap(),
+    });
 
+    let streamed = Arc::new(Mutex::new(Vec::<String>::new()));
+    let streamed_clone = Arc::clone(&streamed);
+    let result = worker
+        .run(
+            1,
+            vec![test_view_selection_input("Is it ok?")],
+            PromptMode::View,
+            settings.clone(),
+            Arc::new(move |chunk| {
+                streamed_clone
+                    .lock()
+                    .unwrap()
+                    .push(chunk)
+            }),
+            Arc::new(|_| {}),
+            Arc::new(|_| "".to_string()),
+            vec![],
+        )
+        .await;
+    assert!(
+        result.is_ok(),
+        "Expected Ok, got Err: {:?}",
+        result
+    );
     assert_eq!(
-        second_input[5]["type"],
-        "function_call_output"
+        streamed_text(&streamed.lock().unwrap()),
+        "{\"ok\": true}"
     );
-    assert_eq!(second_input[5]["call_id"], "call_2");
     assert_eq!(
-        second_input[5]["output"],
-        r#"{"content":"pub mod stream_handler;"}"#
+        responder.recorded_json_bodies()[0]["response_format"],
+        json!({"type": "json_schema", "json_schema": schema})
+    );
+
+    // A schema without a name is reported through the error handler and never sent.
+    let errors = Arc::new(Mutex::new(Vec::<String>::new()));
+    let errors_clone = Arc::clone(&errors);
+    settings.response_format = Some(ResponseFormat::JsonSchema {
+        schema: json!({"schema": {"type": "object"}})
+            .as_object()
+            .cloned()
+            .unwrap(),
+    });
+    let result = worker
+        .run(
+            1,
+            vec![test_view_selection_input("Is it ok?")],
+            PromptMode::View,
+            settings,
+            Arc::new(|_| {}),
+            Arc::new(move |error| {
+                errors_clone
+                    .lock()
+                    .unwrap()
+                    .push(error)
+            }),
+            Arc::new(|_| "".to_string()),
+            vec![],
+        )
+        .await;
+    assert!(result.is_err());
+    assert!(
+        errors
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|error| error.contains("response_format json_schema requires a `name`"))
+    );
+    assert_eq!(
+        responder
+            .recorded_json_bodies()
+            .len(),
+        1
     );
 }